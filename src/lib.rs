@@ -14,6 +14,10 @@
 //!    rm      Multithreaded directory remove
 //!    sync    Multithreaded directory synchronization [aliases: s]
 //! ```
+//!
+//! It can also be used as a library, through `sync`, `copy`, and `remove` with `SyncOptions`.
 
 mod lumins;
+pub use lumins::api::{copy, remove, sync, SyncOptions};
+pub use lumins::parse::{DestNewer, Existing, Flag, Reflink};
 pub use lumins::*;
@@ -0,0 +1,599 @@
+//! Contains the library interface of LuminS, which lets other programs copy, synchronize, and
+//! remove directories without going through the command line.
+//!
+//! ```no_run
+//! use lms::{Flag, SyncOptions};
+//!
+//! let options = SyncOptions::new()
+//!     .flags(Flag::SECURE | Flag::NO_DELETE)
+//!     .threads(4)
+//!     .exclude("*.tmp")
+//!     .include("keep.tmp");
+//! lms::sync("photos", "/mnt/backup/photos", &options).unwrap();
+//! ```
+//!
+//! Calls made at the same time in different threads run at the same time. Every call is a job of
+//! its own, which keeps what it is configured with, such as its backup directory or its
+//! transformations, what it records, such as the files that vanished while it ran, and its
+//! progress apart from every other call, so that calls report their progress independently, such
+//! as on the bars of a `MultiProgress`, given with `progress_bar`. Every call also runs in a
+//! thread pool of its own, rather than the global thread pool, so that calls can use different
+//! numbers of threads, unless the calling program gives one with `thread_pool`. Calls never change
+//! environment variables, which is unsafe while other threads read them, so logging is left to the
+//! calling program.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::time::Duration;
+
+use indicatif::ProgressBar;
+use lazy_static::lazy_static;
+use rayon::ThreadPool;
+
+use crate::lumins::{
+    context, core,
+    filter::{Filter, Order},
+    mounts::MountTable,
+    parse::{self, DestNewer, Existing, Flag, Options, Reflink},
+    paths,
+    progress::{self, Job},
+    transform::Transform,
+};
+
+/// Thread pool given with `thread_pool`, and the lock held by the call that runs in it
+type PoolLock = (Weak<ThreadPool>, Arc<Mutex<()>>);
+
+lazy_static! {
+    /// Locks of the thread pools given with `thread_pool`
+    static ref POOLS: Mutex<Vec<PoolLock>> = Mutex::new(Vec::new());
+}
+
+/// Struct to build the options of `sync`, `copy`, and `remove`
+///
+/// Nothing is logged unless the calling program installs a logger for the `log` crate, and no
+/// progress bar is drawn unless it is enabled with `progress`, or given with `progress_bar`.
+#[derive(Clone, Debug, Default)]
+pub struct SyncOptions {
+    flags: Flag,
+    threads: Option<usize>,
+    exclude: Vec<String>,
+    include: Vec<String>,
+    order: Vec<Vec<String>>,
+    on_dest_newer: DestNewer,
+    reflink: Reflink,
+    existing: Existing,
+    stall_timeout: Option<Duration>,
+    large_file_size: Option<u64>,
+    large_file_jobs: Option<usize>,
+    block_size: Option<usize>,
+    read_bwlimit: Option<u64>,
+    checksum_under: Option<u64>,
+    modify_window: Option<Duration>,
+    transforms: Vec<Transform>,
+    max_dest_size: Option<u64>,
+    backup_dir: Option<PathBuf>,
+    progress: bool,
+    progress_bar: Option<Bar>,
+    thread_pool: Option<Arc<ThreadPool>>,
+}
+
+/// Struct to hold the progress bar given with `progress_bar`, which has no `Debug` of its own
+#[derive(Clone)]
+struct Bar(ProgressBar);
+
+impl fmt::Debug for Bar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ProgressBar")
+    }
+}
+
+impl SyncOptions {
+    /// Creates options with no flags set, which behave like the command without any options
+    pub fn new() -> Self {
+        SyncOptions::default()
+    }
+
+    /// Sets the flags, which match the command line flags of the same name
+    pub fn flags(mut self, flags: Flag) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Sets the number of threads, instead of one per CPU
+    ///
+    /// The threads only serve this call, so different calls may use different numbers of threads.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+
+    /// Sets the thread pool that every call with these options runs in, instead of one of its own
+    /// with `threads` threads
+    ///
+    /// The threads of the pool work for a call while it runs, so calls given the same pool run one
+    /// after the other. `Flag::SEQUENTIAL` still runs a call in one thread of its own.
+    pub fn thread_pool(mut self, thread_pool: Arc<ThreadPool>) -> Self {
+        self.thread_pool = Some(thread_pool);
+        self
+    }
+
+    /// Adds a glob pattern of the paths to skip, like --exclude
+    pub fn exclude(mut self, pattern: &str) -> Self {
+        self.exclude.push(pattern.to_string());
+        self
+    }
+
+    /// Adds a glob pattern of the paths to visit even if they are excluded, like --include
+    pub fn include(mut self, pattern: &str) -> Self {
+        self.include.push(pattern.to_string());
+        self
+    }
+
+    /// Adds a group of glob patterns whose files are copied once the files matching no group,
+    /// and those of the groups added before, are copied, like the `order` key of config files
+    pub fn copy_after(mut self, patterns: &[&str]) -> Self {
+        self.order
+            .push(patterns.iter().map(|pattern| pattern.to_string()).collect());
+        self
+    }
+
+    /// Sets what happens to destination files that were modified later than their source
+    pub fn on_dest_newer(mut self, policy: DestNewer) -> Self {
+        self.on_dest_newer = policy;
+        self
+    }
+
+    /// Sets whether files are cloned on filesystems with copy-on-write support instead of copied,
+    /// like --reflink
+    pub fn reflink(mut self, reflink: Reflink) -> Self {
+        self.reflink = reflink;
+        self
+    }
+
+    /// Sets which files are transferred, depending on whether they exist in the destination, like
+    /// --existing and --ignore-existing
+    pub fn existing(mut self, existing: Existing) -> Self {
+        self.existing = existing;
+        self
+    }
+
+    /// Sets how long a single copy may make no progress before it is reported, like
+    /// --stall-timeout
+    pub fn stall_timeout(mut self, timeout: Duration) -> Self {
+        self.stall_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the size from which files are large, and the number of large files copied at once,
+    /// like --large-file-size and --large-file-jobs
+    pub fn large_files(mut self, size: u64, jobs: usize) -> Self {
+        self.large_file_size = Some(size);
+        self.large_file_jobs = Some(jobs);
+        self
+    }
+
+    /// Sets the size of the blocks that `Flag::DELTA` compares and rewrites, like --block-size
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.block_size = Some(block_size);
+        self
+    }
+
+    /// Sets the bytes per second that copies read from the source, and that hashes read, like
+    /// --read-bwlimit
+    pub fn read_bwlimit(mut self, read_bwlimit: u64) -> Self {
+        self.read_bwlimit = Some(read_bwlimit);
+        self
+    }
+
+    /// Sets the size below which files are hashed to compare them even if their sizes and
+    /// modification times match, like --checksum-under
+    pub fn checksum_under(mut self, checksum_under: u64) -> Self {
+        self.checksum_under = Some(checksum_under);
+        self
+    }
+
+    /// Sets how far modification times may differ and still be taken as the same, like
+    /// --modify-window
+    pub fn modify_window(mut self, modify_window: Duration) -> Self {
+        self.modify_window = Some(modify_window);
+        self
+    }
+
+    /// Adds a command that the files matching its pattern are copied through, like --transform
+    pub fn transform(mut self, transform: Transform) -> Self {
+        self.transforms.push(transform);
+        self
+    }
+
+    /// Sets the size in bytes that the files in the destination of `sync` may take up, like
+    /// --max-dest-size
+    pub fn max_dest_size(mut self, max_dest_size: u64) -> Self {
+        self.max_dest_size = Some(max_dest_size);
+        self
+    }
+
+    /// Sets the directory that destination files overwritten or deleted by `sync` are moved into,
+    /// like --backup-dir
+    pub fn backup_dir<P: AsRef<Path>>(mut self, backup_dir: P) -> Self {
+        self.backup_dir = Some(backup_dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets whether the progress bar is drawn on stderr
+    pub fn progress(mut self, progress: bool) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Sets the progress bar that every call with these options shows its progress on, such as
+    /// one added to a `MultiProgress`, instead of one of its own
+    ///
+    /// The bar is drawn wherever it draws to, regardless of `progress`.
+    pub fn progress_bar(mut self, progress_bar: ProgressBar) -> Self {
+        self.progress_bar = Some(Bar(progress_bar));
+        self
+    }
+
+    /// Converts the options into the options the core functions take
+    fn to_options(&self) -> Options {
+        Options {
+            flags: self.flags,
+            filter: Filter::new(
+                self.exclude.iter().map(String::as_str),
+                self.include.iter().map(String::as_str),
+            ),
+            order: Order::new(
+                self.order
+                    .iter()
+                    .map(|group| group.iter().map(String::as_str)),
+            ),
+            on_dest_newer: self.on_dest_newer,
+            reflink: self.reflink,
+            existing: self.existing,
+            stall_timeout: self.stall_timeout,
+            large_file_size: self.large_file_size,
+            large_file_jobs: self.large_file_jobs,
+            threads: self.threads,
+            block_size: self.block_size,
+            read_bwlimit: self.read_bwlimit,
+            checksum_under: self.checksum_under,
+            modify_window: self.modify_window,
+            transforms: self.transforms.clone(),
+            max_dest_size: self.max_dest_size,
+            backup_dir: self.backup_dir.clone(),
+            ..Options::default()
+        }
+    }
+
+    /// Runs `f` with the options applied, as a job of its own that keeps its settings, records,
+    /// and progress apart from every other call, in a thread pool of its own or the one given with
+    /// `thread_pool`
+    fn run<F>(&self, f: F) -> Result<(), io::Error>
+    where
+        F: FnOnce(&Options) -> Result<(), io::Error> + Send,
+    {
+        let options = self.to_options();
+        let job = match &self.progress_bar {
+            Some(Bar(progress_bar)) => Job::new(progress_bar.clone()),
+            None => {
+                let job = Job::new(ProgressBar::new(0));
+                job.enter(|| progress::set_visible(self.progress));
+                job
+            }
+        };
+        job.enter(|| context::configure(&options));
+
+        match &self.thread_pool {
+            Some(pool) if !options.flags.contains(Flag::SEQUENTIAL) => {
+                // Every thread of the pool works for a single call at a time
+                let lock = pool_lock(pool);
+                let _running = lock.lock().unwrap_or_else(PoisonError::into_inner);
+                pool.broadcast(|_| job.attach());
+                let result = pool.install(|| f(&options));
+                pool.broadcast(|_| progress::detach());
+                result
+            }
+            _ => job.enter(|| parse::run_with_threads(&options, || f(&options))),
+        }
+    }
+}
+
+/// Gets the lock of `pool`, which the calls running in it hold
+fn pool_lock(pool: &Arc<ThreadPool>) -> Arc<Mutex<()>> {
+    let mut pools = POOLS.lock().unwrap();
+    pools.retain(|(pool, _)| pool.strong_count() > 0);
+    match pools
+        .iter()
+        .find(|(other, _)| other.as_ptr() == Arc::as_ptr(pool))
+    {
+        Some((_, lock)) => lock.clone(),
+        None => {
+            let lock = Arc::new(Mutex::new(()));
+            pools.push((Arc::downgrade(pool), lock.clone()));
+            lock
+        }
+    }
+}
+
+/// Synchronizes all files, directories, and symlinks in `dest` with `src`, like `lms sync`
+///
+/// # Arguments
+/// * `src`: Source directory
+/// * `dest`: Destination directory, which is created if it does not exist
+/// * `options`: options of the synchronization
+///
+/// # Errors
+/// This function will return an error if `src` is not a directory, or `dest` cannot be created
+pub fn sync<P, Q>(src: P, dest: Q, options: &SyncOptions) -> Result<(), io::Error>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let (src, dest) = (
+        &paths::normalize(src.as_ref()),
+        &paths::normalize(dest.as_ref()),
+    );
+    check_source(src, options.flags)?;
+    check_nesting(src, dest, options.flags)?;
+    fs::create_dir_all(dest)?;
+    options.run(|options| core::synchronize(src, dest, options))
+}
+
+/// Copies all files, directories, and symlinks in `src` to `dest`, like `lms cp`
+///
+/// Unlike `lms cp`, the contents of `src` are copied into `dest` itself even if it exists,
+/// instead of into a directory named after `src` inside of it.
+///
+/// # Arguments
+/// * `src`: Source directory
+/// * `dest`: Destination directory, which is created if it does not exist
+/// * `options`: options of the copy
+///
+/// # Errors
+/// This function will return an error if `src` is not a directory, or `dest` cannot be created
+pub fn copy<P, Q>(src: P, dest: Q, options: &SyncOptions) -> Result<(), io::Error>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let (src, dest) = (
+        &paths::normalize(src.as_ref()),
+        &paths::normalize(dest.as_ref()),
+    );
+    check_source(src, options.flags)?;
+    check_nesting(src, dest, options.flags)?;
+    fs::create_dir_all(dest)?;
+    options.run(|options| core::copy(src, dest, options))
+}
+
+/// Deletes directory `target` with everything in it, like `lms rm`
+///
+/// # Arguments
+/// * `target`: Target directory
+/// * `options`: options of the removal
+///
+/// # Errors
+/// This function will return an error if `target` is not a directory
+pub fn remove<P: AsRef<Path>>(target: P, options: &SyncOptions) -> Result<(), io::Error> {
+    let target = &paths::normalize(target.as_ref());
+    check_dir(target)?;
+    options.run(|options| core::remove(target, options))
+}
+
+/// Checks that `src` can be copied from, like the command line does
+fn check_source(src: &Path, flags: Flag) -> Result<(), io::Error> {
+    check_dir(src)?;
+
+    // Refuse to copy from /proc, /sys and friends unless explicitly allowed
+    if !flags.contains(Flag::ALLOW_PSEUDO_FS) {
+        if let Some(fs_type) = MountTable::load().pseudo_fs_of(&paths::canonicalize(src)?) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is on a pseudo-filesystem ({})", src.display(), fs_type),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that `src` and `dest` are different directories, which are not nested unless `flags`
+/// contains `FORCE_NESTED`
+fn check_nesting(src: &Path, dest: &Path, flags: Flag) -> Result<(), io::Error> {
+    if parse::are_same(src, dest) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} and {} are the same directory",
+                src.display(),
+                dest.display()
+            ),
+        ));
+    }
+    if !flags.contains(Flag::FORCE_NESTED) && parse::are_nested(src, dest) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} and {} are nested", src.display(), dest.display()),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that `path` is a directory
+fn check_dir(path: &Path) -> Result<(), io::Error> {
+    if fs::metadata(path)?.is_dir() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a directory", path.display()),
+        ))
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_api {
+    use super::*;
+
+    #[test]
+    fn sync_copy_remove() {
+        const TEST_SRC: &str = "test_api_sync_copy_remove_src";
+        const TEST_DEST: &str = "test_api_sync_copy_remove_dest";
+        const TEST_COPY: &str = "test_api_sync_copy_remove_copy";
+
+        fs::create_dir_all([TEST_SRC, "dir"].join("/")).unwrap();
+        fs::write([TEST_SRC, "dir/a.txt"].join("/"), b"a").unwrap();
+        fs::write([TEST_SRC, "b.tmp"].join("/"), b"b").unwrap();
+        fs::write([TEST_SRC, "keep.tmp"].join("/"), b"keep").unwrap();
+
+        let options = SyncOptions::new()
+            .flags(Flag::NO_CACHE)
+            .threads(2)
+            .exclude("*.tmp")
+            .include("keep.tmp");
+        assert_eq!(sync(TEST_SRC, TEST_DEST, &options).is_ok(), true);
+        assert_eq!(fs::read([TEST_DEST, "dir/a.txt"].join("/")).unwrap(), b"a");
+        assert_eq!(
+            fs::read([TEST_DEST, "keep.tmp"].join("/")).unwrap(),
+            b"keep"
+        );
+        assert_eq!(Path::new(&[TEST_DEST, "b.tmp"].join("/")).exists(), false);
+
+        let options = SyncOptions::new().flags(Flag::SEQUENTIAL);
+        assert_eq!(copy(TEST_SRC, TEST_COPY, &options).is_ok(), true);
+        assert_eq!(fs::read([TEST_COPY, "b.tmp"].join("/")).unwrap(), b"b");
+
+        // Removing a directory goes through `core::remove`, so only invalid targets are checked
+        let missing = [TEST_COPY, "missing"].join("/");
+        assert_eq!(remove(&missing, &options).is_ok(), false);
+        assert_eq!(
+            remove(&[TEST_COPY, "b.tmp"].join("/"), &options).is_ok(),
+            false
+        );
+        assert_eq!(sync(&missing, TEST_DEST, &options).is_ok(), false);
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+        fs::remove_dir_all(TEST_COPY).unwrap();
+    }
+    #[test]
+    fn independent_progress() {
+        const TEST_SRC: [&str; 2] = [
+            "test_api_independent_progress_src1",
+            "test_api_independent_progress_src2",
+        ];
+        const TEST_DEST: [&str; 2] = [
+            "test_api_independent_progress_dest1",
+            "test_api_independent_progress_dest2",
+        ];
+
+        fs::create_dir_all(TEST_SRC[0]).unwrap();
+        fs::create_dir_all(TEST_SRC[1]).unwrap();
+        fs::write([TEST_SRC[0], "a.txt"].join("/"), vec![0; 10]).unwrap();
+        fs::write([TEST_SRC[1], "b.txt"].join("/"), vec![0; 1000]).unwrap();
+
+        // Syncs made at the same time only credit their own bars
+        let bars = [ProgressBar::hidden(), ProgressBar::hidden()];
+        std::thread::scope(|scope| {
+            for i in 0..2 {
+                let options = SyncOptions::new()
+                    .flags(Flag::NO_CACHE)
+                    .progress_bar(bars[i].clone());
+                scope.spawn(move || {
+                    assert_eq!(sync(TEST_SRC[i], TEST_DEST[i], &options).is_ok(), true);
+                });
+            }
+        });
+        for bar in bars.iter() {
+            assert_eq!(bar.position(), bar.length());
+        }
+        assert_eq!(bars[0].length() < 1000, true);
+        assert_eq!(bars[1].length() > 1000, true);
+
+        fs::remove_dir_all(TEST_SRC[0]).unwrap();
+        fs::remove_dir_all(TEST_SRC[1]).unwrap();
+        fs::remove_dir_all(TEST_DEST[0]).unwrap();
+        fs::remove_dir_all(TEST_DEST[1]).unwrap();
+    }
+
+    #[test]
+    fn concurrent_options() {
+        const TEST_SRC: [&str; 2] = [
+            "test_api_concurrent_options_src1",
+            "test_api_concurrent_options_src2",
+        ];
+        const TEST_DEST: [&str; 2] = [
+            "test_api_concurrent_options_dest1",
+            "test_api_concurrent_options_dest2",
+        ];
+        const TEST_BACKUP: [&str; 2] = [
+            "test_api_concurrent_options_backup1",
+            "test_api_concurrent_options_backup2",
+        ];
+
+        for i in 0..2 {
+            fs::create_dir_all(TEST_SRC[i]).unwrap();
+            fs::create_dir_all(TEST_DEST[i]).unwrap();
+            fs::write([TEST_SRC[i], "file.txt"].join("/"), b"new").unwrap();
+            fs::write([TEST_DEST[i], "file.txt"].join("/"), b"old contents").unwrap();
+        }
+
+        // Every sync keeps the files it overwrites in its own backup directory
+        std::thread::scope(|scope| {
+            for i in 0..2 {
+                let options = SyncOptions::new()
+                    .flags(Flag::NO_CACHE)
+                    .backup_dir(TEST_BACKUP[i]);
+                scope.spawn(move || {
+                    assert_eq!(sync(TEST_SRC[i], TEST_DEST[i], &options).is_ok(), true);
+                });
+            }
+        });
+        for i in 0..2 {
+            assert_eq!(
+                fs::read([TEST_BACKUP[i], "file.txt"].join("/")).unwrap(),
+                b"old contents"
+            );
+            fs::remove_dir_all(TEST_SRC[i]).unwrap();
+            fs::remove_dir_all(TEST_DEST[i]).unwrap();
+            fs::remove_dir_all(TEST_BACKUP[i]).unwrap();
+        }
+    }
+
+    #[test]
+    fn given_thread_pool() {
+        const TEST_SRC: &str = "test_api_given_thread_pool_src";
+        const TEST_DEST: &str = "test_api_given_thread_pool_dest";
+
+        fs::create_dir_all([TEST_SRC, "dir"].join("/")).unwrap();
+        fs::write([TEST_SRC, "dir/a.txt"].join("/"), b"a").unwrap();
+        fs::write([TEST_SRC, "b.txt"].join("/"), b"b").unwrap();
+
+        let pool = Arc::new(
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(3)
+                .build()
+                .unwrap(),
+        );
+        let bar = ProgressBar::hidden();
+        let options = SyncOptions::new()
+            .flags(Flag::NO_CACHE)
+            .progress_bar(bar.clone())
+            .thread_pool(pool.clone());
+        assert_eq!(sync(TEST_SRC, TEST_DEST, &options).is_ok(), true);
+        assert_eq!(fs::read([TEST_DEST, "dir/a.txt"].join("/")).unwrap(), b"a");
+        assert_eq!(bar.position(), bar.length());
+
+        // The threads of the pool no longer credit the call once it is done
+        assert_eq!(pool.broadcast(|_| progress::in_job()), vec![false; 3]);
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+}
@@ -0,0 +1,295 @@
+//! Contains the copying of the attributes of Windows files, such as hidden, system and read-only,
+//! and of their NTFS alternate data streams, which are carried over to the destination along with
+//! the other metadata, and compared so that a change to them alone still updates a file.
+//! Elsewhere files have neither, and there is nothing to copy.
+
+use std::io;
+use std::path::Path;
+
+/// Copies the attributes and alternate data streams of `src` onto `dest`, removing the streams
+/// that `dest` has but `src` does not
+///
+/// The streams are copied before the attributes, since a read-only `dest` cannot be written.
+///
+/// # Arguments
+/// * `src`: absolute path of the file or dir to copy the attributes of
+/// * `dest`: absolute path of the file or dir to copy the attributes to
+///
+/// # Errors
+/// This function will return an error if the attributes or streams of `src` cannot be read,
+/// or those of `dest` cannot be changed
+#[cfg(target_family = "windows")]
+pub fn copy(src: &Path, dest: &Path) -> Result<(), io::Error> {
+    use log::info;
+    use std::fs;
+
+    let src_streams = sys::streams(src)?;
+    let dest_streams = sys::streams(dest)?;
+    if src_streams != dest_streams || !same_stream_contents(src, dest, &src_streams) {
+        make_writable(dest)?;
+        for (name, _) in src_streams.iter() {
+            let mut reader = fs::File::open(sys::stream_path(src, name))?;
+            let mut writer = fs::File::create(sys::stream_path(dest, name))?;
+            io::copy(&mut reader, &mut writer)?;
+        }
+        for (name, _) in dest_streams.iter() {
+            if !src_streams.iter().any(|(src_name, _)| src_name == name) {
+                fs::remove_file(sys::stream_path(dest, name))?;
+            }
+        }
+        info!("Changing alternate data streams of {:?}", dest);
+    }
+
+    let src_attributes = sys::attributes(src)?;
+    let dest_attributes = sys::attributes(dest)?;
+    if (src_attributes ^ dest_attributes) & sys::PRESERVED != 0 {
+        let attributes = (dest_attributes & !sys::PRESERVED) | (src_attributes & sys::PRESERVED);
+        sys::set_attributes(dest, attributes)?;
+        info!("Changing attributes of {:?} -> {:#x}", dest, attributes);
+    }
+    Ok(())
+}
+
+#[cfg(not(target_family = "windows"))]
+pub fn copy(_src: &Path, _dest: &Path) -> Result<(), io::Error> {
+    Ok(())
+}
+
+/// Checks whether `src` and `dest` have different attributes or alternate data streams, taking
+/// paths whose attributes cannot be read as different
+#[cfg(target_family = "windows")]
+pub fn differ(src: &Path, dest: &Path) -> bool {
+    match (sys::attributes(src), sys::attributes(dest)) {
+        (Ok(src_attributes), Ok(dest_attributes))
+            if (src_attributes ^ dest_attributes) & sys::PRESERVED == 0 =>
+        {
+            match (sys::streams(src), sys::streams(dest)) {
+                (Ok(src_streams), Ok(dest_streams)) => {
+                    src_streams != dest_streams || !same_stream_contents(src, dest, &src_streams)
+                }
+                _ => true,
+            }
+        }
+        _ => true,
+    }
+}
+
+#[cfg(not(target_family = "windows"))]
+pub fn differ(_src: &Path, _dest: &Path) -> bool {
+    false
+}
+
+/// Clears the read-only attribute of `path`, if it exists, so that it can be overwritten or
+/// deleted, which is restored along with the other metadata once it is copied
+///
+/// # Errors
+/// This function will return an error if the attribute is set but cannot be cleared
+#[cfg(target_family = "windows")]
+pub fn make_writable(path: &Path) -> Result<(), io::Error> {
+    match sys::attributes(path) {
+        Ok(attributes) if attributes & sys::FILE_ATTRIBUTE_READONLY != 0 => {
+            sys::set_attributes(path, attributes & !sys::FILE_ATTRIBUTE_READONLY)
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(target_family = "windows"))]
+pub fn make_writable(_path: &Path) -> Result<(), io::Error> {
+    Ok(())
+}
+
+/// Checks whether the alternate data streams `streams`, which `src` and `dest` both have with the
+/// same sizes, hold the same contents, which are compared whole since streams are mostly small,
+/// such as the zone identifiers of downloaded files
+#[cfg(target_family = "windows")]
+fn same_stream_contents(src: &Path, dest: &Path, streams: &[(String, u64)]) -> bool {
+    use std::fs;
+
+    streams.iter().all(|(name, _)| {
+        match (
+            fs::read(sys::stream_path(src, name)),
+            fs::read(sys::stream_path(dest, name)),
+        ) {
+            (Ok(src_contents), Ok(dest_contents)) => src_contents == dest_contents,
+            _ => false,
+        }
+    })
+}
+
+#[cfg(target_family = "windows")]
+mod sys {
+    use std::ffi::OsString;
+    use std::fs;
+    use std::io;
+    use std::iter;
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::fs::MetadataExt;
+    use std::path::{Path, PathBuf};
+
+    pub const FILE_ATTRIBUTE_READONLY: u32 = 0x1;
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+    const FILE_ATTRIBUTE_SYSTEM: u32 = 0x4;
+    const FILE_ATTRIBUTE_NORMAL: u32 = 0x80;
+
+    /// Attributes that are copied, while the others, such as archive, compressed, or sparse,
+    /// belong to the copy itself
+    pub const PRESERVED: u32 =
+        FILE_ATTRIBUTE_READONLY | FILE_ATTRIBUTE_HIDDEN | FILE_ATTRIBUTE_SYSTEM;
+
+    const ERROR_HANDLE_EOF: i32 = 38;
+    const ERROR_INVALID_PARAMETER: i32 = 87;
+    const INVALID_HANDLE_VALUE: isize = -1;
+    const FIND_STREAM_INFO_STANDARD: i32 = 0;
+    const MAX_PATH: usize = 260;
+
+    /// Suffix of the names of the streams that hold data
+    const DATA_SUFFIX: &str = ":$DATA";
+
+    #[repr(C)]
+    struct Win32FindStreamData {
+        stream_size: i64,
+        stream_name: [u16; MAX_PATH + 36],
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn SetFileAttributesW(file_name: *const u16, attributes: u32) -> i32;
+        fn FindFirstStreamW(
+            file_name: *const u16,
+            info_level: i32,
+            data: *mut Win32FindStreamData,
+            flags: u32,
+        ) -> isize;
+        fn FindNextStreamW(find: isize, data: *mut Win32FindStreamData) -> i32;
+        fn FindClose(find: isize) -> i32;
+    }
+
+    /// Gets the attributes of `path`, without following symlinks
+    pub fn attributes(path: &Path) -> Result<u32, io::Error> {
+        Ok(fs::symlink_metadata(path)?.file_attributes())
+    }
+
+    pub fn set_attributes(path: &Path, attributes: u32) -> Result<(), io::Error> {
+        // No attributes at all must be given as normal
+        let attributes = if attributes == 0 {
+            FILE_ATTRIBUTE_NORMAL
+        } else {
+            attributes
+        };
+        match unsafe { SetFileAttributesW(wide(path.as_os_str()).as_ptr(), attributes) } {
+            0 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Gets the names and sizes of the alternate data streams of `path`, sorted by name, leaving
+    /// out its unnamed stream, which holds the contents of a file
+    pub fn streams(path: &Path) -> Result<Vec<(String, u64)>, io::Error> {
+        let mut data = Win32FindStreamData {
+            stream_size: 0,
+            stream_name: [0; MAX_PATH + 36],
+        };
+        let find = unsafe {
+            FindFirstStreamW(
+                wide(path.as_os_str()).as_ptr(),
+                FIND_STREAM_INFO_STANDARD,
+                &mut data,
+                0,
+            )
+        };
+        if find == INVALID_HANDLE_VALUE {
+            let e = io::Error::last_os_error();
+            // Paths without streams, and filesystems without them, such as FAT, have none
+            return match e.raw_os_error() {
+                Some(ERROR_HANDLE_EOF) | Some(ERROR_INVALID_PARAMETER) => Ok(Vec::new()),
+                _ => Err(e),
+            };
+        }
+
+        let mut streams = Vec::new();
+        let result = loop {
+            let len = data
+                .stream_name
+                .iter()
+                .position(|&c| c == 0)
+                .unwrap_or(data.stream_name.len());
+            let name = String::from_utf16_lossy(&data.stream_name[..len]);
+            // Names are of the form `:name:$DATA`, and the unnamed stream is `::$DATA`
+            if let Some(name) = name
+                .strip_prefix(':')
+                .and_then(|name| name.strip_suffix(DATA_SUFFIX))
+                .filter(|name| !name.is_empty())
+            {
+                streams.push((name.to_string(), data.stream_size as u64));
+            }
+
+            if unsafe { FindNextStreamW(find, &mut data) } == 0 {
+                let e = io::Error::last_os_error();
+                break match e.raw_os_error() {
+                    Some(ERROR_HANDLE_EOF) => Ok(()),
+                    _ => Err(e),
+                };
+            }
+        };
+        unsafe { FindClose(find) };
+
+        result?;
+        streams.sort();
+        Ok(streams)
+    }
+
+    /// Gets the path of the alternate data stream `name` of `path`
+    pub fn stream_path(path: &Path, name: &str) -> PathBuf {
+        let mut stream_path = OsString::from(path);
+        stream_path.push(":");
+        stream_path.push(name);
+        PathBuf::from(stream_path)
+    }
+
+    /// Converts `value` to a wide string ending in a null, for the system calls
+    fn wide(value: &std::ffi::OsStr) -> Vec<u16> {
+        value.encode_wide().chain(iter::once(0)).collect()
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(all(test, target_family = "windows"))]
+mod test_attributes {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn copied() {
+        const TEST_DIR: &str = "test_attributes_copied";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        let src = Path::new(TEST_DIR).join("src.txt");
+        let dest = Path::new(TEST_DIR).join("dest.txt");
+        fs::write(&src, b"contents").unwrap();
+        fs::write(&dest, b"contents").unwrap();
+        fs::write(sys::stream_path(&src, "Zone.Identifier"), b"[ZoneTransfer]").unwrap();
+        fs::write(sys::stream_path(&dest, "stale"), b"stale").unwrap();
+        let hidden = sys::attributes(&src).unwrap() | 0x2 | sys::FILE_ATTRIBUTE_READONLY;
+        sys::set_attributes(&src, hidden).unwrap();
+        assert_eq!(differ(&src, &dest), true);
+
+        // Streams only in dest are removed
+        assert_eq!(copy(&src, &dest).is_ok(), true);
+        assert_eq!(differ(&src, &dest), false);
+        assert_eq!(
+            sys::streams(&dest).unwrap(),
+            vec![(String::from("Zone.Identifier"), 14)]
+        );
+
+        // A read-only copy can still be overwritten once it is made writable
+        assert_eq!(make_writable(&dest).is_ok(), true);
+        assert_eq!(fs::write(&dest, b"changed").is_ok(), true);
+
+        make_writable(&src).unwrap();
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
@@ -0,0 +1,212 @@
+//! Contains the backup directory given with --backup-dir, which receives the old version of every
+//! destination file that a synchronization overwrites or deletes, at the same relative path, so
+//! that mirroring a source never loses what only existed in the destination.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::info;
+use rayon::prelude::*;
+
+use crate::lumins::context;
+use crate::lumins::events::{self, Action};
+use crate::lumins::file_ops::{self, FileOps};
+use crate::lumins::rollback;
+use crate::progress;
+
+/// Configures the backup directory of all following synchronizations of the current job
+///
+/// # Arguments
+/// * `backup_dir`: directory to keep old versions in, None to overwrite and delete them
+pub fn configure(backup_dir: Option<PathBuf>) {
+    *context::current().backup_dir.lock().unwrap() = backup_dir;
+}
+
+/// Gets the configured backup directory
+pub fn backup_dir() -> Option<PathBuf> {
+    context::current().backup_dir.lock().unwrap().clone()
+}
+
+/// Checks whether a backup directory is configured
+pub fn is_configured() -> bool {
+    context::current().backup_dir.lock().unwrap().is_some()
+}
+
+/// Keeps the file at `path` in `location` at the same path in the backup directory, replacing
+/// any older backup of it, and records it to roll back the synchronization of `location`
+///
+/// # Arguments
+/// * `location`: base directory of the file
+/// * `path`: path of the file relative to `location`
+/// * `keep`: whether the file also stays where it is, such as for delta copies that update it
+///
+/// # Returns
+/// True if the file was backed up, false if no backup directory is configured
+///
+/// # Errors
+/// This function will return an error if the file cannot be moved or copied into the backup
+/// directory, in which case it is left where it is
+pub fn back_up(location: &Path, path: &Path, keep: bool) -> Result<bool, io::Error> {
+    match backup_dir() {
+        Some(backup_dir) => {
+            back_up_to(&backup_dir, location, path, keep)?;
+            rollback::backed_up(location, path);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Keeps the file at `path` in `location` at the same path in `backup_dir`, like `back_up`
+fn back_up_to(
+    backup_dir: &Path,
+    location: &Path,
+    path: &Path,
+    keep: bool,
+) -> Result<(), io::Error> {
+    let file = location.join(path);
+    let backup = backup_dir.join(path);
+    if let Some(parent) = backup.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    if keep {
+        if file_ops::reflink(&file, &backup).is_err() {
+            fs::copy(&file, &backup)?;
+        }
+    } else if let Err(e) = fs::rename(&file, &backup) {
+        // Files cannot be renamed onto another filesystem, so they are copied there instead
+        if fs::symlink_metadata(&file)?.file_type().is_symlink() {
+            return Err(e);
+        }
+        fs::copy(&file, &backup)?;
+        fs::remove_file(&file)?;
+    }
+    info!("Backed up {:?} to {:?}", file, backup);
+    Ok(())
+}
+
+/// Moves all given files from `location` into the backup directory in parallel, instead of
+/// deleting them
+///
+/// # Arguments
+/// * `files_to_move`: files to move
+/// * `location`: base directory of the files to move, such that for all `file` in
+///   `files_to_move`, `location + file.path()` is the absolute path of the file
+///
+/// # Returns
+/// The number of files that could not be moved, which are kept
+pub fn move_files<'a, T, S>(files_to_move: T, location: &Path) -> usize
+where
+    T: ParallelIterator<Item = &'a S>,
+    S: FileOps + Sync + 'a,
+{
+    files_to_move
+        .filter(|file| {
+            let moved = is_backed_up(location, file.path(), false);
+            if moved {
+                let path = location.join(file.path());
+                events::record(
+                    Action::Delete,
+                    &path,
+                    format_args!("Moving {:?} to the backup dir", path),
+                );
+            }
+            progress::bar().inc(1);
+            !moved
+        })
+        .count()
+}
+
+/// Keeps a copy of the old version of all given files in `location` in the backup directory in
+/// parallel, before they are overwritten
+///
+/// # Arguments
+/// * `files_to_back_up`: files to back up
+/// * `location`: base directory of the files, like for `move_files`
+/// * `keep`: whether the files also stay where they are, like for `back_up`
+///
+/// # Returns
+/// The files that were backed up, which may be overwritten without losing them
+pub fn back_up_files<'a, T, S>(files_to_back_up: T, location: &Path, keep: bool) -> Vec<&'a S>
+where
+    T: ParallelIterator<Item = &'a S>,
+    S: FileOps + Sync + 'a,
+{
+    files_to_back_up
+        .filter(|file| {
+            let backed_up = is_backed_up(location, file.path(), keep);
+            if !backed_up {
+                // The file is not copied, but still counts towards the progress
+                progress::bar().inc(1 + file.size());
+            }
+            backed_up
+        })
+        .collect()
+}
+
+/// Backs up the file at `path` in `location` like `back_up`, logging why it is kept otherwise
+///
+/// # Returns
+/// True if the file may be overwritten
+pub fn is_backed_up(location: &Path, path: &Path, keep: bool) -> bool {
+    match back_up(location, path, keep) {
+        Ok(_) => true,
+        Err(e) => {
+            let path = location.join(path);
+            events::record(
+                Action::Error,
+                &path,
+                format_args!("Error -- Keeping {:?}, it cannot be backed up: {}", path, e),
+            );
+            false
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_backups {
+    use super::*;
+
+    #[test]
+    fn mirrored_paths() {
+        const TEST_DIR: &str = "test_backups_mirrored_paths";
+        const TEST_BACKUP: &str = "test_backups_mirrored_paths_backup";
+
+        fs::create_dir_all([TEST_DIR, "dir"].join("/")).unwrap();
+        fs::write([TEST_DIR, "dir/moved.txt"].join("/"), b"moved").unwrap();
+        fs::write([TEST_DIR, "kept.txt"].join("/"), b"kept").unwrap();
+
+        let backup_dir = Path::new(TEST_BACKUP);
+        let moved = Path::new("dir/moved.txt");
+        assert_eq!(
+            back_up_to(backup_dir, Path::new(TEST_DIR), moved, false).is_ok(),
+            true
+        );
+        assert_eq!(Path::new(TEST_DIR).join(moved).exists(), false);
+        assert_eq!(fs::read(backup_dir.join(moved)).unwrap(), b"moved");
+
+        // Kept files stay where they are, and replace older backups
+        fs::write(backup_dir.join("kept.txt"), b"older").unwrap();
+        let kept = Path::new("kept.txt");
+        assert_eq!(
+            back_up_to(backup_dir, Path::new(TEST_DIR), kept, true).is_ok(),
+            true
+        );
+        assert_eq!(fs::read(Path::new(TEST_DIR).join(kept)).unwrap(), b"kept");
+        assert_eq!(fs::read(backup_dir.join(kept)).unwrap(), b"kept");
+
+        assert_eq!(
+            back_up_to(backup_dir, Path::new(TEST_DIR), moved, false).is_ok(),
+            false
+        );
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_BACKUP).unwrap();
+    }
+}
@@ -0,0 +1,645 @@
+//! Contains the config files that give the defaults of every run, `/etc/lumins/config.toml` for
+//! the system, `~/.config/lumins/config.toml` for the user and `.lumins.toml` for a project, so
+//! that excludes, flags, the number of threads, and the log level need not be given on every
+//! command line.
+//!
+//! The files are read in that order, followed by the command line, and each overrides the
+//! threads, log level, order, profile paths, and flags given by those before it, while excludes
+//! and includes are added to those given before, so that the excludes of an organization set in
+//! the system file are kept when users add their own.
+//!
+//! Every file holds keys that apply to all subcommands, tables named after subcommands, such as
+//! `[sync]`, whose keys apply to that subcommand only, and profiles, such as
+//! `[profile.nas-backup]`, whose keys apply to runs given `--profile nas-backup` only. A profile
+//! may also give the source and destinations, so that `lms sync --profile nas-backup` needs
+//! nothing else:
+//!
+//! ```toml
+//! threads = 4
+//! log-level = "info"
+//! exclude = ["target/", "*.tmp"]
+//!
+//! [sync]
+//! secure = true
+//!
+//! [profile.nas-backup]
+//! source = "/home/me"
+//! destination = ["/mnt/nas/me"]
+//! exclude = [".cache/"]
+//! ```
+//!
+//! The `order` key splits the files that are copied into groups that are copied one after
+//! another, so that directory-based databases can be copied consistently, such as with
+//! `order = ["*.db", ["*-wal", "*-shm"]]`, which copies the write-ahead logs of the databases only
+//! once their data files are copied. Every entry is a pattern or an array of patterns.
+
+use std::collections::BTreeSet;
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::LevelFilter;
+use toml::value::{Table, Value};
+
+use crate::lumins::parse::{Flag, FLAG_NAMES};
+
+/// Name of the config file of a project, which is looked for in the working directory and every
+/// directory above it
+const PROJECT_FILE: &str = ".lumins.toml";
+
+/// Names of the subcommands that config files may have tables for
+const SUB_COMMANDS: [&str; 13] = [
+    "cp",
+    "dedupe",
+    "du",
+    "history",
+    "ingest",
+    "journal",
+    "ls",
+    "mtree-check",
+    "rm",
+    "rollback",
+    "seed-check",
+    "sync",
+    "verify",
+];
+
+/// Struct to represent the defaults given in config files, which the command line adds to or
+/// overrides
+#[derive(Eq, PartialEq, Debug, Default)]
+pub struct Config {
+    /// Flags that are set
+    pub flags: Flag,
+    /// Patterns of the paths that traversal skips, before those given on the command line
+    pub exclude: Vec<String>,
+    /// Patterns of the paths that traversal does not skip, before those given on the command line
+    pub include: Vec<String>,
+    /// Number of threads that files are handled with, unless given on the command line
+    pub threads: Option<usize>,
+    /// Level of the log records shown, unless --verbose is given
+    pub log_level: Option<LevelFilter>,
+    /// Source directory of the profile, unless given on the command line
+    pub source: Option<PathBuf>,
+    /// Destination directories of the profile, unless given on the command line
+    pub destination: Vec<PathBuf>,
+    /// Patterns of the groups of files that are copied one after another, in order
+    pub order: Vec<Vec<String>>,
+}
+
+impl Config {
+    /// Applies the keys of `contents` that concern `sub_command` and `profile` on top of this
+    /// config, where the table of `sub_command` overrides the keys that apply to all subcommands,
+    /// and the table of `profile` overrides both
+    ///
+    /// # Errors
+    /// This function will return an error if `contents` is not valid TOML, or has a key that is
+    /// unknown or holds a value of the wrong type
+    fn apply(
+        &mut self,
+        contents: &str,
+        sub_command: &str,
+        profile: Option<&str>,
+    ) -> Result<(), String> {
+        let table: Table = toml::from_str(contents).map_err(|e| e.to_string())?;
+        for (key, value) in table.iter() {
+            if !value.is_table() {
+                self.apply_key(key, value)?;
+            }
+        }
+
+        let profile_table = profile.and_then(|profile| {
+            table
+                .get("profile")
+                .and_then(|profiles| profiles.get(profile))
+                .map(|profile_table| (format!("profile.{}", profile), profile_table))
+        });
+        let tables = table
+            .get(sub_command)
+            .map(|sub_table| (sub_command.to_string(), sub_table))
+            .into_iter()
+            .chain(profile_table);
+        for (name, sub_table) in tables {
+            let sub_table = sub_table
+                .as_table()
+                .ok_or_else(|| format!("{} must be a table", name))?;
+            for (key, value) in sub_table.iter() {
+                self.apply_key(&format!("{}.{}", name, key), value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies a single key, whose name is `path` within its file, on top of this config
+    fn apply_key(&mut self, path: &str, value: &Value) -> Result<(), String> {
+        let key = path.rsplit('.').next().unwrap_or(path);
+        let invalid = |expected: &str| format!("{} must be {}", path, expected);
+        match key {
+            "exclude" | "include" => {
+                let patterns = value
+                    .as_array()
+                    .and_then(|patterns| {
+                        patterns
+                            .iter()
+                            .map(|pattern| pattern.as_str().map(String::from))
+                            .collect::<Option<Vec<String>>>()
+                    })
+                    .ok_or_else(|| invalid("an array of patterns"))?;
+                if key == "exclude" {
+                    self.exclude.extend(patterns);
+                } else {
+                    self.include.extend(patterns);
+                }
+            }
+            "order" => {
+                let group = |value: &Value| match value {
+                    Value::String(pattern) => Some(vec![pattern.clone()]),
+                    _ => value.as_array().and_then(|patterns| {
+                        patterns
+                            .iter()
+                            .map(|pattern| pattern.as_str().map(String::from))
+                            .collect::<Option<Vec<String>>>()
+                    }),
+                };
+                self.order = value
+                    .as_array()
+                    .and_then(|groups| groups.iter().map(group).collect())
+                    .ok_or_else(|| invalid("an array of patterns or arrays of patterns"))?;
+            }
+            "threads" => {
+                let threads = value
+                    .as_integer()
+                    .filter(|&threads| threads > 0)
+                    .ok_or_else(|| invalid("a positive number"))?;
+                self.threads = Some(threads as usize);
+            }
+            "source" if path.starts_with("profile.") => {
+                let source = value.as_str().ok_or_else(|| invalid("a path"))?;
+                self.source = Some(PathBuf::from(source));
+            }
+            "destination" if path.starts_with("profile.") => {
+                self.destination = match value {
+                    Value::String(destination) => vec![PathBuf::from(destination)],
+                    _ => value
+                        .as_array()
+                        .and_then(|destinations| {
+                            destinations
+                                .iter()
+                                .map(|destination| destination.as_str().map(PathBuf::from))
+                                .collect::<Option<Vec<PathBuf>>>()
+                        })
+                        .ok_or_else(|| invalid("a path or an array of paths"))?,
+                };
+            }
+            "log-level" => {
+                let log_level = value
+                    .as_str()
+                    .and_then(|log_level| log_level.parse().ok())
+                    .ok_or_else(|| invalid("one of off, error, warn, info, debug or trace"))?;
+                self.log_level = Some(log_level);
+            }
+            _ => {
+                let flag = FLAG_NAMES
+                    .iter()
+                    .find(|&&(name, _)| name == key)
+                    .map(|&(_, flag)| flag)
+                    .ok_or_else(|| format!("{} is not a known key", path))?;
+                let set = value.as_bool().ok_or_else(|| invalid("true or false"))?;
+                self.flags.set(flag, set);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Loads the config of `sub_command` from the config file of the system, then from the config
+/// file of the user, and then from the config file of the project, where the keys of every file
+/// override those of the files before it, and excludes and includes are added to them
+///
+/// # Arguments
+/// * `sub_command`: name of the subcommand that is run, such as `sync`
+/// * `profile`: name of the profile given with --profile, if any
+///
+/// # Errors
+/// This function will return an error, naming the file, if a config file cannot be read or is
+/// not valid
+pub fn load(sub_command: &str, profile: Option<&str>) -> Result<Config, String> {
+    let mut config = Config::default();
+    for file in files() {
+        match fs::read_to_string(&file) {
+            Ok(contents) => config
+                .apply(&contents, sub_command, profile)
+                .map_err(|e| format!("{}: {}", file.display(), e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(format!("{}: {}", file.display(), e)),
+        }
+    }
+    Ok(config)
+}
+
+/// Checks the config files for problems, like `lms config check`, and shows the options that
+/// `lms sync` runs with by default and with every profile, so that mistakes are caught before a
+/// scheduled run fails on them
+///
+/// # Arguments
+/// * `profile`: name of the only profile to show, None to show every profile
+///
+/// # Errors
+/// This function will return an error if a config file is not valid, a profile is missing, or a
+/// profile names a source or destination that does not exist
+pub fn check(profile: Option<&str>) -> Result<(), io::Error> {
+    let files: Vec<PathBuf> = files().into_iter().filter(|file| file.is_file()).collect();
+    let mut problems = 0;
+    let mut profiles = BTreeSet::new();
+    for file in files.iter() {
+        let contents = fs::read_to_string(file)?;
+        let file_problems = problems_of(&contents);
+        if file_problems.is_empty() {
+            println!("{}: ok", file.display());
+        }
+        for problem in file_problems.iter() {
+            println!("{}: {}", file.display(), problem);
+        }
+        problems += file_problems.len();
+        profiles.extend(profiles_of(&contents));
+    }
+    if files.is_empty() {
+        println!("No config files, all options are given on the command line");
+    }
+
+    // The options shown are those of every file at once, which cannot be told if one is invalid
+    if problems == 0 {
+        let shown: Vec<Option<&str>> = match profile {
+            Some(profile) if !profiles.contains(profile) => {
+                println!("Profile {} is not defined in any config file", profile);
+                problems += 1;
+                Vec::new()
+            }
+            Some(profile) => vec![Some(profile)],
+            None => std::iter::once(None)
+                .chain(profiles.iter().map(|profile| Some(profile.as_str())))
+                .collect(),
+        };
+        for profile in shown {
+            let config = load("sync", profile).map_err(io::Error::other)?;
+            println!();
+            match profile {
+                Some(profile) => println!("[profile.{}]", profile),
+                None => println!("[sync]"),
+            }
+            print!("{}", describe(&config));
+            for problem in missing_paths(&config) {
+                println!("{}", problem);
+                problems += 1;
+            }
+        }
+    }
+
+    if problems > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Found {} problems in the config files", problems),
+        ));
+    }
+    Ok(())
+}
+
+/// Finds every problem in the contents of a config file, such as keys that are unknown, hold
+/// values of the wrong type, or are in tables that no subcommand reads
+fn problems_of(contents: &str) -> Vec<String> {
+    let table: Table = match toml::from_str(contents) {
+        Ok(table) => table,
+        Err(e) => return vec![e.to_string()],
+    };
+
+    let mut problems = Vec::new();
+    for (key, value) in table.iter() {
+        match (key.as_str(), value.as_table()) {
+            ("profile", Some(profiles)) => {
+                for (profile, value) in profiles.iter() {
+                    problems.extend(table_problems(&format!("profile.{}", profile), value));
+                }
+            }
+            (key, _) if key == "profile" || SUB_COMMANDS.contains(&key) => {
+                problems.extend(table_problems(key, value))
+            }
+            // A table that no subcommand reads is most likely a misspelled subcommand
+            (key, Some(_)) => problems.push(format!(
+                "[{}] is not a subcommand, nor a profile such as [profile.{}]",
+                key, key
+            )),
+            (key, None) => problems.extend(Config::default().apply_key(key, value).err()),
+        }
+    }
+    problems
+}
+
+/// Finds every problem in the keys of the table `name`
+fn table_problems(name: &str, value: &Value) -> Vec<String> {
+    match value.as_table() {
+        Some(table) => table
+            .iter()
+            .filter_map(|(key, value)| {
+                Config::default()
+                    .apply_key(&format!("{}.{}", name, key), value)
+                    .err()
+            })
+            .collect(),
+        None => vec![format!("{} must be a table", name)],
+    }
+}
+
+/// Gets the names of the profiles defined in the contents of a config file
+fn profiles_of(contents: &str) -> Vec<String> {
+    toml::from_str::<Table>(contents)
+        .ok()
+        .and_then(|table| {
+            table
+                .get("profile")
+                .and_then(Value::as_table)
+                .map(|profiles| profiles.keys().cloned().collect())
+        })
+        .unwrap_or_default()
+}
+
+/// Describes the options of `config`, one `key = value` line per option that is set
+fn describe(config: &Config) -> String {
+    let mut description = String::new();
+    if let Some(source) = &config.source {
+        let _ = writeln!(description, "source = {:?}", source);
+    }
+    if !config.destination.is_empty() {
+        let _ = writeln!(description, "destination = {:?}", config.destination);
+    }
+    let flags: Vec<&str> = FLAG_NAMES
+        .iter()
+        .filter(|&&(_, flag)| config.flags.contains(flag))
+        .map(|&(name, _)| name)
+        .collect();
+    if !flags.is_empty() {
+        let _ = writeln!(description, "flags = {}", flags.join(", "));
+    }
+    if !config.exclude.is_empty() {
+        let _ = writeln!(description, "exclude = {:?}", config.exclude);
+    }
+    if !config.include.is_empty() {
+        let _ = writeln!(description, "include = {:?}", config.include);
+    }
+    if !config.order.is_empty() {
+        let _ = writeln!(description, "order = {:?}", config.order);
+    }
+    if let Some(threads) = config.threads {
+        let _ = writeln!(description, "threads = {}", threads);
+    }
+    if let Some(log_level) = config.log_level {
+        let _ = writeln!(
+            description,
+            "log-level = {}",
+            log_level.to_string().to_lowercase()
+        );
+    }
+    if description.is_empty() {
+        description.push_str("(no options)\n");
+    }
+    description
+}
+
+/// Finds the source and destinations of `config` that do not exist, where a destination only
+/// needs the directory it is created in to exist
+fn missing_paths(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+    if let Some(source) = &config.source {
+        if !source.is_dir() {
+            problems.push(format!("Source {:?} is not a directory", source));
+        }
+    }
+    for destination in config.destination.iter() {
+        let parent = destination
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        if !destination.is_dir() && !parent.is_dir() {
+            problems.push(format!(
+                "Destination {:?} cannot be created, {:?} is not a directory",
+                destination, parent
+            ));
+        }
+    }
+    problems
+}
+
+/// Gets the config files that are read, whether they exist or not, in the order of precedence,
+/// which is the file of the system, then the file of the user, and then the file of the project
+fn files() -> Vec<PathBuf> {
+    dirs()
+        .into_iter()
+        .map(|dir| dir.join("config.toml"))
+        .chain(env::current_dir().ok().and_then(|dir| project_file(&dir)))
+        .collect()
+}
+
+/// Gets the config dirs of the system and of the user, in the order of precedence, which hold
+/// their config files and the other files that LuminS reads, such as translations
+pub fn dirs() -> Vec<PathBuf> {
+    system_dir().into_iter().chain(user_dir()).collect()
+}
+
+/// Gets the config dir of the system, such as `/etc/lumins`, which administrators give the
+/// defaults of every user in
+fn system_dir() -> Option<PathBuf> {
+    if cfg!(windows) {
+        env::var_os("PROGRAMDATA").map(|dir| Path::new(&dir).join("lumins"))
+    } else {
+        Some(PathBuf::from("/etc/lumins"))
+    }
+}
+
+/// Gets the config dir of the user, such as `~/.config/lumins`
+fn user_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .or_else(|| env::var_os("APPDATA"))
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|dir| dir.join("lumins"))
+}
+
+/// Gets the config file of the project that `dir` is in, which is the nearest `.lumins.toml` in
+/// `dir` or a directory above it
+fn project_file(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join(PROJECT_FILE))
+        .find(|file| file.is_file())
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_config {
+    use super::*;
+
+    #[test]
+    fn merged_keys() {
+        let mut config = Config::default();
+        let user = "threads = 4\nsecure = true\nexclude = [\"target/\"]\n\n\
+                    [sync]\nnodelete = true\n\n[cp]\nthreads = 1\n";
+        assert_eq!(config.apply(user, "sync", None).is_ok(), true);
+        let project = "log-level = \"info\"\nsecure = false\nexclude = [\"*.tmp\"]\n";
+        assert_eq!(config.apply(project, "sync", None).is_ok(), true);
+        assert_eq!(
+            config,
+            Config {
+                flags: Flag::NO_DELETE,
+                exclude: vec![String::from("target/"), String::from("*.tmp")],
+                include: Vec::new(),
+                threads: Some(4),
+                log_level: Some(LevelFilter::Info),
+                ..Config::default()
+            }
+        );
+
+        let mut config = Config::default();
+        assert_eq!(
+            config.apply("[sync]\nsecure = 1\n", "sync", None),
+            Err(String::from("sync.secure must be true or false"))
+        );
+        assert_eq!(
+            config.apply("colour = true\n", "sync", None),
+            Err(String::from("colour is not a known key"))
+        );
+        assert_eq!(config.apply("threads = 0\n", "sync", None).is_err(), true);
+        assert_eq!(config.apply("threads = ", "sync", None).is_err(), true);
+
+        let mut config = Config::default();
+        let contents = "order = [\"*.db\", [\"*-wal\", \"*-shm\"]]\n";
+        assert_eq!(config.apply(contents, "sync", None).is_ok(), true);
+        assert_eq!(
+            config.order,
+            vec![
+                vec![String::from("*.db")],
+                vec![String::from("*-wal"), String::from("*-shm")]
+            ]
+        );
+        assert_eq!(config.apply("order = [1]\n", "sync", None).is_err(), true);
+    }
+
+    #[test]
+    fn layered() {
+        // The file of the system comes first, so that every other file overrides it
+        let files = files();
+        assert_eq!(
+            files.first(),
+            system_dir().map(|dir| dir.join("config.toml")).as_ref()
+        );
+        if cfg!(unix) {
+            assert_eq!(files[0], PathBuf::from("/etc/lumins/config.toml"));
+        }
+
+        // The excludes of the system are kept, and its other keys are overridden by the user
+        let mut config = Config::default();
+        let system = "threads = 2\nexclude = [\".cache/\"]\n\n[sync]\nsecure = true\n";
+        assert_eq!(config.apply(system, "sync", None).is_ok(), true);
+        let user = "threads = 8\nexclude = [\"*.tmp\"]\n";
+        assert_eq!(config.apply(user, "sync", None).is_ok(), true);
+        let project = "secure = false\n";
+        assert_eq!(config.apply(project, "sync", None).is_ok(), true);
+        assert_eq!(
+            config,
+            Config {
+                exclude: vec![String::from(".cache/"), String::from("*.tmp")],
+                threads: Some(8),
+                ..Config::default()
+            }
+        );
+    }
+
+    #[test]
+    fn profiles() {
+        let contents = "secure = true\n\n[sync]\nnodelete = true\n\n\
+                        [profile.nas-backup]\nsource = \"/home/me\"\ndestination = \"/mnt/nas\"\n\
+                        nodelete = false\nexclude = [\".cache/\"]\n\n\
+                        [profile.other]\nsource = \"/srv\"\n";
+        let mut config = Config::default();
+        assert_eq!(
+            config.apply(contents, "sync", Some("nas-backup")).is_ok(),
+            true
+        );
+        assert_eq!(
+            config,
+            Config {
+                flags: Flag::SECURE,
+                exclude: vec![String::from(".cache/")],
+                source: Some(PathBuf::from("/home/me")),
+                destination: vec![PathBuf::from("/mnt/nas")],
+                ..Config::default()
+            }
+        );
+
+        // Profiles that are not given, and missing ones, are left alone
+        let mut config = Config::default();
+        assert_eq!(
+            config.apply(contents, "sync", Some("missing")).is_ok(),
+            true
+        );
+        assert_eq!(config.source, None);
+        assert_eq!(config.flags, Flag::SECURE | Flag::NO_DELETE);
+
+        // Sources are only given by profiles
+        assert_eq!(
+            config.apply("source = \"/home/me\"\n", "sync", None),
+            Err(String::from("source is not a known key"))
+        );
+    }
+
+    #[test]
+    fn checks() {
+        let contents = "threads = 4\n\n[sync]\nsecure = true\n\n\
+                        [profile.nas]\nsource = \"/home/me\"\nexclude = [\".cache/\"]\n";
+        assert_eq!(problems_of(contents), Vec::<String>::new());
+        assert_eq!(profiles_of(contents), vec![String::from("nas")]);
+        assert_eq!(
+            problems_of(
+                "source = \"/home/me\"\n[snyc]\nsecure = true\n[profile.nas]\ncolour = 1\n"
+            ),
+            vec![
+                String::from("profile.nas.colour is not a known key"),
+                String::from("[snyc] is not a subcommand, nor a profile such as [profile.snyc]"),
+                String::from("source is not a known key"),
+            ]
+        );
+        assert_eq!(problems_of("threads = ").len(), 1);
+
+        let config = Config {
+            flags: Flag::SECURE | Flag::NO_DELETE,
+            threads: Some(2),
+            source: Some(PathBuf::from("test_config_checks_missing")),
+            destination: vec![PathBuf::from("test_config_checks_missing/dest")],
+            ..Config::default()
+        };
+        assert_eq!(
+            describe(&config),
+            "source = \"test_config_checks_missing\"\n\
+             destination = [\"test_config_checks_missing/dest\"]\n\
+             flags = nodelete, secure\nthreads = 2\n"
+        );
+        assert_eq!(describe(&Config::default()), "(no options)\n");
+        assert_eq!(missing_paths(&config).len(), 2);
+        assert_eq!(missing_paths(&Config::default()).len(), 0);
+    }
+
+    #[test]
+    fn project_files() {
+        const TEST_DIR: &str = "test_config_project_files";
+
+        fs::create_dir_all([TEST_DIR, "a/b"].join("/")).unwrap();
+        fs::write([TEST_DIR, PROJECT_FILE].join("/"), b"").unwrap();
+        assert_eq!(
+            project_file(&Path::new(TEST_DIR).join("a/b")),
+            Some(Path::new(TEST_DIR).join(PROJECT_FILE))
+        );
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
@@ -0,0 +1,114 @@
+//! Contains the context of a job, which holds what a run is configured with, such as its backup
+//! directory or its transformations, and what it records while it runs, such as the source files
+//! that vanished, so that library calls running at the same time never see each other's.
+//!
+//! The context belongs to the job that the current thread works for, like its progress, so it
+//! reaches every thread that works for the job without being passed to every function.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hashbrown::HashSet;
+use rayon::ThreadPool;
+
+use crate::lumins::degrade::Degradation;
+use crate::lumins::delta::DEFAULT_BLOCK_SIZE;
+use crate::lumins::parse::{Flag, Options, Reflink};
+use crate::lumins::state::{CachedHashes, HashProgress};
+use crate::lumins::tiers::{DEFAULT_LARGE_FILE_JOBS, DEFAULT_LARGE_FILE_SIZE};
+use crate::lumins::transform::Transform;
+use crate::lumins::{backups, delta, file_ops, stall, throttle, tiers, transform};
+use crate::progress;
+
+/// Struct to represent the settings and records of a single job
+pub struct Context {
+    /// How long a copy may make no progress, and whether it is aborted after that
+    pub stall: Mutex<Option<(Duration, bool)>>,
+    /// Size from which files are large, and the number of large files copied at once
+    pub tiers: Mutex<(u64, usize)>,
+    /// Threads that copy large files outside of a job of its own, created when first needed
+    pub large_pool: Mutex<Option<Arc<ThreadPool>>>,
+    /// Size in bytes of the blocks that delta copies compare and rewrite
+    pub block_size: AtomicUsize,
+    /// Bytes per second that copies read from the source, or 0 if reads are not throttled
+    pub read_limit: AtomicU64,
+    /// Time by which every read so far is paid for, shared by all threads copying
+    pub read_schedule: Mutex<Option<Instant>>,
+    /// Whether files are cloned instead of copied, as the discriminant of a `Reflink`
+    pub reflink: AtomicU8,
+    /// Size in bytes below which files are compared by their contents even if their sizes and
+    /// modification times match
+    pub checksum_under: AtomicU64,
+    /// Nanoseconds by which modification times may differ and still be taken as the same
+    pub modify_window: AtomicU64,
+    /// Transformations of the copies, in the order they were given
+    pub transforms: Mutex<Vec<Transform>>,
+    /// Directory that old versions of destination files are kept in
+    pub backup_dir: Mutex<Option<PathBuf>>,
+    /// Source paths that vanished during the run
+    pub vanished: Mutex<HashSet<PathBuf>>,
+    /// Everything that could not be preserved during the run
+    pub degradations: Mutex<Vec<Degradation>>,
+    /// Progress of hashing large files, kept in the state of the destination being synchronized
+    pub hash_progress: Mutex<Option<HashProgress>>,
+    /// Hashes cached by earlier runs for the destination being synchronized
+    pub hash_cache: Mutex<Option<CachedHashes>>,
+    /// Number of actions recorded during the run, in the order of `Action::ALL`
+    pub counts: [AtomicUsize; 6],
+    /// Number of bytes of the files copied during the run
+    pub bytes: AtomicU64,
+    /// Destinations of a run to several destinations, with the number of file operations that
+    /// failed in each and the error it failed with, if any
+    pub destinations: Mutex<Vec<(PathBuf, usize, Option<String>)>>,
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Context {
+            stall: Mutex::new(None),
+            tiers: Mutex::new((DEFAULT_LARGE_FILE_SIZE, DEFAULT_LARGE_FILE_JOBS)),
+            large_pool: Mutex::new(None),
+            block_size: AtomicUsize::new(DEFAULT_BLOCK_SIZE),
+            read_limit: AtomicU64::new(0),
+            read_schedule: Mutex::new(None),
+            reflink: AtomicU8::new(Reflink::Never as u8),
+            checksum_under: AtomicU64::new(0),
+            modify_window: AtomicU64::new(0),
+            transforms: Mutex::new(Vec::new()),
+            backup_dir: Mutex::new(None),
+            vanished: Mutex::new(HashSet::new()),
+            degradations: Mutex::new(Vec::new()),
+            hash_progress: Mutex::new(None),
+            hash_cache: Mutex::new(None),
+            counts: Default::default(),
+            bytes: AtomicU64::new(0),
+            destinations: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+/// Gets the context of the job that the current thread works for
+pub fn current() -> Arc<Context> {
+    progress::current_job().context().clone()
+}
+
+/// Configures the job that the current thread works for with `options`, for all following
+/// copies, synchronizations, and removals
+///
+/// # Arguments
+/// * `options`: options of the job
+pub fn configure(options: &Options) {
+    stall::configure(
+        options.stall_timeout,
+        options.flags.contains(Flag::ABORT_STALLED),
+    );
+    tiers::configure(options.large_file_size, options.large_file_jobs);
+    delta::configure(options.block_size);
+    throttle::configure(options.read_bwlimit);
+    file_ops::configure_comparisons(options.checksum_under, options.modify_window);
+    file_ops::configure_reflinks(options.reflink);
+    transform::configure(options.transforms.clone());
+    backups::configure(options.backup_dir.clone());
+}
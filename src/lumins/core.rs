@@ -1,77 +1,820 @@
 //! Contains core copy, remove, synchronize functions
 
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
 use std::io;
+use std::path::{Path, PathBuf};
 
+use hashbrown::HashSet;
+use log::{error, info, warn};
 use rayon::prelude::*;
 
-use crate::lumins::{file_ops, file_ops::Dir, parse::Flag};
-use crate::progress::{self, PROGRESS_BAR};
+use crate::lumins::{
+    backups, dedupe, degrade, events, file_ops,
+    file_ops::Dir,
+    file_ops::{File, FileOps, FileSets, HashStore, Symlink},
+    locale,
+    parse::{DestNewer, Existing, Flag, Options},
+    plan::{Action, Plan},
+    quota, recycle, reflinks, reuse, rollback,
+    snapshot::Snapshot,
+    staging,
+    state::{HashCache, State},
+    transform, vanished, verify,
+};
+use crate::progress;
+
+/// Struct to represent a source directory that is scanned once for all destinations
+struct Source<'a> {
+    /// Source directory, or the snapshot of it that is read from
+    path: &'a Path,
+    /// Source directory itself, which the state belongs to and source files are removed from
+    live: &'a Path,
+    /// Files, dirs, and symlinks in the source
+    file_sets: FileSets,
+    /// State of the previous run to the only destination, if it is kept
+    state: Option<State>,
+    /// Hashes of the source files, shared by all destinations
+    hashes: HashStore,
+}
 
 /// Synchronizes all files, directories, and symlinks in `dest` with `src`
 ///
 /// # Arguments
 /// * `src`: Source directory
 /// * `dest`: Destination directory
-/// * `flags`: set for Flag's
+/// * `options`: command line options
 ///
 /// # Errors
 /// This function will return an error in the following situations,
 /// but is not limited to just these cases:
 /// * `src` is an invalid directory
 /// * `dest` is an invalid directory
-pub fn synchronize(src: &str, dest: &str, flags: Flag) -> Result<(), io::Error> {
+pub fn synchronize(src: &Path, dest: &Path, options: &Options) -> Result<(), io::Error> {
+    synchronize_all(src, &[dest], options)
+}
+
+/// Synchronizes all files, directories, and symlinks in every directory of `dests` with `src`
+///
+/// The source is scanned and its files are hashed only once, after which every destination is
+/// synchronized at the same time. Runs that keep the state of every destination, which decides
+/// what is scanned in the source, only repair metadata, or show plans or ask questions,
+/// synchronize one destination after another instead.
+///
+/// # Arguments
+/// * `src`: Source directory
+/// * `dests`: Destination directories
+/// * `options`: command line options
+///
+/// # Errors
+/// This function will return an error in the following situations,
+/// but is not limited to just these cases:
+/// * `src` is an invalid directory
+/// * any of `dests` is an invalid directory
+/// * synchronizing any of `dests` failed, once all of them are synchronized
+pub fn synchronize_all(src: &Path, dests: &[&Path], options: &Options) -> Result<(), io::Error> {
+    // The state of a destination decides what is scanned in the source, and the plans of dry runs
+    // and questions about newer files must not be mixed up between destinations
+    let one_at_a_time = Flag::METADATA_ONLY
+        | Flag::SKIP_UNCHANGED_DIRS
+        | Flag::JOURNAL
+        | Flag::STATE
+        | Flag::DRY_RUN;
+    if dests.len() > 1
+        && (options.flags.intersects(one_at_a_time) || options.on_dest_newer == DestNewer::Prompt)
+    {
+        let results = dests
+            .iter()
+            .map(|dest| synchronize(src, dest, options).map(|_| 0))
+            .collect();
+        return combine_results(dests, results).map(|_| ());
+    }
+
+    if options.flags.contains(Flag::METADATA_ONLY) {
+        return repair_metadata(src, dests[0], options);
+    }
+
+    // A dry run must not create the state, so it neither reads nor records one
+    let state = if options
+        .flags
+        .intersects(Flag::SKIP_UNCHANGED_DIRS | Flag::JOURNAL | Flag::STATE)
+        && !options.flags.contains(Flag::DRY_RUN)
+    {
+        Some(State::open(src, dests[0], options.flags)?)
+    } else {
+        None
+    };
+
+    // The state belongs to the source itself, not to the snapshot it is read from
+    // Source files are removed from the source itself, never from its snapshot
+    let live_src = src;
+    let snapshot = snapshot_source(src, options.flags)?;
+    let src = snapshot.as_ref().map(Snapshot::source).unwrap_or(src);
+
     // Retrieve data from src directory about files, dirs, symlinks
-    let src_file_sets = file_ops::get_all_files(&src)?;
+    progress::scan_init("Scanning source");
+    let src_file_sets = file_ops::get_all_files_with_state(src, options, state.as_ref())?;
+
+    // Files that did not change since the last run need not be hashed again
+    let hashes = HashStore::default();
+    if let Some(state) = &state {
+        state.load_hashes(options.flags, &hashes);
+    }
+    let source = Source {
+        path: src,
+        live: live_src,
+        file_sets: src_file_sets,
+        state,
+        hashes,
+    };
+
+    // Retrieve data from every dest directory about files, dirs, symlinks
+    progress::scan_init(if dests.len() == 1 {
+        "Scanning destination"
+    } else {
+        "Scanning destinations"
+    });
+    let dest_file_sets = dests
+        .iter()
+        .map(|dest| file_ops::get_all_files(dest, options))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Hashes of unchanged files are reused from earlier runs, a dry run does not create the cache
+    // A single cache is open at a time, which is the one of the first destination
+    let _cache = if options.flags.intersects(Flag::NO_CACHE | Flag::DRY_RUN) {
+        None
+    } else {
+        match HashCache::open(dests[0]) {
+            Ok(cache) => Some(cache),
+            Err(e) => {
+                warn!("Warning -- Opening the hash cache of {:?}: {}", dests[0], e);
+                None
+            }
+        }
+    };
+
+    let results = fan_out(dests, |i| {
+        sync_to(&source, dests[i], &dest_file_sets[i], options)
+    });
+    let failed = combine_results(dests, results)?;
+
+    vanished::report(options.flags.contains(Flag::STRICT))?;
+    degrade::report(options.flags.contains(Flag::STRICT))?;
+    report_failed(failed)
+}
+
+/// Synchronizes `dest` with the scanned `source`
+///
+/// # Arguments
+/// * `source`: Source directory, scanned once for all destinations
+/// * `dest`: Destination directory
+/// * `dest_file_sets`: files, dirs, and symlinks in `dest`
+/// * `options`: command line options
+///
+/// # Returns
+/// The number of file operations that failed
+///
+/// # Errors
+/// This function will return an error if the state cannot be recorded, the restore cannot be
+/// verified, or files were left unsynced
+fn sync_to(
+    source: &Source,
+    dest: &Path,
+    dest_file_sets: &FileSets,
+    options: &Options,
+) -> Result<usize, io::Error> {
+    let Source {
+        path: src,
+        live: live_src,
+        file_sets: src_file_sets,
+        state,
+        hashes,
+    } = source;
+    let (src, live_src, state) = (*src, *live_src, state.as_ref());
     let src_files = src_file_sets.files();
     let src_dirs = src_file_sets.dirs();
     let src_symlinks = src_file_sets.symlinks();
-
-    // Retrieve data from dest directory about files, dirs, symlinks
-    let dest_file_sets = file_ops::get_all_files(&dest)?;
     let dest_files = dest_file_sets.files();
     let dest_dirs = dest_file_sets.dirs();
     let dest_symlinks = dest_file_sets.symlinks();
 
-    // Initialize progress bar
-    progress::progress_init(
+    // Determine whether or not to delete
+    let delete = !options.flags.contains(Flag::NO_DELETE);
+
+    // Backups of newer dest files are kept, like the files they were made from
+    let keep_backups = options.on_dest_newer == DestNewer::Backup;
+
+    // Transformed copies differ in size from their source, so they are matched by path alone
+    let transformed: HashSet<&PathBuf> = if transform::is_configured() {
+        let src_paths: HashSet<&PathBuf> = src_files.iter().map(FileOps::path).collect();
+        dest_files
+            .iter()
+            .map(FileOps::path)
+            .filter(|path| src_paths.contains(path) && transform::command_for(path).is_some())
+            .collect()
+    } else {
+        HashSet::new()
+    };
+
+    // Paths skipped in src still exist there, so they must not be deleted from dest
+    let mut files_to_delete: HashSet<PathBuf> = if delete {
+        dest_files
+            .par_difference(&src_files)
+            .filter(|file| !src_file_sets.is_skipped(file.path()))
+            .filter(|file| !(keep_backups && is_backup(file.path())))
+            .filter(|file| !transformed.contains(file.path()))
+            .map(|file| file.path().clone())
+            .collect()
+    } else {
+        HashSet::new()
+    };
+
+    let symlinks_to_delete: Vec<&Symlink> = if delete {
+        dest_symlinks
+            .par_difference(&src_symlinks)
+            .filter(|symlink| !src_file_sets.is_skipped(symlink.path()))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let dirs_to_delete: Vec<&Dir> = if delete {
+        file_ops::sort_files(
+            dest_dirs
+                .par_difference(&src_dirs)
+                .filter(|dir| !src_file_sets.is_skipped(dir.path())),
+        )
+    } else {
+        Vec::new()
+    };
+
+    let mut files_to_compare: Vec<&File> = src_files.par_intersection(&dest_files).collect();
+    let (transformed, files_to_copy): (Vec<&File>, Vec<&File>) = src_files
+        .par_difference(dest_files)
+        .collect::<Vec<&File>>()
+        .into_iter()
+        .partition(|file| transformed.contains(file.path()));
+    files_to_compare.extend(transformed);
+
+    // Files are only updated in dest, or only created in it, as chosen with --existing and
+    // --ignore-existing, where a file exists in dest if its path does, whatever its size
+    let dest_paths: HashSet<&PathBuf> = if options.existing == Existing::All {
+        HashSet::new()
+    } else {
+        dest_files
+            .iter()
+            .map(FileOps::path)
+            .chain(dest_symlinks.iter().map(FileOps::path))
+            .collect()
+    };
+    let (files_to_compare, files_to_copy, existing_count, not_copied) = match options.existing {
+        Existing::All => (files_to_compare, files_to_copy, 0, Vec::new()),
+        Existing::Only => {
+            let (files_to_copy, created): (Vec<&File>, Vec<&File>) = files_to_copy
+                .into_iter()
+                .partition(|file| dest_paths.contains(file.path()));
+            for file in created.iter() {
+                info!(
+                    "Skipping {:?}, it does not exist in the destination",
+                    file.path()
+                );
+            }
+            (files_to_compare, files_to_copy, created.len(), created)
+        }
+        Existing::Ignore => {
+            let (files_to_copy, updated): (Vec<&File>, Vec<&File>) = files_to_copy
+                .into_iter()
+                .partition(|file| !dest_paths.contains(file.path()));
+            for file in files_to_compare.iter().chain(updated.iter()) {
+                info!("Skipping {:?}, it exists in the destination", file.path());
+                files_to_delete.remove(file.path());
+            }
+            let count = 2 * (files_to_compare.len() + updated.len());
+            let mut updated = updated;
+            updated.extend(files_to_compare);
+            (Vec::new(), files_to_copy, count, updated)
+        }
+    };
+    let symlinks_to_delete: Vec<&Symlink> = if options.existing == Existing::Ignore {
+        let src_paths: HashSet<&PathBuf> = src_symlinks.iter().map(FileOps::path).collect();
+        symlinks_to_delete
+            .into_iter()
+            .filter(|symlink| !src_paths.contains(symlink.path()))
+            .collect()
+    } else {
+        symlinks_to_delete
+    };
+    let dirs_to_copy = src_dirs
+        .par_difference(&dest_dirs)
+        .filter(|_| options.existing != Existing::Only);
+    let symlinks_to_copy = src_symlinks
+        .par_difference(&dest_symlinks)
+        .filter(|symlink| match options.existing {
+            Existing::All => true,
+            Existing::Only => dest_paths.contains(symlink.path()),
+            Existing::Ignore => !dest_paths.contains(symlink.path()),
+        });
+
+    // Dest files modified later than in src are only overwritten as chosen with --on-dest-newer,
+    // including those whose size differs from src, which are otherwise deleted and copied again
+    let (files_to_compare, files_to_copy, dest_newer) =
+        if options.on_dest_newer == DestNewer::Overwrite {
+            (files_to_compare, files_to_copy, Vec::new())
+        } else {
+            let dest_paths: HashSet<&PathBuf> = dest_files.iter().map(FileOps::path).collect();
+            let (files_to_compare, mut dest_newer): (Vec<&File>, Vec<&File>) = files_to_compare
+                .into_par_iter()
+                .partition(|file| !file_ops::dest_is_newer(*file, src, dest));
+            let (files_to_copy, resized): (Vec<&File>, Vec<&File>) =
+                files_to_copy.into_par_iter().partition(|file| {
+                    !dest_paths.contains(file.path()) || !file_ops::dest_is_newer(*file, src, dest)
+                });
+            dest_newer.extend(resized);
+            (files_to_compare, files_to_copy, dest_newer)
+        };
+    for file in dest_newer.iter() {
+        files_to_delete.remove(file.path());
+    }
+    let dest_newer_count = dest_newer.len();
+    // Source files that are deliberately left as they are in dest must not be removed from src
+    let mut not_copied: HashSet<&PathBuf> = not_copied
+        .iter()
+        .chain(dest_newer.iter())
+        .map(|file| file.path())
+        .collect();
+    let files_to_overwrite = overwrite_dest_newer(dest_newer, src, dest, options, hashes);
+    for file in files_to_overwrite.iter() {
+        not_copied.remove(file.path());
+    }
+
+    // Files that were only moved in src are renamed in dest, instead of being deleted and copied
+    let (files_to_copy, files_to_rename) = if options.flags.contains(Flag::DETECT_RENAMES) {
+        reuse::find_renames(
+            files_to_copy,
+            dest_files,
+            &files_to_delete,
+            src,
+            dest,
+            options.flags,
+            hashes,
+        )
+    } else {
+        (files_to_copy, Vec::new())
+    };
+
+    // Without deleting, dest files that differ in size from src are copied over, once backed up
+    let files_to_replace: Vec<&File> = if backups::is_configured() && !delete {
+        let dest_paths: HashSet<&PathBuf> = dest_files.iter().map(FileOps::path).collect();
+        files_to_copy
+            .iter()
+            .copied()
+            .filter(|file| dest_paths.contains(file.path()))
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    // Files to copy that would grow dest past --max-dest-size are left unsynced, in copy order
+    let (files_to_copy, unsynced) = match options.max_dest_size {
+        Some(max_dest_size) => quota::fit(
+            prioritize(files_to_copy, &options.priority)
+                .into_iter()
+                .flat_map(interleave)
+                .collect(),
+            quota::remaining_size(dest_files, &files_to_delete)
+                + files_to_rename
+                    .iter()
+                    .map(|(file, _)| file.size())
+                    .sum::<u64>(),
+            max_dest_size,
+        ),
+        None => (files_to_copy, Vec::new()),
+    };
+    not_copied.extend(unsynced.iter().map(|file| file.path()));
+    let report_unsynced = || match options.max_dest_size {
+        Some(max_dest_size) => quota::report(&unsynced, max_dest_size),
+        None => Ok(()),
+    };
+
+    // A dry run only compares files to plan updates, and shows the plan instead of carrying it out
+    if options.flags.contains(Flag::DRY_RUN) {
+        progress::phase_init("Comparing", files_to_compare.len() as u64);
+        let files_to_update = file_ops::differing_files(
+            files_to_compare.into_par_iter(),
+            src,
+            dest,
+            options.flags,
+            hashes,
+        );
+
+        let mut plan = Plan::default();
+        // Files that are renamed are not deleted
+        let renamed: HashSet<&PathBuf> = files_to_rename
+            .iter()
+            .map(|(_, original)| original.path())
+            .collect();
+        let files_to_delete: Vec<&PathBuf> = files_to_delete
+            .iter()
+            .filter(|path| !renamed.contains(path))
+            .collect();
+        // With --backup-dir, the old version of every file deleted or overwritten is kept
+        if backups::is_configured() {
+            plan.add(
+                Action::Backup,
+                symlinks_to_delete
+                    .iter()
+                    .map(|symlink| symlink.path())
+                    .chain(files_to_delete.iter().copied())
+                    .chain(
+                        files_to_replace
+                            .iter()
+                            .chain(files_to_update.iter())
+                            .map(|file| file.path()),
+                    )
+                    .chain(
+                        files_to_overwrite
+                            .iter()
+                            .filter(|_| !keep_backups)
+                            .map(|file| file.path()),
+                    ),
+            );
+        }
+        plan.add(
+            Action::Rename,
+            files_to_rename.iter().map(|(file, _)| file.path()),
+        );
+        plan.add(
+            Action::Delete,
+            symlinks_to_delete.iter().map(|symlink| symlink.path()),
+        );
+        plan.add(Action::Delete, files_to_delete);
+        plan.add(
+            Action::CreateDir,
+            dirs_to_copy.map(FileOps::path).collect::<Vec<_>>(),
+        );
+        plan.add(
+            Action::Symlink,
+            symlinks_to_copy.map(FileOps::path).collect::<Vec<_>>(),
+        );
+        plan.add(Action::Copy, files_to_copy.iter().map(|file| file.path()));
+        if keep_backups {
+            plan.add(
+                Action::Backup,
+                files_to_overwrite.iter().map(|file| file.path()),
+            );
+        }
+        plan.add(
+            Action::Update,
+            files_to_update
+                .iter()
+                .chain(files_to_overwrite.iter())
+                .map(|file| file.path()),
+        );
+        plan.add_ordered(Action::Delete, dirs_to_delete.iter().map(|dir| dir.path()));
+        if options.flags.contains(Flag::REMOVE_SOURCE_FILES) {
+            plan.add(Action::RemoveSource, src_files.iter().map(FileOps::path));
+        }
+        plan.print();
+        return report_unsynced().map(|_| 0);
+    }
+
+    // Initialize progress bar, counting every file once more for each of its bytes
+    let bytes: u64 = files_to_copy
+        .iter()
+        .chain(unsynced.iter())
+        .chain(files_to_compare.iter())
+        .chain(files_to_overwrite.iter())
+        .chain(files_to_rename.iter().map(|(file, _)| file))
+        .map(|file| file.size())
+        .sum();
+    progress::transfer_init(
         (src_files.len()
             + src_dirs.len()
             + src_symlinks.len()
             + dest_files.len()
             + dest_dirs.len()
-            + dest_symlinks.len()) as u64,
+            + dest_symlinks.len()
+            + files_to_overwrite.len()
+            - 2 * dest_newer_count
+            - existing_count) as u64,
+        bytes,
     );
 
-    // Determine whether or not to delete
-    let delete = !flags.contains(Flag::NO_DELETE);
+    // With --backup-dir, changes are recorded as they are made, so that `lms rollback` can undo
+    // them even if the sync fails, starting with the paths new to dest, which it deletes
+    // Only the last synchronization can be rolled back, from the backups it made
+    match backups::backup_dir() {
+        Some(backup_dir) => rollback::start(
+            dest,
+            &backup_dir,
+            &created_paths(src_file_sets, dest_file_sets, &files_to_rename),
+        )?,
+        None => rollback::discard(dest)?,
+    }
+
+    // Files that cannot be backed up are kept, instead of being copied over
+    let kept: Vec<&File> = files_to_replace
+        .par_iter()
+        .copied()
+        .filter(|file| !backups::is_backed_up(dest, file.path(), false))
+        .collect();
+    let mut files_to_copy = files_to_copy;
+    files_to_copy.retain(|file| !kept.contains(file));
+    progress::bar().inc(kept.iter().map(|file| 1 + file.size()).sum());
+    not_copied.extend(kept.iter().map(|file| file.path()));
+
+    // Contents in dest may be reused only before the files holding them are deleted
+    let files_to_copy = if options.flags.contains(Flag::REUSE_DEST) {
+        reuse::copy_files_from_dest(
+            files_to_copy,
+            dest_files,
+            &files_to_delete,
+            src,
+            dest,
+            options.flags,
+            hashes,
+        )
+    } else {
+        files_to_copy
+    };
+
+    // Files that could not be renamed are copied, and the files they were moved from deleted
+    let (renamed, not_renamed) = reuse::rename_files(files_to_rename, dest);
+    let mut files_to_copy = files_to_copy;
+    files_to_copy.extend(not_renamed);
+
+    // Dirs that the deletions may leave empty, which are pruned with --prune-empty-dirs
+    let emptied: Vec<PathBuf> = if options.flags.contains(Flag::PRUNE_EMPTY_DIRS) {
+        files_to_delete
+            .iter()
+            .chain(symlinks_to_delete.iter().map(|symlink| symlink.path()))
+            .chain(dirs_to_delete.iter().map(|dir| dir.path()))
+            .filter_map(|path| path.parent())
+            .map(PathBuf::from)
+            .collect()
+    } else {
+        Vec::new()
+    };
 
     // Delete files and symlinks
-    if delete {
-        let symlinks_to_delete = dest_symlinks.par_difference(&src_symlinks);
-        let files_to_delete = dest_files.par_difference(&src_files);
+    let files_to_delete = dest_files
+        .par_iter()
+        .filter(|file| files_to_delete.contains(file.path()) && !renamed.contains(file.path()));
+    let mut failed = kept.len();
+    // Deletions are staged apart from those kept by earlier runs that failed
+    let staging_dir = staging::run_dir(dest);
+    failed += if backups::is_configured() {
+        backups::move_files(symlinks_to_delete.into_par_iter(), dest)
+            + backups::move_files(files_to_delete, dest)
+    } else if options.flags.contains(Flag::TRASH) {
+        let files_to_delete: Vec<&File> = files_to_delete.collect();
+        recycle::trash_files(symlinks_to_delete, dest) + recycle::trash_files(files_to_delete, dest)
+    } else if options.flags.contains(Flag::STAGE_DELETIONS) {
+        staging::stage_files(symlinks_to_delete.into_par_iter(), dest, &staging_dir)
+            + staging::stage_files(files_to_delete, dest, &staging_dir)
+    } else {
+        file_ops::delete_files(symlinks_to_delete.into_par_iter(), &dest)
+            + file_ops::delete_files(files_to_delete, &dest)
+    };
+
+    failed += file_ops::copy_files(dirs_to_copy, src, dest, options.flags);
+    failed += file_ops::copy_files(symlinks_to_copy, src, dest, options.flags);
+
+    // New files are hashed while they are copied if their hashes are recorded
+    let hash_copies = state.is_some();
+
+    // Hard linked files are cloned or linked once the file they are linked to is copied
+    let (files_to_copy, files_to_clone) = if options
+        .flags
+        .intersects(Flag::REFLINK_HARDLINKS | Flag::HARD_LINKS)
+    {
+        reflinks::split(files_to_copy, src)
+    } else {
+        (files_to_copy, Vec::new())
+    };
+
+    let files_to_copy = order(files_to_copy, options);
+    let files_to_compare = order(files_to_compare, options);
+    for (files_to_copy, files_to_compare) in files_to_copy.into_iter().zip(files_to_compare) {
+        let files_to_copy = interleave(files_to_copy).into_par_iter();
+        failed += if hash_copies {
+            file_ops::copy_files_hashed(files_to_copy, src, dest, options.flags, hashes)
+        } else {
+            file_ops::copy_files(files_to_copy, src, dest, options.flags)
+        };
+        failed += file_ops::compare_and_copy_files(
+            interleave(files_to_compare).into_par_iter(),
+            &src,
+            &dest,
+            options.flags,
+            hashes,
+        );
+    }
+    failed += reflinks::clone_files(
+        files_to_clone,
+        src,
+        dest,
+        options.flags,
+        Some(hashes).filter(|_| hash_copies),
+    );
+
+    // Newer dest files that are backed up are only overwritten once their backup succeeded
+    let overwrite_count = files_to_overwrite.len();
+    let files_to_overwrite = if keep_backups {
+        file_ops::backup_files(files_to_overwrite.into_par_iter(), dest)
+    } else if backups::is_configured() {
+        backups::back_up_files(
+            files_to_overwrite.into_par_iter(),
+            dest,
+            options.flags.contains(Flag::DELTA),
+        )
+    } else {
+        files_to_overwrite
+    };
+    failed += overwrite_count - files_to_overwrite.len();
+    failed += file_ops::copy_files(files_to_overwrite.into_par_iter(), src, dest, options.flags);
+
+    copy_metadata(&src_file_sets, &src, &dest, options.flags);
+    dedupe_dest(src_files, dest, options.flags);
+
+    // Staged deletions are only purged if everything else succeeded, and otherwise the dirs they
+    // were moved out of are kept too, so that they can be moved back
+    let staged = options.flags.contains(Flag::STAGE_DELETIONS);
+    if !staged || failed == 0 {
+        // Delete dirs in the correct order
+        failed += file_ops::delete_files_sequential(dirs_to_delete, &dest);
+        prune_empty_dirs(emptied, src_file_sets, dest);
+    }
+    if staged {
+        staging::finish(dest, &staging_dir, failed)?;
+    }
+
+    failed += remove_source_files(src_files, &not_copied, live_src, dest, options.flags);
+
+    if let Some(state) = state {
+        state.commit(src_file_sets.skipped(), hashes)?;
+    }
+
+    if options.flags.contains(Flag::VERIFY_RESTORE) {
+        verify::verify_restore(live_src, dest, options.flags)?;
+    }
+
+    report_unsynced()?;
+    Ok(failed)
+}
+
+/// Sums the failed file operations of every destination
+///
+/// Every destination that failed is logged, since only the first error is returned.
+///
+/// # Arguments
+/// * `dests`: Destination directories
+/// * `results`: result of every destination, in the same order
+///
+/// # Errors
+/// This function will return the error of the first destination that failed
+fn combine_results(
+    dests: &[&Path],
+    results: Vec<Result<usize, io::Error>>,
+) -> Result<usize, io::Error> {
+    let mut failed = 0;
+    let mut first_error = None;
+    for (dest, result) in dests.iter().zip(results) {
+        if dests.len() > 1 {
+            events::record_destination(dest, *result.as_ref().unwrap_or(&0), result.as_ref().err());
+        }
+        match result {
+            Ok(count) => {
+                if dests.len() > 1 && count > 0 {
+                    error!("Error -- {} file operations failed in {:?}", count, dest);
+                }
+                failed += count
+            }
+            Err(e) => {
+                if dests.len() > 1 {
+                    error!("Error -- Synchronizing {:?}: {}", dest, e);
+                }
+                first_error = first_error.or(Some(e));
+            }
+        }
+    }
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(failed),
+    }
+}
 
-        file_ops::delete_files(symlinks_to_delete, &dest);
-        file_ops::delete_files(files_to_delete, &dest);
+/// Runs `transfer` for the index of every destination in `dests` at the same time, showing the
+/// progress of each on a bar of its own
+///
+/// Within a job of its own, such as a synchronization run through the library, the transfers
+/// add up to the single bar of the job instead.
+///
+/// # Returns
+/// The result of every destination, in the same order
+fn fan_out<F>(dests: &[&Path], transfer: F) -> Vec<Result<usize, io::Error>>
+where
+    F: Fn(usize) -> Result<usize, io::Error> + Sync,
+{
+    if dests.len() == 1 {
+        return vec![transfer(0)];
+    }
+    if progress::in_job() {
+        let _shared = progress::SharedProgress::start();
+        return (0..dests.len()).into_par_iter().map(&transfer).collect();
     }
 
-    let dirs_to_copy = src_dirs.par_difference(&dest_dirs);
-    let symlinks_to_copy = src_symlinks.par_difference(&dest_symlinks);
-    let files_to_copy = src_files.par_difference(&dest_files);
-    let files_to_compare = src_files.par_intersection(&dest_files);
+    let bars = progress::FanOut::start(dests);
+    (0..dests.len())
+        .into_par_iter()
+        .map(|i| bars.run(i, || transfer(i)))
+        .collect()
+}
 
-    file_ops::copy_files(dirs_to_copy, &src, &dest);
-    file_ops::copy_files(symlinks_to_copy, &src, &dest);
-    file_ops::copy_files(files_to_copy, &src, &dest);
-    file_ops::compare_and_copy_files(files_to_compare, &src, &dest, flags);
+/// Selects the files to overwrite out of those modified later in `dest` than in `src`,
+/// as chosen with --on-dest-newer
+///
+/// # Arguments
+/// * `dest_newer`: files that are newer in `dest` than in `src`
+/// * `src`: Source directory
+/// * `dest`: Destination directory
+/// * `options`: command line options
+/// * `hashes`: store to record the hashes of the source files in
+///
+/// # Returns
+/// The files whose contents differ and that are overwritten
+fn overwrite_dest_newer<'a>(
+    dest_newer: Vec<&'a File>,
+    src: &Path,
+    dest: &Path,
+    options: &Options,
+    hashes: &HashStore,
+) -> Vec<&'a File> {
+    if options.on_dest_newer == DestNewer::Skip {
+        for file in dest_newer {
+            info!("Skipping {:?}, it is newer in the destination", file.path());
+        }
+        return Vec::new();
+    }
 
-    // Delete dirs in the correct order
-    if delete {
-        let dirs_to_delete = dest_dirs.par_difference(&src_dirs);
-        let dirs_to_delete: Vec<&file_ops::Dir> = file_ops::sort_files(dirs_to_delete);
-        file_ops::delete_files_sequential(dirs_to_delete, &dest);
+    progress::phase_init("Comparing newer files", dest_newer.len() as u64);
+    let files_to_overwrite =
+        file_ops::differing_files(dest_newer.into_par_iter(), src, dest, options.flags, hashes);
+
+    // A dry run lists every file that it would ask about
+    if options.on_dest_newer == DestNewer::Prompt && !options.flags.contains(Flag::DRY_RUN) {
+        let dest = PathBuf::from(dest);
+        files_to_overwrite
+            .into_iter()
+            .filter(|file| {
+                progress::confirm(&locale::text(
+                    "overwrite-newer",
+                    &[("path", &format!("{:?}", dest.join(file.path())))],
+                ))
+            })
+            .collect()
+    } else {
+        files_to_overwrite
     }
+}
 
-    Ok(())
+/// Gets the paths in the source that do not exist in the destination yet, and so are created by
+/// synchronizing it, leaving out the files renamed from a dest file
+///
+/// # Arguments
+/// * `src_file_sets`: files, dirs, and symlinks in the source
+/// * `dest_file_sets`: files, dirs, and symlinks in the destination
+/// * `files_to_rename`: files to rename in dest, with the dest file they are renamed from
+fn created_paths(
+    src_file_sets: &FileSets,
+    dest_file_sets: &FileSets,
+    files_to_rename: &[(&File, &File)],
+) -> Vec<PathBuf> {
+    let existing: HashSet<&PathBuf> = dest_file_sets
+        .files()
+        .iter()
+        .map(FileOps::path)
+        .chain(dest_file_sets.dirs().iter().map(FileOps::path))
+        .chain(dest_file_sets.symlinks().iter().map(FileOps::path))
+        .chain(files_to_rename.iter().map(|(file, _)| file.path()))
+        .collect();
+    src_file_sets
+        .files()
+        .iter()
+        .map(FileOps::path)
+        .chain(src_file_sets.dirs().iter().map(FileOps::path))
+        .chain(src_file_sets.symlinks().iter().map(FileOps::path))
+        .filter(|path| !existing.contains(path))
+        .cloned()
+        .collect()
+}
+
+/// Checks whether `path` is the backup of a newer dest file, made by --on-dest-newer backup
+fn is_backup(path: &Path) -> bool {
+    path.as_os_str()
+        .to_string_lossy()
+        .ends_with(file_ops::BACKUP_SUFFIX)
 }
 
 /// Copies all files, directories, and symlinks in `src` to `dest`
@@ -79,58 +822,495 @@ pub fn synchronize(src: &str, dest: &str, flags: Flag) -> Result<(), io::Error>
 /// # Arguments
 /// * `src`: Source directory
 /// * `dest`: Destination directory
-/// * `flags`: set for Flag's
+/// * `options`: command line options
 ///
 /// # Errors
 /// This function will return an error in the following situations,
 /// but is not limited to just these cases:
 /// * `src` is an invalid directory
 /// * `dest` is an invalid directory
-pub fn copy(src: &str, dest: &str, _flags: Flag) -> Result<(), io::Error> {
+pub fn copy(src: &Path, dest: &Path, options: &Options) -> Result<(), io::Error> {
+    copy_all(src, &[dest], options)
+}
+
+/// Copies all files, directories, and symlinks in `src` to every directory of `dests`
+///
+/// The source is scanned only once, after which it is copied to every destination at the same
+/// time, like for `synchronize_all`.
+///
+/// # Arguments
+/// * `src`: Source directory
+/// * `dests`: Destination directories
+/// * `options`: command line options
+///
+/// # Errors
+/// This function will return an error in the following situations,
+/// but is not limited to just these cases:
+/// * `src` is an invalid directory
+/// * any of `dests` is an invalid directory
+/// * copying to any of `dests` failed, once all of them are copied to
+pub fn copy_all(src: &Path, dests: &[&Path], options: &Options) -> Result<(), io::Error> {
+    if dests.len() > 1
+        && options
+            .flags
+            .intersects(Flag::METADATA_ONLY | Flag::DRY_RUN)
+    {
+        let results = dests
+            .iter()
+            .map(|dest| copy(src, dest, options).map(|_| 0))
+            .collect();
+        return combine_results(dests, results).map(|_| ());
+    }
+
+    if options.flags.contains(Flag::METADATA_ONLY) {
+        return repair_metadata(src, dests[0], options);
+    }
+
+    // Source files are removed from the source itself, never from its snapshot
+    let live_src = src;
+    let snapshot = snapshot_source(src, options.flags)?;
+    let src = snapshot.as_ref().map(Snapshot::source).unwrap_or(src);
+
     // Retrieve data from src directory about files, dirs, symlinks
-    let src_file_sets = file_ops::get_all_files(&src)?;
+    progress::scan_init("Scanning source");
+    let src_file_sets = file_ops::get_all_files(&src, options)?;
+
+    if options.flags.contains(Flag::DRY_RUN) {
+        Plan::copy(&src_file_sets, options.flags).print();
+        return Ok(());
+    }
+
+    let source = Source {
+        path: src,
+        live: live_src,
+        file_sets: src_file_sets,
+        state: None,
+        hashes: HashStore::default(),
+    };
+
+    let results = fan_out(dests, |i| copy_to(&source, dests[i], options));
+    let failed = combine_results(dests, results)?;
+
+    vanished::report(options.flags.contains(Flag::STRICT))?;
+    degrade::report(options.flags.contains(Flag::STRICT))?;
+    report_failed(failed)
+}
+
+/// Copies the scanned `source` to `dest`
+///
+/// # Arguments
+/// * `source`: Source directory, scanned once for all destinations
+/// * `dest`: Destination directory
+/// * `options`: command line options
+///
+/// # Returns
+/// The number of file operations that failed
+///
+/// # Errors
+/// This function will return an error if the restore cannot be verified
+fn copy_to(source: &Source, dest: &Path, options: &Options) -> Result<usize, io::Error> {
+    let (src, src_file_sets) = (source.path, &source.file_sets);
     let src_files = src_file_sets.files();
     let src_dirs = src_file_sets.dirs();
     let src_symlinks = src_file_sets.symlinks();
 
-    // Initialize progress bar
-    progress::progress_init((src_files.len() + src_dirs.len() + src_symlinks.len()) as u64);
+    // Initialize progress bar, counting every file once more for each of its bytes
+    progress::transfer_init(
+        (src_files.len() + src_dirs.len() + src_symlinks.len()) as u64,
+        src_files.iter().map(FileOps::size).sum(),
+    );
 
     // Copy everything
-    file_ops::copy_files(src_dirs.into_par_iter(), &src, &dest);
-    file_ops::copy_files(src_files.into_par_iter(), &src, &dest);
-    file_ops::copy_files(src_symlinks.into_par_iter(), &src, &dest);
+    let mut failed = file_ops::copy_files(src_dirs.into_par_iter(), src, dest, options.flags);
+
+    // Hard linked files are cloned or linked once the file they are linked to is copied
+    let (files_to_copy, files_to_clone) = if options
+        .flags
+        .intersects(Flag::REFLINK_HARDLINKS | Flag::HARD_LINKS)
+    {
+        reflinks::split(src_files.iter().collect(), src)
+    } else {
+        (src_files.iter().collect(), Vec::new())
+    };
+
+    for files in order(files_to_copy, options) {
+        let files = interleave(files).into_par_iter();
+        failed += file_ops::copy_files(files, src, dest, options.flags);
+    }
+    failed += reflinks::clone_files(files_to_clone, src, dest, options.flags, None);
+    failed += file_ops::copy_files(src_symlinks.into_par_iter(), src, dest, options.flags);
+
+    copy_metadata(src_file_sets, src, dest, options.flags);
+    dedupe_dest(src_files, dest, options.flags);
+    failed += remove_source_files(src_files, &HashSet::new(), source.live, dest, options.flags);
+
+    if options.flags.contains(Flag::VERIFY_RESTORE) {
+        verify::verify_restore(source.live, dest, options.flags)?;
+    }
+    Ok(failed)
+}
+
+/// Makes the metadata of everything that exists in both `src` and `dest` match `src`,
+/// without copying the contents of any file
+///
+/// Symlinks whose target differs are recreated, since their target is all they hold
+///
+/// # Arguments
+/// * `src`: Source directory
+/// * `dest`: Destination directory
+/// * `options`: command line options
+///
+/// # Errors
+/// This function will return an error in the following situations,
+/// but is not limited to just these cases:
+/// * `src` is an invalid directory
+/// * `dest` is an invalid directory
+fn repair_metadata(src: &Path, dest: &Path, options: &Options) -> Result<(), io::Error> {
+    let snapshot = snapshot_source(src, options.flags)?;
+    let src = snapshot.as_ref().map(Snapshot::source).unwrap_or(src);
+
+    progress::scan_init("Scanning source");
+    let src_file_sets = file_ops::get_all_files(src, options)?;
+    progress::scan_init("Scanning destination");
+    let dest_file_sets = file_ops::get_all_files(dest, options)?;
+
+    let dest_symlinks: HashSet<&PathBuf> = dest_file_sets
+        .symlinks()
+        .iter()
+        .map(|symlink| symlink.path())
+        .collect();
+    let common_symlinks: HashSet<Symlink> = src_file_sets
+        .symlinks()
+        .iter()
+        .filter(|symlink| dest_symlinks.contains(symlink.path()))
+        .cloned()
+        .collect();
+    let symlinks_to_retarget: Vec<&Symlink> = common_symlinks
+        .difference(dest_file_sets.symlinks())
+        .collect();
+
+    if options.flags.contains(Flag::DRY_RUN) {
+        let mut plan = Plan::default();
+        plan.add(
+            Action::Symlink,
+            symlinks_to_retarget.iter().map(|symlink| symlink.path()),
+        );
+        if options
+            .flags
+            .intersects(Flag::PERMS | Flag::OWNER | Flag::TIMES)
+        {
+            plan.add(Action::Metadata, common_symlinks.iter().map(FileOps::path));
+            plan.add(
+                Action::Metadata,
+                src_file_sets
+                    .files()
+                    .intersection(dest_file_sets.files())
+                    .map(FileOps::path),
+            );
+            plan.add(
+                Action::Metadata,
+                src_file_sets
+                    .dirs()
+                    .intersection(dest_file_sets.dirs())
+                    .map(FileOps::path),
+            );
+        }
+        plan.print();
+        return Ok(());
+    }
+
+    progress::progress_init(2 * symlinks_to_retarget.len() as u64);
+    let mut failed = file_ops::delete_files(symlinks_to_retarget.par_iter().copied(), dest);
+    failed += file_ops::copy_files(
+        symlinks_to_retarget.into_par_iter(),
+        src,
+        dest,
+        options.flags,
+    );
+
+    let common_file_sets = file_ops::FileSets::with(
+        src_file_sets
+            .files()
+            .intersection(dest_file_sets.files())
+            .cloned()
+            .collect(),
+        src_file_sets
+            .dirs()
+            .intersection(dest_file_sets.dirs())
+            .cloned()
+            .collect(),
+        common_symlinks,
+    );
+    copy_metadata(&common_file_sets, src, dest, options.flags);
+
+    vanished::report(options.flags.contains(Flag::STRICT))?;
+    degrade::report(options.flags.contains(Flag::STRICT))?;
+    report_failed(failed)
+}
+
+/// Creates a snapshot of `src` to read from, if `flags` contains `SNAPSHOT` but not `DRY_RUN`
+///
+/// # Arguments
+/// * `src`: Source directory
+/// * `flags`: set for Flag's
+///
+/// # Returns
+/// * Some: The snapshot, which is removed when dropped
+/// * None: If no snapshot is requested
+///
+/// # Errors
+/// This function will return an error if the snapshot cannot be created
+fn snapshot_source(src: &Path, flags: Flag) -> Result<Option<Snapshot>, io::Error> {
+    // A dry run reads the source itself, since creating a snapshot changes the filesystem
+    if !flags.contains(Flag::SNAPSHOT) || flags.contains(Flag::DRY_RUN) {
+        return Ok(None);
+    }
+
+    Snapshot::create(src).map(Some)
+}
+
+/// Groups `files` by the first of the `priority` paths that they are under, in order
+///
+/// # Arguments
+/// * `files`: files to group
+/// * `priority`: paths to transfer first, in order
+///
+/// # Returns
+/// One group of files per priority path, followed by a group of all remaining files
+fn prioritize<'a, S>(files: Vec<&'a S>, priority: &[PathBuf]) -> Vec<Vec<&'a S>>
+where
+    S: FileOps,
+{
+    let mut groups = vec![Vec::new(); priority.len() + 1];
+    for file in files {
+        let group = priority
+            .iter()
+            .position(|path| file.path().starts_with(path))
+            .unwrap_or(priority.len());
+        groups[group].push(file);
+    }
+    groups
+}
+
+/// Splits `files` into the groups of the order of `options`, and every group into the groups of
+/// its priority paths, so that a group is only transferred once the groups before it are
+///
+/// # Arguments
+/// * `files`: files to group
+/// * `options`: command line options, of which `order` and `priority` give the groups
+///
+/// # Returns
+/// The groups, where every group of the order has the same number of priority groups
+fn order<'a, S>(files: Vec<&'a S>, options: &Options) -> Vec<Vec<&'a S>>
+where
+    S: FileOps,
+{
+    let mut groups = vec![Vec::new(); options.order.group_count()];
+    for file in files {
+        groups[options.order.group_of(file.path())].push(file);
+    }
+    groups
+        .into_iter()
+        .flat_map(|files| prioritize(files, &options.priority))
+        .collect()
+}
+
+/// Orders `files` by taking one file from each top-level directory in turn, so that every
+/// directory makes progress instead of one huge directory holding up all others
+///
+/// Files within a top-level directory are ordered by path.
+///
+/// # Arguments
+/// * `files`: files to order
+///
+/// # Returns
+/// The files of all top-level directories, interleaved
+fn interleave<'a, S>(files: Vec<&'a S>) -> Vec<&'a S>
+where
+    S: FileOps,
+{
+    let len = files.len();
+    let mut dirs: BTreeMap<&OsStr, Vec<&'a S>> = BTreeMap::new();
+    for file in files {
+        let top = file
+            .path()
+            .components()
+            .next()
+            .map_or(OsStr::new(""), |component| component.as_os_str());
+        dirs.entry(top).or_default().push(file);
+    }
+
+    let mut dirs: Vec<_> = dirs
+        .into_values()
+        .map(|mut files| {
+            files.sort_by(|a, b| a.path().cmp(b.path()));
+            files.into_iter()
+        })
+        .collect();
+
+    let mut interleaved = Vec::with_capacity(len);
+    while !dirs.is_empty() {
+        dirs.retain_mut(|files| match files.next() {
+            Some(file) => {
+                interleaved.push(file);
+                true
+            }
+            None => false,
+        });
+    }
+    interleaved
+}
+
+/// Hard links identical files in `dest` if `flags` contains `DEDUPE_DEST`
+///
+/// # Arguments
+/// * `files`: files that were copied or synchronized to `dest`
+/// * `dest`: Destination directory
+/// * `flags`: set for Flag's
+fn dedupe_dest(files: &HashSet<File>, dest: &Path, flags: Flag) {
+    if !flags.contains(Flag::DEDUPE_DEST) {
+        return;
+    }
+
+    let stats = dedupe::dedupe_files(files, dest, flags);
+    info!(
+        "Deduplicated {} files in {:?}, reclaiming {} bytes",
+        stats.files, dest, stats.bytes
+    );
+}
+
+/// Deletes every directory in `dest` that is empty, out of `emptied` and the directories above
+/// them, unless it exists in the source
+///
+/// # Arguments
+/// * `emptied`: directories, relative to `dest`, that deletions may have left empty
+/// * `src_file_sets`: files, dirs, and symlinks in the source
+/// * `dest`: Destination directory
+fn prune_empty_dirs(emptied: Vec<PathBuf>, src_file_sets: &FileSets, dest: &Path) {
+    let src_dirs: HashSet<&Path> = src_file_sets
+        .dirs()
+        .iter()
+        .map(|dir| dir.path().as_path())
+        .collect();
+    let mut dirs: Vec<&Path> = emptied
+        .iter()
+        .flat_map(|dir| dir.ancestors())
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .filter(|dir| !src_dirs.contains(dir) && !src_file_sets.is_skipped(dir))
+        .collect::<HashSet<&Path>>()
+        .into_iter()
+        .collect();
+
+    // Subdirectories are pruned before the directories they are in, which they may leave empty
+    dirs.sort_unstable_by_key(|dir| Reverse(dir.components().count()));
+    for dir in dirs {
+        let path = dest.join(dir);
+        let is_empty = fs::read_dir(&path)
+            .map(|mut entries| entries.next().is_none())
+            .unwrap_or(false);
+        if !is_empty {
+            continue;
+        }
+        match fs::remove_dir(&path) {
+            Ok(_) => events::record(
+                events::Action::Delete,
+                &path,
+                format_args!("Deleting empty dir {:?}", path),
+            ),
+            Err(e) => events::record(
+                events::Action::Error,
+                &path,
+                format_args!("Error -- Deleting empty dir {:?}: {}", path, e),
+            ),
+        }
+    }
+}
+
+/// Deletes every file in `src` whose copy in `dest` is verified, if `flags` contains
+/// `REMOVE_SOURCE_FILES`
+///
+/// Files that were deliberately not copied, such as newer dest files kept with --on-dest-newer,
+/// are kept in `src` without verifying them, since their copy is not meant to match.
+///
+/// # Arguments
+/// * `files`: files that were copied or synchronized to `dest`
+/// * `not_copied`: paths of the files that were deliberately not copied
+/// * `src`: Source directory
+/// * `dest`: Destination directory
+/// * `flags`: set for Flag's
+///
+/// # Returns
+/// The number of source files that were kept because they could not be verified or deleted
+fn remove_source_files(
+    files: &HashSet<File>,
+    not_copied: &HashSet<&PathBuf>,
+    src: &Path,
+    dest: &Path,
+    flags: Flag,
+) -> usize {
+    if !flags.contains(Flag::REMOVE_SOURCE_FILES) {
+        return 0;
+    }
+
+    let files: Vec<&File> = files
+        .iter()
+        .filter(|file| {
+            let copied = !not_copied.contains(file.path());
+            if !copied {
+                info!("Keeping source file {:?}, it was not copied", file.path());
+            }
+            copied
+        })
+        .collect();
+    progress::phase_init("Verifying copies", files.len() as u64);
+    file_ops::remove_source_files(files.into_par_iter(), src, dest, flags)
+}
+
+/// Copies the metadata selected by `flags` of everything in `file_sets` from `src` to `dest`
+///
+/// # Arguments
+/// * `file_sets`: files, dirs, and symlinks to copy the metadata of
+/// * `src`: Source directory
+/// * `dest`: Destination directory
+/// * `flags`: set for Flag's
+fn copy_metadata(file_sets: &file_ops::FileSets, src: &Path, dest: &Path, flags: Flag) {
+    // The attributes and alternate data streams of Windows files are always copied
+    if !flags.intersects(Flag::PERMS | Flag::OWNER | Flag::TIMES | Flag::XATTRS)
+        && !cfg!(target_family = "windows")
+    {
+        return;
+    }
+
+    if flags.contains(Flag::OWNER) && !file_ops::is_privileged() {
+        warn!("Warning -- Not running as root, only groups of the current user can be preserved");
+    }
 
-    Ok(())
+    file_ops::copy_metadata_files(file_sets.files().par_iter(), src, dest, flags);
+    file_ops::copy_metadata_files(file_sets.symlinks().par_iter(), src, dest, flags);
+
+    // Directories go last so that their permissions cannot block copying into them
+    file_ops::copy_metadata_files(file_sets.dirs().par_iter(), src, dest, flags);
+    Dir::from("").copy_metadata(&src.to_path_buf(), &dest.to_path_buf(), flags);
 }
 
 /// Deletes directory `target`
 ///
 /// # Arguments
 /// * `target`: Target directory
-/// * `flags`: set for Flag's
+/// * `options`: command line options
 ///
 /// # Errors
 /// This function will return an error in the following situations,
 /// but is not limited to just these cases:
 /// * `target` is an invalid directory
-pub fn remove(target: &str, _flags: Flag) -> Result<(), io::Error> {
+pub fn remove(target: &Path, options: &Options) -> Result<(), io::Error> {
     // Retrieve data from target directory about files, dirs, symlinks
-    let target_file_sets = file_ops::get_all_files(&target)?;
+    progress::scan_init("Scanning");
+    let target_file_sets = file_ops::get_all_files(&target, options)?;
     let target_files = target_file_sets.files();
     let target_dirs = target_file_sets.dirs();
     let target_symlinks = target_file_sets.symlinks();
 
-    // Initialize progress bar
-    progress::progress_init(
-        (target_files.len() + target_dirs.len() + target_symlinks.len()) as u64,
-    );
-    PROGRESS_BAR.enable_steady_tick(1);
-
-    // Delete everything
-    file_ops::delete_files(target_files.into_par_iter(), &target);
-    file_ops::delete_files(target_symlinks.into_par_iter(), &target);
-
     // Directories must always be deleted sequentially so that they are deleted in the correct order
     let mut target_dirs: Vec<&file_ops::Dir> = file_ops::sort_files(target_dirs.into_par_iter());
 
@@ -138,9 +1318,49 @@ pub fn remove(target: &str, _flags: Flag) -> Result<(), io::Error> {
     let root_dir = Dir::from("");
     target_dirs.push(&root_dir);
 
-    file_ops::delete_files_sequential(target_dirs.into_iter(), &target);
+    if options.flags.contains(Flag::DRY_RUN) {
+        let mut plan = Plan::default();
+        plan.add(Action::Delete, target_files.iter().map(FileOps::path));
+        plan.add(Action::Delete, target_symlinks.iter().map(FileOps::path));
+        plan.add_ordered(Action::Delete, target_dirs.iter().map(|dir| dir.path()));
+        plan.print();
+        return Ok(());
+    }
+
+    // Initialize progress bar
+    progress::progress_init(
+        (target_files.len() + target_dirs.len() + target_symlinks.len()) as u64,
+    );
+    progress::bar().enable_steady_tick(1);
+
+    // Delete everything, where directories are only removed once they are empty
+    let mut failed = if options.flags.contains(Flag::TRASH) {
+        recycle::trash_files(target_files, target) + recycle::trash_files(target_symlinks, target)
+    } else {
+        file_ops::delete_files(target_files.into_par_iter(), &target)
+            + file_ops::delete_files(target_symlinks.into_par_iter(), &target)
+    };
+
+    failed += file_ops::delete_files_sequential(target_dirs.into_iter(), &target);
 
-    Ok(())
+    report_failed(failed)
+}
+
+/// Reports how many operations on files failed during a run, each of which was logged already
+///
+/// # Arguments
+/// * `failed`: number of failed operations
+///
+/// # Errors
+/// This function will return an error if any operation failed
+fn report_failed(failed: usize) -> Result<(), io::Error> {
+    if failed == 0 {
+        return Ok(());
+    }
+    Err(io::Error::other(format!(
+        "{} file operations failed, see the errors above",
+        failed
+    )))
 }
 
 ///////////////////////////////////////////////////////////////////////////////////////////////////
@@ -150,7 +1370,9 @@ pub fn remove(target: &str, _flags: Flag) -> Result<(), io::Error> {
 #[cfg(test)]
 mod test_synchronize {
     use super::*;
+    use crate::lumins::parse;
     use std::fs;
+    use std::path::Path;
     use std::process::Command;
 
     #[cfg(debug_assertions)]
@@ -161,12 +1383,18 @@ mod test_synchronize {
 
     #[test]
     fn invalid_src() {
-        assert_eq!(synchronize("/?", "src", Flag::empty()).is_err(), true);
+        assert_eq!(
+            synchronize(Path::new("/?"), Path::new("src"), &Options::default()).is_err(),
+            true
+        );
     }
 
     #[test]
     fn invalid_dest() {
-        assert_eq!(synchronize("src", "/?", Flag::empty()).is_err(), true);
+        assert_eq!(
+            synchronize(Path::new("src"), Path::new("/?"), &Options::default()).is_err(),
+            true
+        );
     }
 
     #[cfg(target_family = "unix")]
@@ -175,7 +1403,10 @@ mod test_synchronize {
         const TEST_DIR: &str = "test_synchronize_dir1";
         fs::create_dir_all(TEST_DIR).unwrap();
 
-        assert_eq!(synchronize("src", TEST_DIR, Flag::empty()).is_ok(), true);
+        assert_eq!(
+            synchronize(Path::new("src"), Path::new(TEST_DIR), &Options::default()).is_ok(),
+            true
+        );
 
         let diff = Command::new("diff")
             .args(&["-r", "src", TEST_DIR])
@@ -194,7 +1425,12 @@ mod test_synchronize {
         fs::create_dir_all(TEST_DIR).unwrap();
 
         assert_eq!(
-            synchronize(BUILD_DIR, TEST_DIR, Flag::empty()).is_ok(),
+            synchronize(
+                Path::new(BUILD_DIR),
+                Path::new(TEST_DIR),
+                &Options::default()
+            )
+            .is_ok(),
             true
         );
 
@@ -216,7 +1452,12 @@ mod test_synchronize {
         assert_eq!(diff.status.success(), false);
 
         assert_eq!(
-            synchronize(BUILD_DIR, TEST_DIR, Flag::empty()).is_ok(),
+            synchronize(
+                Path::new(BUILD_DIR),
+                Path::new(TEST_DIR),
+                &Options::default()
+            )
+            .is_ok(),
             true
         );
 
@@ -230,6 +1471,37 @@ mod test_synchronize {
         fs::remove_dir_all(TEST_DIR).unwrap();
     }
 
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn multiple_destinations() {
+        const TEST_DEST1: &str = "test_synchronize_multiple_destinations_dest1";
+        const TEST_DEST2: &str = "test_synchronize_multiple_destinations_dest2";
+        fs::create_dir_all(TEST_DEST1).unwrap();
+        fs::create_dir_all(TEST_DEST2).unwrap();
+        fs::write([TEST_DEST2, "extra.txt"].join("/"), b"extra").unwrap();
+
+        assert_eq!(
+            synchronize_all(
+                Path::new("src"),
+                &[Path::new(TEST_DEST1), Path::new(TEST_DEST2)],
+                &Options::default()
+            )
+            .is_ok(),
+            true
+        );
+
+        for dest in [TEST_DEST1, TEST_DEST2].iter() {
+            let diff = Command::new("diff")
+                .args(&["-r", "src", dest])
+                .output()
+                .unwrap();
+            assert_eq!(diff.status.success(), true);
+        }
+
+        fs::remove_dir_all(TEST_DEST1).unwrap();
+        fs::remove_dir_all(TEST_DEST2).unwrap();
+    }
+
     #[cfg(target_family = "unix")]
     #[test]
     fn change_symlink() {
@@ -251,7 +1523,12 @@ mod test_synchronize {
         assert_eq!(diff.status.success(), false);
 
         assert_eq!(
-            synchronize(TEST_SRC, TEST_DEST, Flag::empty()).is_ok(),
+            synchronize(
+                Path::new(TEST_SRC),
+                Path::new(TEST_DEST),
+                &Options::default()
+            )
+            .is_ok(),
             true
         );
 
@@ -283,7 +1560,12 @@ mod test_synchronize {
         fs::File::create([TEST_DIR_EXPECTED, TEST_FILES[1]].join("/")).unwrap();
 
         assert_eq!(
-            synchronize(TEST_DIR, TEST_DIR_OUT, Flag::empty()).is_ok(),
+            synchronize(
+                Path::new(TEST_DIR),
+                Path::new(TEST_DIR_OUT),
+                &Options::default()
+            )
+            .is_ok(),
             true
         );
 
@@ -295,7 +1577,10 @@ mod test_synchronize {
         flags.insert(Flag::SECURE);
         flags.insert(Flag::SEQUENTIAL);
 
-        assert_eq!(synchronize(TEST_DIR, TEST_DIR_OUT, flags).is_ok(), true);
+        assert_eq!(
+            synchronize(Path::new(TEST_DIR), Path::new(TEST_DIR_OUT), &flags.into()).is_ok(),
+            true
+        );
 
         let diff = Command::new("diff")
             .args(&["-r", TEST_DIR_OUT, TEST_DIR_EXPECTED])
@@ -308,23 +1593,387 @@ mod test_synchronize {
         fs::remove_dir_all(TEST_DIR_OUT).unwrap();
         fs::remove_dir_all(TEST_DIR_EXPECTED).unwrap();
     }
-}
 
-#[cfg(test)]
-mod test_copy {
+    #[test]
+    fn remove_source_files() {
+        const TEST_SRC: &str = "test_synchronize_remove_source_files_src";
+        const TEST_DEST: &str = "test_synchronize_remove_source_files_dest";
+        fs::create_dir_all([TEST_SRC, "dir"].join("/")).unwrap();
+        fs::create_dir_all(TEST_DEST).unwrap();
+
+        fs::write([TEST_SRC, "dir/new.txt"].join("/"), b"new").unwrap();
+        fs::write([TEST_SRC, "changed.txt"].join("/"), b"source").unwrap();
+        fs::write([TEST_DEST, "changed.txt"].join("/"), b"dest").unwrap();
+
+        let options = Options::from(Flag::REMOVE_SOURCE_FILES);
+        assert_eq!(
+            synchronize(Path::new(TEST_SRC), Path::new(TEST_DEST), &options).is_ok(),
+            true
+        );
+
+        assert_eq!(
+            fs::read([TEST_DEST, "dir/new.txt"].join("/")).unwrap(),
+            b"new"
+        );
+        assert_eq!(
+            fs::read([TEST_DEST, "changed.txt"].join("/")).unwrap(),
+            b"source"
+        );
+        assert_eq!(fs::read_dir(TEST_SRC).unwrap().count(), 1);
+        assert_eq!(
+            fs::read_dir([TEST_SRC, "dir"].join("/")).unwrap().count(),
+            0
+        );
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+
+    #[test]
+    fn remove_source_files_not_copied() {
+        const TEST_SRC: &str = "test_synchronize_remove_source_files_not_copied_src";
+        const TEST_DEST: &str = "test_synchronize_remove_source_files_not_copied_dest";
+        fs::create_dir_all(TEST_SRC).unwrap();
+        fs::create_dir_all(TEST_DEST).unwrap();
+        fs::write([TEST_SRC, "kept.txt"].join("/"), b"source").unwrap();
+        fs::write([TEST_SRC, "new.txt"].join("/"), b"new").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        fs::write([TEST_DEST, "kept.txt"].join("/"), b"newer").unwrap();
+
+        // The newer dest file is kept, so its source is kept too, without failing the sync
+        let options = Options {
+            flags: Flag::REMOVE_SOURCE_FILES,
+            on_dest_newer: DestNewer::Skip,
+            ..Options::default()
+        };
+        assert_eq!(
+            synchronize(Path::new(TEST_SRC), Path::new(TEST_DEST), &options).is_ok(),
+            true
+        );
+        assert_eq!(
+            fs::read([TEST_DEST, "kept.txt"].join("/")).unwrap(),
+            b"newer"
+        );
+        assert_eq!(
+            fs::read([TEST_SRC, "kept.txt"].join("/")).unwrap(),
+            b"source"
+        );
+        assert_eq!(Path::new(TEST_SRC).join("new.txt").exists(), false);
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+
+    #[test]
+    fn dry_run() {
+        const TEST_SRC: &str = "test_synchronize_dry_run_src";
+        const TEST_DEST: &str = "test_synchronize_dry_run_dest";
+        fs::create_dir_all([TEST_SRC, "new"].join("/")).unwrap();
+        fs::create_dir_all([TEST_DEST, "old"].join("/")).unwrap();
+        fs::write([TEST_SRC, "new/file.txt"].join("/"), b"new").unwrap();
+        fs::write([TEST_SRC, "changed.txt"].join("/"), b"source").unwrap();
+        fs::write([TEST_DEST, "changed.txt"].join("/"), b"dest").unwrap();
+        fs::write([TEST_DEST, "old/file.txt"].join("/"), b"old").unwrap();
+
+        let options = Options::from(Flag::DRY_RUN | Flag::STATE | Flag::SNAPSHOT);
+        assert_eq!(
+            synchronize(Path::new(TEST_SRC), Path::new(TEST_DEST), &options).is_ok(),
+            true
+        );
+
+        // Nothing is copied, deleted or recorded
+        assert_eq!(Path::new(&[TEST_DEST, "new"].join("/")).exists(), false);
+        assert_eq!(
+            fs::read([TEST_DEST, "changed.txt"].join("/")).unwrap(),
+            b"dest"
+        );
+        assert_eq!(
+            fs::read([TEST_DEST, "old/file.txt"].join("/")).unwrap(),
+            b"old"
+        );
+        assert_eq!(fs::read_dir(TEST_DEST).unwrap().count(), 2);
+        assert_eq!(fs::read_dir(TEST_SRC).unwrap().count(), 2);
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+
+    #[test]
+    fn on_dest_newer() {
+        const TEST_SRC: &str = "test_synchronize_on_dest_newer_src";
+        const TEST_DEST: &str = "test_synchronize_on_dest_newer_dest";
+        fs::create_dir_all(TEST_SRC).unwrap();
+        fs::create_dir_all(TEST_DEST).unwrap();
+        fs::write([TEST_SRC, "file.txt"].join("/"), b"source").unwrap();
+        fs::write([TEST_SRC, "resized.txt"].join("/"), b"source").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        fs::write([TEST_DEST, "file.txt"].join("/"), b"edited").unwrap();
+        fs::write([TEST_DEST, "resized.txt"].join("/"), b"edited further").unwrap();
+
+        let options = Options {
+            on_dest_newer: DestNewer::Skip,
+            ..Options::default()
+        };
+        assert_eq!(
+            synchronize(Path::new(TEST_SRC), Path::new(TEST_DEST), &options).is_ok(),
+            true
+        );
+        assert_eq!(
+            fs::read([TEST_DEST, "file.txt"].join("/")).unwrap(),
+            b"edited"
+        );
+        assert_eq!(
+            fs::read([TEST_DEST, "resized.txt"].join("/")).unwrap(),
+            b"edited further"
+        );
+
+        let options = Options {
+            on_dest_newer: DestNewer::Backup,
+            ..Options::default()
+        };
+        assert_eq!(
+            synchronize(Path::new(TEST_SRC), Path::new(TEST_DEST), &options).is_ok(),
+            true
+        );
+        assert_eq!(
+            fs::read([TEST_DEST, "file.txt"].join("/")).unwrap(),
+            b"source"
+        );
+        assert_eq!(
+            fs::read([TEST_DEST, "file.txt~"].join("/")).unwrap(),
+            b"edited"
+        );
+        assert_eq!(
+            fs::read([TEST_DEST, "resized.txt~"].join("/")).unwrap(),
+            b"edited further"
+        );
+
+        // Backups are not deleted by later runs that keep backups
+        assert_eq!(
+            synchronize(Path::new(TEST_SRC), Path::new(TEST_DEST), &options).is_ok(),
+            true
+        );
+        assert_eq!(
+            Path::new(&[TEST_DEST, "file.txt~"].join("/")).exists(),
+            true
+        );
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+
+    #[test]
+    fn update() {
+        const TEST_SRC: &str = "test_synchronize_update_src";
+        const TEST_DEST: &str = "test_synchronize_update_dest";
+        fs::create_dir_all(TEST_SRC).unwrap();
+        fs::create_dir_all(TEST_DEST).unwrap();
+        for name in ["newer.txt", "older.txt"].iter() {
+            fs::write([TEST_SRC, name].join("/"), b"source").unwrap();
+            fs::write([TEST_DEST, name].join("/"), b"dest").unwrap();
+        }
+        let past = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        for path in [[TEST_SRC, "newer.txt"], [TEST_DEST, "older.txt"]].iter() {
+            fs::File::options()
+                .write(true)
+                .open(path.join("/"))
+                .unwrap()
+                .set_modified(past)
+                .unwrap();
+        }
+
+        let yaml = clap::load_yaml!("../cli.yml");
+        let args = clap::App::from_yaml(yaml)
+            .get_matches_from_safe(vec!["lms", "sync", "--update", TEST_SRC, TEST_DEST])
+            .unwrap();
+        let options = parse::parse_args(&args).unwrap().options;
+        assert_eq!(
+            synchronize(Path::new(TEST_SRC), Path::new(TEST_DEST), &options).is_ok(),
+            true
+        );
+
+        // Only the destination file modified later than its source is kept
+        assert_eq!(
+            fs::read([TEST_DEST, "newer.txt"].join("/")).unwrap(),
+            b"dest"
+        );
+        assert_eq!(
+            fs::read([TEST_DEST, "older.txt"].join("/")).unwrap(),
+            b"source"
+        );
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+
+    #[test]
+    fn dedupe_skipped_newer() {
+        const TEST_SRC: &str = "test_synchronize_dedupe_skipped_newer_src";
+        const TEST_DEST: &str = "test_synchronize_dedupe_skipped_newer_dest";
+        fs::create_dir_all(TEST_SRC).unwrap();
+        fs::create_dir_all(TEST_DEST).unwrap();
+        fs::write([TEST_SRC, "a.txt"].join("/"), b"same").unwrap();
+        fs::write([TEST_SRC, "b.txt"].join("/"), b"same").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        fs::write([TEST_DEST, "a.txt"].join("/"), b"NEWR").unwrap();
+
+        // The newer dest file that is kept differs from its source, so it must not be linked
+        let options = Options {
+            flags: Flag::DEDUPE_DEST | Flag::SECURE,
+            on_dest_newer: DestNewer::Skip,
+            ..Options::default()
+        };
+        assert_eq!(
+            synchronize(Path::new(TEST_SRC), Path::new(TEST_DEST), &options).is_ok(),
+            true
+        );
+        assert_eq!(fs::read([TEST_DEST, "a.txt"].join("/")).unwrap(), b"NEWR");
+        assert_eq!(fs::read([TEST_DEST, "b.txt"].join("/")).unwrap(), b"same");
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+
+    #[test]
+    fn existing() {
+        const TEST_SRC: &str = "test_synchronize_existing_src";
+        const TEST_DEST: &str = "test_synchronize_existing_dest";
+        fs::create_dir_all([TEST_SRC, "dir"].join("/")).unwrap();
+        fs::create_dir_all(TEST_DEST).unwrap();
+        fs::write([TEST_SRC, "same.txt"].join("/"), b"source").unwrap();
+        fs::write([TEST_SRC, "resized.txt"].join("/"), b"source").unwrap();
+        fs::write([TEST_SRC, "dir/new.txt"].join("/"), b"new").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        fs::write([TEST_DEST, "same.txt"].join("/"), b"edited").unwrap();
+        fs::write([TEST_DEST, "resized.txt"].join("/"), b"edited further").unwrap();
+
+        let options = Options {
+            existing: Existing::Ignore,
+            ..Options::default()
+        };
+        assert_eq!(
+            synchronize(Path::new(TEST_SRC), Path::new(TEST_DEST), &options).is_ok(),
+            true
+        );
+        assert_eq!(
+            fs::read([TEST_DEST, "same.txt"].join("/")).unwrap(),
+            b"edited"
+        );
+        assert_eq!(
+            fs::read([TEST_DEST, "resized.txt"].join("/")).unwrap(),
+            b"edited further"
+        );
+        assert_eq!(
+            fs::read([TEST_DEST, "dir/new.txt"].join("/")).unwrap(),
+            b"new"
+        );
+
+        fs::remove_dir_all([TEST_DEST, "dir"].join("/")).unwrap();
+        let options = Options {
+            existing: Existing::Only,
+            ..Options::default()
+        };
+        assert_eq!(
+            synchronize(Path::new(TEST_SRC), Path::new(TEST_DEST), &options).is_ok(),
+            true
+        );
+        assert_eq!(
+            fs::read([TEST_DEST, "same.txt"].join("/")).unwrap(),
+            b"source"
+        );
+        assert_eq!(
+            fs::read([TEST_DEST, "resized.txt"].join("/")).unwrap(),
+            b"source"
+        );
+        assert_eq!(Path::new(&[TEST_DEST, "dir"].join("/")).exists(), false);
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+
+    #[test]
+    fn state_hashes_new_files() {
+        const TEST_SRC: &str = "test_synchronize_state_hashes_new_files_src";
+        const TEST_DEST: &str = "test_synchronize_state_hashes_new_files_dest";
+        fs::create_dir_all(TEST_SRC).unwrap();
+        fs::create_dir_all(TEST_DEST).unwrap();
+        fs::write([TEST_SRC, "new.txt"].join("/"), b"new").unwrap();
+
+        let options = Options::from(Flag::STATE);
+        assert_eq!(
+            synchronize(Path::new(TEST_SRC), Path::new(TEST_DEST), &options).is_ok(),
+            true
+        );
+
+        let records = crate::lumins::state::recorded_files(Path::new(TEST_DEST))
+            .unwrap()
+            .unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(
+            records[0].1.hash,
+            Some(file_ops::FileHash::Fast(seahash::hash(b"new")))
+        );
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn delta() {
+        use std::os::unix::fs::MetadataExt;
+
+        const TEST_SRC: &str = "test_synchronize_delta_src";
+        const TEST_DEST: &str = "test_synchronize_delta_dest";
+        fs::create_dir_all(TEST_SRC).unwrap();
+        fs::create_dir_all(TEST_DEST).unwrap();
+        let data: Vec<u8> = (0..300_000).map(|i| i as u8).collect();
+        let mut changed = data.clone();
+        changed[200_000] ^= 0xff;
+        fs::write([TEST_SRC, "file.bin"].join("/"), &data).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        fs::write([TEST_DEST, "file.bin"].join("/"), &changed).unwrap();
+        let inode = fs::metadata([TEST_DEST, "file.bin"].join("/"))
+            .unwrap()
+            .ino();
+
+        let options = Options::from(Flag::DELTA | Flag::NO_CACHE);
+        assert_eq!(
+            synchronize(Path::new(TEST_SRC), Path::new(TEST_DEST), &options).is_ok(),
+            true
+        );
+
+        // The file is updated in place instead of written again
+        let metadata = fs::metadata([TEST_DEST, "file.bin"].join("/")).unwrap();
+        assert_eq!(metadata.ino(), inode);
+        assert_eq!(fs::read([TEST_DEST, "file.bin"].join("/")).unwrap(), data);
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test_copy {
     use super::*;
     use std::fs;
     use std::process::Command;
 
     #[test]
     fn invalid_src() {
-        assert_eq!(copy("/?", "src", Flag::empty()).is_err(), true);
+        assert_eq!(
+            copy(Path::new("/?"), Path::new("src"), &Options::default()).is_err(),
+            true
+        );
     }
 
     #[test]
     fn invalid_dest() {
         const TEST_DIR: &str = "test_copy_invalid_dest";
-        assert_eq!(copy("src", TEST_DIR, Flag::empty()).is_ok(), true);
+        assert_eq!(
+            copy(Path::new("src"), Path::new(TEST_DIR), &Options::default()).is_ok(),
+            true
+        );
         fs::remove_dir_all(TEST_DIR).unwrap();
     }
 
@@ -334,7 +1983,10 @@ mod test_copy {
         const TEST_DIR: &str = "test_copy_dir1";
         fs::create_dir_all(TEST_DIR).unwrap();
 
-        assert_eq!(copy("src", TEST_DIR, Flag::empty()).is_ok(), true);
+        assert_eq!(
+            copy(Path::new("src"), Path::new(TEST_DIR), &Options::default()).is_ok(),
+            true
+        );
 
         let diff = Command::new("diff")
             .args(&["-r", "src", TEST_DIR])
@@ -355,7 +2007,10 @@ mod test_copy {
         let mut flags = Flag::empty();
         flags.insert(Flag::SEQUENTIAL);
 
-        assert_eq!(copy("src", TEST_DIR, flags).is_ok(), true);
+        assert_eq!(
+            copy(Path::new("src"), Path::new(TEST_DIR), &flags.into()).is_ok(),
+            true
+        );
 
         let diff = Command::new("diff")
             .args(&["-r", "src", TEST_DIR])
@@ -368,9 +2023,155 @@ mod test_copy {
     }
 }
 
+#[cfg(all(test, target_family = "unix"))]
+mod test_copy_metadata {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    #[test]
+    fn special_bits() {
+        const TEST_SRC: &str = "test_copy_metadata_special_bits_src";
+        const TEST_DEST: &str = "test_copy_metadata_special_bits_dest";
+        const TEST_SUB_DIR: &str = "tmp";
+        const TEST_FILE: &str = "tmp/program";
+
+        fs::create_dir_all([TEST_SRC, TEST_SUB_DIR].join("/")).unwrap();
+        fs::File::create([TEST_SRC, TEST_FILE].join("/")).unwrap();
+        fs::set_permissions(
+            [TEST_SRC, TEST_FILE].join("/"),
+            fs::Permissions::from_mode(0o6755),
+        )
+        .unwrap();
+        fs::set_permissions(
+            [TEST_SRC, TEST_SUB_DIR].join("/"),
+            fs::Permissions::from_mode(0o1777),
+        )
+        .unwrap();
+
+        let mut flags = Flag::empty();
+        flags.insert(Flag::PERMS);
+        flags.insert(Flag::OWNER);
+
+        assert_eq!(
+            copy(Path::new(TEST_SRC), Path::new(TEST_DEST), &flags.into()).is_ok(),
+            true
+        );
+
+        let file_metadata = fs::metadata([TEST_DEST, TEST_FILE].join("/")).unwrap();
+        let dir_metadata = fs::metadata([TEST_DEST, TEST_SUB_DIR].join("/")).unwrap();
+        let src_metadata = fs::metadata([TEST_SRC, TEST_FILE].join("/")).unwrap();
+
+        assert_eq!(file_metadata.mode() & 0o7777, 0o6755);
+        assert_eq!(dir_metadata.mode() & 0o7777, 0o1777);
+        assert_eq!(file_metadata.gid(), src_metadata.gid());
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+
+    #[test]
+    fn times() {
+        const TEST_SRC: &str = "test_copy_metadata_times_src";
+        const TEST_DEST: &str = "test_copy_metadata_times_dest";
+        const TEST_SUB_DIR: &str = "dir";
+        const TEST_FILE: &str = "dir/file.txt";
+        const TEST_SYMLINK: &str = "dir/link";
+
+        fs::create_dir_all([TEST_SRC, TEST_SUB_DIR].join("/")).unwrap();
+        fs::write([TEST_SRC, TEST_FILE].join("/"), "file").unwrap();
+        std::os::unix::fs::symlink("file.txt", [TEST_SRC, TEST_SYMLINK].join("/")).unwrap();
+        let modified = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        for path in [TEST_FILE, TEST_SUB_DIR].iter() {
+            fs::File::open([TEST_SRC, path].join("/"))
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        }
+
+        assert_eq!(
+            copy(
+                Path::new(TEST_SRC),
+                Path::new(TEST_DEST),
+                &Flag::TIMES.into()
+            )
+            .is_ok(),
+            true
+        );
+
+        for path in [TEST_FILE, TEST_SUB_DIR, TEST_SYMLINK].iter() {
+            let src_metadata = fs::symlink_metadata([TEST_SRC, path].join("/")).unwrap();
+            let dest_metadata = fs::symlink_metadata([TEST_DEST, path].join("/")).unwrap();
+            assert_eq!(
+                dest_metadata.modified().unwrap(),
+                src_metadata.modified().unwrap()
+            );
+        }
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+
+    #[test]
+    fn metadata_only() {
+        const TEST_SRC: &str = "test_copy_metadata_metadata_only_src";
+        const TEST_DEST: &str = "test_copy_metadata_metadata_only_dest";
+        const TEST_FILE: &str = "file.txt";
+        const TEST_SYMLINK: &str = "link";
+
+        for (dir, data, target) in [(TEST_SRC, "new", "new"), (TEST_DEST, "old", "old")].iter() {
+            fs::create_dir_all(dir).unwrap();
+            fs::write([dir, TEST_FILE].join("/"), data).unwrap();
+            std::os::unix::fs::symlink(target, [dir, TEST_SYMLINK].join("/")).unwrap();
+        }
+        fs::set_permissions(
+            [TEST_SRC, TEST_FILE].join("/"),
+            fs::Permissions::from_mode(0o600),
+        )
+        .unwrap();
+        fs::write([TEST_SRC, "missing.txt"].join("/"), "missing").unwrap();
+        fs::File::options()
+            .write(true)
+            .open([TEST_DEST, TEST_FILE].join("/"))
+            .unwrap()
+            .set_modified(std::time::UNIX_EPOCH)
+            .unwrap();
+
+        let mut options = Options::from(Flag::METADATA_ONLY | Flag::PERMS | Flag::TIMES);
+        options.flags.insert(Flag::OWNER);
+        assert_eq!(
+            synchronize(Path::new(TEST_SRC), Path::new(TEST_DEST), &options).is_ok(),
+            true
+        );
+
+        let file_metadata = fs::metadata([TEST_DEST, TEST_FILE].join("/")).unwrap();
+        let src_metadata = fs::metadata([TEST_SRC, TEST_FILE].join("/")).unwrap();
+
+        // Only metadata changes, contents are never copied
+        assert_eq!(file_metadata.mode() & 0o7777, 0o600);
+        assert_eq!(
+            file_metadata.modified().unwrap(),
+            src_metadata.modified().unwrap()
+        );
+        assert_eq!(fs::read([TEST_DEST, TEST_FILE].join("/")).unwrap(), b"old");
+        assert_eq!(
+            fs::read_link([TEST_DEST, TEST_SYMLINK].join("/")).unwrap(),
+            PathBuf::from("new")
+        );
+        assert_eq!(
+            fs::metadata([TEST_DEST, "missing.txt"].join("/")).is_err(),
+            true
+        );
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+}
+
 #[cfg(test)]
 mod test_remove {
     use super::*;
+    use crate::lumins::parse;
     use std::fs;
     use std::process::Command;
 
@@ -382,7 +2183,7 @@ mod test_remove {
 
     #[test]
     fn invalid_target() {
-        assert_eq!(remove("/?", Flag::empty()).is_err(), true);
+        assert_eq!(remove(Path::new("/?"), &Options::default()).is_err(), true);
     }
 
     #[cfg(target_family = "unix")]
@@ -396,7 +2197,10 @@ mod test_remove {
             .output()
             .unwrap();
 
-        assert_eq!(remove(TEST_DIR, Flag::empty()).is_ok(), true);
+        assert_eq!(
+            remove(Path::new(TEST_DIR), &Options::default()).is_ok(),
+            true
+        );
 
         assert_eq!(fs::read_dir(TEST_DIR).is_err(), true);
     }
@@ -415,8 +2219,140 @@ mod test_remove {
             .output()
             .unwrap();
 
-        assert_eq!(remove(TEST_DIR, flags).is_ok(), true);
+        assert_eq!(remove(Path::new(TEST_DIR), &flags.into()).is_ok(), true);
+
+        assert_eq!(fs::read_dir(TEST_DIR).is_err(), true);
+    }
+
+    /// Parses `lms rm` with `args` followed by `target`
+    fn parse_rm(args: &[&str], target: &str) -> Options {
+        let yaml = clap::load_yaml!("../cli.yml");
+        let args = clap::App::from_yaml(yaml)
+            .get_matches_from_safe(["lms", "rm"].iter().chain(args).chain(&[target]))
+            .unwrap();
+        parse::parse_args(&args).unwrap().options
+    }
+
+    #[test]
+    fn permanent() {
+        const TEST_DIR: &str = "test_remove_permanent";
+        fs::create_dir_all([TEST_DIR, "dir"].join("/")).unwrap();
+        fs::write([TEST_DIR, "dir/file.txt"].join("/"), b"file").unwrap();
+
+        let options = parse_rm(&["--permanent"], TEST_DIR);
+        assert_eq!(options.flags.contains(Flag::TRASH), false);
+        assert_eq!(remove(Path::new(TEST_DIR), &options).is_ok(), true);
+        assert_eq!(fs::read_dir(TEST_DIR).is_err(), true);
+    }
 
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn trash() {
+        const TEST_DIR: &str = "test_remove_trash";
+        fs::create_dir_all([TEST_DIR, "dir"].join("/")).unwrap();
+        fs::write([TEST_DIR, "dir/file.txt"].join("/"), b"file").unwrap();
+        let dir = fs::canonicalize([TEST_DIR, "dir"].join("/")).unwrap();
+
+        // Files removed without --permanent can be restored from the trash
+        let options = parse_rm(&[], TEST_DIR);
+        assert_eq!(options.flags.contains(Flag::TRASH), true);
+        assert_eq!(remove(Path::new(TEST_DIR), &options).is_ok(), true);
         assert_eq!(fs::read_dir(TEST_DIR).is_err(), true);
+
+        let trashed: Vec<::trash::TrashItem> = ::trash::os_limited::list()
+            .unwrap()
+            .into_iter()
+            .filter(|item| item.original_parent == dir)
+            .collect();
+        assert_eq!(trashed.len(), 1);
+        assert_eq!(trashed[0].name, "file.txt");
+        ::trash::os_limited::purge_all(trashed).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test_prioritize {
+    use super::*;
+
+    #[test]
+    fn groups() {
+        let files = [
+            File::from("video/a.mp4", 0),
+            File::from("documents/b.txt", 0),
+            File::from("documents/c/d.txt", 0),
+            File::from("documents.txt", 0),
+            File::from("e.txt", 0),
+        ];
+        let priority = [PathBuf::from("documents"), PathBuf::from("e.txt")];
+
+        let groups = prioritize(files.iter().collect(), &priority);
+
+        assert_eq!(groups.len(), 3);
+        assert_eq!(groups[0], vec![&files[1], &files[2]]);
+        assert_eq!(groups[1], vec![&files[4]]);
+        assert_eq!(groups[2], vec![&files[0], &files[3]]);
+    }
+}
+
+#[cfg(test)]
+mod test_interleave {
+    use super::*;
+
+    #[test]
+    fn top_level_dirs() {
+        let files = [
+            File::from("video/b.mp4", 0),
+            File::from("video/a.mp4", 0),
+            File::from("video/c/d.mp4", 0),
+            File::from("documents/e.txt", 0),
+            File::from("f.txt", 0),
+        ];
+
+        assert_eq!(
+            interleave(files.iter().collect()),
+            vec![&files[3], &files[4], &files[1], &files[0], &files[2]]
+        );
+        assert_eq!(interleave(Vec::<&File>::new()), Vec::<&File>::new());
+    }
+}
+
+#[cfg(test)]
+mod test_prune_empty_dirs {
+    use super::*;
+
+    #[test]
+    fn emptied_dirs() {
+        const TEST_SRC: &str = "test_prune_empty_dirs_emptied_dirs_src";
+        const TEST_DEST: &str = "test_prune_empty_dirs_emptied_dirs_dest";
+
+        fs::create_dir_all([TEST_SRC, "kept/empty"].join("/")).unwrap();
+        fs::create_dir_all([TEST_DEST, "kept/empty"].join("/")).unwrap();
+        fs::create_dir_all([TEST_DEST, "gone/a/b"].join("/")).unwrap();
+        fs::create_dir_all([TEST_DEST, "full/a"].join("/")).unwrap();
+        fs::write([TEST_DEST, "full/file.txt"].join("/"), b"").unwrap();
+
+        let src_file_sets =
+            file_ops::get_all_files(Path::new(TEST_SRC), &Options::default()).unwrap();
+        prune_empty_dirs(
+            vec![
+                PathBuf::from("kept/empty"),
+                PathBuf::from("gone/a/b"),
+                PathBuf::from("full/a"),
+            ],
+            &src_file_sets,
+            Path::new(TEST_DEST),
+        );
+
+        // Dirs in the source are kept even if empty, and dirs above a kept file are kept
+        assert_eq!(
+            Path::new(&[TEST_DEST, "kept/empty"].join("/")).is_dir(),
+            true
+        );
+        assert_eq!(Path::new(&[TEST_DEST, "gone"].join("/")).exists(), false);
+        assert_eq!(Path::new(&[TEST_DEST, "full/a"].join("/")).exists(), false);
+        assert_eq!(Path::new(&[TEST_DEST, "full"].join("/")).is_dir(), true);
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
     }
 }
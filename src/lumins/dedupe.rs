@@ -0,0 +1,375 @@
+//! Contains utilities for replacing identical files with hard links, both in a destination after
+//! copying, as given with --dedupe-dest, and in any directory, as `lms dedupe` does.
+
+use std::fs;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use hashbrown::{HashMap, HashSet};
+use indicatif::HumanBytes;
+use log::{error, info};
+use rayon::prelude::*;
+
+use crate::lumins::file_ops::{self, File, FileHash, FileOps};
+use crate::lumins::parse::{Flag, Options};
+use crate::lumins::throttle;
+use crate::progress;
+
+/// Struct to represent the outcome of deduplicating files
+#[derive(Eq, PartialEq, Debug, Default, Clone, Copy)]
+pub struct DedupeStats {
+    /// Number of files replaced by hard links
+    pub files: u64,
+    /// Number of bytes no longer taking up space
+    pub bytes: u64,
+}
+
+impl DedupeStats {
+    fn add(self, other: DedupeStats) -> DedupeStats {
+        DedupeStats {
+            files: self.files + other.files,
+            bytes: self.bytes + other.bytes,
+        }
+    }
+}
+
+/// Attributes that hard linked files share, so only files that agree on them may be linked
+#[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
+struct LinkKey {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+}
+
+/// Identifies the inode of a file, to recognize files that are already hard linked
+#[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
+struct Inode {
+    dev: u64,
+    ino: u64,
+}
+
+/// Gets the attributes that hard linked files share, along with the inode, of the given file
+#[cfg(target_family = "unix")]
+fn link_attributes(metadata: &fs::Metadata) -> (LinkKey, Inode) {
+    use std::os::unix::fs::MetadataExt;
+
+    (
+        LinkKey {
+            mode: metadata.mode(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+        },
+        Inode {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+        },
+    )
+}
+
+#[cfg(target_family = "windows")]
+fn link_attributes(metadata: &fs::Metadata) -> (LinkKey, Inode) {
+    (
+        LinkKey {
+            mode: metadata.permissions().readonly() as u32,
+            uid: 0,
+            gid: 0,
+        },
+        Inode { dev: 0, ino: 0 },
+    )
+}
+
+/// Replaces the files with identical contents in `target` with hard links to a single copy, and
+/// reports the space reclaimed, like `lms dedupe`
+///
+/// # Arguments
+/// * `target`: directory to deduplicate the files of
+/// * `options`: command line options, of which `SECURE` groups files by a cryptographic hash
+///   instead of a fast hash
+///
+/// # Errors
+/// This function will return an error if `target` cannot be read
+pub fn dedupe(target: &Path, options: &Options) -> Result<(), io::Error> {
+    progress::scan_init("Scanning");
+    let file_sets = file_ops::get_all_files(target, options)?;
+    let stats = dedupe_files(file_sets.files(), target, options.flags);
+
+    // The progress bar must not be drawn over the result
+    progress::bar().finish_and_clear();
+    println!(
+        "Linked {} duplicate files in {}, reclaiming {}",
+        stats.files,
+        target.display(),
+        HumanBytes(stats.bytes)
+    );
+    Ok(())
+}
+
+/// Replaces files with identical contents in `location` with hard links to a single copy, in parallel
+///
+/// Only files that also share permissions and ownership are linked, since hard links share them.
+/// Files are hashed as they are in `location`, rather than taking the hashes of their sources,
+/// since a file that was not overwritten, such as a newer one that was skipped, differs from its
+/// source, and contents are compared byte by byte before linking, whatever the hash function.
+///
+/// # Arguments
+/// * `files`: files to deduplicate
+/// * `location`: base directory of the files, such that for all `file` in
+/// `files`, `location + file.path()` is the absolute path of the file
+/// * `flags`: set for Flag's
+///
+/// # Returns
+/// The number of files replaced by hard links, and the number of bytes reclaimed
+pub fn dedupe_files(files: &HashSet<File>, location: &Path, flags: Flag) -> DedupeStats {
+    // Only files of equal size can be identical, and empty files take up no space
+    let mut files_by_size: HashMap<u64, Vec<&File>> = HashMap::new();
+    for file in files.iter().filter(|file| file.size() > 0) {
+        files_by_size.entry(file.size()).or_default().push(file);
+    }
+
+    files_by_size.retain(|_, group| group.len() > 1);
+    progress::phase_init(
+        "Hashing for deduplication",
+        files_by_size.values().map(|group| group.len() as u64).sum(),
+    );
+
+    files_by_size
+        .into_par_iter()
+        .map(|(_, group)| dedupe_group(&group, location, flags))
+        .reduce(DedupeStats::default, DedupeStats::add)
+}
+
+/// Deduplicates a group of files of equal size
+///
+/// # Arguments
+/// * `group`: files of equal size
+/// * `location`: base directory of the files
+/// * `flags`: set for Flag's
+///
+/// # Returns
+/// The number of files replaced by hard links, and the number of bytes reclaimed
+fn dedupe_group(group: &[&File], location: &Path, flags: Flag) -> DedupeStats {
+    let mut stats = DedupeStats::default();
+    let mut originals: HashMap<(FileHash, LinkKey), (PathBuf, Inode)> = HashMap::new();
+
+    for file in group {
+        progress::bar().inc(1);
+        let path = location.join(file.path());
+
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                error!("Error -- Reading metadata of {:?}: {}", path, e);
+                continue;
+            }
+        };
+        let (link_key, inode) = link_attributes(&metadata);
+
+        let hash = match file_ops::hash_file_with_flags(*file, location, flags) {
+            Some(hash) => hash,
+            None => continue,
+        };
+
+        let (original, original_inode) = match originals.get(&(hash.clone(), link_key)) {
+            Some(original) => original,
+            None => {
+                originals.insert((hash, link_key), (path, inode));
+                continue;
+            }
+        };
+
+        if *original_inode == inode {
+            continue;
+        }
+
+        // A hash collision, or a file changed since it was hashed, must never turn into a link to
+        // different contents, which would lose the contents of `path`
+        match contents_equal(original, &path) {
+            Ok(true) => (),
+            Ok(false) => continue,
+            Err(e) => {
+                error!("Error -- Comparing {:?} and {:?}: {}", original, path, e);
+                continue;
+            }
+        }
+
+        match replace_with_link(original, &path) {
+            Ok(_) => {
+                info!("Linking {:?} -> {:?}", path, original);
+                stats.files += 1;
+                stats.bytes += file.size();
+            }
+            Err(e) => error!("Error -- Linking {:?} -> {:?}: {}", path, original, e),
+        }
+    }
+
+    stats
+}
+
+/// Compares the contents of two files byte by byte
+///
+/// # Errors
+/// This function will return an error if either file cannot be read
+fn contents_equal(a: &Path, b: &Path) -> Result<bool, io::Error> {
+    const CHUNK_SIZE: usize = 64 * 1024;
+
+    let mut a = BufReader::with_capacity(CHUNK_SIZE, throttle::Reader(fs::File::open(a)?));
+    let mut b = BufReader::with_capacity(CHUNK_SIZE, throttle::Reader(fs::File::open(b)?));
+    let mut a_buffer = vec![0; CHUNK_SIZE];
+    let mut b_buffer = vec![0; CHUNK_SIZE];
+
+    loop {
+        let a_read = read_full(&mut a, &mut a_buffer)?;
+        let b_read = read_full(&mut b, &mut b_buffer)?;
+
+        if a_read != b_read || a_buffer[..a_read] != b_buffer[..b_read] {
+            return Ok(false);
+        }
+        if a_read == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Reads from `reader` until `buffer` is full or the end of the file is reached
+///
+/// # Returns
+/// The number of bytes read
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<usize, io::Error> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match reader.read(&mut buffer[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+/// Atomically replaces `duplicate` with a hard link to `original`
+///
+/// The link is created next to `duplicate` first and then renamed over it,
+/// so `duplicate` never goes missing, even if linking fails halfway
+///
+/// # Errors
+/// This function will return an error if the link cannot be created or renamed
+fn replace_with_link(original: &Path, duplicate: &Path) -> Result<(), io::Error> {
+    let mut link = duplicate.as_os_str().to_owned();
+    link.push(".lms-link");
+    let link = PathBuf::from(link);
+
+    fs::hard_link(original, &link)?;
+    fs::rename(&link, duplicate).inspect_err(|_| {
+        let _ = fs::remove_file(&link);
+    })
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(all(test, target_family = "unix"))]
+mod test_dedupe_files {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn identical_files() {
+        const TEST_DIR: &str = "test_dedupe_files_identical_files";
+        const TEST_FILES: [&str; 3] = ["a.txt", "sub/b.txt", "c.txt"];
+        const TEST_DATA: [&[u8]; 3] = [b"1234567890", b"1234567890", b"0987654321"];
+
+        fs::create_dir_all([TEST_DIR, "sub"].join("/")).unwrap();
+
+        let mut files = HashSet::new();
+        for i in 0..TEST_FILES.len() {
+            fs::write([TEST_DIR, TEST_FILES[i]].join("/"), TEST_DATA[i]).unwrap();
+            files.insert(File::from(TEST_FILES[i], TEST_DATA[i].len() as u64));
+        }
+
+        for &flags in [Flag::empty(), Flag::SECURE].iter() {
+            let stats = dedupe_files(&files, Path::new(TEST_DIR), flags);
+
+            let inode = |file: &str| fs::metadata([TEST_DIR, file].join("/")).unwrap().ino();
+
+            assert_eq!(inode(TEST_FILES[0]), inode(TEST_FILES[1]));
+            assert_ne!(inode(TEST_FILES[0]), inode(TEST_FILES[2]));
+            assert_eq!(
+                fs::read([TEST_DIR, TEST_FILES[1]].join("/")).unwrap(),
+                TEST_DATA[1]
+            );
+
+            // Files that are already linked are left alone on the second pass
+            if flags.is_empty() {
+                assert_eq!(
+                    stats,
+                    DedupeStats {
+                        files: 1,
+                        bytes: 10
+                    }
+                );
+            } else {
+                assert_eq!(stats, DedupeStats::default());
+            }
+        }
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn same_size_contents() {
+        const TEST_DIR: &str = "test_dedupe_files_same_size_contents";
+        const TEST_FILES: [&str; 3] = ["a.txt", "b.txt", "c.txt"];
+        const TEST_DATA: [&[u8]; 3] = [b"aaaa", b"bbbb", b"aaaa"];
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        for i in 0..TEST_FILES.len() {
+            fs::write([TEST_DIR, TEST_FILES[i]].join("/"), TEST_DATA[i]).unwrap();
+        }
+        let path = |file: &str| PathBuf::from([TEST_DIR, file].join("/"));
+
+        // Files of equal hashes are only linked if their contents are equal too
+        assert_eq!(
+            contents_equal(&path(TEST_FILES[0]), &path(TEST_FILES[1])).unwrap(),
+            false
+        );
+        assert_eq!(
+            contents_equal(&path(TEST_FILES[0]), &path(TEST_FILES[2])).unwrap(),
+            true
+        );
+        assert_eq!(
+            contents_equal(&path(TEST_FILES[0]), &path("missing.txt")).is_err(),
+            true
+        );
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
+
+#[cfg(all(test, target_family = "unix"))]
+mod test_dedupe {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn tree() {
+        const TEST_DIR: &str = "test_dedupe_tree";
+
+        fs::create_dir_all([TEST_DIR, "a/b"].join("/")).unwrap();
+        fs::write([TEST_DIR, "a/photo.jpg"].join("/"), b"photo").unwrap();
+        fs::write([TEST_DIR, "a/b/photo copy.jpg"].join("/"), b"photo").unwrap();
+        fs::write([TEST_DIR, "other.jpg"].join("/"), b"other").unwrap();
+
+        let options = Options::from(Flag::SECURE);
+        assert_eq!(dedupe(Path::new(TEST_DIR), &options).is_ok(), true);
+
+        let inode = |file: &str| fs::metadata([TEST_DIR, file].join("/")).unwrap().ino();
+        assert_eq!(inode("a/photo.jpg"), inode("a/b/photo copy.jpg"));
+        assert_ne!(inode("a/photo.jpg"), inode("other.jpg"));
+        assert_eq!(
+            dedupe(Path::new("test_dedupe_tree_missing"), &options).is_err(),
+            true
+        );
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
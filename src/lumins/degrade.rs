@@ -0,0 +1,189 @@
+//! Collects what could not be preserved because the destination does not support it,
+//! so that it can be reported once at the end of a run instead of scattered across the log.
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+
+use crate::lumins::context;
+
+/// Enum to represent features that a destination may not support
+#[derive(Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Clone, Copy)]
+pub enum Feature {
+    Permissions,
+    Owner,
+    Times,
+    Symlinks,
+    Xattrs,
+    Attributes,
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Feature::Permissions => "permissions",
+            Feature::Owner => "owner",
+            Feature::Times => "times",
+            Feature::Symlinks => "symlinks",
+            Feature::Xattrs => "extended attributes",
+            Feature::Attributes => "file attributes",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Struct to represent a feature that could not be preserved for a single path
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Degradation {
+    /// Path in the destination that lost the feature
+    pub path: PathBuf,
+    /// Feature that was lost
+    pub feature: Feature,
+    /// Why the feature was lost
+    pub reason: String,
+}
+
+/// Checks whether an error means that the filesystem does not support an operation at all,
+/// rather than that the operation failed for this particular file
+///
+/// # Arguments
+/// * `e`: error of the operation
+///
+/// # Returns
+/// True if the operation is unsupported
+pub fn is_unsupported(e: &io::Error) -> bool {
+    #[cfg(target_family = "unix")]
+    {
+        if let Some(code) = e.raw_os_error() {
+            // FAT and exFAT refuse symlinks and owners with EPERM, SMB refuses with ENOTSUP
+            return code == libc::EPERM || code == libc::ENOTSUP || code == libc::EOPNOTSUPP;
+        }
+    }
+    #[cfg(target_family = "windows")]
+    {
+        const ERROR_NOT_SUPPORTED: i32 = 50;
+        const ERROR_PRIVILEGE_NOT_HELD: i32 = 1314;
+
+        if let Some(code) = e.raw_os_error() {
+            return code == ERROR_NOT_SUPPORTED || code == ERROR_PRIVILEGE_NOT_HELD;
+        }
+    }
+
+    e.kind() == io::ErrorKind::Unsupported
+}
+
+/// Records that `feature` could not be preserved for `path`
+///
+/// # Arguments
+/// * `path`: path in the destination that lost the feature
+/// * `feature`: feature that was lost
+/// * `e`: error that prevented preserving the feature
+pub fn record(path: &Path, feature: Feature, e: &io::Error) {
+    info!("Losing {} of {:?}: {}", feature, path, e);
+    context::current()
+        .degradations
+        .lock()
+        .unwrap()
+        .push(Degradation {
+            path: path.to_path_buf(),
+            feature,
+            reason: e.to_string(),
+        });
+}
+
+/// Takes everything recorded so far, sorted by feature and path
+///
+/// # Returns
+/// Everything that was lost since the last call
+pub fn take() -> Vec<Degradation> {
+    let mut degradations = std::mem::take(&mut *context::current().degradations.lock().unwrap());
+    degradations.sort_by(|a, b| (a.feature, &a.path).cmp(&(b.feature, &b.path)));
+    degradations
+}
+
+/// Reports everything that was lost during the run, grouped by feature
+///
+/// # Arguments
+/// * `strict`: whether losing anything fails the run
+///
+/// # Errors
+/// This function will return an error if `strict` is set and anything was lost
+pub fn report(strict: bool) -> Result<(), io::Error> {
+    let degradations = take();
+    if degradations.is_empty() {
+        return Ok(());
+    }
+
+    let mut previous = None;
+    for degradation in degradations.iter() {
+        if previous != Some(degradation.feature) {
+            let count = degradations
+                .iter()
+                .filter(|other| other.feature == degradation.feature)
+                .count();
+            warn!(
+                "Warning -- The destination does not support {}, lost by {} paths:",
+                degradation.feature, count
+            );
+            previous = Some(degradation.feature);
+        }
+        warn!("  {:?}: {}", degradation.path, degradation.reason);
+    }
+
+    if strict {
+        return Err(io::Error::other(format!(
+            "{} paths could not be preserved faithfully",
+            degradations.len()
+        )));
+    }
+    Ok(())
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_degrade {
+    use super::*;
+
+    #[test]
+    fn record_and_report() {
+        let unsupported = io::Error::from(io::ErrorKind::Unsupported);
+        record(Path::new("test_degrade_b"), Feature::Times, &unsupported);
+        record(Path::new("test_degrade_a"), Feature::Symlinks, &unsupported);
+        record(Path::new("test_degrade_a"), Feature::Times, &unsupported);
+
+        // Other tests may record concurrently, so only the paths of this test are looked at
+        let degradations: Vec<(PathBuf, Feature)> = take()
+            .into_iter()
+            .filter(|d| d.path.to_string_lossy().starts_with("test_degrade_"))
+            .map(|d| (d.path, d.feature))
+            .collect();
+        assert_eq!(
+            degradations,
+            vec![
+                (PathBuf::from("test_degrade_a"), Feature::Times),
+                (PathBuf::from("test_degrade_b"), Feature::Times),
+                (PathBuf::from("test_degrade_a"), Feature::Symlinks),
+            ]
+        );
+
+        record(Path::new("test_degrade_c"), Feature::Owner, &unsupported);
+        assert_eq!(report(true).is_err(), true);
+    }
+
+    #[test]
+    fn unsupported() {
+        assert_eq!(
+            is_unsupported(&io::Error::from(io::ErrorKind::Unsupported)),
+            true
+        );
+        assert_eq!(
+            is_unsupported(&io::Error::from(io::ErrorKind::NotFound)),
+            false
+        );
+    }
+}
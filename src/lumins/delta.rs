@@ -0,0 +1,149 @@
+//! Contains the delta copy given with --delta, which updates a destination file in place by
+//! rewriting only the blocks that differ from the source, so that a large file that changed
+//! slightly is not written again as a whole.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use log::info;
+
+use crate::lumins::{context, throttle};
+
+/// Size in bytes of the blocks that are compared and rewritten, unless given with --block-size
+pub const DEFAULT_BLOCK_SIZE: usize = 128 * 1024;
+
+/// Configures the block size of all following delta copies of the current job
+///
+/// # Arguments
+/// * `block_size`: size in bytes of the blocks, None for the default
+pub fn configure(block_size: Option<usize>) {
+    context::current().block_size.store(
+        block_size.unwrap_or(DEFAULT_BLOCK_SIZE).max(1),
+        Ordering::Relaxed,
+    );
+}
+
+/// Copies the contents and permissions of the file `src` to the existing file `dest`, writing
+/// only the blocks of `dest` that differ from `src`, calling `on_block` with every block of `src`
+///
+/// `dest` is truncated or extended to the size of `src`, so a shorter or longer `dest` and a
+/// last block shorter than the block size are handled like any other block.
+///
+/// # Arguments
+/// * `src`: absolute path of the file to copy
+/// * `dest`: absolute path of the copy, which is updated in place
+/// * `on_block`: called after every block, which stops the copy by returning an error
+///
+/// # Returns
+/// The number of bytes written to `dest`
+///
+/// # Errors
+/// This function will return an error if `src` cannot be read, `dest` cannot be updated, or
+/// `on_block` fails
+pub fn copy_with<F>(src: &Path, dest: &Path, mut on_block: F) -> Result<u64, io::Error>
+where
+    F: FnMut(&[u8]) -> Result<(), io::Error>,
+{
+    let mut reader = fs::File::open(src)?;
+    let metadata = reader.metadata()?;
+    let mut writer = OpenOptions::new().read(true).write(true).open(dest)?;
+
+    let block_size = context::current().block_size.load(Ordering::Relaxed);
+    let mut src_block = vec![0; block_size];
+    let mut dest_block = vec![0; block_size];
+    let mut offset = 0;
+    let mut written = 0;
+    loop {
+        let read = read_block(&mut reader, &mut src_block)?;
+        if read == 0 {
+            break;
+        }
+        throttle::read(read as u64);
+
+        // A destination that ends within the block differs in the bytes it is missing
+        let dest_read = read_block(&mut writer, &mut dest_block[..read])?;
+        if dest_read != read || src_block[..read] != dest_block[..read] {
+            writer.seek(SeekFrom::Start(offset))?;
+            writer.write_all(&src_block[..read])?;
+            written += read as u64;
+        }
+        offset += read as u64;
+        on_block(&src_block[..read])?;
+    }
+
+    // The source may have changed size since its metadata was read, so its contents decide
+    writer.set_len(offset)?;
+    writer.set_permissions(metadata.permissions())?;
+    info!(
+        "Delta copying file {:?}: {} of {} bytes changed",
+        src, written, offset
+    );
+    Ok(written)
+}
+
+/// Reads from `reader` until `block` is full or the end is reached
+///
+/// # Returns
+/// The number of bytes read, which is less than the size of `block` only at the end
+fn read_block<R: Read>(reader: &mut R, block: &mut [u8]) -> Result<usize, io::Error> {
+    let mut read = 0;
+    while read < block.len() {
+        match reader.read(&mut block[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_delta {
+    use super::*;
+
+    #[test]
+    fn changed_blocks() {
+        const TEST_DIR: &str = "test_delta_changed_blocks";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        let src = Path::new(TEST_DIR).join("src.bin");
+        let dest = Path::new(TEST_DIR).join("dest.bin");
+        let block_size = context::current().block_size.load(Ordering::Relaxed);
+        let data: Vec<u8> = (0..block_size * 3 + 7).map(|i| i as u8).collect();
+
+        // A single changed byte rewrites only its block
+        let mut changed = data.clone();
+        changed[block_size + 1] ^= 0xff;
+        fs::write(&src, &data).unwrap();
+        fs::write(&dest, &changed).unwrap();
+        let mut hashed = 0;
+        let written = copy_with(&src, &dest, |block| {
+            hashed += block.len();
+            Ok(())
+        });
+        assert_eq!(written.unwrap(), block_size as u64);
+        assert_eq!(hashed, data.len());
+        assert_eq!(fs::read(&dest).unwrap(), data);
+
+        // A shorter destination gets the missing tail, and a longer one is truncated
+        fs::write(&dest, &data[..block_size * 2 + 3]).unwrap();
+        assert_eq!(
+            copy_with(&src, &dest, |_| Ok(())).unwrap(),
+            (block_size + 7) as u64
+        );
+        assert_eq!(fs::read(&dest).unwrap(), data);
+
+        fs::write(&src, &data[..5]).unwrap();
+        assert_eq!(copy_with(&src, &dest, |_| Ok(())).unwrap(), 0);
+        assert_eq!(fs::read(&dest).unwrap(), &data[..5]);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
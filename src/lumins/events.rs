@@ -0,0 +1,255 @@
+//! Contains the sink of the actions taken on files, which logs every action and, with
+//! --output json, also writes it to stdout as one JSON object per line followed by a summary, so
+//! that CI pipelines and wrappers can parse the results of a run.
+
+use std::fmt::{self, Write as _};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::{error, info, warn};
+
+use crate::lumins::context;
+use crate::lumins::locale;
+use crate::lumins::report;
+use crate::lumins::resources;
+
+/// Whether actions are written to stdout as JSON
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Enum to represent an action taken on a file, directory, or symlink
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum Action {
+    /// The path was copied or created in the destination
+    Copy,
+    /// The path was renamed within the destination, instead of being copied again
+    Rename,
+    /// The path was deleted
+    Delete,
+    /// The path was left alone on purpose
+    Skip,
+    /// The path was handled, but not quite as asked
+    Warning,
+    /// The path could not be handled
+    Error,
+}
+
+impl Action {
+    const ALL: [Action; 6] = [
+        Action::Copy,
+        Action::Rename,
+        Action::Delete,
+        Action::Skip,
+        Action::Warning,
+        Action::Error,
+    ];
+
+    /// Gets the name of the action in JSON output
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Copy => "copy",
+            Action::Rename => "rename",
+            Action::Delete => "delete",
+            Action::Skip => "skip",
+            Action::Warning => "warning",
+            Action::Error => "error",
+        }
+    }
+}
+
+/// Configures whether all following actions are written to stdout as JSON
+///
+/// # Arguments
+/// * `json`: whether actions are written as JSON
+pub fn configure(json: bool) {
+    JSON.store(json, Ordering::Relaxed);
+}
+
+/// Records an action taken on `path`, logging `message` at the level of the action
+///
+/// # Arguments
+/// * `action`: action taken
+/// * `path`: path the action was taken on
+/// * `message`: description of the action, as it is logged
+pub fn record(action: Action, path: &Path, message: fmt::Arguments) {
+    match action {
+        Action::Error => error!("{}", message),
+        Action::Warning => warn!("{}", message),
+        _ => info!("{}", message),
+    }
+    context::current().counts[action as usize].fetch_add(1, Ordering::Relaxed);
+    report::record(action, path, &message.to_string());
+
+    if JSON.load(Ordering::Relaxed) {
+        write_line(&format!(
+            "{{\"action\":{},\"path\":{},\"message\":{}}}",
+            json_string(action.name()),
+            json_string(&path.to_string_lossy()),
+            json_string(&message.to_string())
+        ));
+    }
+}
+
+/// Records that a file of `bytes` bytes was copied
+pub fn record_bytes(bytes: u64) {
+    context::current().bytes.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Records how a destination of a run to several destinations went, so that the summary tells
+/// them apart
+///
+/// # Arguments
+/// * `dest`: Destination directory
+/// * `failed`: number of file operations that failed in `dest`
+/// * `error`: error that `dest` failed with, None if it succeeded
+pub fn record_destination(dest: &Path, failed: usize, error: Option<&io::Error>) {
+    context::current().destinations.lock().unwrap().push((
+        dest.to_path_buf(),
+        failed,
+        error.map(ToString::to_string),
+    ));
+}
+
+/// Gets the name and number of every action recorded during this run
+pub fn counts() -> Vec<(&'static str, usize)> {
+    let context = context::current();
+    Action::ALL
+        .iter()
+        .map(|action| {
+            (
+                action.name(),
+                context.counts[*action as usize].load(Ordering::Relaxed),
+            )
+        })
+        .collect()
+}
+
+/// Gets the number of bytes of the files copied during this run
+pub fn bytes() -> u64 {
+    context::current().bytes.load(Ordering::Relaxed)
+}
+
+/// Writes the number of every action recorded during this run, how each of several destinations
+/// went, and the resources it used, to stdout as JSON, if actions are written as JSON, or else
+/// logs the resources it used
+///
+/// # Arguments
+/// * `error`: error that the run failed with, None if it succeeded
+pub fn summary(error: Option<&io::Error>) {
+    let resources = resources::current();
+    if !JSON.load(Ordering::Relaxed) {
+        let fields: Vec<String> = resources
+            .to_fields()
+            .into_iter()
+            .map(|(name, value)| format!("{} {}", name, value))
+            .collect();
+        info!(
+            "{}",
+            locale::text("resources-used", &[("resources", &fields.join(", "))])
+        );
+        return;
+    }
+
+    let mut summary = String::from("{\"summary\":{");
+    for (i, (name, count)) in counts().into_iter().enumerate() {
+        let _ = write!(
+            summary,
+            "{}{}:{}",
+            if i == 0 { "" } else { "," },
+            json_string(name),
+            count
+        );
+    }
+    let _ = write!(summary, "}},\"bytes\":{}", bytes());
+    let context = context::current();
+    let destinations = context.destinations.lock().unwrap();
+    if !destinations.is_empty() {
+        summary.push_str(",\"destinations\":[");
+        for (i, (dest, failed, error)) in destinations.iter().enumerate() {
+            let _ = write!(
+                summary,
+                "{}{{\"path\":{},\"failed\":{},\"result\":{}}}",
+                if i == 0 { "" } else { "," },
+                json_string(&dest.to_string_lossy()),
+                failed,
+                json_string(&match error {
+                    Some(e) => format!("error: {}", e),
+                    None => String::from("ok"),
+                })
+            );
+        }
+        summary.push(']');
+    }
+    let _ = write!(
+        summary,
+        ",\"resources\":{{{}}},\"result\":{}}}",
+        resources.to_json_fields(),
+        json_string(&match error {
+            Some(e) => format!("error: {}", e),
+            None => String::from("ok"),
+        })
+    );
+    write_line(&summary);
+}
+
+/// Writes a whole line to stdout, so that lines written from different threads never interleave
+fn write_line(line: &str) {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    // Output that cannot be written, such as to a closed pipe, must not disturb the run itself
+    let _ = writeln!(stdout, "{}", line);
+}
+
+/// Formats `value` as a JSON string, with quotes
+pub fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_events {
+    use super::*;
+
+    #[test]
+    fn json_strings() {
+        assert_eq!(json_string("a.txt"), "\"a.txt\"");
+        assert_eq!(
+            json_string("say \"hi\"\\\n\u{1}"),
+            "\"say \\\"hi\\\"\\\\\\n\\u0001\""
+        );
+    }
+
+    #[test]
+    fn counts() {
+        let context = context::current();
+        let deleted = context.counts[Action::Delete as usize].load(Ordering::Relaxed);
+        record(
+            Action::Delete,
+            Path::new("a.txt"),
+            format_args!("Deleting file {:?}", "a.txt"),
+        );
+        assert_eq!(
+            context.counts[Action::Delete as usize].load(Ordering::Relaxed) > deleted,
+            true
+        );
+    }
+}
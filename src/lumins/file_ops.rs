@@ -1,28 +1,82 @@
 //! Contains utilities for copying, deleting, sorting, hashing files.
 
+use std::cell::Cell;
+use std::ffi::OsStr;
 use std::fs::OpenOptions;
-use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::hash::Hasher;
+use std::io::{Read, Seek, SeekFrom};
 use std::marker::Sync;
 use std::path::{Path, PathBuf};
-use std::{fs, io};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::time::{Duration, SystemTime};
+use std::{fs, io, thread};
 
 use blake2::{Blake2b, Digest};
-use hashbrown::HashSet;
-use log::{error, info};
+use hashbrown::{HashMap, HashSet};
+use log::info;
 use rayon::prelude::*;
-use seahash;
-
-use crate::lumins::parse::Flag;
-use crate::progress::PROGRESS_BAR;
+use seahash::SeaHasher;
+
+use crate::lumins::attributes;
+use crate::lumins::backups;
+use crate::lumins::context;
+use crate::lumins::degrade::{self, Feature};
+use crate::lumins::delta;
+use crate::lumins::events::{self, Action};
+use crate::lumins::filter::Filter;
+use crate::lumins::mounts::MountTable;
+use crate::lumins::parse::{Flag, Options, Reflink};
+use crate::lumins::paths;
+use crate::lumins::rollback;
+use crate::lumins::snapshot::{self, SNAPSHOT_PREFIX};
+use crate::lumins::stall;
+use crate::lumins::state::{CachedHashes, DirState, FileRecord, HashProgress, State, STATE_DIR};
+use crate::lumins::stream;
+use crate::lumins::throttle;
+use crate::lumins::tiers;
+use crate::lumins::transform;
+use crate::lumins::vanished;
+use crate::lumins::xattrs;
+use crate::progress::{self, FileProgress};
 
 /// Interface for all file structs to perform common operations
 ///
 /// Ensures that all files (file, dir, symlink) have
-/// a way of obtaining their path, copying, and deleting
+/// a way of obtaining their path, copying, copying metadata, and deleting
 pub trait FileOps {
     fn path(&self) -> &PathBuf;
-    fn remove(&self, path: &PathBuf);
-    fn copy(&self, src: &PathBuf, dest: &PathBuf);
+    /// Deletes the file at `path`, logging what happened
+    ///
+    /// # Errors
+    /// This function will return an error if the file could not be deleted
+    fn remove(&self, path: &PathBuf) -> Result<(), io::Error>;
+    /// Copies the file at `src` to `dest`, logging what happened
+    ///
+    /// # Errors
+    /// This function will return an error if the file could not be copied, but not if `src`
+    /// vanished or the destination does not support it, which are reported at the end of a run
+    fn copy(&self, src: &PathBuf, dest: &PathBuf, flags: Flag) -> Result<(), io::Error>;
+    fn copy_metadata(&self, src: &PathBuf, dest: &PathBuf, flags: Flag);
+    /// Copies like `copy`, hashing the contents while they are copied where possible
+    ///
+    /// # Returns
+    /// The hash of the contents copied, selected by `flags`, if it was computed
+    ///
+    /// # Errors
+    /// This function will return an error like `copy`
+    fn copy_hashed(
+        &self,
+        src: &PathBuf,
+        dest: &PathBuf,
+        flags: Flag,
+    ) -> Result<Option<FileHash>, io::Error> {
+        self.copy(src, dest, flags).map(|_| None)
+    }
+    /// Gets the size of the contents in bytes, which decides the tier they are copied in
+    fn size(&self) -> u64 {
+        0
+    }
 }
 
 /// A struct that represents a single file
@@ -36,18 +90,61 @@ impl FileOps for File {
     fn path(&self) -> &PathBuf {
         &self.path
     }
-    fn remove(&self, path: &PathBuf) {
-        match fs::remove_file(&path) {
-            Ok(_) => info!("Deleting file {:?}", path),
-            Err(e) => error!("Error -- Deleting file {:?}: {}", path, e),
+    fn remove(&self, path: &PathBuf) -> Result<(), io::Error> {
+        // Read-only files cannot be deleted on Windows, which an error deleting it still reports
+        let _ = attributes::make_writable(path);
+        let result = fs::remove_file(path);
+        match &result {
+            Ok(_) => events::record(
+                Action::Delete,
+                path,
+                format_args!("Deleting file {:?}", path),
+            ),
+            Err(e) => events::record(
+                Action::Error,
+                path,
+                format_args!("Error -- Deleting file {:?}: {}", path, e),
+            ),
         }
+        result
     }
-    fn copy(&self, src: &PathBuf, dest: &PathBuf) {
-        match fs::copy(&src, &dest) {
-            Ok(_) => info!("Copying file {:?} -> {:?}", src, dest),
-            Err(e) => error!("Error -- Copying file {:?}: {}", src, e),
+    fn copy(&self, src: &PathBuf, dest: &PathBuf, flags: Flag) -> Result<(), io::Error> {
+        let mut progress = FileProgress::new(self.size);
+        File::log_copy(
+            src,
+            dest,
+            self.size,
+            copy_consistent(src, dest, flags, &mut progress),
+        )
+    }
+    fn copy_metadata(&self, src: &PathBuf, dest: &PathBuf, flags: Flag) {
+        match copy_file_metadata(src, dest, flags) {
+            Err(ref e) if vanished::is_vanished(src, e) => vanished::record(src),
+            Err(e) => events::record(
+                Action::Error,
+                src,
+                format_args!("Error -- Copying metadata of file {:?}: {}", src, e),
+            ),
+            Ok(_) => (),
         }
     }
+    fn copy_hashed(
+        &self,
+        src: &PathBuf,
+        dest: &PathBuf,
+        flags: Flag,
+    ) -> Result<Option<FileHash>, io::Error> {
+        let mut progress = FileProgress::new(self.size);
+        let (result, hash) = match copy_consistent_hashed(src, dest, flags, &mut progress) {
+            Ok(hash) => (Ok(hash.is_some()), hash),
+            Err(e) => (Err(e), None),
+        };
+        File::log_copy(src, dest, self.size, result)?;
+        Ok(hash)
+    }
+    fn size(&self) -> u64 {
+        self.size
+    }
 }
 
 impl File {
@@ -58,42 +155,56 @@ impl File {
         }
     }
 
-    #[allow(unused)]
-    #[allow(clippy::unused_io_amount)]
-    fn diff_copy(src: &PathBuf, dest: &PathBuf) -> Result<(), io::Error> {
-        if !Path::new(&dest).exists() {
-            fs::copy(&src, &dest)?;
-        }
-
-        const CHUNK_SIZE: usize = 10000;
-
-        let src_file = fs::File::open(&src)?;
-        let mut src_reader = BufReader::with_capacity(CHUNK_SIZE, &src_file);
-        let dest_file = OpenOptions::new()
-            .write(true)
-            .read(true)
-            .create(true)
-            .open(&dest)?;
-        dest_file.set_len(src_file.metadata()?.len())?;
-        let mut dest_reader = BufReader::with_capacity(CHUNK_SIZE, &dest_file);
-        let mut dest_writer = BufWriter::with_capacity(CHUNK_SIZE, &dest_file);
-
-        loop {
-            let mut src_buffer = [0; CHUNK_SIZE];
-            let mut dest_buffer = [0; CHUNK_SIZE];
-
-            if src_reader.read(&mut src_buffer)? == 0 {
-                break;
-            }
-            dest_reader.read(&mut dest_buffer)?;
+    /// Creates a file from a path that need not be valid unicode
+    pub fn with_path(path: PathBuf, size: u64) -> Self {
+        File { path, size }
+    }
 
-            if seahash::hash(&src_buffer) != seahash::hash(&dest_buffer) {
-                dest_writer.write(&src_buffer)?;
-            } else {
-                dest_writer.seek(SeekFrom::Current(CHUNK_SIZE as i64));
+    /// Logs the result of copying `src` of `size` bytes to `dest`, which is true if `src` stayed
+    /// consistent
+    ///
+    /// # Errors
+    /// This function will return the error of the copy, unless `src` vanished
+    fn log_copy(
+        src: &Path,
+        dest: &Path,
+        size: u64,
+        result: Result<bool, io::Error>,
+    ) -> Result<(), io::Error> {
+        if result.is_ok() {
+            events::record_bytes(size);
+        }
+        match result {
+            Ok(true) => events::record(
+                Action::Copy,
+                src,
+                format_args!("Copying file {:?} -> {:?}", src, dest),
+            ),
+            Ok(false) => events::record(
+                Action::Warning,
+                src,
+                format_args!(
+                    "Warning -- File {:?} kept changing while being copied, {:?} may be inconsistent",
+                    src, dest
+                ),
+            ),
+            Err(ref e) if vanished::is_vanished(src, e) => vanished::record(src),
+            Err(e) => {
+                // Files held open by other programs can be read from a shadow copy instead
+                let hint = if snapshot::is_held_open(&e) {
+                    ", it is held open by another program, use --snapshot to copy it from a \
+                     shadow copy of its volume"
+                } else {
+                    ""
+                };
+                events::record(
+                    Action::Error,
+                    src,
+                    format_args!("Error -- Copying file {:?}: {}{}", src, e, hint),
+                );
+                return Err(e);
             }
         }
-
         Ok(())
     }
 }
@@ -108,16 +219,43 @@ impl FileOps for Dir {
     fn path(&self) -> &PathBuf {
         &self.path
     }
-    fn remove(&self, path: &PathBuf) {
-        match fs::remove_dir(&path) {
-            Ok(_) => info!("Deleting dir {:?}", path),
-            Err(e) => error!("Error -- Deleting dir {:?}: {}", path, e),
+    fn remove(&self, path: &PathBuf) -> Result<(), io::Error> {
+        let result = fs::remove_dir(path);
+        match &result {
+            Ok(_) => events::record(
+                Action::Delete,
+                path,
+                format_args!("Deleting dir {:?}", path),
+            ),
+            Err(e) => events::record(
+                Action::Error,
+                path,
+                format_args!("Error -- Deleting dir {:?}: {}", path, e),
+            ),
         }
+        result
     }
-    fn copy(&self, _src: &PathBuf, dest: &PathBuf) {
-        match fs::create_dir_all(&dest) {
-            Ok(_) => info!("Creating dir {:?}", dest),
-            Err(e) => error!("Error -- Creating dir {:?}: {}", dest, e),
+    fn copy(&self, _src: &PathBuf, dest: &PathBuf, _flags: Flag) -> Result<(), io::Error> {
+        let result = fs::create_dir_all(dest);
+        match &result {
+            Ok(_) => events::record(Action::Copy, dest, format_args!("Creating dir {:?}", dest)),
+            Err(e) => events::record(
+                Action::Error,
+                dest,
+                format_args!("Error -- Creating dir {:?}: {}", dest, e),
+            ),
+        }
+        result
+    }
+    fn copy_metadata(&self, src: &PathBuf, dest: &PathBuf, flags: Flag) {
+        match copy_file_metadata(src, dest, flags) {
+            Err(ref e) if vanished::is_vanished(src, e) => vanished::record(src),
+            Err(e) => events::record(
+                Action::Error,
+                src,
+                format_args!("Error -- Copying metadata of dir {:?}: {}", src, e),
+            ),
+            Ok(_) => (),
         }
     }
 }
@@ -141,37 +279,210 @@ impl FileOps for Symlink {
     fn path(&self) -> &PathBuf {
         &self.path
     }
-    fn remove(&self, path: &PathBuf) {
-        match fs::remove_file(&path) {
-            Ok(_) => info!("Deleting symlink {:?}", path),
-            Err(e) => error!("Error -- Deleting symlink {:?}: {}", path, e),
+    fn remove(&self, path: &PathBuf) -> Result<(), io::Error> {
+        let result = fs::remove_file(path);
+        match &result {
+            Ok(_) => events::record(
+                Action::Delete,
+                path,
+                format_args!("Deleting symlink {:?}", path),
+            ),
+            Err(e) => events::record(
+                Action::Error,
+                path,
+                format_args!("Error -- Deleting symlink {:?}: {}", path, e),
+            ),
         }
+        result
     }
     #[cfg(target_family = "unix")]
-    fn copy(&self, _src: &PathBuf, dest: &PathBuf) {
+    fn copy(&self, _src: &PathBuf, dest: &PathBuf, _flags: Flag) -> Result<(), io::Error> {
         use std::os::unix::fs;
 
         match fs::symlink(&self.target, &dest) {
-            Ok(_) => info!("Creating symlink {:?} -> {:?}", dest, self.target),
-            Err(e) => error!("Error -- Creating symlink {:?}: {}", dest, e),
+            Ok(_) => events::record(
+                Action::Copy,
+                dest,
+                format_args!("Creating symlink {:?} -> {:?}", dest, self.target),
+            ),
+            Err(ref e) if degrade::is_unsupported(e) => degrade::record(dest, Feature::Symlinks, e),
+            Err(e) => {
+                events::record(
+                    Action::Error,
+                    dest,
+                    format_args!("Error -- Creating symlink {:?}: {}", dest, e),
+                );
+                return Err(e);
+            }
         }
+        Ok(())
     }
     #[cfg(target_family = "windows")]
-    fn copy(&self, _src: &PathBuf, dest: &PathBuf) {
+    fn copy(&self, _src: &PathBuf, dest: &PathBuf, _flags: Flag) -> Result<(), io::Error> {
         use std::os::windows::fs;
         if self.target.is_file() {
             match fs::symlink_file(&self.target, &dest) {
-                Ok(_) => info!("Creating symlink file {:?} -> {:?}", dest, self.target),
-                Err(e) => error!("Error -- Creating symlink file{:?}: {}", dest, e),
+                Ok(_) => events::record(
+                    Action::Copy,
+                    dest,
+                    format_args!("Creating symlink file {:?} -> {:?}", dest, self.target),
+                ),
+                Err(ref e) if degrade::is_unsupported(e) => {
+                    degrade::record(dest, Feature::Symlinks, e)
+                }
+                Err(e) => {
+                    events::record(
+                        Action::Error,
+                        dest,
+                        format_args!("Error -- Creating symlink file {:?}: {}", dest, e),
+                    );
+                    return Err(e);
+                }
             }
         }
         if self.target.is_dir() {
             match fs::symlink_dir(&self.target, &dest) {
-                Ok(_) => info!("Creating symlink dir {:?} -> {:?}", dest, self.target),
-                Err(e) => error!("Error -- Creating symlink dir {:?}: {}", dest, e),
+                Ok(_) => events::record(
+                    Action::Copy,
+                    dest,
+                    format_args!("Creating symlink dir {:?} -> {:?}", dest, self.target),
+                ),
+                Err(ref e) if degrade::is_unsupported(e) => {
+                    degrade::record(dest, Feature::Symlinks, e)
+                }
+                Err(e) => {
+                    events::record(
+                        Action::Error,
+                        dest,
+                        format_args!("Error -- Creating symlink dir {:?}: {}", dest, e),
+                    );
+                    return Err(e);
+                }
             }
         }
+        Ok(())
     }
+    #[cfg(target_family = "unix")]
+    fn copy_metadata(&self, src: &PathBuf, dest: &PathBuf, flags: Flag) {
+        use std::os::unix::fs::{lchown, MetadataExt};
+
+        // Symlink permissions are meaningless, only the owner and times can be preserved
+        if flags.contains(Flag::TIMES) {
+            match copy_symlink_times(src, dest) {
+                Err(ref e) if degrade::is_unsupported(e) => {
+                    degrade::record(dest, Feature::Times, e)
+                }
+                Err(ref e) if vanished::is_vanished(src, e) => vanished::record(src),
+                Err(e) => events::record(
+                    Action::Error,
+                    src,
+                    format_args!("Error -- Copying times of symlink {:?}: {}", src, e),
+                ),
+                Ok(_) => (),
+            }
+        }
+        if flags.contains(Flag::XATTRS) {
+            match xattrs::copy(src, dest) {
+                Err(ref e) if degrade::is_unsupported(e) => {
+                    degrade::record(dest, Feature::Xattrs, e)
+                }
+                Err(ref e) if vanished::is_vanished(src, e) => vanished::record(src),
+                Err(e) => events::record(
+                    Action::Error,
+                    src,
+                    format_args!(
+                        "Error -- Copying extended attributes of symlink {:?}: {}",
+                        src, e
+                    ),
+                ),
+                Ok(_) => (),
+            }
+        }
+        if !flags.contains(Flag::OWNER) {
+            return;
+        }
+
+        let result = fs::symlink_metadata(src).and_then(|src_metadata| {
+            let dest_metadata = fs::symlink_metadata(dest)?;
+            if src_metadata.uid() == dest_metadata.uid()
+                && src_metadata.gid() == dest_metadata.gid()
+            {
+                return Ok(());
+            }
+
+            let uid = Some(src_metadata.uid()).filter(|_| is_privileged());
+            lchown(dest, uid, Some(src_metadata.gid()))?;
+            info!(
+                "Changing owner of symlink {:?} -> {}:{}",
+                dest,
+                src_metadata.uid(),
+                src_metadata.gid()
+            );
+            Ok(())
+        });
+
+        match result {
+            Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied && !is_privileged() => {
+                info!("Skipping owner of symlink {:?}: {}", dest, e)
+            }
+            Err(ref e) if degrade::is_unsupported(e) => degrade::record(dest, Feature::Owner, e),
+            Err(ref e) if vanished::is_vanished(src, e) => vanished::record(src),
+            Err(e) => events::record(
+                Action::Error,
+                src,
+                format_args!("Error -- Copying metadata of symlink {:?}: {}", src, e),
+            ),
+            Ok(_) => (),
+        }
+    }
+    #[cfg(target_family = "windows")]
+    fn copy_metadata(&self, _src: &PathBuf, _dest: &PathBuf, _flags: Flag) {}
+}
+
+/// Copies the access and modification times of the symlink `src` onto the symlink `dest`,
+/// without following either, unless they already match
+///
+/// # Errors
+/// This function will return an error if the times of `src` cannot be read,
+/// or the times of `dest` cannot be changed
+#[cfg(target_family = "unix")]
+fn copy_symlink_times(src: &Path, dest: &Path) -> Result<(), io::Error> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+
+    let src_metadata = fs::symlink_metadata(src)?;
+    let dest_metadata = fs::symlink_metadata(dest)?;
+    if (src_metadata.mtime(), src_metadata.mtime_nsec())
+        == (dest_metadata.mtime(), dest_metadata.mtime_nsec())
+    {
+        return Ok(());
+    }
+
+    let times = [
+        libc::timespec {
+            tv_sec: src_metadata.atime() as libc::time_t,
+            tv_nsec: src_metadata.atime_nsec() as _,
+        },
+        libc::timespec {
+            tv_sec: src_metadata.mtime() as libc::time_t,
+            tv_nsec: src_metadata.mtime_nsec() as _,
+        },
+    ];
+    let path = CString::new(dest.as_os_str().as_bytes())?;
+    let result = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    info!("Changing times of symlink {:?}", dest);
+    Ok(())
 }
 
 impl Symlink {
@@ -181,6 +492,11 @@ impl Symlink {
             target: PathBuf::from(target),
         }
     }
+
+    /// Gets the path that the symlink points to
+    pub fn target(&self) -> &Path {
+        &self.target
+    }
 }
 
 /// A struct that represents sets of different types of files
@@ -189,6 +505,7 @@ pub struct FileSets {
     files: HashSet<File>,
     dirs: HashSet<Dir>,
     symlinks: HashSet<Symlink>,
+    skipped: HashSet<PathBuf>,
 }
 
 impl FileSets {
@@ -206,6 +523,7 @@ impl FileSets {
             files,
             dirs,
             symlinks,
+            skipped: HashSet::new(),
         }
     }
     /// Gets the set of files
@@ -229,11 +547,657 @@ impl FileSets {
     pub fn symlinks(&self) -> &HashSet<Symlink> {
         &self.symlinks
     }
+    /// Gets the set of paths that were deliberately skipped during traversal
+    ///
+    /// # Returns
+    /// The FileSets set of skipped paths
+    pub fn skipped(&self) -> &HashSet<PathBuf> {
+        &self.skipped
+    }
+    /// Checks whether `path`, or any directory containing it, was skipped during traversal
+    ///
+    /// Skipped paths are not missing from the tree, so they must never be treated as deleted
+    ///
+    /// # Arguments
+    /// * `path`: relative path to check
+    ///
+    /// # Returns
+    /// True if `path` lies within a skipped path
+    pub fn is_skipped(&self, path: &Path) -> bool {
+        !self.skipped.is_empty() && path.ancestors().any(|p| self.skipped.contains(p))
+    }
 }
 
+/// Checks whether the process runs with the privileges needed to give files away to other users
+///
+/// # Returns
+/// True if the effective user is root
+#[cfg(target_family = "unix")]
+pub fn is_privileged() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(target_family = "windows")]
+pub fn is_privileged() -> bool {
+    false
+}
+
+/// Copies the owner and permissions selected by `flags` from `src` onto `dest`,
+/// including the setuid, setgid and sticky bits
+///
+/// The owner is changed first, since changing it clears the setuid and setgid bits of `dest`.
+/// Without root privileges only the group can be changed, and only to a group of the user.
+///
+/// # Arguments
+/// * `src`: absolute path of the file or directory to copy metadata from
+/// * `dest`: absolute path of the file or directory to copy metadata to
+/// * `flags`: set for Flag's, of which `PERMS` and `OWNER` are used
+///
+/// # Errors
+/// This function will return an error if the metadata of either file cannot be read,
+/// or the metadata of `dest` cannot be changed
+#[cfg(target_family = "unix")]
+fn copy_owner_and_mode(src: &Path, dest: &Path, flags: Flag) -> Result<(), io::Error> {
+    use std::os::unix::fs::{chown, MetadataExt, PermissionsExt};
+
+    const MODE_BITS: u32 = 0o7777;
+
+    let src_metadata = fs::metadata(src)?;
+    let dest_metadata = fs::metadata(dest)?;
+    let mut mode = dest_metadata.mode() & MODE_BITS;
+
+    if flags.contains(Flag::OWNER)
+        && (src_metadata.uid() != dest_metadata.uid() || src_metadata.gid() != dest_metadata.gid())
+    {
+        let uid = Some(src_metadata.uid()).filter(|_| is_privileged());
+        match chown(dest, uid, Some(src_metadata.gid())) {
+            Ok(_) => {
+                info!(
+                    "Changing owner of {:?} -> {}:{}",
+                    dest,
+                    src_metadata.uid(),
+                    src_metadata.gid()
+                );
+                // Force the mode to be reapplied, since chown may have cleared setuid/setgid
+                mode = !mode & MODE_BITS;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied && !is_privileged() => {
+                info!("Skipping owner of {:?}: {}", dest, e)
+            }
+            Err(ref e) if degrade::is_unsupported(e) => degrade::record(dest, Feature::Owner, e),
+            Err(e) => return Err(e),
+        }
+    }
+
+    let target_mode = if flags.contains(Flag::PERMS) {
+        src_metadata.mode() & MODE_BITS
+    } else {
+        dest_metadata.mode() & MODE_BITS
+    };
+
+    if mode != target_mode {
+        match fs::set_permissions(dest, fs::Permissions::from_mode(target_mode)) {
+            Ok(_) => info!("Changing mode of {:?} -> {:o}", dest, target_mode),
+            Err(ref e) if degrade::is_unsupported(e) => {
+                degrade::record(dest, Feature::Permissions, e)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(target_family = "windows")]
+fn copy_owner_and_mode(src: &Path, dest: &Path, flags: Flag) -> Result<(), io::Error> {
+    if flags.contains(Flag::PERMS) {
+        fs::set_permissions(dest, fs::metadata(src)?.permissions())?;
+    }
+
+    Ok(())
+}
+
+/// Copies the Windows attributes, and the owner, permissions, timestamps and extended attributes
+/// selected by `flags`, from the file or directory `src` onto `dest`
+///
+/// The Windows attributes and alternate data streams go first, since writing the streams changes
+/// the modification time of `dest`.
+///
+/// # Errors
+/// This function will return an error if the metadata of `dest` cannot be changed
+fn copy_file_metadata(src: &Path, dest: &Path, flags: Flag) -> Result<(), io::Error> {
+    match attributes::copy(src, dest) {
+        Err(ref e) if degrade::is_unsupported(e) => degrade::record(dest, Feature::Attributes, e),
+        result => result?,
+    }
+    copy_owner_and_mode(src, dest, flags)?;
+    if flags.contains(Flag::TIMES) {
+        match copy_times(src, dest) {
+            Err(ref e) if degrade::is_unsupported(e) => degrade::record(dest, Feature::Times, e),
+            result => result?,
+        }
+    }
+    if flags.contains(Flag::XATTRS) {
+        match xattrs::copy(src, dest) {
+            Err(ref e) if degrade::is_unsupported(e) => degrade::record(dest, Feature::Xattrs, e),
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+/// Copies the access and modification times of `src` onto `dest`, unless they already match
+///
+/// # Arguments
+/// * `src`: absolute path of the file or directory to copy the times of
+/// * `dest`: absolute path of the file or directory to copy the times to
+///
+/// # Errors
+/// This function will return an error if the times of `src` cannot be read,
+/// or the times of `dest` cannot be changed
+pub fn copy_times(src: &Path, dest: &Path) -> Result<(), io::Error> {
+    let src_metadata = fs::metadata(src)?;
+    let modified = src_metadata.modified()?;
+    if fs::metadata(dest)?.modified()? == modified {
+        return Ok(());
+    }
+
+    let times = fs::FileTimes::new()
+        .set_accessed(src_metadata.accessed()?)
+        .set_modified(modified);
+    open_for_times(dest)?.set_times(times)?;
+    info!("Changing times of {:?}", dest);
+    Ok(())
+}
+
+/// Opens a file or directory so that its times can be changed
+#[cfg(target_family = "unix")]
+fn open_for_times(path: &Path) -> Result<fs::File, io::Error> {
+    // Changing times only requires owning the file, not being able to write to it
+    fs::File::open(path)
+}
+
+#[cfg(target_family = "windows")]
+fn open_for_times(path: &Path) -> Result<fs::File, io::Error> {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    // Directories can only be opened with backup semantics
+    const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x02000000;
+    const FILE_WRITE_ATTRIBUTES: u32 = 0x100;
+    OpenOptions::new()
+        .access_mode(FILE_WRITE_ATTRIBUTES)
+        .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+        .open(path)
+}
+
+/// Copies the metadata selected by `flags` of all given files from `src` to `dest` in parallel
+///
+/// Directories should be given last, so that restrictive directory permissions
+/// cannot prevent files from being copied into them
+///
+/// # Arguments
+/// * `files`: files to copy the metadata of
+/// * `src`: base directory of the files to copy from, such that for all `file` in
+/// `files`, `src + file.path()` is the absolute path of the source file
+/// * `dest`: base directory of the files to copy to, such that for all `file` in
+/// `files`, `dest + file.path()` is the absolute path of the destination file
+/// * `flags`: set for Flag's
+pub fn copy_metadata_files<'a, T, S>(files: T, src: &Path, dest: &Path, flags: Flag)
+where
+    T: ParallelIterator<Item = &'a S>,
+    S: FileOps + Sync + 'a,
+{
+    files.for_each(|file| {
+        let src_file = src.join(file.path());
+        let dest_file = dest.join(file.path());
+
+        file.copy_metadata(&src_file, &dest_file, flags);
+    });
+}
+
+/// Clones the file `src` to the new file `dest`, sharing the data of `src` instead of copying it
+///
+/// Only filesystems with copy-on-write support (Btrfs, XFS, etc.) can clone files,
+/// and the permissions of `src` are copied along with it
+///
+/// # Arguments
+/// * `src`: absolute path of the file to clone
+/// * `dest`: absolute path of the clone, which must not exist yet
+///
+/// # Errors
+/// This function will return an error if `dest` already exists,
+/// or if the filesystem cannot clone `src` to `dest`
+#[cfg(target_os = "linux")]
+pub fn reflink(src: &Path, dest: &Path) -> Result<(), io::Error> {
+    use std::os::unix::io::AsRawFd;
+
+    let src_file = fs::File::open(src)?;
+    let dest_file = OpenOptions::new().write(true).create_new(true).open(dest)?;
+
+    if unsafe { libc::ioctl(dest_file.as_raw_fd(), libc::FICLONE, src_file.as_raw_fd()) } == -1 {
+        let e = io::Error::last_os_error();
+        drop(dest_file);
+        let _ = fs::remove_file(dest);
+        return Err(e);
+    }
+
+    dest_file.set_permissions(src_file.metadata()?.permissions())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn reflink(_src: &Path, _dest: &Path) -> Result<(), io::Error> {
+    Err(io::Error::other(
+        "cloning files is not supported on this platform",
+    ))
+}
+
+/// Configures whether all following copies of the current job clone files instead of copying
+/// their contents
+///
+/// # Arguments
+/// * `reflink`: whether files are cloned, like with --reflink
+pub fn configure_reflinks(reflink: Reflink) {
+    context::current()
+        .reflink
+        .store(reflink as u8, Ordering::Relaxed);
+}
+
+/// Gets whether files are cloned instead of copied
+fn reflink_mode() -> Reflink {
+    match context::current().reflink.load(Ordering::Relaxed) {
+        mode if mode == Reflink::Auto as u8 => Reflink::Auto,
+        mode if mode == Reflink::Always as u8 => Reflink::Always,
+        _ => Reflink::Never,
+    }
+}
+
+/// Clones the file `src` to `dest` like `reflink`, replacing `dest` if it exists
+///
+/// The clone is made next to `dest` first and then renamed over it, so that `dest` is left as
+/// it was if the filesystem cannot clone `src`.
+///
+/// # Errors
+/// This function will return an error if the filesystem cannot clone `src` to `dest`
+fn reflink_over(src: &Path, dest: &Path) -> Result<(), io::Error> {
+    if fs::symlink_metadata(dest).is_err() {
+        return reflink(src, dest);
+    }
+
+    let mut clone = dest.as_os_str().to_owned();
+    clone.push(".lms-clone");
+    let clone = PathBuf::from(clone);
+    let _ = fs::remove_file(&clone);
+    reflink(src, &clone)?;
+    fs::rename(&clone, dest).inspect_err(|_| {
+        let _ = fs::remove_file(&clone);
+    })
+}
+
+/// Number of times a file that changes while it is being copied is copied again
+const COPY_ATTEMPTS: usize = 3;
+
+/// Copies the file `src` to `dest`, copying it again if `src` changed while it was read,
+/// so that `dest` never silently ends up with a mix of old and new contents
+///
+/// # Arguments
+/// * `src`: absolute path of the file to copy
+/// * `dest`: absolute path of the copy
+/// * `flags`: set for Flag's, of which `LOCK_SOURCE` takes a shared advisory lock on `src`
+/// while it is copied, which waits for writers that lock the file themselves
+/// * `progress`: progress of the file, which the contents copied are credited to
+///
+/// # Returns
+/// True if `src` did not change while it was last copied, false if it kept changing
+///
+/// # Errors
+/// This function will return an error if `src` cannot be locked or copied
+pub fn copy_consistent(
+    src: &Path,
+    dest: &Path,
+    flags: Flag,
+    progress: &mut FileProgress,
+) -> Result<bool, io::Error> {
+    copy_attempts(src, flags, progress, |progress| {
+        transfer(src, dest, flags, None, progress)
+    })
+}
+
+/// Copies the file `src` to `dest` like `copy_consistent`, hashing the contents while they are
+/// copied so that `src` is read only once
+///
+/// # Arguments
+/// * `src`: absolute path of the file to copy
+/// * `dest`: absolute path of the copy
+/// * `flags`: set for Flag's, of which `SECURE` selects the cryptographic hash function
+/// * `progress`: progress of the file, which the contents copied are credited to
+///
+/// # Returns
+/// * Some: The hash of the contents copied, if `src` did not change while it was last copied
+/// * None: If `src` kept changing
+///
+/// # Errors
+/// This function will return an error if `src` cannot be locked or copied
+pub fn copy_consistent_hashed(
+    src: &Path,
+    dest: &Path,
+    flags: Flag,
+    progress: &mut FileProgress,
+) -> Result<Option<FileHash>, io::Error> {
+    let mut hash = None;
+    let consistent = copy_attempts(src, flags, progress, |progress| {
+        let mut hasher = ContentHasher::new(flags);
+        transfer(src, dest, flags, Some(&mut hasher), progress)?;
+        hash = Some(hasher.finish());
+        Ok(())
+    })?;
+    Ok(hash.filter(|_| consistent))
+}
+
+/// Copies the file `src` to `dest` once, feeding the contents written to `hasher` and crediting
+/// them to `progress`
+///
+/// Files with a transformation are copied through its command. Otherwise, if `flags` contains
+/// `DELTA` and `dest` is an existing file, only its blocks that differ from `src` are rewritten,
+/// and else `dest` is cloned from `src` if --reflink is given and the filesystem supports it, or
+/// written as a whole, monitored for stalls.
+///
+/// # Errors
+/// This function will return an error if `src` cannot be copied
+fn transfer(
+    src: &Path,
+    dest: &Path,
+    flags: Flag,
+    mut hasher: Option<&mut ContentHasher>,
+    progress: &mut FileProgress,
+) -> Result<(), io::Error> {
+    let on_chunk = |chunk: &[u8]| -> Result<(), io::Error> {
+        if let Some(hasher) = hasher.as_deref_mut() {
+            hasher.update(chunk);
+        }
+        progress.inc(chunk.len() as u64);
+        Ok(())
+    };
+
+    // A read-only copy is made read-only again once its metadata is copied
+    attributes::make_writable(dest)?;
+
+    if let Some(command) = transform::command_for(src) {
+        return transform::copy_with(&command, src, dest, on_chunk).map(|_| ());
+    }
+    if flags.contains(Flag::DELTA) && fs::symlink_metadata(dest).is_ok_and(|m| m.is_file()) {
+        return delta::copy_with(src, dest, on_chunk).map(|_| ());
+    }
+
+    let reflink = reflink_mode();
+    if reflink != Reflink::Never {
+        match reflink_over(src, dest) {
+            Ok(()) => {
+                // A clone shares the contents of `src`, which are only read if they are hashed
+                if let Some(hasher) = hasher {
+                    let mut reader = fs::File::open(src)?;
+                    let mut buffer = vec![0; stream::CHUNK_SIZE];
+                    loop {
+                        match reader.read(&mut buffer)? {
+                            0 => break,
+                            read => hasher.update(&buffer[..read]),
+                        }
+                    }
+                }
+                progress.inc(fs::metadata(dest)?.len());
+                return Ok(());
+            }
+            Err(e) if reflink == Reflink::Always => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("cloning is not supported here ({}), see --reflink", e),
+                ));
+            }
+            Err(_) => (),
+        }
+    }
+    if flags.contains(Flag::SPARSE) {
+        return stream::copy_sparse(src, dest, |chunk, _| {
+            if let Some(hasher) = hasher.as_deref_mut() {
+                hasher.update(chunk);
+            }
+            progress.inc(chunk.len() as u64);
+            Ok(())
+        })
+        .map(|_| ());
+    }
+    stall::copy(src, dest, hasher, progress).map(|_| ())
+}
+
+/// Hashes the output of `command` for the file `src`, which is what a copy through it holds
+///
+/// # Returns
+/// * Some: The hash of the output, selected by `flags`
+/// * None: If the command failed
+fn hash_transformed(command: &str, src: &Path, flags: Flag) -> Option<FileHash> {
+    let mut hasher = ContentHasher::new(flags);
+    let result = transform::run(command, src, |chunk| {
+        hasher.update(chunk);
+        Ok(())
+    });
+    match result {
+        Ok(_) => Some(hasher.finish()),
+        Err(e) => {
+            events::record(
+                Action::Error,
+                src,
+                format_args!("Error -- Transforming {:?}: {}", src, e),
+            );
+            None
+        }
+    }
+}
+
+/// Runs `copy` until `src` did not change while it ran, at most `COPY_ATTEMPTS` times
+///
+/// Each attempt is credited to `progress` only beyond what the attempts before it credited, so
+/// that a file copied again is not counted twice.
+///
+/// # Returns
+/// True if `src` did not change while it was last copied, false if it kept changing
+///
+/// # Errors
+/// This function will return an error if `src` cannot be locked, or `copy` fails
+fn copy_attempts<F>(
+    src: &Path,
+    flags: Flag,
+    progress: &mut FileProgress,
+    mut copy: F,
+) -> Result<bool, io::Error>
+where
+    F: FnMut(&mut FileProgress) -> Result<(), io::Error>,
+{
+    let _lock = if flags.contains(Flag::LOCK_SOURCE) {
+        Some(lock_shared(src)?)
+    } else {
+        None
+    };
+
+    for _ in 0..COPY_ATTEMPTS {
+        let before = change_stamp(&fs::metadata(src)?);
+        progress.restart();
+        copy(progress)?;
+        if change_stamp(&fs::metadata(src)?) == before {
+            return Ok(true);
+        }
+        info!(
+            "File {:?} changed while being copied, copying it again",
+            src
+        );
+    }
+
+    Ok(false)
+}
+
+/// Gets the attributes of a file that change whenever its contents are written
+#[cfg(target_family = "unix")]
+fn change_stamp(metadata: &fs::Metadata) -> (u64, i64, i64, i64, i64) {
+    use std::os::unix::fs::MetadataExt;
+
+    (
+        metadata.len(),
+        metadata.mtime(),
+        metadata.mtime_nsec(),
+        metadata.ctime(),
+        metadata.ctime_nsec(),
+    )
+}
+
+#[cfg(target_family = "windows")]
+fn change_stamp(metadata: &fs::Metadata) -> (u64, Option<std::time::SystemTime>) {
+    (metadata.len(), metadata.modified().ok())
+}
+
+/// Takes a shared advisory lock on the file at `path`, which is released when the
+/// returned file is dropped
+///
+/// # Errors
+/// This function will return an error if the file cannot be opened or locked
+#[cfg(target_family = "unix")]
+fn lock_shared(path: &Path) -> Result<fs::File, io::Error> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = fs::File::open(path)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+#[cfg(target_family = "windows")]
+fn lock_shared(path: &Path) -> Result<fs::File, io::Error> {
+    // Opening a file without write sharing locks out writers until the file is closed
+    use std::os::windows::fs::OpenOptionsExt;
+
+    const FILE_SHARE_READ: u32 = 0x1;
+    OpenOptions::new()
+        .read(true)
+        .share_mode(FILE_SHARE_READ)
+        .open(path)
+}
+
+/// A hash of the contents of a file, produced by the hash function selected by the flags
+#[derive(Hash, Eq, PartialEq, Debug, Clone)]
+pub enum FileHash {
+    /// Seahash, non-cryptographic
+    Fast(u64),
+    /// BLAKE2b, cryptographic
+    Secure(Vec<u8>),
+}
+
+/// Hashes contents as they are read, such as while they are copied, producing the same hash as
+/// `hash_file_with_flags` does for the whole file
+pub enum ContentHasher {
+    /// Seahash, non-cryptographic
+    Fast(SeaHasher),
+    /// BLAKE2b over the digests of all chunks of `HASH_CHUNK_SIZE` bytes
+    Secure {
+        chunk: Box<Blake2b>,
+        chunk_len: u64,
+        digests: Vec<Vec<u8>>,
+    },
+}
+
+impl ContentHasher {
+    /// Creates a hasher for the hash function selected by `flags`
+    pub fn new(flags: Flag) -> Self {
+        if flags.contains(Flag::SECURE) {
+            ContentHasher::Secure {
+                chunk: Box::new(Blake2b::new()),
+                chunk_len: 0,
+                digests: Vec::new(),
+            }
+        } else {
+            ContentHasher::Fast(SeaHasher::new())
+        }
+    }
+
+    /// Hashes the next `bytes` of the contents
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        match self {
+            ContentHasher::Fast(hasher) => hasher.write(bytes),
+            ContentHasher::Secure {
+                chunk,
+                chunk_len,
+                digests,
+            } => {
+                while !bytes.is_empty() {
+                    let len = bytes.len().min((HASH_CHUNK_SIZE - *chunk_len) as usize);
+                    chunk.update(&bytes[..len]);
+                    *chunk_len += len as u64;
+                    bytes = &bytes[len..];
+
+                    if *chunk_len == HASH_CHUNK_SIZE {
+                        digests.push(chunk.finalize_reset().to_vec());
+                        *chunk_len = 0;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Gets the hash of all contents
+    pub fn finish(self) -> FileHash {
+        match self {
+            ContentHasher::Fast(hasher) => FileHash::Fast(hasher.finish()),
+            ContentHasher::Secure {
+                chunk,
+                chunk_len,
+                mut digests,
+            } => {
+                // Empty files still hash a single empty chunk, like `hash_chunks`
+                if chunk_len > 0 || digests.is_empty() {
+                    digests.push((*chunk).finalize().to_vec());
+                }
+                FileHash::Secure(hash_digests(&digests))
+            }
+        }
+    }
+}
+
+/// A thread-safe store of file hashes computed during a run, keyed by relative path,
+/// so that later stages can reuse them instead of hashing files again
+#[derive(Debug, Default)]
+pub struct HashStore {
+    hashes: Mutex<HashMap<PathBuf, FileHash>>,
+}
+
+impl HashStore {
+    /// Records the hash of the file at `path`
+    ///
+    /// # Arguments
+    /// * `path`: relative path of the file
+    /// * `hash`: hash of the contents of the file
+    pub fn insert(&self, path: PathBuf, hash: FileHash) {
+        self.hashes.lock().unwrap().insert(path, hash);
+    }
+    /// Gets the recorded hash of the file at `path`
+    ///
+    /// # Arguments
+    /// * `path`: relative path of the file
+    ///
+    /// # Returns
+    /// * Some: The recorded hash
+    /// * None: If no hash was recorded for `path`
+    pub fn get(&self, path: &Path) -> Option<FileHash> {
+        self.hashes.lock().unwrap().get(path).cloned()
+    }
+}
+
+/// Number of differing files that may wait to be copied while more files are hashed
+const COPY_QUEUE_SIZE: usize = 64;
+
 /// Compares all files in `files_to_compare` in `src` with all files in `files_to_compare` in `dest`
 /// and copies them over if they are different, in parallel
 ///
+/// Hashing and copying run in separate stages connected by a bounded queue, so that
+/// slow hashes do not leave the copying idle, and copying cannot fall arbitrarily behind.
+/// Large files are copied by their own, fewer copiers, as set with `tiers::configure`.
+///
 /// # Arguments
 /// * `files_to_compare`: files to compare
 /// * `src`: base directory of the files to copy from, such that for all `file` in
@@ -241,18 +1205,232 @@ impl FileSets {
 /// * `dest`: base directory of the files to copy to, such that for all `file` in
 /// `files_to_compare`, `dest + file.path()` is the absolute path of the destination file
 /// * `flags`: set for Flag's
-pub fn compare_and_copy_files<'a, T, S>(files_to_compare: T, src: &str, dest: &str, flags: Flag)
+/// * `hashes`: store to record the hashes of the source files in
+///
+/// # Returns
+/// The number of differing files that could not be copied
+pub fn compare_and_copy_files<'a, T, S>(
+    files_to_compare: T,
+    src: &Path,
+    dest: &Path,
+    flags: Flag,
+    hashes: &HashStore,
+) -> usize
 where
     T: ParallelIterator<Item = &'a S>,
     S: FileOps + Sync + 'a,
 {
-    files_to_compare.for_each(|file| {
-        compare_and_copy_file(file, src, dest, flags);
-        PROGRESS_BAR.inc(2);
+    // Large files have their own queue and fewer copiers, so that they do not hold up small files
+    let (small_sender, small_receiver) = mpsc::sync_channel::<&S>(COPY_QUEUE_SIZE);
+    let (large_sender, large_receiver) = mpsc::sync_channel::<&S>(COPY_QUEUE_SIZE);
+    let small_receiver = Mutex::new(small_receiver);
+    let large_receiver = Mutex::new(large_receiver);
+    // With a single thread, as with --sequential, large files are queued with the small files
+    let large_copiers = match rayon::current_num_threads() {
+        1 => 0,
+        threads => tiers::large_file_jobs().min(threads),
+    };
+    let failed = AtomicUsize::new(0);
+
+    let copier = |receiver: &Mutex<mpsc::Receiver<&S>>| loop {
+        // The lock is released before copying, so the copies themselves run in parallel
+        let file = match receiver.lock().unwrap().recv() {
+            Ok(file) => file,
+            Err(_) => break,
+        };
+
+        // With --backup-dir, files whose old version cannot be kept are not overwritten
+        if !backups::is_backed_up(dest, file.path(), flags.contains(Flag::DELTA)) {
+            failed.fetch_add(1, Ordering::Relaxed);
+            progress::bar().inc(file.size());
+            continue;
+        }
+
+        // Sources that were not hashed to compare them are hashed while they are copied
+        let result = if hashes.get(file.path()).is_some() {
+            copy_file(file, src, dest, flags)
+        } else {
+            copy_file_hashed(file, src, dest, flags, hashes)
+        };
+        if result.is_err() {
+            failed.fetch_add(1, Ordering::Relaxed);
+        }
+    };
+    let job = progress::current_job();
+    thread::scope(|scope| {
+        for _ in 0..rayon::current_num_threads() {
+            scope.spawn(|| job.enter(|| copier(&small_receiver)));
+        }
+        for _ in 0..large_copiers {
+            scope.spawn(|| job.enter(|| copier(&large_receiver)));
+        }
+
+        // The queues close once every clone of their senders is dropped, which stops the copying
+        files_to_compare.for_each_with(
+            (small_sender, large_sender),
+            |(small_sender, large_sender), file| {
+                if differs(file, src, dest, flags, hashes) {
+                    let sender = if large_copiers > 0 && tiers::is_large(file.size()) {
+                        &*large_sender
+                    } else {
+                        &*small_sender
+                    };
+                    // Sending only fails if all copying stopped, which cannot happen before this
+                    let _ = sender.send(file);
+                } else {
+                    // Files that need no copy are done with all of their bytes at once
+                    progress::bar().inc(file.size());
+                }
+                progress::bar().inc(2);
+            },
+        );
     });
+    failed.into_inner()
+}
+
+/// Compares all files in `files_to_compare` in `src` and `dest` like `compare_and_copy_files`,
+/// without copying anything, in parallel
+///
+/// # Arguments
+/// * `files_to_compare`: files to compare
+/// * `src`: base directory of the files to compare, like for `compare_and_copy_files`
+/// * `dest`: base directory of the files to compare with, like for `compare_and_copy_files`
+/// * `flags`: set for Flag's
+/// * `hashes`: store to record the hashes of the source files in
+///
+/// # Returns
+/// The files that `compare_and_copy_files` would copy
+pub fn differing_files<'a, T, S>(
+    files_to_compare: T,
+    src: &Path,
+    dest: &Path,
+    flags: Flag,
+    hashes: &HashStore,
+) -> Vec<&'a S>
+where
+    T: ParallelIterator<Item = &'a S>,
+    S: FileOps + Sync + 'a,
+{
+    files_to_compare
+        .filter(|file| {
+            progress::bar().inc(1);
+            differs(*file, src, dest, flags, hashes)
+        })
+        .collect()
+}
+
+/// Suffix appended to the name of a destination file that is kept as a backup
+pub const BACKUP_SUFFIX: &str = "~";
+
+/// Configures the comparisons of all following synchronizations of the current job
+///
+/// # Arguments
+/// * `checksum_under`: size in bytes below which files are always hashed to compare them, None
+///   to take every file of the same size and modification time as the same
+/// * `modify_window`: how far modification times may differ and still be taken as the same, for
+///   filesystems that store them coarsely or hosts whose clocks are skewed, None for exact times
+pub fn configure_comparisons(checksum_under: Option<u64>, modify_window: Option<Duration>) {
+    let context = context::current();
+    context
+        .checksum_under
+        .store(checksum_under.unwrap_or(0), Ordering::Relaxed);
+    context.modify_window.store(
+        modify_window.map_or(0, |window| window.as_nanos() as u64),
+        Ordering::Relaxed,
+    );
+}
+
+/// Gets the size in bytes below which files are compared by their contents even if their sizes
+/// and modification times match, like with --checksum
+fn checksum_under() -> u64 {
+    context::current().checksum_under.load(Ordering::Relaxed)
+}
+
+/// Gets how far modification times may differ and still be taken as the same
+fn modify_window() -> Duration {
+    Duration::from_nanos(context::current().modify_window.load(Ordering::Relaxed))
+}
+
+/// Checks whether `a` and `b` are at most `window` apart
+fn is_within(a: SystemTime, b: SystemTime, window: Duration) -> bool {
+    match a.duration_since(b) {
+        Ok(difference) => difference <= window,
+        Err(e) => e.duration() <= window,
+    }
+}
+
+/// Checks whether the given file in `dest` was modified later than the same file in `src`
+///
+/// # Arguments
+/// * `file`: file to check
+/// * `src`: base directory of the file, such that `src + file.path()` is the source file
+/// * `dest`: base directory of the file, such that `dest + file.path()` is the destination file
+///
+/// # Returns
+/// True if the destination file is newer by more than the modify window, false if it is not or
+/// either time cannot be read
+pub fn dest_is_newer<S>(file: &S, src: &Path, dest: &Path) -> bool
+where
+    S: FileOps,
+{
+    let modified = |location: &Path| {
+        let path = location.join(file.path());
+        fs::metadata(path).and_then(|metadata| metadata.modified())
+    };
+    match (modified(src), modified(dest)) {
+        (Ok(src_modified), Ok(dest_modified)) => {
+            dest_modified > src_modified && !is_within(dest_modified, src_modified, modify_window())
+        }
+        _ => false,
+    }
+}
+
+/// Renames all given files in `location` by appending `BACKUP_SUFFIX` to their names, in parallel,
+/// so that they are kept when copies overwrite them
+///
+/// # Arguments
+/// * `files_to_backup`: files to rename
+/// * `location`: base directory of the files to rename, like for `delete_files`
+///
+/// # Returns
+/// The files that were renamed, which may be overwritten without losing them
+pub fn backup_files<'a, T, S>(files_to_backup: T, location: &Path) -> Vec<&'a S>
+where
+    T: ParallelIterator<Item = &'a S>,
+    S: FileOps + Sync + 'a,
+{
+    files_to_backup
+        .filter(|file| {
+            let path: PathBuf = [&PathBuf::from(&location), file.path()].iter().collect();
+            let mut backup = path.clone().into_os_string();
+            backup.push(BACKUP_SUFFIX);
+            match fs::rename(&path, &backup) {
+                Ok(_) => {
+                    info!("Backed up {:?} to {:?}", path, backup);
+                    // With --backup-dir, rolling back renames the backup over the new version
+                    if backups::is_configured() {
+                        let mut renamed = file.path().clone().into_os_string();
+                        renamed.push(BACKUP_SUFFIX);
+                        rollback::renamed(location, file.path(), Path::new(&renamed));
+                    }
+                    true
+                }
+                Err(e) => {
+                    events::record(
+                        Action::Error,
+                        &path,
+                        format_args!("Error -- Keeping {:?}, it cannot be backed up: {}", path, e),
+                    );
+                    // The file is not copied, but still counts towards the progress
+                    progress::bar().inc(1 + file.size());
+                    false
+                }
+            }
+        })
+        .collect()
 }
 
-/// Compares the given file and copies the src file over if it differs from the dest file
+/// Compares the given file in `src` and `dest` by hashing both
 ///
 /// # Arguments
 /// * `file_to_compare`: file to compare
@@ -261,36 +1439,86 @@ where
 /// * `dest`: base directory of the files to copy to, such that `dest + file.path()`
 /// is the absolute path of the destination file
 /// * `flags`: set for Flag's
-fn compare_and_copy_file<S>(file_to_compare: &S, src: &str, dest: &str, flags: Flag)
+/// * `hashes`: store to record the hash of the source file in
+///
+/// # Returns
+/// True if the src file has to be copied over the dest file
+fn differs<S>(file_to_compare: &S, src: &Path, dest: &Path, flags: Flag, hashes: &HashStore) -> bool
 where
     S: FileOps,
 {
-    if flags.contains(Flag::SECURE) {
-        let src_file_hash_secure = hash_file_secure(file_to_compare, &src);
-
-        if src_file_hash_secure.is_none() {
-            copy_file(file_to_compare, &src, &dest);
-            return;
-        }
+    let src_file: PathBuf = [&PathBuf::from(&src), file_to_compare.path()]
+        .iter()
+        .collect();
+    let dest_file: PathBuf = [&PathBuf::from(&dest), file_to_compare.path()]
+        .iter()
+        .collect();
 
-        let dest_file_hash_secure = hash_file_secure(file_to_compare, &dest);
+    // With --xattrs, a file whose attributes alone changed is updated too
+    if flags.contains(Flag::XATTRS) && xattrs::differ(&src_file, &dest_file) {
+        return true;
+    }
+    // So is a file whose Windows attributes or alternate data streams alone changed
+    if attributes::differ(&src_file, &dest_file) {
+        return true;
+    }
 
-        if src_file_hash_secure != dest_file_hash_secure {
-            copy_file(file_to_compare, &src, &dest);
+    // Files whose sizes differ need not be hashed, the source is hashed while it is copied
+    // A transformed copy is compared with the output of its command instead, whatever its size
+    let command = transform::command_for(&src_file);
+    if let (Ok(src_metadata), None) = (fs::metadata(&src_file), &command) {
+        match fs::metadata(&dest_file) {
+            Ok(dest_metadata) if dest_metadata.len() == src_metadata.len() => {
+                // Like rsync, files of the same size and modification time are taken as the same,
+                // unless they are compared by their contents with --checksum, or --checksum-under
+                // for small files
+                if !flags.contains(Flag::CHECKSUM)
+                    && src_metadata.len() >= checksum_under()
+                    && is_same_modified(&src_metadata, &dest_metadata)
+                {
+                    return false;
+                }
+            }
+            _ => return true,
         }
-    } else {
-        let src_file_hash = hash_file(file_to_compare, &src);
+    }
 
-        if src_file_hash.is_none() {
-            copy_file(file_to_compare, &src, &dest);
-            return;
+    // Files compared by their contents do not trust their sizes and modification times, so neither
+    // do they trust the hashes cached for them
+    let checksum = flags.contains(Flag::CHECKSUM) || file_to_compare.size() < checksum_under();
+    let hash = |location: &Path| {
+        if checksum {
+            hash_file_with_flags(file_to_compare, location, flags)
+        } else {
+            hash_file_cached(file_to_compare, location, flags)
         }
+    };
+
+    let src_file_hash = match hashes
+        .get(file_to_compare.path())
+        .or_else(|| match &command {
+            Some(command) => hash_transformed(command, &src_file, flags),
+            None => hash(src),
+        }) {
+        Some(hash) => hash,
+        None => return true,
+    };
+
+    let dest_file_hash = hash(dest);
+    let differs = Some(&src_file_hash) != dest_file_hash.as_ref();
+
+    // Once copied, the dest file has the same contents as the src file
+    hashes.insert(file_to_compare.path().clone(), src_file_hash);
+    differs
+}
 
-        let dest_file_hash = hash_file(file_to_compare, &dest);
-
-        if src_file_hash != dest_file_hash {
-            copy_file(file_to_compare, &src, &dest);
+/// Checks whether two files were last modified at the same time, within the modify window
+fn is_same_modified(src_metadata: &fs::Metadata, dest_metadata: &fs::Metadata) -> bool {
+    match (src_metadata.modified(), dest_metadata.modified()) {
+        (Ok(src_modified), Ok(dest_modified)) => {
+            is_within(src_modified, dest_modified, modify_window())
         }
+        _ => false,
     }
 }
 
@@ -302,15 +1530,120 @@ where
 /// `files_to_copy`, `src + file.path()` is the absolute path of the source file
 /// * `dest`: base directory of the files to copy to, such that for all `file` in
 /// `files_to_copy`, `dest + file.path()` is the absolute path of the destination file
-pub fn copy_files<'a, T, S>(files_to_copy: T, src: &str, dest: &str)
+/// * `flags`: set for Flag's
+///
+/// # Returns
+/// The number of files that could not be copied
+pub fn copy_files<'a, T, S>(files_to_copy: T, src: &Path, dest: &Path, flags: Flag) -> usize
 where
     T: ParallelIterator<Item = &'a S>,
     S: FileOps + Sync + 'a,
 {
-    files_to_copy.for_each(|file| {
-        copy_file(file, &src, &dest);
-        PROGRESS_BAR.inc(1);
-    });
+    let (large, small) = split_by_tier(files_to_copy);
+    let failed = AtomicUsize::new(0);
+    let copy = |files: Vec<&S>| {
+        files.into_par_iter().for_each(|file| {
+            if copy_file(file, src, dest, flags).is_err() {
+                failed.fetch_add(1, Ordering::Relaxed);
+            }
+            progress::bar().inc(1);
+        })
+    };
+    tiers::join(|| copy(large), || copy(small));
+    failed.into_inner()
+}
+
+/// Copies all given files from `src` to `dest` in parallel like `copy_files`, hashing every file
+/// while it is copied so that its hash is known without reading it again
+///
+/// # Arguments
+/// * `files_to_copy`: files to copy
+/// * `src`: base directory of the files to copy from, like for `copy_files`
+/// * `dest`: base directory of the files to copy to, like for `copy_files`
+/// * `flags`: set for Flag's, of which `SECURE` selects the cryptographic hash function
+/// * `hashes`: store to record the hashes of the copied files in
+///
+/// # Returns
+/// The number of files that could not be copied
+pub fn copy_files_hashed<'a, T, S>(
+    files_to_copy: T,
+    src: &Path,
+    dest: &Path,
+    flags: Flag,
+    hashes: &HashStore,
+) -> usize
+where
+    T: ParallelIterator<Item = &'a S>,
+    S: FileOps + Sync + 'a,
+{
+    let (large, small) = split_by_tier(files_to_copy);
+    let failed = AtomicUsize::new(0);
+    let copy = |files: Vec<&S>| {
+        files.into_par_iter().for_each(|file| {
+            if copy_file_hashed(file, src, dest, flags, hashes).is_err() {
+                failed.fetch_add(1, Ordering::Relaxed);
+            }
+            progress::bar().inc(1);
+        })
+    };
+    tiers::join(|| copy(large), || copy(small));
+    failed.into_inner()
+}
+
+/// Splits `files` into the large files and the small files, as decided by `tiers::is_large`
+fn split_by_tier<'a, T, S>(files: T) -> (Vec<&'a S>, Vec<&'a S>)
+where
+    T: ParallelIterator<Item = &'a S>,
+    S: FileOps + Sync + 'a,
+{
+    // Files are pushed one by one rather than collected, since collecting asks hashbrown's
+    // parallel iterators for a size hint, which overflows in debug builds
+    files
+        .fold(
+            || (Vec::new(), Vec::new()),
+            |(mut large, mut small), file| {
+                if tiers::is_large(file.size()) {
+                    large.push(file);
+                } else {
+                    small.push(file);
+                }
+                (large, small)
+            },
+        )
+        .reduce(
+            || (Vec::new(), Vec::new()),
+            |(mut large, mut small), (more_large, more_small)| {
+                large.extend(more_large);
+                small.extend(more_small);
+                (large, small)
+            },
+        )
+}
+
+/// Copies a single file from `src` to `dest`, recording its hash in `hashes` if it was computed
+/// while copying
+///
+/// # Errors
+/// This function will return an error if the file could not be copied
+fn copy_file_hashed<S>(
+    file_to_copy: &S,
+    src: &Path,
+    dest: &Path,
+    flags: Flag,
+    hashes: &HashStore,
+) -> Result<(), io::Error>
+where
+    S: FileOps,
+{
+    let src_file = [&PathBuf::from(&src), file_to_copy.path()].iter().collect();
+    let dest_file = [&PathBuf::from(&dest), file_to_copy.path()]
+        .iter()
+        .collect();
+
+    if let Some(hash) = file_to_copy.copy_hashed(&src_file, &dest_file, flags)? {
+        hashes.insert(file_to_copy.path().clone(), hash);
+    }
+    Ok(())
 }
 
 /// Copies a single file from `src` to `dest`
@@ -321,7 +1654,11 @@ where
 /// is the absolute path of the source file
 /// * `dest`: base directory of the files to copy to, such that `dest + file.path()`
 /// is the absolute path of the destination file
-fn copy_file<S>(file_to_copy: &S, src: &str, dest: &str)
+/// * `flags`: set for Flag's
+///
+/// # Errors
+/// This function will return an error if the file could not be copied
+fn copy_file<S>(file_to_copy: &S, src: &Path, dest: &Path, flags: Flag) -> Result<(), io::Error>
 where
     S: FileOps,
 {
@@ -330,7 +1667,7 @@ where
         .iter()
         .collect();
 
-    file_to_copy.copy(&src_file, &dest_file);
+    file_to_copy.copy(&src_file, &dest_file, flags)
 }
 
 /// Deletes all given files in parallel
@@ -341,16 +1678,66 @@ where
 /// `files_to_delete`: files to delete
 /// * `location`: base directory of the files to delete, such that for all `file` in
 /// `files_to_delete`, `location + file.path()` is the absolute path of the file
-pub fn delete_files<'a, T, S>(files_to_delete: T, location: &str)
+///
+/// # Returns
+/// The number of files that could not be deleted
+pub fn delete_files<'a, T, S>(files_to_delete: T, location: &Path) -> usize
 where
     T: ParallelIterator<Item = &'a S>,
     S: FileOps + Sync + 'a,
 {
-    files_to_delete.for_each(|file| {
-        let path = [&PathBuf::from(&location), file.path()].iter().collect();
-        file.remove(&path);
-        PROGRESS_BAR.inc(1);
-    });
+    files_to_delete
+        .filter(|file| {
+            let path = [&PathBuf::from(&location), file.path()].iter().collect();
+            let result = file.remove(&path);
+            progress::bar().inc(1);
+            result.is_err()
+        })
+        .count()
+}
+
+/// Deletes all given files from `src` in parallel, but only once their copy in `dest` is verified
+/// to hold the same contents, so that no file is lost to a failed or incomplete copy
+///
+/// # Arguments
+/// * `files_to_remove`: files that were copied
+/// * `src`: base directory of the files to delete, such that for all `file` in
+/// `files_to_remove`, `src + file.path()` is the absolute path of the source file
+/// * `dest`: base directory of the copies, such that for all `file` in
+/// `files_to_remove`, `dest + file.path()` is the absolute path of the copy
+/// * `flags`: set for Flag's, of which `SECURE` selects the cryptographic hash function
+///
+/// # Returns
+/// The number of source files that were kept because they could not be verified or deleted
+pub fn remove_source_files<'a, T, S>(
+    files_to_remove: T,
+    src: &Path,
+    dest: &Path,
+    flags: Flag,
+) -> usize
+where
+    T: ParallelIterator<Item = &'a S>,
+    S: FileOps + Sync + 'a,
+{
+    files_to_remove
+        .filter(|&file| {
+            progress::bar().inc(1);
+            let src_file: PathBuf = [&PathBuf::from(&src), file.path()].iter().collect();
+            let src_file_hash = hash_file_with_flags(file, src, flags);
+            if src_file_hash.is_none() || src_file_hash != hash_file_with_flags(file, dest, flags) {
+                events::record(
+                    Action::Error,
+                    &src_file,
+                    format_args!(
+                        "Error -- Keeping source file {:?}, its copy could not be verified",
+                        src_file
+                    ),
+                );
+                return true;
+            }
+            file.remove(&src_file).is_err()
+        })
+        .count()
 }
 
 /// Deletes all given files sequentially
@@ -361,16 +1748,23 @@ where
 /// * `files_to_delete`: files to delete, or sorted empty directories
 /// * `location`: base directory of the files to delete, such that for all `file` in
 /// `files_to_delete`, `location + file.path()` is the absolute path of the file
-pub fn delete_files_sequential<'a, T, S>(files_to_delete: T, location: &str)
+///
+/// # Returns
+/// The number of files that could not be deleted
+pub fn delete_files_sequential<'a, T, S>(files_to_delete: T, location: &Path) -> usize
 where
     T: IntoIterator<Item = &'a S>,
     S: FileOps + 'a,
 {
+    let mut failed = 0;
     for file in files_to_delete {
         let path = [&PathBuf::from(&location), file.path()].iter().collect();
-        file.remove(&path);
-        PROGRESS_BAR.inc(1);
+        if file.remove(&path).is_err() {
+            failed += 1;
+        }
+        progress::bar().inc(1);
     }
+    failed
 }
 
 /// Sorts (unstable) file paths in descending order by number of components, in parallel
@@ -409,7 +1803,7 @@ where
 /// # Returns
 /// * Some: The hash of the given file
 /// * Err: If the given file cannot be hashed
-pub fn hash_file<S>(file_to_hash: &S, location: &str) -> Option<u64>
+pub fn hash_file<S>(file_to_hash: &S, location: &Path) -> Option<u64>
 where
     S: FileOps,
 {
@@ -417,8 +1811,11 @@ where
         .iter()
         .collect();
 
-    match fs::read(file) {
-        Ok(contents) => Some(seahash::hash(&contents)),
+    let mut contents = Vec::new();
+    match fs::File::open(file)
+        .and_then(|opened| throttle::Reader(opened).read_to_end(&mut contents))
+    {
+        Ok(_) => Some(seahash::hash(&contents)),
         Err(_) => None,
     }
 }
@@ -433,7 +1830,7 @@ where
 /// # Returns
 /// * Some: The hash of the given file
 /// * Err: If the given file cannot be hashed
-pub fn hash_file_secure<S>(file_to_hash: &S, location: &str) -> Option<Vec<u8>>
+pub fn hash_file_secure<S>(file_to_hash: &S, location: &Path) -> Option<Vec<u8>>
 where
     S: FileOps,
 {
@@ -442,22 +1839,336 @@ where
         .collect();
 
     match &mut fs::File::open(&file) {
-        Ok(file) => {
-            let mut hasher = Blake2b::new();
-
-            match io::copy(file, &mut hasher) {
-                Ok(_) => Some(hasher.finalize().to_vec()),
-                Err(e) => {
-                    error!("Error -- Hashing: {:?}: {}", file_to_hash.path(), e);
-                    None
-                }
+        Ok(opened) => match hash_chunks(&file, opened, HashProgress::current().as_ref()) {
+            Ok(hash) => Some(hash),
+            Err(e) => {
+                events::record(
+                    Action::Error,
+                    &file,
+                    format_args!("Error -- Hashing: {:?}: {}", file_to_hash.path(), e),
+                );
+                None
             }
+        },
+        Err(ref e) if vanished::is_vanished(&file, e) => {
+            vanished::record(&file);
+            None
         }
         Err(e) => {
-            error!("Error -- Opening File: {:?}: {}", file_to_hash.path(), e);
+            events::record(
+                Action::Error,
+                &file,
+                format_args!("Error -- Opening File: {:?}: {}", file_to_hash.path(), e),
+            );
+            None
+        }
+    }
+}
+
+/// Size in bytes of the chunks that are hashed separately by the secure hash function
+#[cfg(not(test))]
+const HASH_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
+#[cfg(test)]
+const HASH_CHUNK_SIZE: u64 = 4096;
+
+/// Number of chunks hashed between two saves of the progress of hashing a file
+const HASH_CHECKPOINT_CHUNKS: usize = 64;
+
+/// Hashes `file` with BLAKE2b chunk by chunk, and then hashes the digests of all chunks,
+/// saving the digests to `progress` along the way so that an interrupted hash can be resumed
+///
+/// # Arguments
+/// * `path`: path of the file
+/// * `file`: the opened file
+/// * `progress`: progress of hashing large files, if it is kept
+///
+/// # Returns
+/// The hash of the digests of all chunks
+///
+/// # Errors
+/// This function will return an error if `file` cannot be read
+fn hash_chunks(
+    path: &Path,
+    file: &mut fs::File,
+    progress: Option<&HashProgress>,
+) -> Result<Vec<u8>, io::Error> {
+    let record = FileRecord::from_metadata(&file.metadata()?);
+    let mut digests = progress.map_or_else(Vec::new, |progress| progress.load(path, &record));
+    if !digests.is_empty() {
+        info!("Resuming hashing {:?} after {} chunks", path, digests.len());
+        file.seek(SeekFrom::Start(digests.len() as u64 * HASH_CHUNK_SIZE))?;
+    }
+
+    loop {
+        let mut hasher = Blake2b::new();
+        let read = io::copy(
+            &mut throttle::Reader(file.take(HASH_CHUNK_SIZE)),
+            &mut hasher,
+        )?;
+        if read == 0 && !digests.is_empty() {
+            break;
+        }
+        digests.push(hasher.finalize().to_vec());
+        if read < HASH_CHUNK_SIZE {
+            break;
+        }
+
+        if let Some(progress) = progress {
+            if digests.len().is_multiple_of(HASH_CHECKPOINT_CHUNKS) {
+                progress.save(path, &record, &digests);
+            }
+        }
+    }
+
+    if let Some(progress) = progress {
+        if digests.len() >= HASH_CHECKPOINT_CHUNKS {
+            progress.clear(path);
+        }
+    }
+
+    Ok(hash_digests(&digests))
+}
+
+/// Hashes the digests of all chunks of a file with BLAKE2b, in order
+fn hash_digests(digests: &[Vec<u8>]) -> Vec<u8> {
+    let mut hasher = Blake2b::new();
+    for digest in digests {
+        hasher.update(digest);
+    }
+    hasher.finalize().to_vec()
+}
+
+/// Checks whether the given metadata belongs to a dataless file, i.e. a file whose
+/// contents have been evicted to iCloud and must be downloaded before they can be read
+///
+/// # Arguments
+/// * `metadata`: metadata of the file to check
+///
+/// # Returns
+/// True if the file is dataless
+#[cfg(target_os = "macos")]
+fn is_dataless(metadata: &fs::Metadata) -> bool {
+    use std::os::macos::fs::MetadataExt;
+
+    const SF_DATALESS: u32 = 0x4000_0000;
+
+    metadata.st_flags() & SF_DATALESS != 0
+}
+
+#[cfg(not(target_os = "macos"))]
+fn is_dataless(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+/// Checks whether a directory entry is one of LuminS' own artifacts, which are never part of
+/// what is copied, hashed or deleted, wherever they are found
+///
+/// # Arguments
+/// * `file_name`: name of the entry
+///
+/// # Returns
+/// True if the entry is the state of a destination or a snapshot of a source
+fn is_artifact(file_name: &OsStr) -> bool {
+    file_name == STATE_DIR
+        || file_name
+            .to_str()
+            .is_some_and(|name| name.trim_start_matches('.').starts_with(SNAPSHOT_PREFIX))
+}
+
+/// State shared by every level of a traversal started by `get_all_files`
+struct Traversal<'a> {
+    /// Directory the traversal started from, which all paths are made relative to
+    base: &'a Path,
+    /// Absolute path of `base`
+    root: PathBuf,
+    flags: Flag,
+    mounts: MountTable,
+    /// Absolute paths of mount points to skip
+    exclude_mounts: HashSet<PathBuf>,
+    /// Device of `root`, if directories on other devices are skipped
+    root_device: Option<u64>,
+    /// Relative paths of the only subtrees to traverse, or empty to traverse everything
+    only: &'a [PathBuf],
+    /// State of the previous run, used to skip unchanged directories and to observe this run
+    state: Option<&'a State>,
+    /// Patterns of the paths to skip
+    filter: &'a Filter,
+    /// Number of entries after which the traversal is stopped
+    max_files: Option<u64>,
+    /// Depth below which the traversal is stopped
+    max_depth: Option<usize>,
+    /// Size in bytes below which files are skipped
+    min_size: Option<u64>,
+    /// Size in bytes above which files are skipped
+    max_size: Option<u64>,
+    /// Number of entries found so far
+    entries: Cell<u64>,
+    /// Whether a limit was exceeded, which stops the whole traversal
+    stopped: Cell<bool>,
+}
+
+impl Traversal<'_> {
+    /// Checks whether the entry at `relative_path` lies within the subtrees to traverse
+    ///
+    /// # Arguments
+    /// * `relative_path`: path of the entry relative to `base`
+    /// * `is_dir`: whether the entry is a directory, which is also traversed if it leads to a subtree
+    ///
+    /// # Returns
+    /// True if the entry should be traversed
+    fn in_scope(&self, relative_path: &Path, is_dir: bool) -> bool {
+        self.only.is_empty()
+            || self.only.iter().any(|only| {
+                relative_path.starts_with(only) || (is_dir && only.starts_with(relative_path))
+            })
+    }
+
+    /// Checks whether a file of `size` bytes is outside of the sizes given with --min-size and
+    /// --max-size
+    ///
+    /// # Returns
+    /// * Some: A description of why the file is excluded
+    /// * None: If the file should be traversed
+    fn excluded_size(&self, size: u64) -> Option<&'static str> {
+        if self.min_size.is_some_and(|min_size| size < min_size) {
+            Some("smaller than --min-size")
+        } else if self.max_size.is_some_and(|max_size| size > max_size) {
+            Some("larger than --max-size")
+        } else {
             None
         }
     }
+
+    /// Checks whether the directory at `absolute_path` is a mount point that must be skipped
+    ///
+    /// # Arguments
+    /// * `absolute_path`: absolute path of the directory to check
+    /// * `metadata`: metadata of the directory to check
+    ///
+    /// # Returns
+    /// * Some: A description of why the mount point is excluded
+    /// * None: If the directory should be traversed
+    fn excluded_mount(&self, absolute_path: &Path, metadata: &fs::Metadata) -> Option<String> {
+        if self.exclude_mounts.contains(absolute_path) {
+            return Some(String::from("excluded"));
+        }
+
+        if let Some(root_device) = self.root_device {
+            if device(metadata).is_some_and(|device| device != root_device) {
+                return Some(String::from("another filesystem"));
+            }
+        }
+
+        if self.flags.contains(Flag::EXCLUDE_NETWORK_MOUNTS) {
+            if let Some(fs_type) = self.mounts.network_mountpoint(absolute_path) {
+                return Some(format!("network filesystem {}", fs_type));
+            }
+        }
+
+        None
+    }
+
+    /// Counts the entry at `relative_path` and checks it against the limits of the traversal
+    ///
+    /// # Arguments
+    /// * `relative_path`: path of the entry relative to `base`
+    ///
+    /// # Errors
+    /// This function will return an error, which stops the traversal, if a limit is exceeded
+    fn check_limits(&self, relative_path: &Path) -> Result<(), io::Error> {
+        self.entries.set(self.entries.get() + 1);
+
+        let reason = match (self.max_files, self.max_depth) {
+            (Some(max_files), _) if self.entries.get() > max_files => {
+                format!("more than {} entries were found (--max-files)", max_files)
+            }
+            (_, Some(max_depth)) if relative_path.components().count() > max_depth => format!(
+                "it is more than {} levels deep (--max-depth-abort), \
+                 which suggests a recursive bind mount or a symlink loop",
+                max_depth
+            ),
+            _ => return Ok(()),
+        };
+
+        self.stopped.set(true);
+        Err(io::Error::other(format!(
+            "Scan stopped at {:?}: {}",
+            Path::new(self.base).join(relative_path),
+            reason
+        )))
+    }
+}
+
+/// Gets the device that the entry of `metadata` is on
+#[cfg(target_family = "unix")]
+fn device(metadata: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some(metadata.dev())
+}
+
+#[cfg(target_family = "windows")]
+fn device(_metadata: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// Generates a hash of the given file, using the hash function selected by `flags`
+///
+/// # Arguments
+/// * `file_to_hash`: file object to hash
+/// * `location`: base directory of the file to hash, such that
+/// `location + file_to_hash.path()` is the absolute path of the file
+/// * `flags`: set for Flag's, of which `SECURE` selects the cryptographic hash function
+///
+/// # Returns
+/// * Some: The hash of the given file
+/// * None: If the given file cannot be hashed
+pub fn hash_file_with_flags<S>(file_to_hash: &S, location: &Path, flags: Flag) -> Option<FileHash>
+where
+    S: FileOps,
+{
+    if flags.contains(Flag::SECURE) {
+        hash_file_secure(file_to_hash, location).map(FileHash::Secure)
+    } else {
+        hash_file(file_to_hash, location).map(FileHash::Fast)
+    }
+}
+
+/// Generates a hash of the given file like `hash_file_with_flags`, reusing the hash cached by an
+/// earlier run if the file did not change since, and caching the hash otherwise
+///
+/// Hashes that verify copies must not use this, nor comparisons with --checksum, since a cached
+/// hash cannot tell a file apart from a corrupted copy with the same size and modification time.
+///
+/// # Arguments
+/// * `file_to_hash`: file object to hash
+/// * `location`: base directory of the file to hash, like for `hash_file_with_flags`
+/// * `flags`: set for Flag's, of which `SECURE` selects the cryptographic hash function
+///
+/// # Returns
+/// * Some: The hash of the given file
+/// * None: If the given file cannot be hashed
+pub fn hash_file_cached<S>(file_to_hash: &S, location: &Path, flags: Flag) -> Option<FileHash>
+where
+    S: FileOps,
+{
+    let cache = match CachedHashes::current() {
+        Some(cache) => cache,
+        None => return hash_file_with_flags(file_to_hash, location, flags),
+    };
+    let path = location.join(file_to_hash.path());
+    let metadata = match fs::metadata(&path) {
+        Ok(metadata) => metadata,
+        Err(_) => return hash_file_with_flags(file_to_hash, location, flags),
+    };
+    if let Some(hash) = cache.get(&path, &metadata, flags) {
+        return Some(hash);
+    }
+
+    let hash = hash_file_with_flags(file_to_hash, location, flags)?;
+    cache.insert(&path, &metadata, &hash);
+    Some(hash)
 }
 
 /// Recursively traverses a directory and all its subdirectories and returns
@@ -465,78 +2176,241 @@ where
 ///
 /// # Arguments
 /// * `src`: directory to traverse
+/// * `options`: command line options
 ///
 /// # Returns
 /// * Ok: A `FileSets` containing a set of files a set of directories
 /// * Error: If `src` is an invalid directory
-pub fn get_all_files(src: &str) -> Result<FileSets, io::Error> {
-    get_all_files_helper(&PathBuf::from(&src), &src)
+pub fn get_all_files(src: &Path, options: &Options) -> Result<FileSets, io::Error> {
+    get_all_files_with_state(src, options, None)
+}
+
+/// Recursively traverses a directory like `get_all_files`, skipping the subdirectories
+/// that `state` considers unchanged and observing the directories traversed into `state`
+///
+/// # Arguments
+/// * `src`: directory to traverse
+/// * `options`: command line options
+/// * `state`: state of the previous run from `src`
+///
+/// # Returns
+/// * Ok: A `FileSets` containing a set of files a set of directories,
+/// in which the unchanged directories are skipped
+/// * Error: If `src` is an invalid directory
+pub fn get_all_files_with_state(
+    src: &Path,
+    options: &Options,
+    state: Option<&State>,
+) -> Result<FileSets, io::Error> {
+    let root = paths::canonicalize(src)?;
+    let root_device = if options.flags.contains(Flag::ONE_FILE_SYSTEM) {
+        device(&fs::metadata(&root)?)
+    } else {
+        None
+    };
+    let traversal = Traversal {
+        base: src,
+        root,
+        flags: options.flags,
+        mounts: MountTable::load(),
+        exclude_mounts: options
+            .exclude_mounts
+            .iter()
+            .filter_map(|path| paths::canonicalize(path).ok())
+            .collect(),
+        root_device,
+        only: &options.only,
+        state,
+        filter: &options.filter,
+        max_files: options.max_files,
+        max_depth: options.max_depth,
+        min_size: options.min_size,
+        max_size: options.max_size,
+        entries: Cell::new(0),
+        stopped: Cell::new(false),
+    };
+
+    get_all_files_helper(&PathBuf::from(&src), &traversal)
 }
 
 /// Recursive helper for `get_all_files`
 ///
 /// # Arguments
 /// * `src`: directory to traverse
-/// * `base`: directory to traverse, used for recursive calls
+/// * `traversal`: state of the traversal, used for recursive calls
 ///
 /// # Returns
 /// * Ok: A `FileSets` containing a set of files a set of directories
 /// * Error: If `src` is an invalid directory
-fn get_all_files_helper(src: &PathBuf, base: &str) -> Result<FileSets, io::Error> {
+fn get_all_files_helper(src: &PathBuf, traversal: &Traversal) -> Result<FileSets, io::Error> {
+    // The state must be taken before reading the entries, so that changes made while
+    // reading are noticed by the next run
+    let dir_state = traversal.state.and_then(|_| {
+        fs::metadata(src)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    });
     let dir = src.read_dir()?;
+    let mut children = 0;
 
     let mut files = HashSet::new();
     let mut dirs = HashSet::new();
     let mut symlinks = HashSet::new();
+    let mut skipped = HashSet::new();
 
     for file in dir {
-        if file.is_err() {
-            error!("{}", file.err().unwrap());
-            continue;
-        }
+        children += 1;
 
-        let file = file.unwrap();
+        let file = match file {
+            Ok(file) => file,
+            Err(e) => {
+                events::record(Action::Error, src, format_args!("{}", e));
+                continue;
+            }
+        };
         let metadata = file.metadata();
 
-        if metadata.is_err() {
-            error!(
-                "Error -- Reading metadata of {:?} {}",
-                file.path(),
-                metadata.err().unwrap()
-            );
-            continue;
-        }
-
-        let metadata = metadata.unwrap();
+        let metadata = match metadata {
+            Ok(metadata) => metadata,
+            Err(ref e) if vanished::is_vanished(&file.path(), e) => {
+                vanished::record(&file.path());
+                continue;
+            }
+            Err(e) => {
+                events::record(
+                    Action::Error,
+                    &file.path(),
+                    format_args!("Error -- Reading metadata of {:?} {}", file.path(), e),
+                );
+                continue;
+            }
+        };
 
         let path = file.path();
         // This is safe to unwrap, since `get_all_files` always calls this helper
         // with `base` equal to `src`
-        let relative_path = path.strip_prefix(base).unwrap();
+        let relative_path = path.strip_prefix(traversal.base).unwrap();
+
+        if !traversal.in_scope(relative_path, metadata.is_dir())
+            || traversal.filter.excludes(relative_path, metadata.is_dir())
+        {
+            continue;
+        }
+        traversal.check_limits(relative_path)?;
+
+        if is_artifact(&file.file_name()) {
+            events::record(
+                Action::Skip,
+                &path,
+                format_args!("Skipping LuminS artifact {:?}", path),
+            );
+            skipped.insert(relative_path.to_path_buf());
+            continue;
+        }
+
+        if is_dataless(&metadata) {
+            if traversal.flags.contains(Flag::SKIP_DATALESS) {
+                events::record(
+                    Action::Skip,
+                    &path,
+                    format_args!("Skipping dataless {:?}", path),
+                );
+                skipped.insert(relative_path.to_path_buf());
+                continue;
+            }
+            info!("Materializing dataless {:?}", path);
+        }
 
         if metadata.is_dir() {
+            let absolute_path = traversal.root.join(relative_path);
+
+            // Never descend into /proc, /sys and friends, they only contain generated garbage
+            if !traversal.flags.contains(Flag::ALLOW_PSEUDO_FS) {
+                if let Some(fs_type) = traversal.mounts.pseudo_mountpoint(&absolute_path) {
+                    // Shown whatever the log level, since the source is not copied as a whole
+                    eprintln!(
+                        "Warning -- Skipping pseudo-filesystem {:?} ({})",
+                        path, fs_type
+                    );
+                    events::record(
+                        Action::Skip,
+                        &path,
+                        format_args!("Skipping pseudo-filesystem {:?} ({})", path, fs_type),
+                    );
+                    skipped.insert(relative_path.to_path_buf());
+                    continue;
+                }
+            }
+
+            if let Some(reason) = traversal.excluded_mount(&absolute_path, &metadata) {
+                events::record(
+                    Action::Skip,
+                    &path,
+                    format_args!("Skipping mount {:?} ({})", path, reason),
+                );
+                skipped.insert(relative_path.to_path_buf());
+                continue;
+            }
+
+            if let Some(state) = traversal.state {
+                if state.is_unchanged(relative_path) {
+                    events::record(
+                        Action::Skip,
+                        &path,
+                        format_args!("Skipping unchanged dir {:?}", path),
+                    );
+                    skipped.insert(relative_path.to_path_buf());
+                    continue;
+                }
+            }
+
             dirs.insert(Dir {
                 path: relative_path.to_path_buf(),
             });
+            progress::bar().inc_length(1);
 
             // Recursively call `get_all_files_helper` on the subdirectory
-            match get_all_files_helper(&file.path(), base) {
+            match get_all_files_helper(&file.path(), traversal) {
                 Ok(file_sets) => {
                     // Add subdirectory subdirectories and files to sets
                     files.extend(file_sets.files);
                     dirs.extend(file_sets.dirs);
                     symlinks.extend(file_sets.symlinks);
+                    skipped.extend(file_sets.skipped);
+                }
+                Err(e) if traversal.stopped.get() => return Err(e),
+                Err(ref e) if vanished::is_vanished(&path, e) => {
+                    vanished::record(&path);
+                    continue;
                 }
                 Err(e) => {
-                    error!("Error - Retrieving files: {}", e);
+                    events::record(
+                        Action::Error,
+                        &path,
+                        format_args!("Error - Retrieving files: {}", e),
+                    );
                     continue;
                 }
             }
         } else if metadata.is_file() {
+            // Files skipped for their size still exist, so their copies must not be deleted
+            if let Some(reason) = traversal.excluded_size(metadata.len()) {
+                events::record(
+                    Action::Skip,
+                    &path,
+                    format_args!("Skipping {:?}, it is {}", path, reason),
+                );
+                skipped.insert(relative_path.to_path_buf());
+                continue;
+            }
+            if let Some(state) = traversal.state {
+                state.observe_file(relative_path, FileRecord::from_metadata(&metadata));
+            }
             files.insert(File {
                 path: relative_path.to_path_buf(),
                 size: metadata.len(),
             });
+            progress::bar().inc_length(1);
         } else {
             // If not a file nor dir, must be a symlink
             match fs::read_link(&path) {
@@ -545,16 +2419,36 @@ fn get_all_files_helper(src: &PathBuf, base: &str) -> Result<FileSets, io::Error
                         path: relative_path.to_path_buf(),
                         target,
                     });
+                    progress::bar().inc_length(1);
+                }
+                Err(ref e) if vanished::is_vanished(&path, e) => {
+                    vanished::record(&path);
+                    continue;
                 }
                 Err(e) => {
-                    error!("Error - Reading symlink: {}", e);
+                    events::record(
+                        Action::Error,
+                        &path,
+                        format_args!("Error - Reading symlink: {}", e),
+                    );
                     continue;
                 }
             }
         }
     }
 
-    Ok(FileSets::with(files, dirs, symlinks))
+    if let (Some(state), Some(modified)) = (traversal.state, dir_state) {
+        // This is safe to unwrap, since `src` is always `base` or inside of it
+        let relative_path = src.strip_prefix(traversal.base).unwrap();
+        state.observe_dir(relative_path, DirState { modified, children });
+    }
+
+    Ok(FileSets {
+        files,
+        dirs,
+        symlinks,
+        skipped,
+    })
 }
 
 ///////////////////////////////////////////////////////////////////////////////////////////////////
@@ -586,6 +2480,17 @@ mod test_file_ops {
         )
     }
 
+    #[test]
+    fn skipped_paths() {
+        let mut file_sets = FileSets::with(HashSet::new(), HashSet::new(), HashSet::new());
+        file_sets.skipped.insert(PathBuf::from("a/b"));
+
+        assert_eq!(file_sets.is_skipped(Path::new("a/b")), true);
+        assert_eq!(file_sets.is_skipped(Path::new("a/b/c.txt")), true);
+        assert_eq!(file_sets.is_skipped(Path::new("a")), false);
+        assert_eq!(file_sets.is_skipped(Path::new("a/bc")), false);
+    }
+
     #[test]
     fn create_symlink() {
         assert_eq!(
@@ -605,13 +2510,19 @@ mod test_get_all_files {
 
     #[test]
     fn invalid_dir() {
-        assert_eq!(get_all_files("/?").is_err(), true);
+        assert_eq!(
+            get_all_files(Path::new("/?"), &Options::default()).is_err(),
+            true
+        );
     }
 
     #[cfg(target_family = "unix")]
     #[test]
     fn dir_insufficient_permissions() {
-        assert_eq!(get_all_files("/root").is_err(), true);
+        assert_eq!(
+            get_all_files(Path::new("/root"), &Options::default()).is_err(),
+            true
+        );
     }
 
     #[test]
@@ -620,7 +2531,7 @@ mod test_get_all_files {
 
         fs::create_dir(TEST_DIR).unwrap();
 
-        let file_sets = get_all_files(TEST_DIR).unwrap();
+        let file_sets = get_all_files(Path::new(TEST_DIR), &Options::default()).unwrap();
 
         assert_eq!(file_sets.files(), &HashSet::new());
         assert_eq!(file_sets.dirs(), &HashSet::new());
@@ -635,7 +2546,7 @@ mod test_get_all_files {
 
         fs::create_dir_all([TEST_DIR, TEST_SUB_DIR].join("/")).unwrap();
 
-        let file_sets = get_all_files(&TEST_DIR).unwrap();
+        let file_sets = get_all_files(Path::new(TEST_DIR), &Options::default()).unwrap();
         let mut dir_set = HashSet::new();
         dir_set.insert(Dir {
             path: PathBuf::from(&TEST_SUB_DIR),
@@ -657,7 +2568,7 @@ mod test_get_all_files {
         fs::File::create([TEST_DIR, TEST_FILE].join("/")).unwrap();
         fs::write([TEST_DIR, TEST_FILE].join("/"), b"1234").unwrap();
 
-        let file_sets = get_all_files(TEST_DIR).unwrap();
+        let file_sets = get_all_files(Path::new(TEST_DIR), &Options::default()).unwrap();
         let mut file_set = HashSet::new();
         file_set.insert(File {
             path: PathBuf::from(TEST_FILE),
@@ -687,7 +2598,7 @@ mod test_get_all_files {
             target: PathBuf::from(TEST_FILE),
         });
 
-        let file_sets = get_all_files(TEST_DIR).unwrap();
+        let file_sets = get_all_files(Path::new(TEST_DIR), &Options::default()).unwrap();
 
         assert_eq!(
             file_sets,
@@ -695,6 +2606,7 @@ mod test_get_all_files {
                 files: HashSet::new(),
                 dirs: HashSet::new(),
                 symlinks: symlink_set,
+                skipped: HashSet::new(),
             }
         );
 
@@ -716,7 +2628,7 @@ mod test_get_all_files {
             fs::write(&path, TEST_DATA[i]).unwrap();
         }
 
-        let file_sets = get_all_files(TEST_DIR).unwrap();
+        let file_sets = get_all_files(Path::new(TEST_DIR), &Options::default()).unwrap();
         let mut file_set = HashSet::new();
         let mut dir_set = HashSet::new();
 
@@ -739,6 +2651,176 @@ mod test_get_all_files {
         fs::remove_dir_all(TEST_DIR).unwrap();
     }
 
+    #[test]
+    fn exclude_mount() {
+        const TEST_DIR: &str = "test_get_all_files_exclude_mount";
+        const SUB_DIR: &str = "mnt";
+        const TEST_FILE: &str = "mnt/file.txt";
+
+        fs::create_dir_all([TEST_DIR, SUB_DIR].join("/")).unwrap();
+        fs::File::create([TEST_DIR, TEST_FILE].join("/")).unwrap();
+
+        let mut options = Options::default();
+        options
+            .exclude_mounts
+            .push(PathBuf::from([TEST_DIR, SUB_DIR].join("/")));
+
+        let file_sets = get_all_files(Path::new(TEST_DIR), &options).unwrap();
+
+        assert_eq!(file_sets.files(), &HashSet::new());
+        assert_eq!(file_sets.dirs(), &HashSet::new());
+        assert_eq!(file_sets.is_skipped(Path::new(TEST_FILE)), true);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn only() {
+        const TEST_DIR: &str = "test_get_all_files_only";
+        const TEST_DIRS: [&str; 3] = ["a/b", "a/c", "d"];
+        const TEST_FILES: [&str; 3] = ["a/b/file.txt", "a/c/file.txt", "d/file.txt"];
+
+        for dir in TEST_DIRS.iter() {
+            fs::create_dir_all([TEST_DIR, dir].join("/")).unwrap();
+        }
+        for file in TEST_FILES.iter() {
+            fs::File::create([TEST_DIR, file].join("/")).unwrap();
+        }
+
+        let mut options = Options::default();
+        options.only.push(PathBuf::from("a/b"));
+
+        let file_sets = get_all_files(Path::new(TEST_DIR), &options).unwrap();
+
+        let mut file_set = HashSet::new();
+        file_set.insert(File::from(TEST_FILES[0], 0));
+        let mut dir_set = HashSet::new();
+        dir_set.insert(Dir::from("a"));
+        dir_set.insert(Dir::from("a/b"));
+
+        assert_eq!(file_sets.files(), &file_set);
+        assert_eq!(file_sets.dirs(), &dir_set);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn sizes() {
+        const TEST_DIR: &str = "test_get_all_files_sizes";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::write([TEST_DIR, "empty.txt"].join("/"), b"").unwrap();
+        fs::write([TEST_DIR, "small.txt"].join("/"), b"small").unwrap();
+        fs::write([TEST_DIR, "large.txt"].join("/"), vec![0; 100]).unwrap();
+
+        let mut options = Options::default();
+        options.min_size = Some(1);
+        options.max_size = Some(99);
+        let file_sets = get_all_files(Path::new(TEST_DIR), &options).unwrap();
+
+        let mut file_set = HashSet::new();
+        file_set.insert(File::from("small.txt", 5));
+        assert_eq!(file_sets.files(), &file_set);
+        assert_eq!(file_sets.is_skipped(Path::new("empty.txt")), true);
+        assert_eq!(file_sets.is_skipped(Path::new("large.txt")), true);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn one_file_system() {
+        const TEST_DIR: &str = "test_get_all_files_one_file_system";
+
+        fs::create_dir_all([TEST_DIR, "dir"].join("/")).unwrap();
+        fs::write([TEST_DIR, "dir/file.txt"].join("/"), b"file").unwrap();
+
+        // Directories on the same filesystem as the directory given are still traversed
+        let file_sets =
+            get_all_files(Path::new(TEST_DIR), &Options::from(Flag::ONE_FILE_SYSTEM)).unwrap();
+        assert_eq!(file_sets.dirs().len(), 1);
+        assert_eq!(file_sets.files().len(), 1);
+        assert_eq!(file_sets.is_skipped(Path::new("dir")), false);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn limits() {
+        const TEST_DIR: &str = "test_get_all_files_limits";
+
+        fs::create_dir_all([TEST_DIR, "a/b/c"].join("/")).unwrap();
+        fs::File::create([TEST_DIR, "a/b/file.txt"].join("/")).unwrap();
+
+        let mut options = Options::default();
+        options.max_files = Some(4);
+        options.max_depth = Some(3);
+        assert_eq!(get_all_files(Path::new(TEST_DIR), &options).is_ok(), true);
+
+        options.max_files = Some(3);
+        assert_eq!(get_all_files(Path::new(TEST_DIR), &options).is_err(), true);
+
+        options.max_files = None;
+        options.max_depth = Some(2);
+        assert_eq!(get_all_files(Path::new(TEST_DIR), &options).is_err(), true);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn artifacts() {
+        const TEST_DIR: &str = "test_get_all_files_artifacts";
+        const TEST_DIRS: [&str; 3] = [".lumins", "a/.lumins", ".lms-snapshot-1-2/a"];
+
+        for dir in TEST_DIRS.iter() {
+            fs::create_dir_all([TEST_DIR, dir].join("/")).unwrap();
+        }
+        fs::File::create([TEST_DIR, "a/file.txt"].join("/")).unwrap();
+        fs::File::create([TEST_DIR, "a/.lumins/state"].join("/")).unwrap();
+
+        let file_sets = get_all_files(Path::new(TEST_DIR), &Options::default()).unwrap();
+
+        let mut file_set = HashSet::new();
+        file_set.insert(File::from("a/file.txt", 0));
+        let mut dir_set = HashSet::new();
+        dir_set.insert(Dir::from("a"));
+
+        assert_eq!(file_sets.files(), &file_set);
+        assert_eq!(file_sets.dirs(), &dir_set);
+
+        // Artifacts are skipped, so that they are never deleted either
+        assert_eq!(file_sets.is_skipped(Path::new("a/.lumins/state")), true);
+        assert_eq!(file_sets.is_skipped(Path::new(".lms-snapshot-1-2")), true);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn filter() {
+        const TEST_DIR: &str = "test_get_all_files_filter";
+
+        fs::create_dir_all([TEST_DIR, "target/debug"].join("/")).unwrap();
+        fs::create_dir_all([TEST_DIR, "src"].join("/")).unwrap();
+        fs::File::create([TEST_DIR, "target/debug/lms"].join("/")).unwrap();
+        fs::File::create([TEST_DIR, "src/main.rs"].join("/")).unwrap();
+        fs::File::create([TEST_DIR, "src/main.rs.tmp"].join("/")).unwrap();
+
+        let options = Options {
+            filter: Filter::new(vec!["target/", "*.tmp"], vec![]),
+            ..Options::default()
+        };
+        let file_sets = get_all_files(Path::new(TEST_DIR), &options).unwrap();
+
+        let mut file_set = HashSet::new();
+        file_set.insert(File::from("src/main.rs", 0));
+        let mut dir_set = HashSet::new();
+        dir_set.insert(Dir::from("src"));
+
+        assert_eq!(file_sets.files(), &file_set);
+        assert_eq!(file_sets.dirs(), &dir_set);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
     #[cfg(target_family = "unix")]
     #[test]
     fn multi_level_insufficient_permissions() {
@@ -761,7 +2843,7 @@ mod test_get_all_files {
             .output()
             .unwrap();
 
-        let file_sets = get_all_files(TEST_DIR).unwrap();
+        let file_sets = get_all_files(Path::new(TEST_DIR), &Options::default()).unwrap();
 
         let mut file_set = HashSet::new();
         file_set.insert(File {
@@ -863,7 +2945,7 @@ mod test_hash_file {
                     path: PathBuf::from("test"),
                     size: 0,
                 },
-                "."
+                Path::new(".")
             ),
             None
         );
@@ -883,14 +2965,14 @@ mod test_hash_file {
                     path: PathBuf::from(TEST_FILE1),
                     size: 0,
                 },
-                "."
+                Path::new(".")
             ),
             hash_file(
                 &File {
                     path: PathBuf::from(TEST_FILE2),
                     size: 0,
                 },
-                "."
+                Path::new(".")
             )
         );
         assert_eq!(
@@ -899,14 +2981,14 @@ mod test_hash_file {
                     path: PathBuf::from(TEST_FILE1),
                     size: 0,
                 },
-                "."
+                Path::new(".")
             ),
             hash_file_secure(
                 &File {
                     path: PathBuf::from(TEST_FILE2),
                     size: 0,
                 },
-                "."
+                Path::new(".")
             )
         );
 
@@ -935,14 +3017,14 @@ mod test_hash_file {
                     path: PathBuf::from(TEST_FILE1),
                     size: 10,
                 },
-                "."
+                Path::new(".")
             ),
             hash_file(
                 &File {
                     path: PathBuf::from(TEST_FILE2),
                     size: 10,
                 },
-                "."
+                Path::new(".")
             )
         );
         assert_eq!(
@@ -951,14 +3033,14 @@ mod test_hash_file {
                     path: PathBuf::from(TEST_FILE1),
                     size: 10,
                 },
-                "."
+                Path::new(".")
             ),
             hash_file_secure(
                 &File {
                     path: PathBuf::from(TEST_FILE2),
                     size: 10,
                 },
-                "."
+                Path::new(".")
             )
         );
 
@@ -973,14 +3055,14 @@ mod test_hash_file {
                     path: PathBuf::from("lumins/file_ops.rs"),
                     size: 0,
                 },
-                "src"
+                Path::new("src")
             ),
             hash_file(
                 &File {
                     path: PathBuf::from("main.rs"),
                     size: 0,
                 },
-                "src"
+                Path::new("src")
             )
         );
         assert_ne!(
@@ -989,17 +3071,94 @@ mod test_hash_file {
                     path: PathBuf::from("lumins/file_ops.rs"),
                     size: 0,
                 },
-                "src"
+                Path::new("src")
             ),
             hash_file_secure(
                 &File {
                     path: PathBuf::from("main.rs"),
                     size: 0,
                 },
-                "src"
+                Path::new("src")
             )
         );
     }
+
+    #[test]
+    fn resume_secure() {
+        const TEST_SRC: &str = "test_hash_file_resume_secure_src";
+        const TEST_DEST: &str = "test_hash_file_resume_secure_dest";
+        const TEST_FILE: &str = "test_hash_file_resume_secure_dest/file.bin";
+
+        fs::create_dir_all(TEST_SRC).unwrap();
+        fs::create_dir_all(TEST_DEST).unwrap();
+        let chunks = HASH_CHECKPOINT_CHUNKS * 2 + 1;
+        fs::write(TEST_FILE, vec![7; chunks * HASH_CHUNK_SIZE as usize - 3]).unwrap();
+
+        let state = State::open(Path::new(TEST_SRC), Path::new(TEST_DEST), Flag::STATE).unwrap();
+        let progress = state.hash_progress();
+        let path = Path::new(TEST_FILE);
+        let hash = hash_chunks(path, &mut fs::File::open(path).unwrap(), Some(progress)).unwrap();
+        assert_eq!(
+            hash_chunks(path, &mut fs::File::open(path).unwrap(), None).unwrap(),
+            hash
+        );
+
+        // Completely hashed files keep no progress
+        let record = FileRecord::from_metadata(&fs::metadata(path).unwrap());
+        assert_eq!(progress.load(path, &record).is_empty(), true);
+
+        // Saved digests are used instead of reading the chunks again
+        let mut digests = vec![vec![0; 64]; HASH_CHECKPOINT_CHUNKS];
+        progress.save(path, &record, &digests);
+        assert_eq!(progress.load(path, &record), digests);
+        assert_ne!(
+            hash_chunks(path, &mut fs::File::open(path).unwrap(), Some(progress)).unwrap(),
+            hash
+        );
+
+        // Saved digests of a file that changed since are ignored
+        digests.push(vec![0; 64]);
+        let modified = FileRecord {
+            size: record.size + 1,
+            ..record.clone()
+        };
+        progress.save(path, &modified, &digests);
+        assert_eq!(
+            hash_chunks(path, &mut fs::File::open(path).unwrap(), Some(progress)).unwrap(),
+            hash
+        );
+
+        drop(state);
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+
+    #[test]
+    fn content_hasher() {
+        const TEST_DIR: &str = "test_hash_file_content_hasher";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        let chunk = HASH_CHUNK_SIZE as usize;
+        for &len in [0, 5, chunk, chunk * 3 + 17].iter() {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            fs::write([TEST_DIR, "file.bin"].join("/"), &data).unwrap();
+            let file = File::from("file.bin", len as u64);
+
+            for &flags in [Flag::empty(), Flag::SECURE].iter() {
+                // Pieces that do not line up with the chunks are hashed the same
+                let mut hasher = ContentHasher::new(flags);
+                for piece in data.chunks(chunk / 3 + 1) {
+                    hasher.update(piece);
+                }
+                assert_eq!(
+                    Some(hasher.finish()),
+                    hash_file_with_flags(&file, Path::new(TEST_DIR), flags)
+                );
+            }
+        }
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
 }
 
 #[cfg(test)]
@@ -1026,15 +3185,16 @@ mod test_delete_files {
             file_set.insert(file);
         }
 
-        delete_files(files_to_delete.par_iter(), TEST_DIR);
-        delete_files_sequential(files_to_delete_sequential.into_iter(), TEST_DIR);
+        delete_files(files_to_delete.par_iter(), Path::new(TEST_DIR));
+        delete_files_sequential(files_to_delete_sequential.into_iter(), Path::new(TEST_DIR));
 
         assert_eq!(
-            get_all_files(TEST_DIR).unwrap(),
+            get_all_files(Path::new(TEST_DIR), &Options::default()).unwrap(),
             FileSets {
                 files: file_set,
                 dirs: HashSet::new(),
                 symlinks: HashSet::new(),
+                skipped: HashSet::new(),
             }
         );
 
@@ -1089,25 +3249,33 @@ mod test_delete_files {
         links_to_delete.insert(link.clone());
         links_to_delete_sequential.push(&link);
 
-        delete_files(files_to_delete.par_iter(), TEST_DIR);
-        delete_files_sequential(files_to_delete_sequential.into_iter(), TEST_DIR_SEQ);
-        delete_files(links_to_delete.par_iter(), TEST_DIR);
-        delete_files_sequential(links_to_delete_sequential.into_iter(), TEST_DIR_SEQ);
+        delete_files(files_to_delete.par_iter(), Path::new(TEST_DIR));
+        delete_files_sequential(
+            files_to_delete_sequential.into_iter(),
+            Path::new(TEST_DIR_SEQ),
+        );
+        delete_files(links_to_delete.par_iter(), Path::new(TEST_DIR));
+        delete_files_sequential(
+            links_to_delete_sequential.into_iter(),
+            Path::new(TEST_DIR_SEQ),
+        );
 
         assert_eq!(
-            get_all_files(TEST_DIR).unwrap(),
+            get_all_files(Path::new(TEST_DIR), &Options::default()).unwrap(),
             FileSets {
                 files: file_set.clone(),
                 dirs: HashSet::new(),
                 symlinks: link_set.clone(),
+                skipped: HashSet::new(),
             }
         );
         assert_eq!(
-            get_all_files(TEST_DIR_SEQ).unwrap(),
+            get_all_files(Path::new(TEST_DIR_SEQ), &Options::default()).unwrap(),
             FileSets {
                 files: file_set,
                 dirs: HashSet::new(),
                 symlinks: link_set,
+                skipped: HashSet::new(),
             }
         );
 
@@ -1155,25 +3323,33 @@ mod test_delete_files {
         links_to_delete.insert(link.clone());
         links_to_delete_sequential.push(&link);
 
-        delete_files(files_to_delete.par_iter(), TEST_DIR);
-        delete_files_sequential(files_to_delete_sequential.into_iter(), TEST_DIR_SEQ);
-        delete_files(links_to_delete.par_iter(), TEST_DIR);
-        delete_files_sequential(links_to_delete_sequential.into_iter(), TEST_DIR_SEQ);
+        delete_files(files_to_delete.par_iter(), Path::new(TEST_DIR));
+        delete_files_sequential(
+            files_to_delete_sequential.into_iter(),
+            Path::new(TEST_DIR_SEQ),
+        );
+        delete_files(links_to_delete.par_iter(), Path::new(TEST_DIR));
+        delete_files_sequential(
+            links_to_delete_sequential.into_iter(),
+            Path::new(TEST_DIR_SEQ),
+        );
 
         assert_eq!(
-            get_all_files(TEST_DIR).unwrap(),
+            get_all_files(Path::new(TEST_DIR), &Options::default()).unwrap(),
             FileSets {
                 files: HashSet::new(),
                 dirs: HashSet::new(),
                 symlinks: HashSet::new(),
+                skipped: HashSet::new(),
             }
         );
         assert_eq!(
-            get_all_files(TEST_DIR_SEQ).unwrap(),
+            get_all_files(Path::new(TEST_DIR_SEQ), &Options::default()).unwrap(),
             FileSets {
                 files: HashSet::new(),
                 dirs: HashSet::new(),
                 symlinks: HashSet::new(),
+                skipped: HashSet::new(),
             }
         );
 
@@ -1208,8 +3384,11 @@ mod test_delete_files {
         dirs_to_delete_sequential.push(&dir0);
         dirs_to_delete_sequential.push(&dir2);
 
-        delete_files(dirs_to_delete.par_iter(), TEST_DIR);
-        delete_files_sequential(dirs_to_delete_sequential.into_iter(), TEST_DIR_SEQ);
+        delete_files(dirs_to_delete.par_iter(), Path::new(TEST_DIR));
+        delete_files_sequential(
+            dirs_to_delete_sequential.into_iter(),
+            Path::new(TEST_DIR_SEQ),
+        );
 
         file_set.insert(Dir {
             path: PathBuf::from(TEST_SUB_DIRS[0]),
@@ -1219,25 +3398,68 @@ mod test_delete_files {
         });
 
         assert_eq!(
-            get_all_files(TEST_DIR).unwrap(),
+            get_all_files(Path::new(TEST_DIR), &Options::default()).unwrap(),
             FileSets {
                 files: HashSet::new(),
                 dirs: file_set.clone(),
                 symlinks: HashSet::new(),
+                skipped: HashSet::new(),
             }
         );
         assert_eq!(
-            get_all_files(TEST_DIR_SEQ).unwrap(),
+            get_all_files(Path::new(TEST_DIR_SEQ), &Options::default()).unwrap(),
             FileSets {
                 files: HashSet::new(),
                 dirs: file_set,
                 symlinks: HashSet::new(),
+                skipped: HashSet::new(),
             }
         );
 
         fs::remove_dir_all(TEST_DIR).unwrap();
         fs::remove_dir_all(TEST_DIR_SEQ).unwrap();
     }
+
+    #[test]
+    fn count_failures() {
+        const TEST_DIR: &str = "test_delete_files_count_failures";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::File::create([TEST_DIR, "file.txt"].join("/")).unwrap();
+
+        let files_to_delete = vec![File::from("file.txt", 0), File::from("missing.txt", 0)];
+        assert_eq!(
+            delete_files(files_to_delete.par_iter(), Path::new(TEST_DIR)),
+            1
+        );
+        assert_eq!(
+            delete_files_sequential(files_to_delete.iter(), Path::new(TEST_DIR)),
+            2
+        );
+        // Sources that vanished are reported at the end of a run instead of failing
+        assert_eq!(
+            copy_files(
+                files_to_delete.par_iter(),
+                Path::new(TEST_DIR),
+                Path::new(TEST_DIR),
+                Flag::empty()
+            ),
+            0
+        );
+        fs::File::create([TEST_DIR, "file.txt"].join("/")).unwrap();
+        let dest = [TEST_DIR, "file.txt"].join("/");
+        assert_eq!(
+            copy_files(
+                files_to_delete[..1].par_iter(),
+                Path::new(TEST_DIR),
+                Path::new(&dest),
+                Flag::empty()
+            ),
+            1
+        );
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
 }
 
 #[cfg(test)]
@@ -1245,6 +3467,38 @@ mod test_copy_files {
     use super::*;
     use std::process::Command;
 
+    #[test]
+    fn reflinks() {
+        const TEST_DIR: &str = "test_copy_files_reflinks";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::write([TEST_DIR, "a.txt"].join("/"), b"contents").unwrap();
+        fs::write([TEST_DIR, "b.txt"].join("/"), b"old").unwrap();
+
+        // Files are copied wherever they cannot be cloned, and existing files are replaced
+        configure_reflinks(Reflink::Auto);
+        assert_eq!(reflink_mode(), Reflink::Auto);
+        let mut progress = FileProgress::new(8);
+        let result = copy_consistent(
+            Path::new(&[TEST_DIR, "a.txt"].join("/")),
+            Path::new(&[TEST_DIR, "b.txt"].join("/")),
+            Flag::empty(),
+            &mut progress,
+        );
+        configure_reflinks(Reflink::Never);
+        assert_eq!(result.is_ok(), true);
+        assert_eq!(
+            fs::read([TEST_DIR, "b.txt"].join("/")).unwrap(),
+            b"contents"
+        );
+        assert_eq!(
+            Path::new(&[TEST_DIR, "b.txt.lms-clone"].join("/")).exists(),
+            false
+        );
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
     #[test]
     fn no_files() {
         const TEST_DIR: &str = "test_copy_files_no_files";
@@ -1253,14 +3507,20 @@ mod test_copy_files {
         fs::create_dir_all(TEST_DIR).unwrap();
         fs::create_dir_all(TEST_DIR_OUT).unwrap();
 
-        copy_files(HashSet::<File>::new().par_iter(), TEST_DIR, TEST_DIR_OUT);
+        copy_files(
+            HashSet::<File>::new().par_iter(),
+            Path::new(TEST_DIR),
+            Path::new(TEST_DIR_OUT),
+            Flag::empty(),
+        );
 
         assert_eq!(
-            get_all_files(TEST_DIR_OUT).unwrap(),
+            get_all_files(Path::new(TEST_DIR_OUT), &Options::default()).unwrap(),
             FileSets {
                 files: HashSet::new(),
                 dirs: HashSet::new(),
                 symlinks: HashSet::new(),
+                skipped: HashSet::new(),
             }
         );
 
@@ -1276,19 +3536,21 @@ mod test_copy_files {
         fs::create_dir_all(TEST_DIR_OUT).unwrap();
 
         copy_files(
-            get_all_files(TEST_DIR).unwrap().dirs().par_iter(),
-            TEST_DIR,
-            TEST_DIR_OUT,
+            get_all_files(Path::new(TEST_DIR), &Options::default()).unwrap().dirs().par_iter(),
+            Path::new(TEST_DIR),
+            Path::new(TEST_DIR_OUT),
+            Flag::empty(),
         );
         copy_files(
-            get_all_files(TEST_DIR).unwrap().files().par_iter(),
-            TEST_DIR,
-            TEST_DIR_OUT,
+            get_all_files(Path::new(TEST_DIR), &Options::default()).unwrap().files().par_iter(),
+            Path::new(TEST_DIR),
+            Path::new(TEST_DIR_OUT),
+            Flag::empty(),
         );
 
         assert_eq!(
-            get_all_files(TEST_DIR_OUT).unwrap(),
-            get_all_files(TEST_DIR).unwrap()
+            get_all_files(Path::new(TEST_DIR_OUT), &Options::default()).unwrap(),
+            get_all_files(Path::new(TEST_DIR), &Options::default()).unwrap()
         );
 
         fs::remove_dir_all(TEST_DIR_OUT).unwrap();
@@ -1327,14 +3589,16 @@ mod test_copy_files {
             .unwrap();
 
         copy_files(
-            get_all_files(TEST_DIR).unwrap().dirs().par_iter(),
-            TEST_DIR,
-            TEST_DIR_OUT,
+            get_all_files(Path::new(TEST_DIR), &Options::default()).unwrap().dirs().par_iter(),
+            Path::new(TEST_DIR),
+            Path::new(TEST_DIR_OUT),
+            Flag::empty(),
         );
         copy_files(
-            get_all_files(TEST_DIR).unwrap().files().par_iter(),
-            TEST_DIR,
-            TEST_DIR_OUT,
+            get_all_files(Path::new(TEST_DIR), &Options::default()).unwrap().files().par_iter(),
+            Path::new(TEST_DIR),
+            Path::new(TEST_DIR_OUT),
+            Flag::empty(),
         );
 
         let mut files = HashSet::new();
@@ -1356,11 +3620,12 @@ mod test_copy_files {
         });
 
         assert_eq!(
-            get_all_files(TEST_DIR_OUT).unwrap(),
+            get_all_files(Path::new(TEST_DIR_OUT), &Options::default()).unwrap(),
             FileSets {
                 files: files.clone(),
                 dirs: dirs.clone(),
                 symlinks: HashSet::new(),
+                skipped: HashSet::new(),
             }
         );
 
@@ -1400,14 +3665,16 @@ mod test_copy_files {
             .unwrap();
 
         copy_files(
-            get_all_files(TEST_DIR).unwrap().dirs().par_iter(),
-            TEST_DIR,
-            TEST_DIR_OUT,
+            get_all_files(Path::new(TEST_DIR), &Options::default()).unwrap().dirs().par_iter(),
+            Path::new(TEST_DIR),
+            Path::new(TEST_DIR_OUT),
+            Flag::empty(),
         );
         copy_files(
-            get_all_files(TEST_DIR).unwrap().files().par_iter(),
-            TEST_DIR,
-            TEST_DIR_OUT,
+            get_all_files(Path::new(TEST_DIR), &Options::default()).unwrap().files().par_iter(),
+            Path::new(TEST_DIR),
+            Path::new(TEST_DIR_OUT),
+            Flag::empty(),
         );
 
         let files = HashSet::new();
@@ -1417,11 +3684,12 @@ mod test_copy_files {
         });
 
         assert_eq!(
-            get_all_files(TEST_DIR_OUT).unwrap(),
+            get_all_files(Path::new(TEST_DIR_OUT), &Options::default()).unwrap(),
             FileSets {
                 files: files.clone(),
                 dirs: dirs.clone(),
                 symlinks: HashSet::new(),
+                skipped: HashSet::new(),
             }
         );
 
@@ -1452,9 +3720,10 @@ mod test_copy_files {
         symlink("src/main.rs", [TEST_DIR, "file"].join("/")).unwrap();
 
         copy_files(
-            get_all_files(TEST_DIR).unwrap().symlinks().par_iter(),
-            TEST_DIR,
-            TEST_DIR_OUT,
+            get_all_files(Path::new(TEST_DIR), &Options::default()).unwrap().symlinks().par_iter(),
+            Path::new(TEST_DIR),
+            Path::new(TEST_DIR_OUT),
+            Flag::empty(),
         );
 
         let mut links_set = HashSet::new();
@@ -1464,11 +3733,12 @@ mod test_copy_files {
         });
 
         assert_eq!(
-            get_all_files(TEST_DIR_OUT).unwrap(),
+            get_all_files(Path::new(TEST_DIR_OUT), &Options::default()).unwrap(),
             FileSets {
                 files: HashSet::new(),
                 dirs: HashSet::new(),
                 symlinks: links_set.clone(),
+                skipped: HashSet::new(),
             }
         );
 
@@ -1491,9 +3761,10 @@ mod test_copy_files {
         wfs::symlink_dir("src", [TEST_DIR, "dir"].join("/")).unwrap();
 
         copy_files(
-            get_all_files(TEST_DIR).unwrap().symlinks().par_iter(),
+            get_all_files(TEST_DIR, &Options::default()).unwrap().symlinks().par_iter(),
             TEST_DIR,
             TEST_DIR_OUT,
+            Flag::empty(),
         );
 
         let mut links_set = HashSet::new();
@@ -1508,17 +3779,47 @@ mod test_copy_files {
         });
 
         assert_eq!(
-            get_all_files(TEST_DIR_OUT).unwrap(),
+            get_all_files(TEST_DIR_OUT, &Options::default()).unwrap(),
             FileSets {
                 files: HashSet::new(),
                 dirs: HashSet::new(),
                 symlinks: links_set.clone(),
+                skipped: HashSet::new(),
             }
         );
 
        fs::remove_dir_all(TEST_DIR).unwrap();
        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
     }
+
+    #[test]
+    fn consistent_copy() {
+        const TEST_DIR: &str = "test_copy_files_consistent_copy";
+        const TEST_DATA: &[u8] = b"consistent contents";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        let src = PathBuf::from([TEST_DIR, "src.txt"].join("/"));
+        fs::write(&src, TEST_DATA).unwrap();
+
+        for &flags in [Flag::empty(), Flag::LOCK_SOURCE].iter() {
+            let dest = PathBuf::from([TEST_DIR, "dest.txt"].join("/"));
+
+            assert_eq!(
+                copy_consistent(&src, &dest, flags, &mut FileProgress::new(0)).unwrap(),
+                true
+            );
+            assert_eq!(fs::read(&dest).unwrap(), TEST_DATA);
+        }
+
+        let dest = PathBuf::from([TEST_DIR, "hashed.txt"].join("/"));
+        assert_eq!(
+            copy_consistent_hashed(&src, &dest, Flag::SECURE, &mut FileProgress::new(0)).unwrap(),
+            hash_file_with_flags(&File::from("src.txt", 0), Path::new(TEST_DIR), Flag::SECURE)
+        );
+        assert_eq!(fs::read(&dest).unwrap(), TEST_DATA);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
 }
 
 #[cfg(test)]
@@ -1551,12 +3852,19 @@ mod test_compare_and_copy_files {
 
         compare_and_copy_files(
             files_to_compare.clone().par_iter(),
-            TEST_DIR,
-            TEST_DIR_OUT,
+            Path::new(TEST_DIR),
+            Path::new(TEST_DIR_OUT),
             Flag::empty(),
+            &HashStore::default(),
         );
 
-        compare_and_copy_files(files_to_compare.par_iter(), TEST_DIR, TEST_DIR_OUT, flags);
+        compare_and_copy_files(
+            files_to_compare.par_iter(),
+            Path::new(TEST_DIR),
+            Path::new(TEST_DIR_OUT),
+            flags,
+            &HashStore::default(),
+        );
 
         let actual = fs::read([TEST_DIR_OUT, "main.rs"].join("/")).unwrap();
         let expected = fs::read([TEST_DIR, "main.rs"].join("/")).unwrap();
@@ -1582,9 +3890,10 @@ mod test_compare_and_copy_files {
 
         compare_and_copy_files(
             files_to_compare.par_iter(),
-            TEST_DIR,
-            TEST_DIR_OUT,
+            Path::new(TEST_DIR),
+            Path::new(TEST_DIR_OUT),
             Flag::empty(),
+            &HashStore::default(),
         );
 
         let actual = fs::read([TEST_DIR_OUT, "main.rs"].join("/")).unwrap();
@@ -1594,4 +3903,162 @@ mod test_compare_and_copy_files {
 
         fs::remove_dir_all(TEST_DIR_OUT).unwrap();
     }
+
+    #[test]
+    fn same_size_and_time() {
+        const TEST_DIR: &str = "test_compare_and_copy_files_same_size_and_time";
+        const TEST_DIR_OUT: &str = "test_compare_and_copy_files_same_size_and_time_out";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        fs::write([TEST_DIR, "file.txt"].join("/"), b"new").unwrap();
+        fs::write([TEST_DIR_OUT, "file.txt"].join("/"), b"old").unwrap();
+        copy_times(
+            Path::new(&[TEST_DIR, "file.txt"].join("/")),
+            Path::new(&[TEST_DIR_OUT, "file.txt"].join("/")),
+        )
+        .unwrap();
+
+        let mut files_to_compare = HashSet::new();
+        files_to_compare.insert(File {
+            path: PathBuf::from("file.txt"),
+            size: 3,
+        });
+
+        // Files of the same size and modification time are taken as the same
+        compare_and_copy_files(
+            files_to_compare.par_iter(),
+            Path::new(TEST_DIR),
+            Path::new(TEST_DIR_OUT),
+            Flag::empty(),
+            &HashStore::default(),
+        );
+        let actual = fs::read([TEST_DIR_OUT, "file.txt"].join("/")).unwrap();
+        assert_eq!(actual, b"old".to_vec());
+
+        compare_and_copy_files(
+            files_to_compare.par_iter(),
+            Path::new(TEST_DIR),
+            Path::new(TEST_DIR_OUT),
+            Flag::CHECKSUM,
+            &HashStore::default(),
+        );
+        let actual = fs::read([TEST_DIR_OUT, "file.txt"].join("/")).unwrap();
+        assert_eq!(actual, b"new".to_vec());
+
+        // Files under the size given with --checksum-under are always compared by their contents
+        fs::write([TEST_DIR_OUT, "file.txt"].join("/"), b"old").unwrap();
+        copy_times(
+            Path::new(&[TEST_DIR, "file.txt"].join("/")),
+            Path::new(&[TEST_DIR_OUT, "file.txt"].join("/")),
+        )
+        .unwrap();
+        configure_comparisons(Some(4), None);
+        compare_and_copy_files(
+            files_to_compare.par_iter(),
+            Path::new(TEST_DIR),
+            Path::new(TEST_DIR_OUT),
+            Flag::empty(),
+            &HashStore::default(),
+        );
+        configure_comparisons(None, None);
+        let actual = fs::read([TEST_DIR_OUT, "file.txt"].join("/")).unwrap();
+        assert_eq!(actual, b"new".to_vec());
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[test]
+    fn checksum_uncached() {
+        use crate::lumins::state::HashCache;
+
+        const TEST_DIR: &str = "test_compare_and_copy_files_checksum_uncached";
+        const TEST_DIR_OUT: &str = "test_compare_and_copy_files_checksum_uncached_out";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::create_dir_all(TEST_DIR_OUT).unwrap();
+        let src_file = Path::new(TEST_DIR).join("file.txt");
+        let dest_file = Path::new(TEST_DIR_OUT).join("file.txt");
+        fs::write(&src_file, b"new").unwrap();
+        fs::write(&dest_file, b"old").unwrap();
+        copy_times(&src_file, &dest_file).unwrap();
+
+        let file = File {
+            path: PathBuf::from("file.txt"),
+            size: 3,
+        };
+        let mut files_to_compare = HashSet::new();
+        files_to_compare.insert(file.clone());
+
+        // The dest file was changed after its hash was cached, keeping its size and time
+        let cache = HashCache::open(Path::new(TEST_DIR_OUT)).unwrap();
+        let src_hash = hash_file_with_flags(&file, Path::new(TEST_DIR), Flag::empty()).unwrap();
+        let metadata = fs::metadata(&dest_file).unwrap();
+        cache.hashes().insert(&dest_file, &metadata, &src_hash);
+
+        compare_and_copy_files(
+            files_to_compare.par_iter(),
+            Path::new(TEST_DIR),
+            Path::new(TEST_DIR_OUT),
+            Flag::CHECKSUM,
+            &HashStore::default(),
+        );
+        assert_eq!(fs::read(&dest_file).unwrap(), b"new".to_vec());
+
+        drop(cache);
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
+
+    #[test]
+    fn modify_window() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let window = Duration::from_secs(2);
+        assert_eq!(is_within(time, time, Duration::ZERO), true);
+        assert_eq!(is_within(time, time + window, window), true);
+        assert_eq!(is_within(time + window, time, window), true);
+        assert_eq!(is_within(time, time + window * 2, window), false);
+        assert_eq!(
+            is_within(time, time + Duration::from_nanos(1), Duration::ZERO),
+            false
+        );
+    }
+
+    #[test]
+    fn more_than_queued() {
+        const TEST_DIR: &str = "test_compare_and_copy_files_more_than_queued";
+        const TEST_DIR_OUT: &str = "test_compare_and_copy_files_more_than_queued_out";
+        const FILES: usize = COPY_QUEUE_SIZE * 3;
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        let files_to_compare: Vec<File> = (0..FILES)
+            .map(|i| {
+                let name = format!("file{}.txt", i);
+                fs::write([TEST_DIR, &name].join("/"), &name).unwrap();
+                File::from(&name, name.len() as u64)
+            })
+            .collect();
+        // A directory in the way of a copy makes it fail
+        fs::create_dir_all([TEST_DIR_OUT, "file7.txt", "dir"].join("/")).unwrap();
+
+        let failed = compare_and_copy_files(
+            files_to_compare.par_iter(),
+            Path::new(TEST_DIR),
+            Path::new(TEST_DIR_OUT),
+            Flag::empty(),
+            &HashStore::default(),
+        );
+        assert_eq!(failed, 1);
+        for i in (0..FILES).filter(|&i| i != 7) {
+            let name = format!("file{}.txt", i);
+            assert_eq!(
+                fs::read([TEST_DIR_OUT, &name].join("/")).unwrap(),
+                name.as_bytes()
+            );
+        }
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_DIR_OUT).unwrap();
+    }
 }
@@ -0,0 +1,358 @@
+//! Contains the glob patterns given with --exclude and --include, or read from files given with
+//! --exclude-from and --include-from, which select the paths that a traversal visits.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Patterns of the names of files that are still being written, such as partial downloads and
+/// the temporary files of rsync, named like `.photo.jpg.Xy12Ab`, which ingesting skips unless it is
+/// given --include-partial
+pub const IN_PROGRESS_PATTERNS: [&str; 7] = [
+    "*.part",
+    "*.partial",
+    "*.crdownload",
+    "*.download",
+    "*.tmp",
+    "~$*",
+    ".*.??????",
+];
+
+/// Struct to represent the patterns that select the paths a traversal visits
+///
+/// A path is excluded if it matches any exclude pattern and no include pattern, so include
+/// patterns re-admit paths that the exclude patterns would skip. Excluded directories are not
+/// walked, so nothing below them can be included again.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    exclude: Vec<Pattern>,
+    include: Vec<Pattern>,
+}
+
+impl Filter {
+    /// Creates a filter from glob patterns
+    ///
+    /// # Arguments
+    /// * `exclude`: patterns of the paths to skip
+    /// * `include`: patterns of the paths to visit even if they match an exclude pattern
+    pub fn new<'a, E, I>(exclude: E, include: I) -> Self
+    where
+        E: IntoIterator<Item = &'a str>,
+        I: IntoIterator<Item = &'a str>,
+    {
+        Filter {
+            exclude: exclude.into_iter().map(Pattern::new).collect(),
+            include: include.into_iter().map(Pattern::new).collect(),
+        }
+    }
+
+    /// Checks whether a traversal skips the entry at `relative_path`
+    ///
+    /// # Arguments
+    /// * `relative_path`: path of the entry relative to the directory traversed
+    /// * `is_dir`: whether the entry is a directory
+    ///
+    /// # Returns
+    /// True if the entry is excluded
+    pub fn excludes(&self, relative_path: &Path, is_dir: bool) -> bool {
+        self.exclude
+            .iter()
+            .any(|pattern| pattern.matches(relative_path, is_dir))
+            && !self
+                .include
+                .iter()
+                .any(|pattern| pattern.matches(relative_path, is_dir))
+    }
+}
+
+/// Struct to represent the groups of files that are copied one after another, given with the
+/// `order` key of config files, so that files such as the write-ahead log of a database are only
+/// copied once its data files are
+///
+/// Files matching no group are copied first, and then the files of every group in turn, each
+/// group once the one before it is complete. A file matching several groups belongs to the first.
+#[derive(Clone, Debug, Default)]
+pub struct Order {
+    groups: Vec<Vec<Pattern>>,
+}
+
+impl Order {
+    /// Creates an order from groups of glob patterns
+    ///
+    /// # Arguments
+    /// * `groups`: patterns of the files of every group, in the order the groups are copied in
+    pub fn new<'a, G, P>(groups: G) -> Self
+    where
+        G: IntoIterator<Item = P>,
+        P: IntoIterator<Item = &'a str>,
+    {
+        Order {
+            groups: groups
+                .into_iter()
+                .map(|group| group.into_iter().map(Pattern::new).collect())
+                .collect(),
+        }
+    }
+
+    /// Gets the number of groups that files are split into, including the group of the files
+    /// matching no patterns
+    pub fn group_count(&self) -> usize {
+        self.groups.len() + 1
+    }
+
+    /// Gets the group of the file at `relative_path`
+    ///
+    /// # Returns
+    /// 0 if the file matches no group, or one more than the index of the first group it matches
+    pub fn group_of(&self, relative_path: &Path) -> usize {
+        self.groups
+            .iter()
+            .position(|group| {
+                group
+                    .iter()
+                    .any(|pattern| pattern.matches(relative_path, false))
+            })
+            .map_or(0, |group| group + 1)
+    }
+}
+
+/// Reads the patterns of a pattern file, one pattern per line, leaving out empty lines and
+/// comments, which are lines starting with `#`
+///
+/// # Arguments
+/// * `path`: path of the pattern file
+///
+/// # Errors
+/// This function will return an error if the file cannot be read
+pub fn read_patterns(path: &Path) -> Result<Vec<String>, io::Error> {
+    Ok(parse_patterns(&fs::read_to_string(path)?))
+}
+
+/// Parses the patterns out of the contents of a pattern file
+fn parse_patterns(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+/// Struct to represent a single glob pattern
+///
+/// * `*` matches anything but `/`, `**` matches anything, `?` matches a single character but `/`
+/// * `[abc]`, `[a-z]` and `[!abc]` match a single character in or not in the set
+/// * A pattern without `/` matches the name of an entry at any depth, such as `*.tmp` or `.git`
+/// * A pattern with `/` matches the whole relative path, such as `docs/*.pdf` or `/build`
+/// * A pattern ending in `/` only matches directories, such as `target/`
+#[derive(Clone, Debug)]
+pub(crate) struct Pattern {
+    glob: Vec<char>,
+    /// Whether the pattern is matched against the whole relative path instead of the name
+    anchored: bool,
+    /// Whether the pattern only matches directories
+    dir_only: bool,
+}
+
+impl Pattern {
+    pub(crate) fn new(pattern: &str) -> Self {
+        let dir_only = pattern.ends_with('/');
+        let pattern = pattern.trim_end_matches('/');
+        Pattern {
+            glob: pattern.trim_start_matches('/').chars().collect(),
+            anchored: pattern.contains('/'),
+            dir_only,
+        }
+    }
+
+    pub(crate) fn matches(&self, relative_path: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+
+        let text: Vec<char> = if self.anchored {
+            // Paths are matched with `/` as the separator on every platform
+            relative_path
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/")
+                .chars()
+                .collect()
+        } else {
+            match relative_path.file_name() {
+                Some(name) => name.to_string_lossy().chars().collect(),
+                None => return false,
+            }
+        };
+        glob_matches(&self.glob, &text)
+    }
+}
+
+/// Checks whether `text` matches the glob `pattern` as a whole
+fn glob_matches(pattern: &[char], text: &[char]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        ['*', '*', rest @ ..] => {
+            // `**/` also matches no directory at all
+            (rest.first() == Some(&'/') && glob_matches(&rest[1..], text))
+                || (0..=text.len()).any(|skip| glob_matches(rest, &text[skip..]))
+        }
+        ['*', rest @ ..] => (0..=text.len())
+            .take_while(|&skip| skip == 0 || text[skip - 1] != '/')
+            .any(|skip| glob_matches(rest, &text[skip..])),
+        ['?', rest @ ..] => match text {
+            [c, text @ ..] if *c != '/' => glob_matches(rest, text),
+            _ => false,
+        },
+        ['[', class @ ..] => match (class_matches(class, text.first()), text) {
+            (Some((true, rest)), [_, text @ ..]) => glob_matches(rest, text),
+            (Some(_), _) => false,
+            // A `[` without a closing `]` is matched literally
+            (None, [c, text @ ..]) if *c == '[' => glob_matches(class, text),
+            (None, _) => false,
+        },
+        [c, rest @ ..] => match text {
+            [t, text @ ..] if t == c => glob_matches(rest, text),
+            _ => false,
+        },
+    }
+}
+
+/// Matches `c` against the character class at the start of `class`, just after its `[`
+///
+/// # Returns
+/// * Some: Whether `c` is in the class, and the pattern after the class
+/// * None: If the class is not closed
+fn class_matches<'a>(class: &'a [char], c: Option<&char>) -> Option<(bool, &'a [char])> {
+    let (negated, class) = match class {
+        ['!', class @ ..] | ['^', class @ ..] => (true, class),
+        _ => (false, class),
+    };
+
+    // A `]` right after the `[` is part of the class
+    let end = class
+        .iter()
+        .skip(1)
+        .position(|&c| c == ']')
+        .map(|end| end + 1)?;
+
+    let c = match c {
+        Some(&c) if c != '/' => c,
+        _ => return Some((false, &class[end + 1..])),
+    };
+    let mut found = false;
+    let mut set = &class[..end];
+    while !set.is_empty() {
+        match set {
+            [low, '-', high, rest @ ..] => {
+                found |= (*low..=*high).contains(&c);
+                set = rest;
+            }
+            [member, rest @ ..] => {
+                found |= *member == c;
+                set = rest;
+            }
+            [] => unreachable!(),
+        }
+    }
+    Some((found != negated, &class[end + 1..]))
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_filter {
+    use super::*;
+
+    #[test]
+    fn globs() {
+        let matches = |pattern: &str, text: &str| {
+            glob_matches(
+                &pattern.chars().collect::<Vec<_>>(),
+                &text.chars().collect::<Vec<_>>(),
+            )
+        };
+
+        assert_eq!(matches("*.tmp", "a.tmp"), true);
+        assert_eq!(matches("*.tmp", "a.tmp.txt"), false);
+        assert_eq!(matches("docs/*.pdf", "docs/a/b.pdf"), false);
+        assert_eq!(matches("docs/**/*.pdf", "docs/a/b.pdf"), true);
+        assert_eq!(matches("docs/**/*.pdf", "docs/b.pdf"), true);
+        assert_eq!(matches("file?.txt", "file1.txt"), true);
+        assert_eq!(matches("file?.txt", "file.txt"), false);
+        assert_eq!(matches("[a-c]x", "bx"), true);
+        assert_eq!(matches("[!a-c]x", "bx"), false);
+        assert_eq!(matches("[]]", "]"), true);
+        assert_eq!(matches("[ab", "[ab"), true);
+    }
+
+    #[test]
+    fn excludes() {
+        let filter = Filter::new(
+            vec!["target/", "*.tmp", ".git", "/docs/*.pdf"],
+            vec!["keep.tmp"],
+        );
+
+        assert_eq!(filter.excludes(Path::new("target"), true), true);
+        assert_eq!(filter.excludes(Path::new("a/target"), true), true);
+        assert_eq!(filter.excludes(Path::new("target"), false), false);
+        assert_eq!(filter.excludes(Path::new("a/b.tmp"), false), true);
+        assert_eq!(filter.excludes(Path::new("a/keep.tmp"), false), false);
+        assert_eq!(filter.excludes(Path::new("a/.git"), true), true);
+        assert_eq!(filter.excludes(Path::new("docs/a.pdf"), false), true);
+        assert_eq!(filter.excludes(Path::new("a/docs/a.pdf"), false), false);
+        assert_eq!(filter.excludes(Path::new("src/main.rs"), false), false);
+        assert_eq!(Filter::default().excludes(Path::new("a.tmp"), false), false);
+    }
+
+    #[test]
+    fn pattern_files() {
+        let contents = "# Build outputs\ntarget/\n\n  # Editors\n*.swp\r\n.idea\n";
+        assert_eq!(
+            parse_patterns(contents),
+            vec![
+                String::from("target/"),
+                String::from("*.swp"),
+                String::from(".idea")
+            ]
+        );
+        assert_eq!(
+            read_patterns(Path::new("test_filter_pattern_files_missing")).is_err(),
+            true
+        );
+    }
+
+    #[test]
+    fn order() {
+        let order = Order::new(vec![vec!["*.db", "*.idx"], vec!["*-wal", "*-shm"]]);
+        assert_eq!(order.group_count(), 3);
+        assert_eq!(order.group_of(Path::new("README")), 0);
+        assert_eq!(order.group_of(Path::new("db/main.db")), 1);
+        assert_eq!(order.group_of(Path::new("db/main.db-wal")), 2);
+        assert_eq!(order.group_of(Path::new("main.db-shm")), 2);
+        assert_eq!(Order::default().group_of(Path::new("main.db")), 0);
+    }
+
+    #[test]
+    fn in_progress() {
+        let filter = Filter::new(IN_PROGRESS_PATTERNS.iter().copied(), Vec::new());
+        assert_eq!(
+            filter.excludes(Path::new("DCIM/video.mp4.part"), false),
+            true
+        );
+        assert_eq!(
+            filter.excludes(Path::new("setup.exe.crdownload"), false),
+            true
+        );
+        assert_eq!(
+            filter.excludes(Path::new("DCIM/.a.jpg.Xy12Ab"), false),
+            true
+        );
+        assert_eq!(filter.excludes(Path::new("~$report.docx"), false), true);
+        assert_eq!(filter.excludes(Path::new("DCIM/a.jpg"), false), false);
+        assert_eq!(filter.excludes(Path::new(".hidden"), false), false);
+    }
+}
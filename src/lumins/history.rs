@@ -0,0 +1,363 @@
+//! Contains the history of the runs of a profile given with --profile, which appends the summary
+//! of every run to a JSONL file, and `lms history`, which shows how the runs of a profile changed
+//! over time, so that a backup that quietly grew tenfold stands out.
+
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use hashbrown::HashMap;
+use indicatif::HumanBytes;
+
+use crate::lumins::events;
+use crate::lumins::parse::{Options, Output};
+use crate::lumins::resources;
+use crate::lumins::session;
+
+/// Number of runs that `lms history` shows, unless given with --last
+const DEFAULT_LAST: usize = 20;
+
+/// Number of earlier runs that the bytes of a run are compared with
+const TREND_RUNS: usize = 10;
+
+/// Factor of the usual bytes from which a run is pointed out
+const TREND_FACTOR: u64 = 10;
+
+/// Struct to represent the summary of a single run, as it is read back from the history
+#[derive(Eq, PartialEq, Debug, Default)]
+struct Run {
+    started: String,
+    duration: String,
+    bytes: u64,
+    copied: u64,
+    deleted: u64,
+    errors: u64,
+    result: String,
+}
+
+impl Run {
+    /// Reads a run from a line of the history, leaving out fields it does not have
+    ///
+    /// # Returns
+    /// * Some: The run
+    /// * None: If the line is not a JSON object
+    fn from_line(line: &str) -> Option<Self> {
+        let fields = parse_object(line)?;
+        let field = |name: &str| fields.get(name).cloned().unwrap_or_default();
+        let number = |name: &str| field(name).parse().unwrap_or_default();
+        Some(Run {
+            started: field("started"),
+            duration: field("duration"),
+            bytes: number("bytes"),
+            copied: number("copy"),
+            deleted: number("delete"),
+            errors: number("error"),
+            result: field("result"),
+        })
+    }
+}
+
+/// Appends the summary of this run to the history of `profile`
+///
+/// # Arguments
+/// * `profile`: name of the profile the run belongs to
+/// * `started`: time the run was started
+/// * `duration`: time the run took
+/// * `error`: error that the run failed with, None if it succeeded
+///
+/// # Errors
+/// This function will return an error if the history cannot be written
+pub fn record(
+    profile: &str,
+    started: SystemTime,
+    duration: Duration,
+    error: Option<&io::Error>,
+) -> Result<(), io::Error> {
+    let path = history_file(profile)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut line = format!(
+        "{{\"run\":{},\"started\":{},\"duration\":\"{:.1}s\",\"command\":{},\"bytes\":{}",
+        events::json_string(session::run_id()),
+        events::json_string(&humantime::format_rfc3339_seconds(started).to_string()),
+        duration.as_secs_f64(),
+        events::json_string(&env::args().collect::<Vec<_>>().join(" ")),
+        events::bytes()
+    );
+    for (name, count) in events::counts() {
+        let _ = write!(line, ",{}:{}", events::json_string(name), count);
+    }
+    let resources = resources::current().to_json_fields();
+    if !resources.is_empty() {
+        let _ = write!(line, ",{}", resources);
+    }
+    let _ = writeln!(
+        line,
+        ",\"result\":{}}}",
+        events::json_string(&match error {
+            Some(e) => format!("error: {}", e),
+            None => String::from("ok"),
+        })
+    );
+
+    // A single write keeps the line whole, even if runs of the same profile overlap
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)?
+        .write_all(line.as_bytes())
+}
+
+/// Shows the last runs of `profile`, pointing out every run that transferred far more bytes
+/// than the runs before it
+///
+/// # Arguments
+/// * `profile`: name of the profile to show the runs of
+/// * `options`: command line options, of which `last` is the number of runs shown, and `output`
+///   the format they are shown in
+///
+/// # Errors
+/// This function will return an error if the history of `profile` cannot be read
+pub fn show(profile: &str, options: &Options) -> Result<(), io::Error> {
+    let path = history_file(profile)?;
+    let history = fs::read_to_string(&path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("No history of profile {} in {:?}: {}", profile, path, e),
+        )
+    })?;
+    let lines: Vec<&str> = history.lines().filter(|line| !line.is_empty()).collect();
+    let last = options.last.unwrap_or(DEFAULT_LAST);
+    let skipped = lines.len().saturating_sub(last);
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    match options.output {
+        Output::Json => {
+            for line in &lines[skipped..] {
+                writeln!(out, "{}", line)?;
+            }
+        }
+        Output::Text => {
+            let runs: Vec<Run> = lines
+                .iter()
+                .filter_map(|line| Run::from_line(line))
+                .collect();
+            let skipped = runs.len().saturating_sub(last);
+            out.write_all(to_text(&runs, skipped).as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Formats all `runs` after the first `skipped` as a table, one line per run, meant for people
+fn to_text(runs: &[Run], skipped: usize) -> String {
+    let mut text = format!(
+        "{:<20} {:>9} {:>12} {:>8} {:>8} {:>7}  {}\n",
+        "started", "duration", "bytes", "copied", "deleted", "errors", "result"
+    );
+    for (i, run) in runs.iter().enumerate().skip(skipped) {
+        let _ = write!(
+            text,
+            "{:<20} {:>9} {:>12} {:>8} {:>8} {:>7}  {}",
+            run.started,
+            run.duration,
+            HumanBytes(run.bytes).to_string(),
+            run.copied,
+            run.deleted,
+            run.errors,
+            run.result
+        );
+        if let Some(usual) = usual_bytes(&runs[i.saturating_sub(TREND_RUNS)..i]) {
+            if run.bytes >= usual.saturating_mul(TREND_FACTOR) {
+                let _ = write!(
+                    text,
+                    "  <- {:.0}x the usual {}",
+                    run.bytes as f64 / usual as f64,
+                    HumanBytes(usual)
+                );
+            }
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// Gets the median of the bytes transferred by `runs`
+///
+/// # Returns
+/// * Some: The median
+/// * None: If there are no runs, or the median is 0, which no run can be compared with
+fn usual_bytes(runs: &[Run]) -> Option<u64> {
+    let mut bytes: Vec<u64> = runs.iter().map(|run| run.bytes).collect();
+    bytes.sort_unstable();
+    bytes
+        .get(bytes.len() / 2)
+        .copied()
+        .filter(|&usual| usual > 0)
+}
+
+/// Gets the file that the history of `profile` is kept in, under the data directory of the user
+///
+/// # Errors
+/// This function will return an error if `profile` is not a plain name, or there is no data
+/// directory
+pub fn history_file(profile: &str) -> Result<PathBuf, io::Error> {
+    if profile.is_empty() || profile.starts_with('.') || profile.contains(['/', '\\']) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} is not a valid profile name", profile),
+        ));
+    }
+    data_dir()
+        .map(|dir| {
+            dir.join("lumins")
+                .join("history")
+                .join(format!("{}.jsonl", profile))
+        })
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "There is no data directory to keep the history in",
+            )
+        })
+}
+
+/// Gets the data directory of the user, such as `~/.local/share`
+fn data_dir() -> Option<PathBuf> {
+    env::var_os("XDG_DATA_HOME")
+        .or_else(|| env::var_os("APPDATA"))
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))
+}
+
+/// Parses a flat JSON object, like those of the history, into its fields
+///
+/// Strings are unescaped, and every other value is kept as it is written, such as numbers.
+///
+/// # Returns
+/// * Some: The fields of the object
+/// * None: If `line` is not a flat JSON object
+fn parse_object(line: &str) -> Option<HashMap<String, String>> {
+    let mut chars = line.trim().chars().peekable();
+    let mut fields = HashMap::new();
+    if chars.next()? != '{' {
+        return None;
+    }
+
+    loop {
+        match chars.peek()? {
+            '}' => break,
+            ',' => {
+                chars.next();
+            }
+            _ => (),
+        }
+        if *chars.peek()? == '}' {
+            break;
+        }
+        let name = parse_string(&mut chars)?;
+        if chars.next()? != ':' {
+            return None;
+        }
+        let value = if *chars.peek()? == '"' {
+            parse_string(&mut chars)?
+        } else {
+            let mut value = String::new();
+            while !matches!(chars.peek()?, ',' | '}') {
+                value.push(chars.next()?);
+            }
+            value
+        };
+        fields.insert(name, value);
+    }
+    Some(fields)
+}
+
+/// Parses a JSON string, with quotes, from `chars`
+fn parse_string<I: Iterator<Item = char>>(chars: &mut I) -> Option<String> {
+    if chars.next()? != '"' {
+        return None;
+    }
+
+    let mut string = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(string),
+            '\\' => match chars.next()? {
+                'n' => string.push('\n'),
+                'r' => string.push('\r'),
+                't' => string.push('\t'),
+                'u' => {
+                    let code: String = chars.by_ref().take(4).collect();
+                    string.push(char::from_u32(u32::from_str_radix(&code, 16).ok()?)?);
+                }
+                c => string.push(c),
+            },
+            c => string.push(c),
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_history {
+    use super::*;
+
+    #[test]
+    fn runs() {
+        let run = Run::from_line(
+            "{\"run\":\"id\",\"started\":\"2026-01-02T03:04:05Z\",\"duration\":\"1.5s\",\
+             \"command\":\"lms sync \\\"a\\\" b\",\"bytes\":2048,\"copy\":3,\"rename\":0,\
+             \"delete\":1,\"skip\":0,\"warning\":0,\"error\":2,\"result\":\"ok\"}",
+        )
+        .unwrap();
+        assert_eq!(
+            run,
+            Run {
+                started: String::from("2026-01-02T03:04:05Z"),
+                duration: String::from("1.5s"),
+                bytes: 2048,
+                copied: 3,
+                deleted: 1,
+                errors: 2,
+                result: String::from("ok"),
+            }
+        );
+        assert_eq!(Run::from_line("not json"), None);
+        assert_eq!(
+            parse_object("{\"a\":\"\\u0041\\\\\"}").unwrap().get("a"),
+            Some(&String::from("A\\"))
+        );
+    }
+
+    #[test]
+    fn trends() {
+        let runs: Vec<Run> = [100, 120, 90, 110, 5000]
+            .iter()
+            .map(|&bytes| Run {
+                bytes,
+                ..Run::default()
+            })
+            .collect();
+        assert_eq!(usual_bytes(&runs[..4]), Some(110));
+        assert_eq!(usual_bytes(&[]), None);
+
+        let text = to_text(&runs, 0);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[1].contains("<-"), false);
+        assert_eq!(lines[5].contains("<- 45x the usual 110B"), true);
+
+        assert_eq!(to_text(&runs, 3).lines().count(), 3);
+        assert_eq!(history_file("../nightly").is_err(), true);
+    }
+}
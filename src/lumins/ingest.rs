@@ -0,0 +1,283 @@
+//! Contains an ingest mode that moves files appearing in a hot folder, such as a mounted camera
+//! card or a shared drop folder, into a destination as soon as they are completely written.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use hashbrown::HashMap;
+use log::{info, warn};
+use notify::{EventKind, RecursiveMode, Watcher};
+use rayon::prelude::*;
+
+use crate::lumins::{
+    file_ops,
+    journal::to_io_error,
+    parse::{Flag, Options},
+    paths,
+    plan::Plan,
+    vanished,
+};
+
+/// How long a file must go without changes before it is ingested, unless given with --settle
+const DEFAULT_SETTLE: Duration = Duration::from_secs(2);
+
+/// Copies every file that appears in `src` to the same path in `dest`, until an error occurs, or
+/// until `src` went without changes for the idle exit time of `options` if it is given
+///
+/// Files are ingested in batches, once they went without changes for the settle time of
+/// `options`, so that files that are still being written are not picked up halfway.
+/// Files already in `src` are ingested right away.
+///
+/// # Arguments
+/// * `src`: Source directory to watch
+/// * `dest`: Destination directory
+/// * `options`: command line options, of which `REMOVE_SOURCE_FILES` deletes verified sources
+///
+/// # Errors
+/// This function will return an error if `src` cannot be watched or read
+pub fn watch(src: &Path, dest: &Path, options: &Options) -> Result<(), io::Error> {
+    // Files that appear later cannot be planned for, so a dry run only shows the files already there
+    if options.flags.contains(Flag::DRY_RUN) {
+        Plan::copy(&file_ops::get_all_files(src, options)?, options.flags).print();
+        return Ok(());
+    }
+
+    let root = paths::canonicalize(src)?;
+    let settle = options.settle.unwrap_or(DEFAULT_SETTLE);
+    let timeout = options
+        .idle_exit
+        .map_or(settle, |idle_exit| idle_exit.min(settle));
+
+    // The watch must be in place before the source is read, so that no file is missed
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).map_err(to_io_error)?;
+    watcher
+        .watch(&root, RecursiveMode::Recursive)
+        .map_err(to_io_error)?;
+    info!("Ingesting files appearing in {:?} into {:?}", root, dest);
+
+    let mut pending = HashMap::new();
+    let mut last_change = Instant::now();
+    pending.insert(PathBuf::new(), last_change);
+
+    loop {
+        match receiver.recv_timeout(timeout) {
+            Ok(Ok(event)) if event.need_rescan() => {
+                pending.insert(PathBuf::new(), Instant::now());
+            }
+            Ok(Ok(event)) if !matches!(event.kind, EventKind::Access(_)) => {
+                let now = Instant::now();
+                last_change = now;
+                for changed in event.paths {
+                    if let Ok(relative_path) = changed.strip_prefix(&root) {
+                        pending.insert(relative_path.to_path_buf(), now);
+                    }
+                }
+            }
+            Ok(Ok(_)) => (),
+            Ok(Err(e)) => {
+                warn!("Warning -- Watching {:?}: {}", root, e);
+                pending.insert(PathBuf::new(), Instant::now());
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+
+        let settled = take_settled(&mut pending, Instant::now(), settle);
+        if !settled.is_empty() {
+            ingest(settled, src, dest, options)?;
+        }
+
+        if is_idle(&pending, last_change, Instant::now(), options.idle_exit) {
+            info!(
+                "{:?} did not change for {}s, stopping",
+                root,
+                last_change.elapsed().as_secs()
+            );
+            return Ok(());
+        }
+    }
+}
+
+/// Checks whether ingesting can stop, because nothing is left to ingest and the source did not
+/// change for `idle_exit` until `now`
+///
+/// # Arguments
+/// * `pending`: relative paths that changed and were not ingested yet
+/// * `last_change`: time the source last changed
+/// * `now`: current time
+/// * `idle_exit`: how long the source must go without changes, or `None` to never stop
+fn is_idle(
+    pending: &HashMap<PathBuf, Instant>,
+    last_change: Instant,
+    now: Instant,
+    idle_exit: Option<Duration>,
+) -> bool {
+    match idle_exit {
+        Some(idle_exit) => {
+            pending.is_empty() && now.saturating_duration_since(last_change) >= idle_exit
+        }
+        None => false,
+    }
+}
+
+/// Takes the paths out of `pending` that did not change for `settle` until `now`
+///
+/// # Arguments
+/// * `pending`: relative paths that changed, with the time they last changed
+/// * `now`: current time
+/// * `settle`: how long a path must go without changes
+///
+/// # Returns
+/// The paths that settled
+fn take_settled(
+    pending: &mut HashMap<PathBuf, Instant>,
+    now: Instant,
+    settle: Duration,
+) -> Vec<PathBuf> {
+    let settled: Vec<PathBuf> = pending
+        .iter()
+        .filter(|(_, &changed)| now.saturating_duration_since(changed) >= settle)
+        .map(|(path, _)| path.clone())
+        .collect();
+    for path in settled.iter() {
+        pending.remove(path);
+    }
+    settled
+}
+
+/// Copies a batch of paths from `src` to `dest`, including everything in the directories
+///
+/// # Arguments
+/// * `paths`: paths relative to `src` to ingest, of which an empty path ingests all of `src`
+/// * `src`: Source directory
+/// * `dest`: Destination directory
+/// * `options`: command line options
+///
+/// # Errors
+/// This function will return an error if `src` cannot be read
+fn ingest(
+    paths: Vec<PathBuf>,
+    src: &Path,
+    dest: &Path,
+    options: &Options,
+) -> Result<(), io::Error> {
+    let only = if paths.iter().any(|path| path.as_os_str().is_empty()) {
+        Vec::new()
+    } else {
+        paths
+    };
+    let file_sets = file_ops::get_all_files(
+        src,
+        &Options {
+            only,
+            ..options.clone()
+        },
+    )?;
+
+    let mut failed = file_ops::copy_files(file_sets.dirs().par_iter(), src, dest, options.flags);
+    failed += file_ops::copy_files(file_sets.files().par_iter(), src, dest, options.flags);
+    if options.flags.contains(Flag::REMOVE_SOURCE_FILES) {
+        failed +=
+            file_ops::remove_source_files(file_sets.files().par_iter(), src, dest, options.flags);
+    }
+
+    // A failed batch does not stop watching, since its files are retried once they change again
+    if failed > 0 {
+        warn!(
+            "Warning -- {} file operations of this batch failed, see the errors above",
+            failed
+        );
+    }
+    info!("Ingested {} files", file_sets.files().len());
+    vanished::report(false)
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_ingest {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn settled() {
+        let settle = Duration::from_secs(2);
+        let now = Instant::now();
+        let mut pending = HashMap::new();
+        pending.insert(PathBuf::from("old.jpg"), now);
+        pending.insert(PathBuf::from("new.jpg"), now + settle);
+
+        assert_eq!(
+            take_settled(&mut pending, now + settle, settle),
+            vec![PathBuf::from("old.jpg")]
+        );
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending.contains_key(Path::new("new.jpg")), true);
+    }
+
+    #[test]
+    fn idle() {
+        let idle_exit = Duration::from_secs(10);
+        let now = Instant::now();
+        let mut pending = HashMap::new();
+
+        assert_eq!(
+            is_idle(&pending, now, now + idle_exit, Some(idle_exit)),
+            true
+        );
+        assert_eq!(
+            is_idle(&pending, now, now + idle_exit / 2, Some(idle_exit)),
+            false
+        );
+        assert_eq!(is_idle(&pending, now, now + idle_exit, None), false);
+
+        // Files that are still settling are ingested before stopping
+        pending.insert(PathBuf::from("late.jpg"), now);
+        assert_eq!(
+            is_idle(&pending, now, now + idle_exit, Some(idle_exit)),
+            false
+        );
+    }
+
+    #[test]
+    fn remove_source_files() {
+        const TEST_SRC: &str = "test_ingest_remove_source_files_src";
+        const TEST_DEST: &str = "test_ingest_remove_source_files_dest";
+
+        fs::create_dir_all([TEST_SRC, "DCIM"].join("/")).unwrap();
+        fs::create_dir_all(TEST_DEST).unwrap();
+        fs::write([TEST_SRC, "DCIM/a.jpg"].join("/"), b"a").unwrap();
+        fs::write([TEST_SRC, "b.jpg"].join("/"), b"b").unwrap();
+
+        let options = Options::from(Flag::REMOVE_SOURCE_FILES);
+        assert_eq!(
+            ingest(
+                vec![PathBuf::from("DCIM")],
+                Path::new(TEST_SRC),
+                Path::new(TEST_DEST),
+                &options
+            )
+            .is_ok(),
+            true
+        );
+
+        assert_eq!(fs::read([TEST_DEST, "DCIM/a.jpg"].join("/")).unwrap(), b"a");
+        assert_eq!(
+            Path::new(&[TEST_SRC, "DCIM/a.jpg"].join("/")).exists(),
+            false
+        );
+
+        // Only the given paths are ingested
+        assert_eq!(Path::new(&[TEST_DEST, "b.jpg"].join("/")).exists(), false);
+        assert_eq!(Path::new(&[TEST_SRC, "b.jpg"].join("/")).exists(), true);
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+}
@@ -0,0 +1,316 @@
+//! Contains a journal of the changes made to a source directory, recorded by `lms journal`,
+//! which lets later syncs read only the directories that changed instead of the whole source.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hashbrown::HashSet;
+use log::{info, warn};
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use crate::lumins::paths;
+use crate::lumins::state::{self, STATE_DIR};
+
+/// Name of the journal inside `STATE_DIR`
+const JOURNAL_FILE: &str = "journal";
+
+/// First line of a journal, followed by the ID of the recording
+const HEADER: &str = "lms-journal ";
+
+/// Struct to represent a position in a journal
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct JournalCursor {
+    /// ID of the recording, which changes every time the journal is restarted
+    pub id: u64,
+    /// Offset in bytes of the first change that has not been read yet
+    pub offset: u64,
+}
+
+/// Struct to represent the changes read from a journal
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct JournalChanges {
+    /// Paths relative to the source that changed since the given cursor,
+    /// or None if the journal cannot tell, so that the whole source must be read
+    pub changed: Option<HashSet<PathBuf>>,
+    /// Position to read the next changes from
+    pub cursor: JournalCursor,
+}
+
+/// Records every change made to `src` in the journal of `dest`, until an error occurs
+///
+/// The journal is restarted from scratch, so the next sync reads the whole source once.
+///
+/// # Arguments
+/// * `src`: Source directory to watch
+/// * `dest`: Destination directory whose state holds the journal
+///
+/// # Errors
+/// This function will return an error if `src` cannot be watched
+/// or the journal cannot be written
+pub fn record(src: &Path, dest: &Path) -> Result<(), io::Error> {
+    let root = paths::canonicalize(src)?;
+
+    // The watch must be in place before the journal is started,
+    // so that no change is missed by anyone reading the journal
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).map_err(to_io_error)?;
+    watcher
+        .watch(&root, RecursiveMode::Recursive)
+        .map_err(to_io_error)?;
+
+    let path = journal_path(dest);
+    fs::create_dir_all(dest.join(STATE_DIR))?;
+    let mut journal = fs::File::create(&path)?;
+    let id = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    journal.write_all(format!("{}{:016x}\n", HEADER, id).as_bytes())?;
+    info!("Recording changes to {:?} in {:?}", root, path);
+
+    for event in receiver {
+        let mut records = Vec::new();
+        match event {
+            Ok(event) if event.need_rescan() => records.extend_from_slice(b"R\n"),
+            Ok(event) => {
+                if let EventKind::Access(_) = event.kind {
+                    continue;
+                }
+                for changed in event.paths {
+                    if let Ok(relative_path) = changed.strip_prefix(&root) {
+                        records.extend_from_slice(b"C ");
+                        records.extend(escape(&state::path_key(relative_path)));
+                        records.push(b'\n');
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("Warning -- Watching {:?}: {}", root, e);
+                records.extend_from_slice(b"R\n");
+            }
+        }
+        journal.write_all(&records)?;
+    }
+
+    Ok(())
+}
+
+/// Reads the changes recorded in the journal of `dest` since `cursor`
+///
+/// # Arguments
+/// * `dest`: Destination directory whose state holds the journal
+/// * `cursor`: Position that the previous sync read up to
+///
+/// # Returns
+/// * Some: The changes since `cursor`, unknown if the journal restarted or fell behind
+/// * None: If no journal is being recorded
+///
+/// # Errors
+/// This function will return an error if the journal cannot be read
+pub fn read(
+    dest: &Path,
+    cursor: Option<JournalCursor>,
+) -> Result<Option<JournalChanges>, io::Error> {
+    let journal = match fs::File::open(journal_path(dest)) {
+        Ok(journal) => journal,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut journal = BufReader::new(journal);
+
+    let mut header = String::new();
+    let mut offset = journal.read_line(&mut header)? as u64;
+    let id = match header
+        .strip_prefix(HEADER)
+        .and_then(|id| u64::from_str_radix(id.trim_end(), 16).ok())
+    {
+        Some(id) if header.ends_with('\n') => id,
+        _ => return Ok(None),
+    };
+
+    let mut changed = match cursor {
+        Some(cursor) if cursor.id == id && cursor.offset >= offset => {
+            offset = cursor.offset;
+            journal.seek(SeekFrom::Start(offset))?;
+            Some(HashSet::new())
+        }
+        _ => None,
+    };
+
+    let mut record = Vec::new();
+    loop {
+        record.clear();
+        let read = journal.read_until(b'\n', &mut record)?;
+
+        // A record without a newline is still being written
+        if read == 0 || record.last() != Some(&b'\n') {
+            break;
+        }
+        offset += read as u64;
+
+        let record = &record[..record.len() - 1];
+        if record.starts_with(b"C ") {
+            if let Some(changed) = changed.as_mut() {
+                changed.insert(state::key_path(&unescape(&record[2..])));
+            }
+        } else {
+            changed = None;
+        }
+    }
+
+    Ok(Some(JournalChanges {
+        changed,
+        cursor: JournalCursor { id, offset },
+    }))
+}
+
+/// Gets the path of the journal of `dest`
+fn journal_path(dest: &Path) -> PathBuf {
+    dest.join(STATE_DIR).join(JOURNAL_FILE)
+}
+
+/// Escapes newlines and backslashes, so that every record takes up exactly one line
+pub fn escape(bytes: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(bytes.len());
+    for &byte in bytes {
+        match byte {
+            b'\\' => escaped.extend_from_slice(b"\\\\"),
+            b'\n' => escaped.extend_from_slice(b"\\n"),
+            byte => escaped.push(byte),
+        }
+    }
+    escaped
+}
+
+/// Reverses `escape`
+pub fn unescape(bytes: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut bytes = bytes.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte == b'\\' {
+            match bytes.next() {
+                Some(b'n') => unescaped.push(b'\n'),
+                Some(&byte) => unescaped.push(byte),
+                None => unescaped.push(b'\\'),
+            }
+        } else {
+            unescaped.push(byte);
+        }
+    }
+    unescaped
+}
+
+/// Converts an error of the watcher into an IO error
+pub fn to_io_error(e: notify::Error) -> io::Error {
+    match e.kind {
+        notify::ErrorKind::Io(e) => e,
+        kind => io::Error::other(format!("{:?}", kind)),
+    }
+}
+
+/// Appends raw records to the journal of `dest`, for testing readers without watching
+#[cfg(test)]
+fn append(dest: &Path, records: &[u8]) {
+    let mut journal = fs::OpenOptions::new()
+        .append(true)
+        .open(journal_path(dest))
+        .unwrap();
+    journal.write_all(records).unwrap();
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_journal {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn escape_unescape() {
+        let path = b"a\\b\nc";
+
+        assert_eq!(escape(path).contains(&b'\n'), false);
+        assert_eq!(unescape(&escape(path)), path.to_vec());
+    }
+
+    #[test]
+    fn read_changes() {
+        const TEST_DIR: &str = "test_journal_read_changes";
+
+        assert_eq!(read(Path::new(TEST_DIR), None).unwrap(), None);
+
+        fs::create_dir_all([TEST_DIR, STATE_DIR].join("/")).unwrap();
+        fs::write(
+            journal_path(Path::new(TEST_DIR)),
+            format!("{}{:016x}\n", HEADER, 42).as_bytes(),
+        )
+        .unwrap();
+
+        // Without a cursor into this recording, the changes are unknown
+        let first = read(Path::new(TEST_DIR), None).unwrap().unwrap();
+        assert_eq!(first.changed, None);
+        assert_eq!(first.cursor.id, 42);
+
+        append(Path::new(TEST_DIR), b"C a/b\nC c\\\\d\nC partial");
+
+        let second = read(Path::new(TEST_DIR), Some(first.cursor))
+            .unwrap()
+            .unwrap();
+        let mut changed = HashSet::new();
+        changed.insert(PathBuf::from("a/b"));
+        changed.insert(PathBuf::from("c\\d"));
+        assert_eq!(second.changed, Some(changed));
+
+        append(Path::new(TEST_DIR), b"\nR\n");
+
+        let third = read(Path::new(TEST_DIR), Some(second.cursor))
+            .unwrap()
+            .unwrap();
+        assert_eq!(third.changed, None);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn record_changes() {
+        const TEST_SRC: &str = "test_journal_record_changes_src";
+        const TEST_DEST: &str = "test_journal_record_changes_dest";
+
+        fs::create_dir_all([TEST_SRC, "a"].join("/")).unwrap();
+        fs::create_dir_all(TEST_DEST).unwrap();
+
+        std::thread::spawn(|| record(Path::new(TEST_SRC), Path::new(TEST_DEST)));
+        let mut cursor = None;
+        while cursor.is_none() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            cursor = read(Path::new(TEST_DEST), None)
+                .unwrap()
+                .map(|changes| changes.cursor);
+        }
+
+        fs::write([TEST_SRC, "a/file.txt"].join("/"), b"changed").unwrap();
+
+        let mut changed = None;
+        for _ in 0..100 {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            changed = read(Path::new(TEST_DEST), cursor).unwrap().unwrap().changed;
+            if changed
+                .as_ref()
+                .map_or(false, |changed| !changed.is_empty())
+            {
+                break;
+            }
+        }
+
+        assert_eq!(changed.unwrap().contains(Path::new("a/file.txt")), true);
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+}
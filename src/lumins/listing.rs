@@ -0,0 +1,214 @@
+//! Contains the listing of `lms ls`, which writes the entries of a directory as scanned by the
+//! parallel traversal to stdout, either NUL separated like `find . -print0` or as an mtree
+//! specification, so that the scan can feed tools such as cpio, tar, and bsdtar in pipelines.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use log::warn;
+use rayon::prelude::*;
+
+use crate::lumins::file_ops::{self, FileOps, FileSets};
+use crate::lumins::mtree;
+use crate::lumins::parse::{Flag, ListFormat, Options};
+use crate::lumins::state;
+use crate::progress;
+
+/// Enum to represent the type of a listed entry
+#[derive(Eq, PartialEq, Ord, PartialOrd, Debug, Clone)]
+enum Kind {
+    File,
+    Dir,
+    Symlink(PathBuf),
+}
+
+/// Lists all entries in `target`, and writes the listing to stdout
+///
+/// Paths are relative to `target` and start with `./`, like those of `find . -print0` run in
+/// `target`, so that the listing can be read by tools run in `target`, such as
+/// `tar --null --no-recursion -T -` or `cpio -0 -o`.
+///
+/// # Arguments
+/// * `target`: directory to list the entries of
+/// * `options`: command line options, of which `list_format` selects the format of the listing
+///
+/// # Errors
+/// This function will return an error if `target` cannot be read, or the listing cannot be
+/// written
+pub fn list(target: &Path, options: &Options) -> Result<(), io::Error> {
+    progress::scan_init("Scanning");
+    let file_sets = file_ops::get_all_files(target, options)?;
+    let entries = entries(&file_sets);
+
+    // The scanning spinner must not be drawn over the listing
+    progress::bar().finish_and_clear();
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    match options.list_format {
+        ListFormat::Print0 => write_print0(&entries, &mut out)?,
+        ListFormat::Mtree => write_mtree(&entries, target, options.flags, &mut out)?,
+    }
+    out.flush()
+}
+
+/// Gets all entries of `file_sets`, along with the root directory, sorted by path so that every
+/// directory comes before its contents
+fn entries(file_sets: &FileSets) -> Vec<(&Path, Kind)> {
+    let mut entries: Vec<(&Path, Kind)> = file_sets
+        .files()
+        .iter()
+        .map(|file| (file.path().as_path(), Kind::File))
+        .chain(
+            file_sets
+                .dirs()
+                .iter()
+                .map(|dir| (dir.path().as_path(), Kind::Dir)),
+        )
+        .chain(file_sets.symlinks().iter().map(|symlink| {
+            (
+                symlink.path().as_path(),
+                Kind::Symlink(symlink.target().to_path_buf()),
+            )
+        }))
+        .filter(|(path, _)| !path.as_os_str().is_empty())
+        .collect();
+    entries.push((Path::new(""), Kind::Dir));
+    entries.par_sort_unstable();
+    entries
+}
+
+/// Writes the path of every entry, terminated by a NUL byte
+fn write_print0<W: Write>(entries: &[(&Path, Kind)], out: &mut W) -> Result<(), io::Error> {
+    for (path, _) in entries {
+        out.write_all(&listed_path(path))?;
+        out.write_all(b"\0")?;
+    }
+    Ok(())
+}
+
+/// Writes an mtree specification of all entries in `target`, with the type, mode, size, and
+/// modification time of every entry, and the SHA-256 digest of every file if `flags` contains
+/// `SHA256`
+fn write_mtree<W: Write>(
+    entries: &[(&Path, Kind)],
+    target: &Path,
+    flags: Flag,
+    out: &mut W,
+) -> Result<(), io::Error> {
+    let lines: Vec<String> = entries
+        .par_iter()
+        .map(|(path, kind)| mtree_line(path, kind, target, flags))
+        .collect();
+
+    out.write_all(b"#mtree\n")?;
+    for line in lines {
+        out.write_all(line.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Formats the mtree line of a single entry, leaving out what cannot be read from it
+fn mtree_line(path: &Path, kind: &Kind, target: &Path, flags: Flag) -> String {
+    let mut line = mtree::escape(&listed_path(path));
+    match kind {
+        Kind::File => line.push_str(" type=file"),
+        Kind::Dir => line.push_str(" type=dir"),
+        Kind::Symlink(link) => {
+            let _ = write!(
+                line,
+                " type=link link={}",
+                mtree::escape(&state::path_key(link))
+            );
+        }
+    }
+
+    match fs::symlink_metadata(target.join(path)) {
+        Ok(metadata) => {
+            if let Some(mode) = mtree::mode(&metadata) {
+                let _ = write!(line, " mode={:04o}", mode);
+            }
+            if *kind == Kind::File {
+                let _ = write!(line, " size={}", metadata.len());
+            }
+            if *kind == Kind::File && flags.contains(Flag::SHA256) {
+                match mtree::sha256_file(&target.join(path)) {
+                    Ok(digest) => {
+                        let _ = write!(line, " sha256digest={}", digest);
+                    }
+                    Err(e) => warn!(
+                        "Warning -- Listing {:?} without its digest: {}",
+                        target.join(path),
+                        e
+                    ),
+                }
+            }
+            if let Ok(modified) = metadata.modified() {
+                if let Ok(time) = modified.duration_since(UNIX_EPOCH) {
+                    let _ = write!(line, " time={}.{:09}", time.as_secs(), time.subsec_nanos());
+                }
+            }
+        }
+        Err(e) => warn!(
+            "Warning -- Listing {:?} without its metadata: {}",
+            target.join(path),
+            e
+        ),
+    }
+    line.push('\n');
+    line
+}
+
+/// Gets the path of an entry as it is listed, starting with `./` and separated by `/`
+fn listed_path(path: &Path) -> Vec<u8> {
+    let mut listed = b".".to_vec();
+    if !path.as_os_str().is_empty() {
+        listed.push(b'/');
+        listed.extend(state::path_key(path));
+    }
+    listed
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_listing {
+    use super::*;
+
+    #[test]
+    fn formats() {
+        const TEST_DIR: &str = "test_listing_formats";
+
+        fs::create_dir_all([TEST_DIR, "dir"].join("/")).unwrap();
+        fs::write([TEST_DIR, "dir/a file.txt"].join("/"), b"contents").unwrap();
+        fs::write([TEST_DIR, "b#1.txt"].join("/"), b"").unwrap();
+
+        let file_sets = file_ops::get_all_files(Path::new(TEST_DIR), &Options::default()).unwrap();
+        let entries = entries(&file_sets);
+
+        let mut print0 = Vec::new();
+        write_print0(&entries, &mut print0).unwrap();
+        assert_eq!(print0, b".\0./b#1.txt\0./dir\0./dir/a file.txt\0".to_vec());
+
+        let mut mtree = Vec::new();
+        write_mtree(&entries, Path::new(TEST_DIR), Flag::SHA256, &mut mtree).unwrap();
+        let mtree = String::from_utf8(mtree).unwrap();
+        let lines: Vec<&str> = mtree.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], "#mtree");
+        assert_eq!(lines[1].starts_with(". type=dir "), true);
+        assert_eq!(lines[2].starts_with("./b\\0431.txt type=file "), true);
+        assert_eq!(lines[2].contains(" size=0 "), true);
+        assert_eq!(
+            lines[4].starts_with("./dir/a\\040file.txt type=file "),
+            true
+        );
+        assert_eq!(lines[4].contains(" size=8 "), true);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
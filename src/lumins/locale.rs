@@ -0,0 +1,356 @@
+//! Contains the localization of the messages that the command line shows to its users, such as
+//! its errors, prompts, and summaries, while log records are always in English.
+//!
+//! Every message has an id and an English text that is built in. Translations are kept in
+//! catalogs named after their language, such as `de.toml` or `de_AT.toml`, which map ids to
+//! translated texts, where names in braces are filled in with the values of the message:
+//!
+//! ```toml
+//! source-not-dir = "Quellfehler -- {path} ist kein Verzeichnis"
+//! confirm-answers = "[j/N]"
+//! confirm-yes = "j|ja"
+//! ```
+//!
+//! The language is taken from `LUMINS_LANG`, or else from `LC_ALL`, `LC_MESSAGES`, or `LANG` like
+//! other programs. Its catalogs are read from the `locale` dir of the config dir of the system,
+//! then of the user, and then from the dir given by `LUMINS_LOCALE_DIR`, where each overrides
+//! the texts of those before it, and a catalog of a country, such as `de_AT.toml`, overrides the
+//! catalog of its language. Messages that no catalog translates are shown in English.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use hashbrown::HashMap;
+use lazy_static::lazy_static;
+use toml::value::Table;
+
+use crate::lumins::config;
+
+/// Translated texts of messages, by id
+type Catalog = HashMap<String, String>;
+
+lazy_static! {
+    /// Catalog of the language of the current run, once it is configured
+    static ref CATALOG: RwLock<Catalog> = RwLock::new(Catalog::new());
+}
+
+/// Ids of every message and their English texts
+const MESSAGES: [(&str, &str); 32] = [
+    ("backup-dir-error", "Backup Dir Error -- {path}: {error}"),
+    (
+        "backup-dir-inside",
+        "Backup Dir Error -- {path} is inside of the destination {dest}",
+    ),
+    (
+        "backup-dir-single",
+        "Backup Dir Error -- --backup-dir takes a single destination",
+    ),
+    ("config-error", "Config Error -- {error}"),
+    ("confirm-answers", "[y/N]"),
+    ("confirm-yes", "y|yes"),
+    ("creating-dir", "Creating dir {path}"),
+    ("destination-error", "Destination Error -- {path}: {error}"),
+    (
+        "destination-nested",
+        "Destination Error -- {src} and {dest} are nested, use --force-nested to run anyway",
+    ),
+    (
+        "destination-not-dir",
+        "Destination Error -- {path} is not a directory",
+    ),
+    (
+        "destination-same",
+        "Destination Error -- {src} and {dest} are the same directory",
+    ),
+    (
+        "dry-run-summary",
+        "Dry run -- {count} changes planned, nothing was changed",
+    ),
+    (
+        "exclude-mount-error",
+        "Exclude Mount Error -- {path}: {error}",
+    ),
+    ("log-dir-error", "Log Dir Error -- {error}"),
+    ("log-dir-start-error", "Log Dir Error -- {path}: {error}"),
+    (
+        "option-error",
+        "Option Error -- --{option} {value}: {error}",
+    ),
+    (
+        "overwrite-newer",
+        "Overwrite {path}, which is newer than the source?",
+    ),
+    ("profile-error", "Profile Error -- {error}"),
+    (
+        "profile-no-destination",
+        "Profile Error -- {profile} defines no destination in the config files",
+    ),
+    (
+        "profile-no-source",
+        "Profile Error -- {profile} defines no source in the config files",
+    ),
+    ("progress-fd-error", "Progress FD Error -- {error}"),
+    (
+        "remote-location",
+        "Location Error -- {path}: remote locations on {host} are not supported, \
+         mount the remote directory (for example with sshfs) and give its local path, \
+         or start the path with ./ if it is a local one",
+    ),
+    (
+        "remove-source-single",
+        "Option Error -- --remove-source-files takes a single destination",
+    ),
+    ("report-error", "Report Error -- {error}"),
+    ("resources-used", "Resources used: {resources}"),
+    ("source-error", "Source Error -- {path}: {error}"),
+    (
+        "source-not-dir",
+        "Source Error -- {path} is not a directory",
+    ),
+    (
+        "source-pseudo-fs",
+        "Source Error -- {path} is on a pseudo-filesystem ({fs_type}), \
+         use --allow-pseudo-fs to copy it anyway",
+    ),
+    ("target-error", "Target Error -- {path}: {error}"),
+    (
+        "target-not-dir",
+        "Target Error -- {path} is not a directory",
+    ),
+    ("translation-error", "Locale Error -- {path}: {error}"),
+    (
+        "unknown-language",
+        "Locale Error -- no translations of {language} were found",
+    ),
+];
+
+/// Configures the language of the messages of the current run, reading the catalogs of the
+/// language of the environment
+///
+/// # Errors
+/// This function will return an error, naming the file, if a catalog cannot be read or is not
+/// valid, in which case the catalogs that could be read are used anyway
+pub fn configure() -> Result<(), String> {
+    let mut catalog = Catalog::new();
+    let language = match language() {
+        Some(language) => language,
+        None => {
+            *CATALOG.write().unwrap() = catalog;
+            return Ok(());
+        }
+    };
+
+    let mut result = Ok(());
+    let mut found = false;
+    for file in catalog_files(&language) {
+        match fs::read_to_string(&file) {
+            Ok(contents) => {
+                found = true;
+                if let Err(e) = read_catalog(&contents, &mut catalog) {
+                    result = Err(text(
+                        "translation-error",
+                        &[("path", &file.display()), ("error", &e)],
+                    ));
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => {
+                result = Err(text(
+                    "translation-error",
+                    &[("path", &file.display()), ("error", &e)],
+                ))
+            }
+        }
+    }
+    *CATALOG.write().unwrap() = catalog;
+
+    // Only languages that a user asked for explicitly must be translated, since English is the
+    // fallback of every locale
+    if !found && env::var_os("LUMINS_LANG").is_some() {
+        return Err(text("unknown-language", &[("language", &language)]));
+    }
+    result
+}
+
+/// Gets the text of the message `id` in the language of the current run, with the names in
+/// braces filled in with `args`
+///
+/// # Arguments
+/// * `id`: id of the message, such as `source-not-dir`
+/// * `args`: names and values to fill in
+pub fn text(id: &str, args: &[(&str, &dyn fmt::Display)]) -> String {
+    let catalog = CATALOG.read().unwrap();
+    let template = catalog
+        .get(id)
+        .map(String::as_str)
+        .or_else(|| english(id))
+        .unwrap_or(id);
+    fill(template, args)
+}
+
+/// Checks whether `answer` to a question asked with `confirm-answers` means yes
+pub fn is_yes(answer: &str) -> bool {
+    let answer = answer.trim().to_lowercase();
+    !answer.is_empty()
+        && text("confirm-yes", &[])
+            .split('|')
+            .any(|yes| yes.trim().to_lowercase() == answer)
+}
+
+/// Gets the English text of the message `id`
+fn english(id: &str) -> Option<&'static str> {
+    MESSAGES
+        .iter()
+        .find(|&&(message_id, _)| message_id == id)
+        .map(|&(_, english)| english)
+}
+
+/// Fills the names in braces in `template` with `args`, leaving names without a value as they are
+fn fill(template: &str, args: &[(&str, &dyn fmt::Display)]) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        let value = rest[start..].find('}').and_then(|end| {
+            let name = &rest[start + 1..start + end];
+            args.iter()
+                .find(|&&(arg, _)| arg == name)
+                .map(|(_, value)| (value.to_string(), start + end + 1))
+        });
+        match value {
+            Some((value, next)) => {
+                filled.push_str(&value);
+                rest = &rest[next..];
+            }
+            None => {
+                filled.push('{');
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+/// Gets the language of the environment, such as `de_AT`, without its encoding
+///
+/// # Returns
+/// * Some: The language
+/// * None: If no language is set, or it is the C locale, which is English
+fn language() -> Option<String> {
+    ["LUMINS_LANG", "LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|name| env::var(name).ok())
+        .find(|language| !language.is_empty())
+        .map(|language| {
+            language
+                .split(['.', '@'])
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        })
+        .filter(|language| !language.is_empty() && language != "C" && language != "POSIX")
+}
+
+/// Gets the catalog files of `language` that are read, whether they exist or not, in the order of
+/// precedence
+fn catalog_files(language: &str) -> Vec<PathBuf> {
+    // The catalog of the language comes before the catalog of its country, which overrides it
+    let mut names = vec![language];
+    if let Some((base, _)) = language.split_once('_') {
+        names.insert(0, base);
+    }
+
+    let dirs = config::dirs()
+        .into_iter()
+        .map(|dir| dir.join("locale"))
+        .chain(env::var_os("LUMINS_LOCALE_DIR").map(PathBuf::from));
+    dirs.flat_map(|dir| {
+        names
+            .iter()
+            .map(|name| Path::new(&dir).join(format!("{}.toml", name)))
+            .collect::<Vec<PathBuf>>()
+    })
+    .collect()
+}
+
+/// Reads the texts of the catalog `contents` into `catalog`, overriding those it already has
+///
+/// # Errors
+/// This function will return an error if `contents` is not valid TOML, or has an id that is
+/// unknown or a text that is not a string
+fn read_catalog(contents: &str, catalog: &mut Catalog) -> Result<(), String> {
+    let table: Table = toml::from_str(contents).map_err(|e| e.to_string())?;
+    for (id, value) in table.iter() {
+        if english(id).is_none() {
+            return Err(format!("{} is not a known message", id));
+        }
+        let text = value
+            .as_str()
+            .ok_or_else(|| format!("{} must be a string", id))?;
+        catalog.insert(id.clone(), text.to_string());
+    }
+    Ok(())
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_locale {
+    use super::*;
+
+    #[test]
+    fn filled() {
+        assert_eq!(
+            text("target-not-dir", &[("path", &"/mnt/nas")]),
+            "Target Error -- /mnt/nas is not a directory"
+        );
+        assert_eq!(
+            fill("{a} {b} {} {a", &[("a", &1), ("c", &3)]),
+            "1 {b} {} {a"
+        );
+        assert_eq!(is_yes(" Yes\n"), true);
+        assert_eq!(is_yes(""), false);
+
+        // Every message has a single English text
+        let mut ids: Vec<&str> = MESSAGES.iter().map(|&(id, _)| id).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), MESSAGES.len());
+    }
+
+    #[test]
+    fn catalogs() {
+        let mut catalog = Catalog::new();
+        let language = "source-not-dir = \"Quellfehler -- {path} ist kein Verzeichnis\"\n\
+                        confirm-yes = \"j|ja\"\n";
+        assert_eq!(read_catalog(language, &mut catalog).is_ok(), true);
+        let country = "confirm-yes = \"j|ja|jo\"\n";
+        assert_eq!(read_catalog(country, &mut catalog).is_ok(), true);
+        assert_eq!(catalog.get("confirm-yes").unwrap(), "j|ja|jo");
+        assert_eq!(catalog.len(), 2);
+
+        assert_eq!(
+            read_catalog("sorce-not-dir = \"\"\n", &mut catalog),
+            Err(String::from("sorce-not-dir is not a known message"))
+        );
+        assert_eq!(
+            read_catalog("source-not-dir = 1\n", &mut catalog).is_err(),
+            true
+        );
+
+        let files = catalog_files("de_AT");
+        let names: Vec<&str> = files
+            .iter()
+            .take(2)
+            .filter_map(|file| file.file_name()?.to_str())
+            .collect();
+        assert_eq!(names, vec!["de.toml", "de_AT.toml"]);
+    }
+}
@@ -1,4 +1,43 @@
+pub mod api;
+pub mod attributes;
+pub mod backups;
+pub mod config;
+pub mod context;
 pub mod core;
+pub mod dedupe;
+pub mod degrade;
+pub mod delta;
+pub mod events;
 pub mod file_ops;
+pub mod filter;
+pub mod history;
+pub mod ingest;
+pub mod journal;
+pub mod listing;
+pub mod locale;
+pub mod mounts;
+pub mod mtree;
 pub mod parse;
+pub mod paths;
+pub mod plan;
 pub mod progress;
+pub mod quota;
+pub mod recycle;
+pub mod reflinks;
+pub mod report;
+pub mod resources;
+pub mod reuse;
+pub mod rollback;
+pub mod session;
+pub mod snapshot;
+pub mod staging;
+pub mod stall;
+pub mod state;
+pub mod stream;
+pub mod throttle;
+pub mod tiers;
+pub mod transform;
+pub mod usage;
+pub mod vanished;
+pub mod verify;
+pub mod xattrs;
@@ -0,0 +1,354 @@
+//! Contains utilities for inspecting mounted filesystems.
+
+use std::path::{Path, PathBuf};
+
+use hashbrown::HashMap;
+
+/// Filesystem types that are generated by the kernel rather than stored on a device
+///
+/// Copying these produces garbage, or never finishes at all
+pub const PSEUDO_FILESYSTEMS: [&str; 22] = [
+    "autofs",
+    "binfmt_misc",
+    "bpf",
+    "cgroup",
+    "cgroup2",
+    "configfs",
+    "debugfs",
+    "devfs",
+    "devpts",
+    "devtmpfs",
+    "efivarfs",
+    "fusectl",
+    "hugetlbfs",
+    "mqueue",
+    "nsfs",
+    "proc",
+    "pstore",
+    "rpc_pipefs",
+    "securityfs",
+    "selinuxfs",
+    "sysfs",
+    "tracefs",
+];
+
+/// Filesystem types whose contents live on another machine
+pub const NETWORK_FILESYSTEMS: [&str; 17] = [
+    "9p",
+    "afs",
+    "ceph",
+    "cifs",
+    "davfs",
+    "fuse.davfs2",
+    "fuse.glusterfs",
+    "fuse.rclone",
+    "fuse.s3fs",
+    "fuse.sshfs",
+    "glusterfs",
+    "lustre",
+    "ncpfs",
+    "nfs",
+    "nfs4",
+    "smb3",
+    "smbfs",
+];
+
+/// Mount points that hold pseudo-filesystems on virtually every system,
+/// used when the mount table cannot be read
+pub const PSEUDO_MOUNTPOINTS: [&str; 3] = ["/proc", "/sys", "/dev"];
+
+/// A struct that represents a single entry of the mount table
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Mount {
+    /// Device, dataset or remote location that is mounted
+    pub device: String,
+    /// Filesystem type of the mount
+    pub fs_type: String,
+}
+
+/// A struct that represents the mount table of the system
+#[derive(Eq, PartialEq, Debug, Default)]
+pub struct MountTable {
+    mounts: HashMap<PathBuf, Mount>,
+}
+
+impl MountTable {
+    /// Reads the mount table of the running system
+    ///
+    /// # Returns
+    /// The mount table, which is empty if it could not be read
+    #[cfg(target_os = "linux")]
+    pub fn load() -> Self {
+        match std::fs::read_to_string("/proc/self/mounts") {
+            Ok(contents) => MountTable::parse(&contents),
+            Err(_) => MountTable::default(),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn load() -> Self {
+        MountTable::default()
+    }
+
+    /// Parses a mount table in the fstab format used by `/proc/self/mounts`
+    ///
+    /// # Arguments
+    /// * `contents`: contents of the mount table
+    ///
+    /// # Returns
+    /// The parsed mount table
+    pub fn parse(contents: &str) -> Self {
+        let mounts = contents
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let device = fields.next()?;
+                let point = fields.next()?;
+                let fs_type = fields.next()?;
+                let mount = Mount {
+                    device: unescape(device),
+                    fs_type: fs_type.to_string(),
+                };
+                Some((PathBuf::from(unescape(point)), mount))
+            })
+            .collect();
+
+        MountTable { mounts }
+    }
+
+    /// Checks whether the mount table has any entries
+    ///
+    /// # Returns
+    /// True if no mounts are known
+    pub fn is_empty(&self) -> bool {
+        self.mounts.is_empty()
+    }
+
+    /// Gets the filesystem type mounted exactly at `path`
+    ///
+    /// # Arguments
+    /// * `path`: absolute path to check
+    ///
+    /// # Returns
+    /// * Some: The filesystem type, if `path` is a mount point
+    /// * None: If `path` is not a mount point
+    pub fn fs_type(&self, path: &Path) -> Option<&str> {
+        self.mounts.get(path).map(|mount| mount.fs_type.as_str())
+    }
+
+    /// Gets the mount that contains `path`
+    ///
+    /// # Arguments
+    /// * `path`: absolute path to check
+    ///
+    /// # Returns
+    /// * Some: The closest mount point above `path`, and the mount there
+    /// * None: If no mount contains `path`
+    pub fn containing_mount<'a>(&'a self, path: &'a Path) -> Option<(&'a Path, &'a Mount)> {
+        path.ancestors()
+            .find_map(|p| self.mounts.get(p).map(|mount| (p, mount)))
+    }
+
+    /// Gets the filesystem type of the mount that contains `path`
+    ///
+    /// # Arguments
+    /// * `path`: absolute path to check
+    ///
+    /// # Returns
+    /// * Some: The filesystem type of the closest mount point above `path`
+    /// * None: If no mount contains `path`
+    pub fn containing_fs_type(&self, path: &Path) -> Option<&str> {
+        path.ancestors().find_map(|p| self.fs_type(p))
+    }
+
+    /// Checks whether the directory `path` is the mount point of a pseudo-filesystem
+    ///
+    /// # Arguments
+    /// * `path`: absolute path of the directory to check
+    ///
+    /// # Returns
+    /// * Some: The pseudo-filesystem type mounted at `path`
+    /// * None: If `path` is not a pseudo-filesystem mount point
+    pub fn pseudo_mountpoint(&self, path: &Path) -> Option<&str> {
+        if self.is_empty() {
+            return PSEUDO_MOUNTPOINTS
+                .iter()
+                .find(|&&point| Path::new(point) == path)
+                .map(|_| "unknown");
+        }
+
+        self.fs_type(path).filter(|fs_type| is_pseudo_fs(fs_type))
+    }
+
+    /// Checks whether the directory `path` is the mount point of a network filesystem
+    ///
+    /// # Arguments
+    /// * `path`: absolute path of the directory to check
+    ///
+    /// # Returns
+    /// * Some: The network filesystem type mounted at `path`
+    /// * None: If `path` is not a network filesystem mount point
+    pub fn network_mountpoint(&self, path: &Path) -> Option<&str> {
+        self.fs_type(path).filter(|fs_type| is_network_fs(fs_type))
+    }
+
+    /// Checks whether `path` lies on a pseudo-filesystem
+    ///
+    /// # Arguments
+    /// * `path`: absolute path to check
+    ///
+    /// # Returns
+    /// * Some: The pseudo-filesystem type that `path` lies on
+    /// * None: If `path` lies on a regular filesystem
+    pub fn pseudo_fs_of(&self, path: &Path) -> Option<&str> {
+        if self.is_empty() {
+            return PSEUDO_MOUNTPOINTS
+                .iter()
+                .find(|&&point| path.starts_with(point))
+                .map(|_| "unknown");
+        }
+
+        self.containing_fs_type(path)
+            .filter(|fs_type| is_pseudo_fs(fs_type))
+    }
+}
+
+/// Checks whether the given filesystem type is a pseudo-filesystem
+///
+/// # Arguments
+/// * `fs_type`: filesystem type, as listed in the mount table
+///
+/// # Returns
+/// True if `fs_type` is a pseudo-filesystem
+pub fn is_pseudo_fs(fs_type: &str) -> bool {
+    PSEUDO_FILESYSTEMS.contains(&fs_type)
+}
+
+/// Checks whether the given filesystem type is a network filesystem
+///
+/// # Arguments
+/// * `fs_type`: filesystem type, as listed in the mount table
+///
+/// # Returns
+/// True if `fs_type` is a network filesystem
+pub fn is_network_fs(fs_type: &str) -> bool {
+    NETWORK_FILESYSTEMS.contains(&fs_type)
+}
+
+/// Decodes the octal escapes (`\040` for space, etc.) used in mount table fields
+///
+/// # Arguments
+/// * `field`: escaped field
+///
+/// # Returns
+/// The unescaped field
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 4 <= bytes.len() {
+            let digits = std::str::from_utf8(&bytes[i + 1..i + 4]).unwrap_or("");
+            if let Ok(byte) = u8::from_str_radix(digits, 8) {
+                unescaped.push(byte);
+                i += 4;
+                continue;
+            }
+        }
+        unescaped.push(bytes[i]);
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&unescaped).to_string()
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_mount_table {
+    use super::*;
+
+    const MOUNTS: &str = "\
+/dev/sda1 / ext4 rw,relatime 0 0
+proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0
+sysfs /sys sysfs rw,nosuid,nodev,noexec,relatime 0 0
+tmpfs /dev/shm tmpfs rw,nosuid,nodev 0 0
+/dev/sdb1 /mnt/my\\040disk ext4 rw,relatime 0 0
+";
+
+    #[test]
+    fn parse() {
+        let mounts = MountTable::parse(MOUNTS);
+
+        assert_eq!(mounts.fs_type(Path::new("/")), Some("ext4"));
+        assert_eq!(mounts.fs_type(Path::new("/proc")), Some("proc"));
+        assert_eq!(mounts.fs_type(Path::new("/mnt/my disk")), Some("ext4"));
+        assert_eq!(mounts.fs_type(Path::new("/mnt")), None);
+    }
+
+    #[test]
+    fn containing_mount() {
+        let mounts = MountTable::parse(MOUNTS);
+
+        let (point, mount) = mounts
+            .containing_mount(Path::new("/mnt/my disk/data"))
+            .unwrap();
+        assert_eq!(point, Path::new("/mnt/my disk"));
+        assert_eq!(mount.device, "/dev/sdb1");
+        assert_eq!(
+            mounts.containing_mount(Path::new("/mnt")).unwrap().0,
+            Path::new("/")
+        );
+    }
+
+    #[test]
+    fn pseudo_mountpoint() {
+        let mounts = MountTable::parse(MOUNTS);
+
+        assert_eq!(mounts.pseudo_mountpoint(Path::new("/proc")), Some("proc"));
+        assert_eq!(mounts.pseudo_mountpoint(Path::new("/sys")), Some("sysfs"));
+        assert_eq!(mounts.pseudo_mountpoint(Path::new("/dev/shm")), None);
+        assert_eq!(mounts.pseudo_mountpoint(Path::new("/home")), None);
+    }
+
+    #[test]
+    fn network_mountpoint() {
+        let mounts = MountTable::parse(
+            "server:/export /mnt/nfs nfs4 rw 0 0\nuser@host:/ /mnt/ssh fuse.sshfs rw 0 0\n",
+        );
+
+        assert_eq!(
+            mounts.network_mountpoint(Path::new("/mnt/nfs")),
+            Some("nfs4")
+        );
+        assert_eq!(
+            mounts.network_mountpoint(Path::new("/mnt/ssh")),
+            Some("fuse.sshfs")
+        );
+        assert_eq!(mounts.network_mountpoint(Path::new("/mnt")), None);
+    }
+
+    #[test]
+    fn pseudo_fs_of() {
+        let mounts = MountTable::parse(MOUNTS);
+
+        assert_eq!(mounts.pseudo_fs_of(Path::new("/proc/self")), Some("proc"));
+        assert_eq!(mounts.pseudo_fs_of(Path::new("/dev/shm/data")), None);
+        assert_eq!(mounts.pseudo_fs_of(Path::new("/home/user")), None);
+    }
+
+    #[test]
+    fn empty_table() {
+        let mounts = MountTable::default();
+
+        assert_eq!(mounts.pseudo_mountpoint(Path::new("/proc")).is_some(), true);
+        assert_eq!(
+            mounts.pseudo_fs_of(Path::new("/sys/kernel")).is_some(),
+            true
+        );
+        assert_eq!(mounts.pseudo_fs_of(Path::new("/home")), None);
+    }
+}
@@ -0,0 +1,479 @@
+//! Contains BSD mtree specifications, which `lms ls --format mtree` writes and `lms mtree-check`
+//! verifies a directory against, so that manifests can be exchanged with mtree(8), bsdtar, and
+//! pkg based workflows on BSD systems.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use hashbrown::{HashMap, HashSet};
+use log::info;
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+
+use crate::lumins::events::{self, Action};
+use crate::lumins::file_ops::{self, FileOps};
+use crate::lumins::parse::Options;
+use crate::lumins::state;
+use crate::lumins::verify::Mismatch;
+use crate::progress;
+
+/// Struct to represent an entry of an mtree specification, along with the keywords set for it
+/// with `/set`
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Entry {
+    /// Path of the entry, relative to the root of the specification
+    pub path: PathBuf,
+    keywords: HashMap<String, String>,
+}
+
+impl Entry {
+    /// Gets the value of the keyword `name`, which is empty for keywords without a value such as
+    /// `optional`
+    pub fn keyword(&self, name: &str) -> Option<&str> {
+        self.keywords.get(name).map(String::as_str)
+    }
+}
+
+/// Reads the mtree specification at `path`
+///
+/// # Errors
+/// This function will return an error if `path` cannot be read or is not a valid specification
+pub fn read(path: &Path) -> Result<Vec<Entry>, io::Error> {
+    let spec = fs::read(path).and_then(|spec| {
+        String::from_utf8(spec)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    });
+    spec.and_then(|spec| parse(&spec))
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+/// Parses an mtree specification
+///
+/// Entries are given either by a name relative to the last directory entered, where `..` leaves
+/// it, as mtree(8) writes them, or by a path containing a `/`, as bsdtar writes them. Keywords
+/// given with `/set` apply to all following entries, until they are removed with `/unset`.
+///
+/// # Errors
+/// This function will return an error if the specification leaves its root or holds an unknown
+/// command
+pub fn parse(spec: &str) -> Result<Vec<Entry>, io::Error> {
+    let mut entries = Vec::new();
+    let mut defaults: HashMap<String, String> = HashMap::new();
+    let mut cwd = PathBuf::new();
+    let mut continued = String::new();
+
+    for (number, line) in spec.lines().enumerate() {
+        // A backslash at the end of a line continues it on the next line
+        if let Some(line) = line.strip_suffix('\\') {
+            continued.push_str(line);
+            continued.push(' ');
+            continue;
+        }
+        continued.push_str(line);
+        let line = std::mem::take(&mut continued);
+
+        let mut words = line.split_whitespace();
+        let name = match words.next() {
+            Some(name) if !name.starts_with('#') => name,
+            _ => continue,
+        };
+        let invalid = |message: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {}", number + 1, message),
+            )
+        };
+
+        match name {
+            "/set" => defaults.extend(words.map(keyword)),
+            "/unset" => {
+                for word in words {
+                    if word == "all" {
+                        defaults.clear();
+                    } else {
+                        defaults.remove(word);
+                    }
+                }
+            }
+            ".." => {
+                if !cwd.pop() {
+                    return Err(invalid("leaves the root of the specification"));
+                }
+            }
+            _ if name.starts_with('/') => {
+                return Err(invalid(&format!("unknown command {}", name)));
+            }
+            _ => {
+                let mut keywords = defaults.clone();
+                keywords.extend(words.map(keyword));
+
+                let unescaped = state::key_path(&unescape(name));
+                let path: PathBuf = if name.contains('/') {
+                    unescaped
+                        .components()
+                        .filter(|component| *component != Component::CurDir)
+                        .collect()
+                } else if name == "." {
+                    PathBuf::new()
+                } else {
+                    cwd.join(unescaped)
+                };
+
+                // Only relative entries of directories are entered
+                if keywords.get("type").map(String::as_str) == Some("dir")
+                    && !name.contains('/')
+                    && name != "."
+                {
+                    cwd = path.clone();
+                }
+                entries.push(Entry { path, keywords });
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Verifies every entry in `target` against the mtree specification at `spec`, and reports all
+/// mismatches
+///
+/// The type, size, symlink target, mode, and SHA-256 digest of every entry are verified where
+/// the specification gives them, and everything in `target` that the specification does not hold
+/// is reported as well. Entries with the `optional` keyword may be missing, and nothing below
+/// entries with the `ignore` keyword is reported.
+///
+/// # Arguments
+/// * `spec`: mtree specification to verify against
+/// * `target`: directory to verify
+/// * `options`: command line options, of which `filter` skips paths of `target`
+///
+/// # Errors
+/// This function will return an error if `spec` or `target` cannot be read, or if anything in
+/// `target` does not match `spec`
+pub fn check(spec: &Path, target: &Path, options: &Options) -> Result<(), io::Error> {
+    let entries = read(spec)?;
+
+    progress::scan_init("Scanning");
+    let file_sets = file_ops::get_all_files(target, options)?;
+
+    progress::phase_init("Verifying", entries.len() as u64);
+    let mut mismatches: Vec<(PathBuf, Mismatch)> = entries
+        .par_iter()
+        .filter_map(|entry| {
+            progress::bar().inc(1);
+            compare_entry(entry, target).map(|mismatch| (entry.path.clone(), mismatch))
+        })
+        .collect();
+
+    // Everything in the target must also be in the specification
+    let listed: HashSet<&Path> = entries.iter().map(|entry| entry.path.as_path()).collect();
+    let ignored: Vec<&Path> = entries
+        .iter()
+        .filter(|entry| entry.keyword("ignore").is_some())
+        .map(|entry| entry.path.as_path())
+        .collect();
+    mismatches.extend(
+        file_sets
+            .files()
+            .iter()
+            .map(FileOps::path)
+            .chain(file_sets.dirs().iter().map(FileOps::path))
+            .chain(file_sets.symlinks().iter().map(FileOps::path))
+            .filter(|path| !listed.contains(path.as_path()))
+            .filter(|path| !ignored.iter().any(|dir| path.starts_with(dir)))
+            .map(|path| (path.clone(), Mismatch::Extra)),
+    );
+    mismatches.sort();
+
+    info!(
+        "Verified {} entries in {:?} against {:?}",
+        entries.len(),
+        target,
+        spec
+    );
+    if mismatches.is_empty() {
+        return Ok(());
+    }
+    for (path, mismatch) in mismatches.iter() {
+        events::record(
+            Action::Warning,
+            path,
+            format_args!("Warning -- {:?} {}", path, mismatch),
+        );
+    }
+    Err(io::Error::other(format!(
+        "{} entries of {} do not match {}",
+        mismatches.len(),
+        target.display(),
+        spec.display()
+    )))
+}
+
+/// Compares a single entry in `target` with its specification
+///
+/// # Returns
+/// * Some: How the entry differs from its specification
+/// * None: If the entry matches its specification
+fn compare_entry(entry: &Entry, target: &Path) -> Option<Mismatch> {
+    let path = target.join(&entry.path);
+    let metadata = match fs::symlink_metadata(&path) {
+        Ok(metadata) => metadata,
+        Err(_) if entry.keyword("optional").is_some() => return None,
+        Err(_) => return Some(Mismatch::Missing),
+    };
+
+    let file_type = metadata.file_type();
+    let matches_type = match entry.keyword("type") {
+        Some("file") => file_type.is_file(),
+        Some("dir") => file_type.is_dir(),
+        Some("link") => file_type.is_symlink(),
+        _ => true,
+    };
+    if !matches_type {
+        return Some(Mismatch::Type);
+    }
+
+    if let Some(Ok(expected)) = entry.keyword("size").map(str::parse) {
+        if file_type.is_file() && metadata.len() != expected {
+            return Some(Mismatch::Size {
+                expected,
+                actual: metadata.len(),
+            });
+        }
+    }
+
+    if let Some(link) = entry.keyword("link") {
+        match fs::read_link(&path) {
+            Ok(target) if target == state::key_path(&unescape(link)) => (),
+            _ => return Some(Mismatch::Target),
+        }
+    }
+
+    if let (Some(Ok(expected)), Some(actual)) = (
+        entry
+            .keyword("mode")
+            .map(|mode| u32::from_str_radix(mode, 8)),
+        mode(&metadata),
+    ) {
+        if expected & 0o7777 != actual {
+            return Some(Mismatch::Mode { expected, actual });
+        }
+    }
+
+    if let Some(expected) = entry
+        .keyword("sha256digest")
+        .or_else(|| entry.keyword("sha256"))
+    {
+        if file_type.is_file() {
+            match sha256_file(&path) {
+                Ok(actual) if actual == expected.to_ascii_lowercase() => (),
+                _ => return Some(Mismatch::Contents),
+            }
+        }
+    }
+    None
+}
+
+/// Splits a keyword of an entry or of `/set` into its name and value
+fn keyword(word: &str) -> (String, String) {
+    match word.split_once('=') {
+        Some((name, value)) => (name.to_string(), value.to_string()),
+        None => (word.to_string(), String::new()),
+    }
+}
+
+/// Escapes a name for mtree, in which whitespace, `#`, `=`, `\`, and all bytes that are not
+/// printable ASCII are written as a backslash and three octal digits
+pub fn escape(name: &[u8]) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for &byte in name {
+        if byte.is_ascii_graphic() && byte != b'#' && byte != b'=' && byte != b'\\' {
+            escaped.push(byte as char);
+        } else {
+            let _ = write!(escaped, "\\{:03o}", byte);
+        }
+    }
+    escaped
+}
+
+/// Reverses `escape`, along with the `\s`, `\t`, `\n`, and `\\` escapes that some
+/// implementations write
+fn unescape(name: &str) -> Vec<u8> {
+    let bytes = name.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes
+            .get(i + 1..i + 4)
+            .filter(|digits| digits.iter().all(|digit| (b'0'..=b'7').contains(digit)))
+            .and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok());
+        match (bytes[i], octal, bytes.get(i + 1)) {
+            (b'\\', Some(byte), _) => {
+                unescaped.push(byte);
+                i += 4;
+            }
+            (b'\\', None, Some(&escaped)) => {
+                unescaped.push(match escaped {
+                    b's' => b' ',
+                    b't' => b'\t',
+                    b'n' => b'\n',
+                    other => other,
+                });
+                i += 2;
+            }
+            (byte, _, _) => {
+                unescaped.push(byte);
+                i += 1;
+            }
+        }
+    }
+    unescaped
+}
+
+/// Gets the SHA-256 digest of the file at `path`, in lower case hexadecimal like mtree writes it
+///
+/// # Errors
+/// This function will return an error if the file cannot be read
+pub fn sha256_file(path: &Path) -> Result<String, io::Error> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+
+    let mut digest = String::with_capacity(64);
+    for byte in hasher.finalize() {
+        let _ = write!(digest, "{:02x}", byte);
+    }
+    Ok(digest)
+}
+
+/// Gets the permission bits of an entry
+#[cfg(target_family = "unix")]
+pub fn mode(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(target_family = "windows")]
+pub fn mode(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_mtree {
+    use super::*;
+
+    #[test]
+    fn escapes() {
+        assert_eq!(escape(b"a b#=\\\xff"), "a\\040b\\043\\075\\134\\377");
+        assert_eq!(
+            unescape("a\\040b\\043\\075\\134\\377"),
+            b"a b#=\\\xff".to_vec()
+        );
+        assert_eq!(unescape("a\\sb\\\\c"), b"a b\\c".to_vec());
+    }
+
+    #[test]
+    fn relative_and_full_paths() {
+        let spec = "#mtree\n\
+                    /set type=file mode=0644\n\
+                    .               type=dir mode=0755\n\
+                    \x20   a\\040file   size=3 \\\n\
+                    \x20       sha256digest=abc\n\
+                    dir             type=dir mode=0755\n\
+                    \x20   nested      size=0\n\
+                    ..\n\
+                    /unset mode\n\
+                    ./dir/link      type=link link=nested\n\
+                    other           optional\n";
+        let entries = parse(spec).unwrap();
+        let paths: Vec<&Path> = entries.iter().map(|entry| entry.path.as_path()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                Path::new(""),
+                Path::new("a file"),
+                Path::new("dir"),
+                Path::new("dir/nested"),
+                Path::new("dir/link"),
+                Path::new("other"),
+            ]
+        );
+        assert_eq!(entries[1].keyword("type"), Some("file"));
+        assert_eq!(entries[1].keyword("mode"), Some("0644"));
+        assert_eq!(entries[1].keyword("sha256digest"), Some("abc"));
+        assert_eq!(entries[4].keyword("mode"), None);
+        assert_eq!(entries[5].keyword("optional"), Some(""));
+
+        assert_eq!(parse("..\n").is_err(), true);
+        assert_eq!(parse("/include other\n").is_err(), true);
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn mismatches() {
+        const TEST_DIR: &str = "test_mtree_mismatches";
+
+        fs::create_dir_all([TEST_DIR, "dir"].join("/")).unwrap();
+        fs::write([TEST_DIR, "same.txt"].join("/"), b"same").unwrap();
+        fs::write([TEST_DIR, "changed.txt"].join("/"), b"0riginal").unwrap();
+        fs::write([TEST_DIR, "extra.txt"].join("/"), b"extra").unwrap();
+        fs::write([TEST_DIR, "dir/ignored.txt"].join("/"), b"ignored").unwrap();
+
+        let spec = format!(
+            "/set type=file\n\
+             . type=dir\n\
+             ./same.txt size=4 sha256digest={}\n\
+             ./changed.txt size=8 sha256digest={}\n\
+             ./missing.txt size=1\n\
+             ./dir type=file\n\
+             ./optional.txt optional\n",
+            sha256_file(Path::new(&[TEST_DIR, "same.txt"].join("/"))).unwrap(),
+            sha256_file(Path::new(&[TEST_DIR, "same.txt"].join("/"))).unwrap(),
+        );
+        let entries = parse(&spec).unwrap();
+        let mismatches: Vec<Option<Mismatch>> = entries
+            .iter()
+            .map(|entry| compare_entry(entry, Path::new(TEST_DIR)))
+            .collect();
+        assert_eq!(
+            mismatches,
+            vec![
+                None,
+                None,
+                Some(Mismatch::Contents),
+                Some(Mismatch::Missing),
+                Some(Mismatch::Type),
+                None,
+            ]
+        );
+
+        let spec_file = [TEST_DIR, "spec"].join("/");
+        fs::write(&spec_file, "/set type=file\n. type=dir ignore\n").unwrap();
+        assert_eq!(
+            check(
+                Path::new(&spec_file),
+                Path::new(TEST_DIR),
+                &Options::default()
+            )
+            .is_ok(),
+            true
+        );
+        fs::write(&spec_file, spec).unwrap();
+        assert_eq!(
+            check(
+                Path::new(&spec_file),
+                Path::new(TEST_DIR),
+                &Options::default()
+            )
+            .is_err(),
+            true
+        );
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
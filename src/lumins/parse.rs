@@ -1,23 +1,410 @@
 //! Some utilities for command line parsing.
 
-use std::env;
+use std::ffi::OsStr;
+use std::fmt;
 use std::fs;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 
 use bitflags::bitflags;
 use clap::ArgMatches;
 use env_logger::Builder;
 use log::LevelFilter;
+use rayon::ThreadPoolBuilder;
 
-use crate::progress::PROGRESS_BAR;
+use crate::lumins::config;
+use crate::lumins::filter::{self, Filter, Order};
+use crate::lumins::history;
+use crate::lumins::locale;
+use crate::lumins::mounts::MountTable;
+use crate::lumins::paths;
+use crate::lumins::session;
+use crate::lumins::transform::{self, Transform};
+use crate::progress;
 
 bitflags! {
     /// Enum to represent command line flags
-    pub struct Flag: u32 {
-        const NO_DELETE     = 0x1;
-        const SECURE        = 0x2;
-        const VERBOSE       = 0x4;
-        const SEQUENTIAL    = 0x8;
+    #[derive(Default)]
+    pub struct Flag: u64 {
+        const NO_DELETE              = 0x1;
+        const SECURE                 = 0x2;
+        const VERBOSE                = 0x4;
+        const SEQUENTIAL             = 0x8;
+        const SKIP_DATALESS          = 0x10;
+        const ALLOW_PSEUDO_FS        = 0x20;
+        const EXCLUDE_NETWORK_MOUNTS = 0x40;
+        const PERMS                  = 0x80;
+        const OWNER                  = 0x100;
+        const DEDUPE_DEST            = 0x200;
+        const REUSE_DEST             = 0x400;
+        const SKIP_UNCHANGED_DIRS    = 0x800;
+        const JOURNAL                = 0x1000;
+        const STATE                  = 0x2000;
+        const LOCK_SOURCE            = 0x4000;
+        const SNAPSHOT               = 0x8000;
+        const METADATA_ONLY          = 0x10000;
+        const TIMES                  = 0x20000;
+        const STRICT                 = 0x40000;
+        const ABORT_STALLED          = 0x80000;
+        const REMOVE_SOURCE_FILES    = 0x100000;
+        const VERIFY_RESTORE         = 0x200000;
+        const DRY_RUN                = 0x400000;
+        const NO_CACHE               = 0x800000;
+        const DELTA                  = 0x1000000;
+        const REFLINK_HARDLINKS      = 0x2000000;
+        const DETECT_RENAMES         = 0x4000000;
+        const FORCE_NESTED           = 0x8000000;
+        const TRASH                  = 0x10000000;
+        const SHA256                 = 0x20000000;
+        const CHECKSUM               = 0x40000000;
+        const PRUNE_EMPTY_DIRS       = 0x80000000;
+        const ONE_FILE_SYSTEM        = 0x100000000;
+        const HARD_LINKS             = 0x200000000;
+        const STAGE_DELETIONS        = 0x400000000;
+        const SPARSE                 = 0x800000000;
+        const XATTRS                 = 0x1000000000;
+        const NO_ENV_MUTATION        = 0x2000000000;
+    }
+}
+
+/// Names of the command line flags, which are also the keys of the flags in config files
+pub const FLAG_NAMES: [(&str, Flag); 37] = [
+    ("nodelete", Flag::NO_DELETE),
+    ("secure", Flag::SECURE),
+    ("verbose", Flag::VERBOSE),
+    ("sequential", Flag::SEQUENTIAL),
+    ("allow-pseudo-fs", Flag::ALLOW_PSEUDO_FS),
+    ("force-nested", Flag::FORCE_NESTED),
+    ("exclude-network-mounts", Flag::EXCLUDE_NETWORK_MOUNTS),
+    ("one-file-system", Flag::ONE_FILE_SYSTEM),
+    ("perms", Flag::PERMS),
+    ("owner", Flag::OWNER),
+    ("times", Flag::TIMES),
+    ("xattrs", Flag::XATTRS),
+    ("dedupe-dest", Flag::DEDUPE_DEST),
+    ("reuse-dest", Flag::REUSE_DEST),
+    ("detect-renames", Flag::DETECT_RENAMES),
+    ("reflink-hardlinks", Flag::REFLINK_HARDLINKS),
+    ("hard-links", Flag::HARD_LINKS),
+    ("skip-unchanged-dirs", Flag::SKIP_UNCHANGED_DIRS),
+    ("journal", Flag::JOURNAL),
+    ("state", Flag::STATE),
+    ("lock-source", Flag::LOCK_SOURCE),
+    ("snapshot", Flag::SNAPSHOT),
+    ("metadata-only", Flag::METADATA_ONLY),
+    ("strict", Flag::STRICT),
+    ("abort-stalled", Flag::ABORT_STALLED),
+    ("remove-source-files", Flag::REMOVE_SOURCE_FILES),
+    ("verify-restore", Flag::VERIFY_RESTORE),
+    ("dry-run", Flag::DRY_RUN),
+    ("no-cache", Flag::NO_CACHE),
+    ("delta", Flag::DELTA),
+    ("sparse", Flag::SPARSE),
+    ("trash", Flag::TRASH),
+    ("stage-deletions", Flag::STAGE_DELETIONS),
+    ("sha256", Flag::SHA256),
+    ("checksum", Flag::CHECKSUM),
+    ("prune-empty-dirs", Flag::PRUNE_EMPTY_DIRS),
+    ("no-env-mutation", Flag::NO_ENV_MUTATION),
+];
+
+/// Struct to represent command line options, both flags and options that carry values
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub flags: Flag,
+    /// Mount points that traversal never descends into
+    pub exclude_mounts: Vec<PathBuf>,
+    /// Patterns of the paths that traversal skips
+    pub filter: Filter,
+    /// Paths relative to the source that are transferred before everything else, in order
+    pub priority: Vec<PathBuf>,
+    /// Groups of files that are copied one after another
+    pub order: Order,
+    /// Paths relative to the source, and the destination, of the only subtrees to synchronize
+    pub only: Vec<PathBuf>,
+    /// File descriptor or named pipe that receives machine readable progress records
+    pub progress_fd: Option<String>,
+    /// How long a single copy may make no progress before it is reported
+    pub stall_timeout: Option<Duration>,
+    /// Size in bytes from which files are copied with the threads for large files
+    pub large_file_size: Option<u64>,
+    /// Number of large files copied at once
+    pub large_file_jobs: Option<usize>,
+    /// Number of threads that files are handled with, instead of one per CPU
+    pub threads: Option<usize>,
+    /// Commands that the files matching their patterns are copied through
+    pub transforms: Vec<Transform>,
+    /// Size in bytes of the blocks that delta copies compare and rewrite
+    pub block_size: Option<usize>,
+    /// Bytes per second that copies read from the source
+    pub read_bwlimit: Option<u64>,
+    /// Size in bytes below which files are hashed to compare them, whatever their modification time
+    pub checksum_under: Option<u64>,
+    /// How far modification times may differ and still be taken as the same
+    pub modify_window: Option<Duration>,
+    /// How long a file in a hot folder must go without changes before it is ingested
+    pub settle: Option<Duration>,
+    /// How long a hot folder must go without changes before ingesting it stops
+    pub idle_exit: Option<Duration>,
+    /// Size in bytes that the files in the destination may take up after a synchronization
+    pub max_dest_size: Option<u64>,
+    /// Size in bytes below which files are skipped
+    pub min_size: Option<u64>,
+    /// Size in bytes above which files are skipped
+    pub max_size: Option<u64>,
+    /// Number of entries after which a scan is stopped with an error
+    pub max_files: Option<u64>,
+    /// Depth below which a scan is stopped with an error
+    pub max_depth: Option<usize>,
+    /// What happens to destination files that were modified later than their source
+    pub on_dest_newer: DestNewer,
+    /// Whether files are cloned instead of copied
+    pub reflink: Reflink,
+    /// Which source files are transferred, depending on whether they exist in the destination
+    pub existing: Existing,
+    /// Directory that receives the log and summary of the run
+    pub log_dir: Option<PathBuf>,
+    /// Format of what is written to stdout about every action taken
+    pub output: Output,
+    /// File that what the run planned, did and failed to do is written to as JSON
+    pub report: Option<PathBuf>,
+    /// Directory that destination files are moved into instead of being overwritten or deleted
+    pub backup_dir: Option<PathBuf>,
+    /// Time that `lms du` takes the ages of files from
+    pub age_by: AgeBy,
+    /// Side that `lms verify` copies every mismatched entry from, to repair the other side
+    pub repair: Option<Side>,
+    /// Format that `lms ls` writes the entries of a directory in
+    pub list_format: ListFormat,
+    /// Name of the profile whose history the run is recorded in, or shown by `lms history`
+    pub profile: Option<String>,
+    /// Number of runs that `lms history` shows
+    pub last: Option<usize>,
+    /// Level of the log records shown, unless --verbose is given
+    pub log_level: Option<LevelFilter>,
+}
+
+impl From<Flag> for Options {
+    fn from(flags: Flag) -> Self {
+        Options {
+            flags,
+            ..Options::default()
+        }
+    }
+}
+
+/// Enum to represent what happens to a destination file that was modified later than its source,
+/// and whose contents differ from it
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default)]
+pub enum DestNewer {
+    /// The destination file is overwritten, like any other differing file
+    #[default]
+    Overwrite,
+    /// The destination file is kept as it is
+    Skip,
+    /// The destination file is renamed with a `~` suffix before it is overwritten
+    Backup,
+    /// The user is asked whether to overwrite the destination file
+    Prompt,
+}
+
+/// Enum to represent which source files are transferred, depending on whether they exist in the
+/// destination
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default)]
+pub enum Existing {
+    /// Every source file is transferred
+    #[default]
+    All,
+    /// Only files that exist in the destination are updated, and nothing is created in it
+    Only,
+    /// Only files that do not exist in the destination are created, and nothing in it is updated
+    Ignore,
+}
+
+/// Enum to represent whether files are cloned on filesystems with copy-on-write support, such
+/// as Btrfs and XFS, instead of having their contents copied
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default)]
+pub enum Reflink {
+    /// Files are copied
+    #[default]
+    Never,
+    /// Files are cloned where the filesystem supports it, and copied elsewhere
+    Auto,
+    /// Files are cloned, and fail to copy where the filesystem does not support it
+    Always,
+}
+
+impl FromStr for Reflink {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "never" => Ok(Reflink::Never),
+            "auto" => Ok(Reflink::Auto),
+            "always" => Ok(Reflink::Always),
+            _ => Err(format!(
+                "expected one of auto, always or never, found {}",
+                mode
+            )),
+        }
+    }
+}
+
+impl FromStr for DestNewer {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "overwrite" => Ok(DestNewer::Overwrite),
+            "skip" => Ok(DestNewer::Skip),
+            "backup" => Ok(DestNewer::Backup),
+            "prompt" => Ok(DestNewer::Prompt),
+            _ => Err(format!(
+                "expected one of overwrite, skip, backup or prompt, found {}",
+                policy
+            )),
+        }
+    }
+}
+
+/// Enum to represent the format of what is written to stdout about every action taken
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default)]
+pub enum Output {
+    /// Nothing but the log, which is meant for people
+    #[default]
+    Text,
+    /// One JSON object per action, followed by a summary, which is meant for programs
+    Json,
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "text" => Ok(Output::Text),
+            "json" => Ok(Output::Json),
+            _ => Err(format!("expected one of text or json, found {}", format)),
+        }
+    }
+}
+
+/// Enum to represent the time that the age of a file is taken from
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default)]
+pub enum AgeBy {
+    /// The time the file was last modified
+    #[default]
+    Modify,
+    /// The time the file was last accessed, which filesystems mounted with noatime do not update
+    Access,
+}
+
+impl FromStr for AgeBy {
+    type Err = String;
+
+    fn from_str(time: &str) -> Result<Self, Self::Err> {
+        match time {
+            "modify" => Ok(AgeBy::Modify),
+            "access" => Ok(AgeBy::Access),
+            _ => Err(format!("expected one of modify or access, found {}", time)),
+        }
+    }
+}
+
+impl fmt::Display for AgeBy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            AgeBy::Modify => "modification",
+            AgeBy::Access => "access",
+        })
+    }
+}
+
+/// Enum to represent the format that `lms ls` writes the entries of a directory in
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default)]
+pub enum ListFormat {
+    /// Every path terminated by a NUL byte, like `find -print0`
+    #[default]
+    Print0,
+    /// An mtree specification, with the type, mode, size, and modification time of every entry
+    Mtree,
+}
+
+impl FromStr for ListFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "print0" => Ok(ListFormat::Print0),
+            "mtree" => Ok(ListFormat::Mtree),
+            _ => Err(format!("expected one of print0 or mtree, found {}", format)),
+        }
+    }
+}
+
+/// Enum to represent a side of a synchronization, such as the one taken as correct by
+/// `lms verify --repair`
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum Side {
+    /// The source directory
+    Src,
+    /// The destination directory
+    Dest,
+}
+
+impl FromStr for Side {
+    type Err = String;
+
+    fn from_str(side: &str) -> Result<Self, Self::Err> {
+        match side {
+            "src" => Ok(Side::Src),
+            "dest" => Ok(Side::Dest),
+            _ => Err(format!("expected one of src or dest, found {}", side)),
+        }
+    }
+}
+
+/// Struct to represent a size in bytes given on the command line, such as `512`, `10K`, or
+/// `1.5G`, where the suffixes K, M, G, T, and P are powers of 1024
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub struct Size(pub u64);
+
+impl FromStr for Size {
+    type Err = String;
+
+    fn from_str(size: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "expected a size such as 512, 10K, 1.5M or 2G, found {}",
+                size
+            )
+        };
+        let unit_start = size
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(size.len());
+        let (number, unit) = size.split_at(unit_start);
+        let unit = unit.to_ascii_uppercase();
+        let power = match unit.trim_end_matches("IB").trim_end_matches('B') {
+            "" => 0,
+            "K" => 1,
+            "M" => 2,
+            "G" => 3,
+            "T" => 4,
+            "P" => 5,
+            _ => return Err(invalid()),
+        };
+        if let (0, Ok(bytes)) = (power, number.parse()) {
+            return Ok(Size(bytes));
+        }
+
+        let bytes = number.parse::<f64>().map_err(|_| invalid())? * 1024f64.powi(power);
+        if bytes.is_finite() && bytes < u64::MAX as f64 {
+            Ok(Size(bytes as u64))
+        } else {
+            Err(invalid())
+        }
     }
 }
 
@@ -27,19 +414,30 @@ pub enum SubCommandType {
     Copy,
     Synchronize,
     Remove,
+    Journal,
+    Ingest,
+    SeedCheck,
+    Usage,
+    Verify,
+    List,
+    MtreeCheck,
+    History,
+    ConfigCheck,
+    Dedupe,
+    Rollback,
 }
 
 /// Struct to represent subcommands
-pub struct SubCommand<'a> {
-    pub src: Option<&'a str>,
-    pub dest: Vec<String>,
+pub struct SubCommand {
+    pub src: Option<PathBuf>,
+    pub dest: Vec<PathBuf>,
     pub sub_command_type: SubCommandType,
 }
 
 /// Struct to represent the result of parsing args
-pub struct ParseResult<'a> {
-    pub sub_command: SubCommand<'a>,
-    pub flags: Flag,
+pub struct ParseResult {
+    pub sub_command: SubCommand,
+    pub options: Options,
 }
 
 /// Parses command line arguments for source and destination folders and
@@ -50,59 +448,336 @@ pub struct ParseResult<'a> {
 /// but is not limited to just these cases:
 /// * The source folder is not a valid directory
 /// * The destination folder could not be created
-pub fn parse_args<'a>(args: &'a ArgMatches) -> Result<ParseResult<'a>, ()> {
+pub fn parse_args(args: &ArgMatches) -> Result<ParseResult, ()> {
     // These are safe to unwrap since subcommands are required
     let sub_command_name = args.subcommand_name().unwrap();
     let args = args.subcommand_matches(sub_command_name).unwrap();
 
-    const FLAG_NAMES: [&str; 4] = ["nodelete", "secure", "verbose", "sequential"];
+    // `lms config check` reports the problems of the config files itself
+    let (args, config) = if sub_command_name == "config" {
+        // This is safe to unwrap since `check` is the only subcommand of `config`
+        (
+            args.subcommand_matches("check").unwrap(),
+            config::Config::default(),
+        )
+    } else {
+        // Config files give the defaults, which the command line adds to or overrides
+        match config::load(sub_command_name, args.value_of("profile")) {
+            Ok(config) => (args, config),
+            Err(e) => {
+                eprintln!("{}", locale::text("config-error", &[("error", &e)]));
+                return Err(());
+            }
+        }
+    };
 
     // Parse for flags
-    let mut flags = Flag::empty();
-    for (i, &flag_name) in FLAG_NAMES.iter().enumerate() {
+    let mut flags = config.flags;
+    for &(flag_name, flag) in FLAG_NAMES.iter() {
         if args.is_present(flag_name) {
-            flags |= Flag::from_bits_truncate(1 << i);
+            flags |= flag;
+        }
+    }
+    // What `lms rm` removes can still be restored, unless it is removed for good
+    if sub_command_name == "rm" && !args.is_present("permanent") {
+        flags |= Flag::TRASH;
+    }
+    if args.value_of("dataless") == Some("skip") {
+        flags |= Flag::SKIP_DATALESS;
+    }
+    if flags.contains(Flag::METADATA_ONLY) || args.is_present("archive") {
+        flags |= Flag::PERMS | Flag::OWNER | Flag::TIMES;
+    }
+
+    let mut options = Options::from(flags);
+
+    // Parse for options with values
+    if let Some(exclude_mounts) = args.values_of_os("exclude-mount") {
+        for exclude_mount in exclude_mounts {
+            match paths::canonicalize(exclude_mount) {
+                Ok(path) => options.exclude_mounts.push(path),
+                Err(e) => {
+                    eprintln!(
+                        "{}",
+                        locale::text(
+                            "exclude-mount-error",
+                            &[("path", &Path::new(exclude_mount).display()), ("error", &e)]
+                        )
+                    );
+                    return Err(());
+                }
+            }
+        }
+    }
+
+    let exclude_from = read_pattern_files(args, "exclude-from")?;
+    let include_from = read_pattern_files(args, "include-from")?;
+    // Files that are still being written are only ingested once they get their final names
+    let in_progress: &[&str] =
+        if sub_command_name == "ingest" && !args.is_present("include-partial") {
+            &filter::IN_PROGRESS_PATTERNS
+        } else {
+            &[]
+        };
+    options.filter = Filter::new(
+        config
+            .exclude
+            .iter()
+            .chain(exclude_from.iter())
+            .map(String::as_str)
+            .chain(in_progress.iter().copied())
+            .chain(args.values_of("exclude").into_iter().flatten()),
+        config
+            .include
+            .iter()
+            .chain(include_from.iter())
+            .map(String::as_str)
+            .chain(args.values_of("include").into_iter().flatten()),
+    );
+
+    options.order = Order::new(
+        config
+            .order
+            .iter()
+            .map(|group| group.iter().map(String::as_str)),
+    );
+
+    if let Some(priority) = args.values_of_os("priority") {
+        options.priority = priority.map(relative_path).collect();
+    }
+    if let Some(only) = args.values_of_os("only") {
+        options.only = only.map(relative_path).collect();
+    }
+    for transform in args.values_of("transform").into_iter().flatten() {
+        match transform.parse() {
+            Ok(parsed) => options.transforms.push(parsed),
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    locale::text(
+                        "option-error",
+                        &[
+                            ("option", &"transform"),
+                            ("value", &transform),
+                            ("error", &e)
+                        ]
+                    )
+                );
+                return Err(());
+            }
+        }
+    }
+    options.progress_fd = args.value_of("progress-fd").map(String::from);
+    options.stall_timeout = parse_value(args, "stall-timeout")?.map(Duration::from_secs);
+    options.large_file_size = parse_value(args, "large-file-size")?;
+    options.large_file_jobs = parse_value(args, "large-file-jobs")?;
+    options.threads = parse_value(args, "threads")?.or(config.threads);
+    options.log_level = config.log_level;
+    options.block_size = parse_value(args, "block-size")?;
+    options.read_bwlimit = parse_value(args, "read-bwlimit")?.map(|Size(size)| size);
+    options.checksum_under = parse_value(args, "checksum-under")?;
+    options.modify_window = parse_value(args, "modify-window")?.map(Duration::from_secs);
+    options.settle = parse_value(args, "settle")?.map(Duration::from_secs);
+    options.idle_exit = parse_value(args, "idle-exit")?.map(Duration::from_secs);
+    options.max_dest_size = parse_value(args, "max-dest-size")?;
+    options.min_size = parse_value(args, "min-size")?.map(|Size(size)| size);
+    options.max_size = parse_value(args, "max-size")?.map(|Size(size)| size);
+    options.max_files = parse_value(args, "max-files")?;
+    options.max_depth = parse_value(args, "max-depth-abort")?;
+    options.existing = if args.is_present("existing") {
+        Existing::Only
+    } else if args.is_present("ignore-existing") {
+        Existing::Ignore
+    } else {
+        Existing::All
+    };
+    options.on_dest_newer = if args.is_present("update") {
+        DestNewer::Skip
+    } else {
+        parse_value(args, "on-dest-newer")?.unwrap_or_default()
+    };
+    options.reflink = parse_value(args, "reflink")?.unwrap_or_default();
+    options.log_dir = args.value_of_os("log-dir").map(PathBuf::from);
+    options.output = parse_value(args, "output")?.unwrap_or_default();
+    options.report = args.value_of_os("report").map(PathBuf::from);
+    options.backup_dir = args.value_of_os("backup-dir").map(PathBuf::from);
+    options.age_by = parse_value(args, "age-by")?.unwrap_or_default();
+    options.repair = parse_value(args, "repair")?;
+    options.list_format = parse_value(args, "format")?.unwrap_or_default();
+    options.profile = args
+        .value_of("profile")
+        .or_else(|| args.value_of("PROFILE"))
+        .map(String::from);
+    options.last = parse_value(args, "last")?;
+    if let Some(profile) = &options.profile {
+        if let Err(e) = history::history_file(profile) {
+            eprintln!("{}", locale::text("profile-error", &[("error", &e)]));
+            return Err(());
         }
     }
 
     // These values are safe to unwrap since the args are required
     let mut sub_command = match sub_command_name {
         "cp" => SubCommand {
-            src: Some(args.value_of("SOURCE").unwrap()),
-            dest: vec![args.value_of("DESTINATION").unwrap().to_string()],
+            src: args
+                .value_of_os("SOURCE")
+                .map(PathBuf::from)
+                .or_else(|| config.source.clone()),
+            dest: match args.values_of_os("DESTINATION") {
+                Some(dest) => dest.map(PathBuf::from).collect(),
+                None => config.destination.clone(),
+            },
             sub_command_type: SubCommandType::Copy,
         },
         "rm" => SubCommand {
             src: None,
             dest: args
-                .values_of("TARGET")
+                .values_of_os("TARGET")
                 .unwrap()
-                .map(|value| value.to_string())
+                .map(PathBuf::from)
                 .collect(),
             sub_command_type: SubCommandType::Remove,
         },
         "sync" => SubCommand {
-            src: Some(args.value_of("SOURCE").unwrap()),
-            dest: vec![args.value_of("DESTINATION").unwrap().to_string()],
+            src: args
+                .value_of_os("SOURCE")
+                .map(PathBuf::from)
+                .or_else(|| config.source.clone()),
+            dest: match args.values_of_os("DESTINATION") {
+                Some(dest) => dest.map(PathBuf::from).collect(),
+                None => config.destination.clone(),
+            },
             sub_command_type: SubCommandType::Synchronize,
         },
+        "journal" => SubCommand {
+            src: args.value_of_os("SOURCE").map(PathBuf::from),
+            dest: vec![PathBuf::from(args.value_of_os("DESTINATION").unwrap())],
+            sub_command_type: SubCommandType::Journal,
+        },
+        "history" => SubCommand {
+            src: None,
+            dest: Vec::new(),
+            sub_command_type: SubCommandType::History,
+        },
+        "config" => SubCommand {
+            src: None,
+            dest: Vec::new(),
+            sub_command_type: SubCommandType::ConfigCheck,
+        },
+        "ingest" => SubCommand {
+            src: args.value_of_os("SOURCE").map(PathBuf::from),
+            dest: vec![PathBuf::from(args.value_of_os("DESTINATION").unwrap())],
+            sub_command_type: SubCommandType::Ingest,
+        },
+        "dedupe" => SubCommand {
+            src: None,
+            dest: vec![PathBuf::from(args.value_of_os("TARGET").unwrap())],
+            sub_command_type: SubCommandType::Dedupe,
+        },
+        "rollback" => SubCommand {
+            src: None,
+            dest: vec![PathBuf::from(args.value_of_os("DESTINATION").unwrap())],
+            sub_command_type: SubCommandType::Rollback,
+        },
+        "du" => SubCommand {
+            src: None,
+            dest: vec![PathBuf::from(args.value_of_os("TARGET").unwrap())],
+            sub_command_type: SubCommandType::Usage,
+        },
+        "ls" => SubCommand {
+            src: None,
+            dest: vec![PathBuf::from(args.value_of_os("TARGET").unwrap())],
+            sub_command_type: SubCommandType::List,
+        },
+        "mtree-check" => SubCommand {
+            src: args.value_of_os("SPEC").map(PathBuf::from),
+            dest: vec![PathBuf::from(args.value_of_os("TARGET").unwrap())],
+            sub_command_type: SubCommandType::MtreeCheck,
+        },
+        "seed-check" => SubCommand {
+            src: args.value_of_os("SOURCE").map(PathBuf::from),
+            dest: vec![PathBuf::from(args.value_of_os("DESTINATION").unwrap())],
+            sub_command_type: SubCommandType::SeedCheck,
+        },
+        "verify" => SubCommand {
+            src: args.value_of_os("SOURCE").map(PathBuf::from),
+            dest: vec![PathBuf::from(args.value_of_os("DESTINATION").unwrap())],
+            sub_command_type: SubCommandType::Verify,
+        },
         _ => return Err(()),
     };
 
+    // Without a source or destination on the command line, the profile must give them
+    if matches!(sub_command_name, "cp" | "sync")
+        && (sub_command.src.is_none() || sub_command.dest.is_empty())
+    {
+        eprintln!(
+            "{}",
+            locale::text(
+                if sub_command.src.is_none() {
+                    "profile-no-source"
+                } else {
+                    "profile-no-destination"
+                },
+                &[("profile", &options.profile.as_deref().unwrap_or_default())]
+            )
+        );
+        return Err(());
+    }
+
+    // Remote locations would otherwise be created as local directories named after the host
+    if let Some(path) = sub_command
+        .src
+        .iter()
+        .chain(sub_command.dest.iter())
+        .filter_map(|path| path.to_str())
+        .find(|path| remote_host(path).is_some())
+    {
+        eprintln!(
+            "{}",
+            locale::text(
+                "remote-location",
+                &[("path", &path), ("host", &remote_host(path).unwrap())]
+            )
+        );
+        return Err(());
+    }
+
+    // Different spellings of the same directory are scanned and compared alike
+    sub_command.src = sub_command.src.map(|src| paths::normalize(&src));
+    for dest in sub_command.dest.iter_mut() {
+        *dest = paths::normalize(dest);
+    }
+
     // Validate directories
     match sub_command.sub_command_type {
-        SubCommandType::Remove => {
+        SubCommandType::History | SubCommandType::ConfigCheck => (),
+        SubCommandType::Remove
+        | SubCommandType::Dedupe
+        | SubCommandType::Rollback
+        | SubCommandType::Usage
+        | SubCommandType::List
+        | SubCommandType::MtreeCheck => {
             sub_command.dest.retain(|dest| {
                 // Target directory must be a valid directory
                 match fs::metadata(dest) {
                     Ok(m) => {
                         if !m.is_dir() {
-                            eprintln!("Target Error -- {} is not a directory", dest);
+                            eprintln!(
+                                "{}",
+                                locale::text("target-not-dir", &[("path", &dest.display())])
+                            );
                         }
                         m.is_dir()
                     }
                     Err(e) => {
-                        eprintln!("Target Error -- {}: {}", dest, e);
+                        eprintln!(
+                            "{}",
+                            locale::text(
+                                "target-error",
+                                &[("path", &dest.display()), ("error", &e)]
+                            )
+                        );
                         false
                     }
                 }
@@ -112,46 +787,188 @@ pub fn parse_args<'a>(args: &'a ArgMatches) -> Result<ParseResult<'a>, ()> {
                 return Err(());
             }
         }
-        SubCommandType::Copy | SubCommandType::Synchronize => {
+        SubCommandType::Copy
+        | SubCommandType::Synchronize
+        | SubCommandType::Journal
+        | SubCommandType::Ingest
+        | SubCommandType::SeedCheck
+        | SubCommandType::Verify => {
+            let src = sub_command.src.as_deref().unwrap();
+
             // Check if src is valid
-            match fs::metadata(sub_command.src.unwrap()) {
+            match fs::metadata(src) {
                 Ok(m) => {
                     if !m.is_dir() {
                         eprintln!(
-                            "Source Error -- {} is not a directory",
-                            sub_command.src.unwrap()
+                            "{}",
+                            locale::text("source-not-dir", &[("path", &src.display())])
                         );
                         return Err(());
                     }
                 }
                 Err(e) => {
-                    eprintln!("Source Error -- {}: {}", sub_command.src.unwrap(), e);
+                    eprintln!(
+                        "{}",
+                        locale::text("source-error", &[("path", &src.display()), ("error", &e)])
+                    );
                     return Err(());
                 }
             };
 
-            // If the directory already exists, then the directory is directory + src name
-            if sub_command.sub_command_type == SubCommandType::Copy
-                && fs::metadata(&sub_command.dest[0]).is_ok()
-            {
-                let mut new_dest = PathBuf::from(&sub_command.dest[0]);
-                let src_name = PathBuf::from(sub_command.src.unwrap());
-                if let Some(src_name) = src_name.file_name() {
-                    new_dest.push(src_name);
-                    sub_command.dest = vec![new_dest.to_string_lossy().to_string()];
+            // Refuse to copy from /proc, /sys and friends unless explicitly allowed
+            if !flags.contains(Flag::ALLOW_PSEUDO_FS) {
+                if let Ok(canonical) = paths::canonicalize(src) {
+                    if let Some(fs_type) = MountTable::load().pseudo_fs_of(&canonical) {
+                        eprintln!(
+                            "{}",
+                            locale::text(
+                                "source-pseudo-fs",
+                                &[("path", &src.display()), ("fs_type", &fs_type)]
+                            )
+                        );
+                        return Err(());
+                    }
                 }
             }
 
-            if fs::metadata(&sub_command.dest[0]).is_err() {
-                // Create destination folder if not already existing
-                match fs::create_dir_all(&sub_command.dest[0]) {
-                    Ok(_) => {
-                        if flags.contains(Flag::VERBOSE) {
-                            println!("Creating dir {:?}", sub_command.dest[0]);
+            // Every destination is checked, and created, on its own
+            for dest in sub_command.dest.iter_mut() {
+                // A seeded or verified destination is only compared, so it must already exist
+                if sub_command.sub_command_type == SubCommandType::SeedCheck
+                    || sub_command.sub_command_type == SubCommandType::Verify
+                {
+                    match fs::metadata(&dest) {
+                        Ok(m) if m.is_dir() => (),
+                        Ok(_) => {
+                            eprintln!(
+                                "{}",
+                                locale::text("destination-not-dir", &[("path", &dest.display())])
+                            );
+                            return Err(());
+                        }
+                        Err(e) => {
+                            eprintln!(
+                                "{}",
+                                locale::text(
+                                    "destination-error",
+                                    &[("path", &dest.display()), ("error", &e)]
+                                )
+                            );
+                            return Err(());
                         }
                     }
+                }
+
+                // If the directory already exists, then the directory is directory + src name
+                if sub_command.sub_command_type == SubCommandType::Copy
+                    && fs::metadata(&dest).is_ok()
+                {
+                    if let Some(src_name) = src.file_name() {
+                        *dest = dest.join(src_name);
+                    }
+                }
+
+                // A run from a directory to itself has nothing to do, but would still go through
+                // it all
+                if are_same(src, dest) {
+                    eprintln!(
+                        "{}",
+                        locale::text(
+                            "destination-same",
+                            &[("src", &src.display()), ("dest", &dest.display())]
+                        )
+                    );
+                    return Err(());
+                }
+
+                // A source containing its destination, or the other way around, would be copied
+                // into itself, growing with every file copied
+                if (sub_command.sub_command_type == SubCommandType::Copy
+                    || sub_command.sub_command_type == SubCommandType::Synchronize
+                    || sub_command.sub_command_type == SubCommandType::Ingest)
+                    && !flags.contains(Flag::FORCE_NESTED)
+                    && are_nested(src, dest)
+                {
+                    eprintln!(
+                        "{}",
+                        locale::text(
+                            "destination-nested",
+                            &[("src", &src.display()), ("dest", &dest.display())]
+                        )
+                    );
+                    return Err(());
+                }
+
+                if fs::metadata(&dest).is_err() {
+                    // Create destination folder if not already existing
+                    match fs::create_dir_all(&dest) {
+                        Ok(_) => {
+                            // Nothing but JSON may reach stdout when it is parsed by a program
+                            if flags.contains(Flag::VERBOSE) && options.output == Output::Text {
+                                println!(
+                                    "{}",
+                                    locale::text(
+                                        "creating-dir",
+                                        &[("path", &format!("{:?}", dest))]
+                                    )
+                                );
+                            }
+                        }
+                        Err(e) => {
+                            eprintln!(
+                                "{}",
+                                locale::text(
+                                    "destination-error",
+                                    &[("path", &dest.display()), ("error", &e)]
+                                )
+                            );
+                            return Err(());
+                        }
+                    }
+                }
+            }
+
+            // Several destinations would back up their old versions over each other, and
+            // source files could only be removed once every destination holds them
+            if sub_command.dest.len() > 1 {
+                if options.backup_dir.is_some() {
+                    eprintln!("{}", locale::text("backup-dir-single", &[]));
+                    return Err(());
+                }
+                if flags.contains(Flag::REMOVE_SOURCE_FILES) {
+                    eprintln!("{}", locale::text("remove-source-single", &[]));
+                    return Err(());
+                }
+            }
+
+            // Backups inside the destination would be deleted by the next synchronization
+            if let Some(backup_dir) = options.backup_dir.take() {
+                let canonical = fs::create_dir_all(&backup_dir)
+                    .and_then(|_| paths::canonicalize(&backup_dir))
+                    .and_then(|backup| Ok((backup, paths::canonicalize(&sub_command.dest[0])?)));
+                match canonical {
+                    Ok((backup, dest)) if backup.starts_with(&dest) => {
+                        eprintln!(
+                            "{}",
+                            locale::text(
+                                "backup-dir-inside",
+                                &[
+                                    ("path", &backup_dir.display()),
+                                    ("dest", &sub_command.dest[0].display())
+                                ]
+                            )
+                        );
+                        return Err(());
+                    }
+                    Ok((backup, _)) => options.backup_dir = Some(backup),
                     Err(e) => {
-                        eprintln!("Destination Error -- {}: {}", sub_command.dest[0], e);
+                        eprintln!(
+                            "{}",
+                            locale::text(
+                                "backup-dir-error",
+                                &[("path", &backup_dir.display()), ("error", &e)]
+                            )
+                        );
                         return Err(());
                     }
                 }
@@ -159,29 +976,360 @@ pub fn parse_args<'a>(args: &'a ArgMatches) -> Result<ParseResult<'a>, ()> {
         }
     }
 
-    Ok(ParseResult { sub_command, flags })
+    Ok(ParseResult {
+        sub_command,
+        options,
+    })
+}
+
+/// Parses the value of the option `name`, if it is given
+///
+/// # Arguments
+/// * `args`: arguments of the subcommand
+/// * `name`: name of the option
+///
+/// # Errors
+/// This function will print an error and fail if the value cannot be parsed
+fn parse_value<T>(args: &ArgMatches, name: &str) -> Result<Option<T>, ()>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match args.value_of(name) {
+        None => Ok(None),
+        Some(value) => match value.parse() {
+            Ok(parsed) => Ok(Some(parsed)),
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    locale::text(
+                        "option-error",
+                        &[("option", &name), ("value", &value), ("error", &e)]
+                    )
+                );
+                Err(())
+            }
+        },
+    }
+}
+
+/// Reads the patterns of every pattern file given with the option `name`, such as --exclude-from
+///
+/// # Errors
+/// This function will print an error and fail if a pattern file cannot be read
+fn read_pattern_files(args: &ArgMatches, name: &str) -> Result<Vec<String>, ()> {
+    let mut patterns = Vec::new();
+    for path in args.values_of_os(name).into_iter().flatten() {
+        match filter::read_patterns(Path::new(path)) {
+            Ok(file_patterns) => patterns.extend(file_patterns),
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    locale::text(
+                        "option-error",
+                        &[
+                            ("option", &name),
+                            ("value", &Path::new(path).display()),
+                            ("error", &e)
+                        ]
+                    )
+                );
+                return Err(());
+            }
+        }
+    }
+    Ok(patterns)
+}
+
+/// Normalizes a path given relative to the source directory, such as `./docs/`, to `docs`
+///
+/// # Arguments
+/// * `path`: path relative to the source directory
+///
+/// # Returns
+/// The path without `.` components
+fn relative_path(path: &OsStr) -> PathBuf {
+    Path::new(path)
+        .components()
+        .filter(|component| *component != Component::CurDir)
+        .collect()
 }
 
-/// Sets up the environment based on given flags
-pub fn set_env(flags: Flag) {
+/// Gets the host of a remote location in the form `[user@]host:path`, as given to rsync or scp
+///
+/// Only paths that cannot be meant locally count as remote, so that local paths with colons keep
+/// working: a colon only makes a remote location if it comes before the first `/`, such that
+/// `./a:b` and Windows drives such as `C:\dir` stay local, everything before it has to be a
+/// valid `[user@]host`, and nothing may exist at the path locally.
+///
+/// # Arguments
+/// * `path`: path given on the command line
+///
+/// # Returns
+/// * Some: The `[user@]host` part of a remote location
+/// * None: If `path` is a local path
+fn remote_host(path: &str) -> Option<&str> {
+    // The colons of IPv6 addresses are inside brackets, like for scp
+    let host = match path.find("]:") {
+        Some(end) if path[..end].contains('[') => &path[..=end],
+        _ => path.split_once(':')?.0,
+    };
+    let is_drive = host.len() == 1 && host.chars().all(|c| c.is_ascii_alphabetic());
+    let (user, name) = host.rsplit_once('@').unwrap_or(("", host));
+    let is_name = |name: &str| {
+        name.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+    };
+    let is_address = name.len() > 2
+        && name.starts_with('[')
+        && name.ends_with(']')
+        && name[1..name.len() - 1]
+            .chars()
+            .all(|c| c.is_ascii_hexdigit() || c == ':' || c == '.');
+    if name.is_empty()
+        || is_drive
+        || !is_name(user)
+        || !(is_name(name) || is_address)
+        || fs::symlink_metadata(path).is_ok()
+    {
+        None
+    } else {
+        Some(host)
+    }
+}
+
+/// Checks whether `src` and `dest` are nested, such that either one is inside of the other or
+/// both are the same directory
+///
+/// # Arguments
+/// * `src`: source directory
+/// * `dest`: destination directory, which may not exist yet
+pub fn are_nested(src: &Path, dest: &Path) -> bool {
+    match (resolve(src), resolve(dest)) {
+        (Ok(src), Ok(dest)) => {
+            let (src, dest) = (paths::comparable(&src), paths::comparable(&dest));
+            src.starts_with(&dest) || dest.starts_with(&src)
+        }
+        _ => false,
+    }
+}
+
+/// Checks whether `src` and `dest` are the same directory, once symlinks and relative components
+/// are resolved
+///
+/// # Arguments
+/// * `src`: source directory
+/// * `dest`: destination directory, which may not exist yet
+pub fn are_same(src: &Path, dest: &Path) -> bool {
+    match (resolve(src), resolve(dest)) {
+        (Ok(src), Ok(dest)) => paths::comparable(&src) == paths::comparable(&dest),
+        _ => false,
+    }
+}
+
+/// Resolves `path` to an absolute path like fs::canonicalize, even if its last components do not
+/// exist yet
+fn resolve(path: &Path) -> Result<PathBuf, io::Error> {
+    match paths::canonicalize(path) {
+        Ok(path) => Ok(path),
+        Err(e) => match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) if parent.as_os_str().is_empty() => {
+                Ok(paths::canonicalize(".")?.join(name))
+            }
+            (Some(parent), Some(name)) => Ok(resolve(parent)?.join(name)),
+            _ => Err(e),
+        },
+    }
+}
+
+/// Sets up the environment based on given flags, showing the log records of `log_level`, if it
+/// is given, unless the flags are verbose
+///
+/// The logger is configured explicitly, and the commands of --transform are given RUST_LOG on
+/// their own, so the environment of the process is never changed, which is unsafe while other
+/// threads of the process may read it. --no-env-mutation is therefore accepted, but changes
+/// nothing.
+pub fn set_env(flags: Flag, log_level: Option<LevelFilter>) {
+    // If verbose, show info logging, or else show only error logging
+    let level = if flags.contains(Flag::VERBOSE) {
+        LevelFilter::Info
+    } else {
+        log_level.unwrap_or(LevelFilter::Error)
+    };
+    transform::set_log_level(level);
+
+    // A recorded run logs everything to its session log, whatever is shown
     let mut builder = Builder::new();
-    builder.format(|_, record| {
-        PROGRESS_BAR.println(format!("{}", record.args()));
+    builder.format(move |_, record| {
+        session::log(record.level(), &record.args().to_string());
+        if record.level() <= level {
+            progress::bar().println(format!("{}", record.args()));
+        }
         Ok(())
     });
+    builder.filter(None, level);
+    if session::is_recorded() {
+        builder.filter(Some("lms"), LevelFilter::Info);
+    }
+    builder.init();
+}
 
-    // If verbose, enable info logging
-    if flags.contains(Flag::VERBOSE) {
-        env::set_var("RUST_LOG", "info");
-        builder.filter(None, LevelFilter::Info).init();
+/// Runs `f` in a thread pool of its own if the number of threads is limited with --threads, or
+/// to one with --sequential, so that every parallel iterator it drives uses that many threads
+///
+/// Within a job of its own, such as a synchronization run through the library, `f` always runs in
+/// a thread pool of its own, whose threads credit their progress to that job.
+///
+/// # Arguments
+/// * `options`: command line options
+/// * `f`: run to carry out
+///
+/// # Errors
+/// This function will return an error if the thread pool cannot be built, or if `f` fails
+pub fn run_with_threads<F>(options: &Options, f: F) -> Result<(), io::Error>
+where
+    F: FnOnce() -> Result<(), io::Error> + Send,
+{
+    let threads = if options.flags.contains(Flag::SEQUENTIAL) {
+        Some(1)
     } else {
-        // or else enable only error logging
-        env::set_var("RUST_LOG", "error");
-        builder.filter(None, LevelFilter::Error).init();
+        options.threads
+    };
+    if threads.is_none() && !progress::in_job() {
+        return f();
+    }
+
+    let job = progress::current_job();
+    ThreadPoolBuilder::new()
+        .num_threads(threads.unwrap_or_default())
+        .start_handler(move |_| job.attach())
+        .build()
+        .map_err(io::Error::other)?
+        .install(f)
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_parse {
+    use super::*;
+
+    #[test]
+    fn remote_locations() {
+        assert_eq!(remote_host("user@host:/backup"), Some("user@host"));
+        assert_eq!(remote_host("host:backup"), Some("host"));
+        assert_eq!(remote_host("/backup"), None);
+        assert_eq!(remote_host("./a:b"), None);
+        assert_eq!(remote_host("dir/a:b"), None);
+        assert_eq!(remote_host("C:\\backup"), None);
+        assert_eq!(remote_host(":backup"), None);
+        assert_eq!(remote_host("user@[::1]:backup"), Some("user@[::1]"));
+        assert_eq!(remote_host("notes: draft"), Some("notes"));
+        assert_eq!(remote_host("draft notes:1"), None);
+        assert_eq!(remote_host("a@b@host:backup"), None);
+
+        // Local paths with a colon are never remote
+        const TEST_DIR: &str = "test_parse_remote:locations";
+        fs::create_dir_all(TEST_DIR).unwrap();
+        assert_eq!(remote_host(TEST_DIR), None);
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!("512".parse(), Ok(Size(512)));
+        assert_eq!("10K".parse(), Ok(Size(10 * 1024)));
+        assert_eq!("1.5m".parse(), Ok(Size(3 * 512 * 1024)));
+        assert_eq!("2GiB".parse(), Ok(Size(2 * 1024 * 1024 * 1024)));
+        assert_eq!("4TB".parse(), Ok(Size(4 << 40)));
+        assert_eq!("10X".parse::<Size>().is_err(), true);
+        assert_eq!("M".parse::<Size>().is_err(), true);
+        assert_eq!("-1".parse::<Size>().is_err(), true);
+        assert_eq!("99999999P".parse::<Size>().is_err(), true);
+    }
+
+    #[test]
+    fn nested_dirs() {
+        const TEST_DIR: &str = "test_parse_nested_dirs";
+
+        fs::create_dir_all([TEST_DIR, "src"].join("/")).unwrap();
+        fs::create_dir_all([TEST_DIR, "other"].join("/")).unwrap();
+
+        let src = [TEST_DIR, "src"].join("/");
+        assert_eq!(
+            are_nested(
+                Path::new(&src),
+                Path::new(&[TEST_DIR, "src/new/dest"].join("/"))
+            ),
+            true
+        );
+        assert_eq!(are_nested(Path::new(&src), Path::new(TEST_DIR)), true);
+        assert_eq!(
+            are_nested(
+                Path::new(&src),
+                Path::new(&[TEST_DIR, "other/../src"].join("/"))
+            ),
+            true
+        );
+        assert_eq!(
+            are_nested(Path::new(&src), Path::new(&[TEST_DIR, "other"].join("/"))),
+            false
+        );
+        // Paths that only share a prefix of their names are not nested
+        assert_eq!(
+            are_nested(Path::new(&src), Path::new(&[TEST_DIR, "src2"].join("/"))),
+            false
+        );
+
+        assert_eq!(
+            are_same(
+                Path::new(&src),
+                Path::new(&[TEST_DIR, "other/../src/"].join("/"))
+            ),
+            true
+        );
+        assert_eq!(
+            are_same(Path::new(&src), Path::new(&[TEST_DIR, "src/new"].join("/"))),
+            false
+        );
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
     }
 
-    // If sequential, set Rayon to use only 1 thread
-    if flags.contains(Flag::SEQUENTIAL) {
-        env::set_var("RAYON_NUM_THREADS", "1");
+    #[test]
+    fn subcommands() {
+        const TEST_SRC: &str = "test_parse_subcommands_src";
+        const TEST_DEST: &str = "test_parse_subcommands_dest";
+
+        fs::create_dir_all(TEST_SRC).unwrap();
+        let yaml = clap::load_yaml!("../cli.yml");
+        let app = clap::App::from_yaml(yaml);
+
+        // `sync --journal` and `ingest` are told apart by the command line definition
+        let args = app
+            .clone()
+            .get_matches_from_safe(vec!["lms", "sync", "--journal", TEST_SRC, TEST_DEST])
+            .unwrap();
+        let result = parse_args(&args).unwrap();
+        assert_eq!(
+            result.sub_command.sub_command_type == SubCommandType::Synchronize,
+            true
+        );
+        assert_eq!(result.options.flags.contains(Flag::JOURNAL), true);
+
+        let args = app
+            .get_matches_from_safe(vec!["lms", "ingest", TEST_SRC, TEST_DEST])
+            .unwrap();
+        let result = parse_args(&args).unwrap();
+        assert_eq!(
+            result.sub_command.sub_command_type == SubCommandType::Ingest,
+            true
+        );
+        assert_eq!(result.options.flags.contains(Flag::JOURNAL), false);
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        let _ = fs::remove_dir_all(TEST_DEST);
     }
 }
@@ -0,0 +1,120 @@
+//! Contains utilities for normalizing the paths of sources and destinations, so that different
+//! spellings of the same directory, such as `D:\Data` and `d:/data/` on Windows, are scanned,
+//! compared, and joined alike.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Longest path that Windows accepts without a verbatim `\\?\` prefix
+const MAX_PATH: usize = 260;
+
+/// Normalizes a path given on the command line or to the library
+///
+/// On Windows, separators are turned into `\`, drive letters into upper case, repeated and
+/// trailing separators are removed, and verbatim `\\?\` prefixes are dropped where they are not
+/// needed. Other platforms take paths as they are given.
+///
+/// # Arguments
+/// * `path`: path to normalize
+pub fn normalize(path: &Path) -> PathBuf {
+    match path.to_str() {
+        Some(path) if cfg!(target_family = "windows") => PathBuf::from(normalize_windows(path)),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Resolves `path` to an absolute path like fs::canonicalize, but without the verbatim prefix
+/// that it adds on Windows, which other paths never start with
+///
+/// # Errors
+/// This function will return an error if `path` does not exist
+pub fn canonicalize<P: AsRef<Path>>(path: P) -> Result<PathBuf, io::Error> {
+    let path = fs::canonicalize(path)?;
+    Ok(match path.to_str() {
+        Some(path) if cfg!(target_family = "windows") => PathBuf::from(normalize_windows(path)),
+        _ => path,
+    })
+}
+
+/// Gets the form of the absolute `path` that other paths are compared with, in which paths that
+/// only differ in case are the same on Windows, like they are on its filesystems
+pub fn comparable(path: &Path) -> PathBuf {
+    match path.to_str() {
+        Some(path) if cfg!(target_family = "windows") => {
+            PathBuf::from(normalize_windows(path).to_lowercase())
+        }
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Normalizes a Windows path, like `normalize` does on Windows
+fn normalize_windows(path: &str) -> String {
+    // Verbatim paths are taken literally, and long ones must stay verbatim to be opened at all
+    let path = match (path.strip_prefix(r"\\?\UNC\"), path.strip_prefix(r"\\?\")) {
+        (_, Some(_)) if path.len() >= MAX_PATH => return path.to_string(),
+        (Some(unc), _) => format!(r"\\{}", unc),
+        (None, Some(disk)) if has_drive(disk) => disk.to_string(),
+        (None, Some(_)) => return path.to_string(),
+        (None, None) => path.replace('/', r"\"),
+    };
+
+    // UNC paths keep the two separators they start with
+    let mut normalized = String::with_capacity(path.len());
+    for (i, c) in path.chars().enumerate() {
+        if c != '\\' || i < 2 || !normalized.ends_with('\\') {
+            normalized.push(c);
+        }
+    }
+
+    // The separator of a root, such as after a drive, must stay
+    while normalized.ends_with('\\') {
+        let trimmed = &normalized[..normalized.len() - 1];
+        if trimmed.is_empty() || trimmed.ends_with(':') || trimmed == r"\" {
+            break;
+        }
+        normalized.truncate(trimmed.len());
+    }
+
+    if has_drive(&normalized) {
+        normalized[..1].make_ascii_uppercase();
+    }
+    normalized
+}
+
+/// Checks whether `path` starts with a drive letter, such as `C:`
+fn has_drive(path: &str) -> bool {
+    let bytes = path.as_bytes();
+    bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_paths {
+    use super::*;
+
+    #[test]
+    fn windows_paths() {
+        assert_eq!(normalize_windows(r"D:\Data"), r"D:\Data");
+        assert_eq!(normalize_windows("d:/Data/"), r"D:\Data");
+        assert_eq!(normalize_windows(r"d:\\Data\\sub\"), r"D:\Data\sub");
+        assert_eq!(normalize_windows("c:/"), r"C:\");
+        assert_eq!(normalize_windows(r"\\?\C:\Data"), r"C:\Data");
+        assert_eq!(
+            normalize_windows(r"\\?\UNC\server\share\dir"),
+            r"\\server\share\dir"
+        );
+        assert_eq!(
+            normalize_windows("//server/share/dir/"),
+            r"\\server\share\dir"
+        );
+        assert_eq!(
+            normalize_windows(r"\\?\Volume{1234}\dir"),
+            r"\\?\Volume{1234}\dir"
+        );
+        assert_eq!(normalize_windows("data/sub"), r"data\sub");
+    }
+}
@@ -0,0 +1,185 @@
+//! Contains the plan of a run, which lists everything a run would change before anything is
+//! changed, so that --dry-run can show it instead.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::lumins::file_ops::{FileOps, FileSets};
+use crate::lumins::locale;
+use crate::lumins::parse::Flag;
+use crate::lumins::report;
+use crate::progress;
+
+/// Enum to represent a single kind of change that a run makes
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum Action {
+    /// A directory is created in the destination
+    CreateDir,
+    /// A file is copied to the destination, where it does not exist yet
+    Copy,
+    /// A file in the destination is overwritten, since its contents differ
+    Update,
+    /// A file in the destination is renamed, since it was only moved in the source
+    Rename,
+    /// A file in the destination is kept as a backup, before it is overwritten or deleted
+    Backup,
+    /// A symlink is created in the destination
+    Symlink,
+    /// The metadata of an entry in the destination is made to match the source
+    Metadata,
+    /// An entry is deleted from the destination
+    Delete,
+    /// A file is deleted from the source once its copy is verified
+    RemoveSource,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Action::CreateDir => "mkdir",
+            Action::Copy => "copy",
+            Action::Update => "update",
+            Action::Rename => "rename",
+            Action::Backup => "backup",
+            Action::Symlink => "symlink",
+            Action::Metadata => "metadata",
+            Action::Delete => "delete",
+            Action::RemoveSource => "remove-source",
+        };
+        // Padding is applied to the name, so that plans line up in columns
+        f.pad(name)
+    }
+}
+
+/// Struct to represent everything that a run would change, in the order it would change it
+#[derive(Debug, Default)]
+pub struct Plan {
+    actions: Vec<(Action, PathBuf)>,
+}
+
+impl Plan {
+    /// Creates the plan of copying everything in `file_sets`, as done by `lms cp`
+    ///
+    /// # Arguments
+    /// * `file_sets`: files, dirs, and symlinks in the source
+    /// * `flags`: set for Flag's, of which `REMOVE_SOURCE_FILES` removes the copied files
+    pub fn copy(file_sets: &FileSets, flags: Flag) -> Self {
+        let mut plan = Plan::default();
+        plan.add(
+            Action::CreateDir,
+            file_sets.dirs().iter().map(FileOps::path),
+        );
+        plan.add(Action::Copy, file_sets.files().iter().map(FileOps::path));
+        plan.add(
+            Action::Symlink,
+            file_sets.symlinks().iter().map(FileOps::path),
+        );
+        if flags.contains(Flag::REMOVE_SOURCE_FILES) {
+            plan.add(
+                Action::RemoveSource,
+                file_sets.files().iter().map(FileOps::path),
+            );
+        }
+        plan
+    }
+
+    /// Adds `action` on every path in `paths`, sorted by path
+    ///
+    /// # Arguments
+    /// * `action`: change made to the paths
+    /// * `paths`: paths relative to the source or destination
+    pub fn add<I, P>(&mut self, action: Action, paths: I)
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let mut paths: Vec<PathBuf> = paths
+            .into_iter()
+            .map(|path| path.as_ref().to_path_buf())
+            .collect();
+        paths.sort();
+        self.actions
+            .extend(paths.into_iter().map(|path| (action, path)));
+    }
+
+    /// Adds `action` on every path in `paths`, in the given order
+    ///
+    /// # Arguments
+    /// * `action`: change made to the paths
+    /// * `paths`: paths relative to the source or destination, in the order they are changed
+    pub fn add_ordered<I, P>(&mut self, action: Action, paths: I)
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        self.actions.extend(
+            paths
+                .into_iter()
+                .map(|path| (action, path.as_ref().to_path_buf())),
+        );
+    }
+
+    /// Gets every change in the plan, in order
+    pub fn actions(&self) -> &[(Action, PathBuf)] {
+        &self.actions
+    }
+
+    /// Prints every change in the plan to stdout, one per line, followed by a summary
+    pub fn print(&self) {
+        // The scanning spinner must not be drawn over the plan
+        progress::bar().finish_and_clear();
+        report::record_plan(self);
+
+        for (action, path) in self.actions.iter() {
+            // The base directory itself has an empty relative path
+            let path = if path.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                path
+            };
+            println!("{:<13} {}", action, path.display());
+        }
+        println!(
+            "{}",
+            locale::text("dry-run-summary", &[("count", &self.actions.len())])
+        );
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_plan {
+    use super::*;
+    use crate::lumins::file_ops::{Dir, File, Symlink};
+    use hashbrown::HashSet;
+
+    #[test]
+    fn copy() {
+        let mut files = HashSet::new();
+        files.insert(File::from("dir/b.txt", 1));
+        files.insert(File::from("a.txt", 1));
+        let mut dirs = HashSet::new();
+        dirs.insert(Dir::from("dir"));
+        let mut symlinks = HashSet::new();
+        symlinks.insert(Symlink::from("link", "a.txt"));
+        let file_sets = FileSets::with(files, dirs, symlinks);
+
+        let plan = Plan::copy(&file_sets, Flag::REMOVE_SOURCE_FILES);
+
+        assert_eq!(
+            plan.actions(),
+            &[
+                (Action::CreateDir, PathBuf::from("dir")),
+                (Action::Copy, PathBuf::from("a.txt")),
+                (Action::Copy, PathBuf::from("dir/b.txt")),
+                (Action::Symlink, PathBuf::from("link")),
+                (Action::RemoveSource, PathBuf::from("a.txt")),
+                (Action::RemoveSource, PathBuf::from("dir/b.txt")),
+            ]
+        );
+        assert_eq!(format!("{:<8}|", Action::Copy), "copy    |");
+    }
+}
@@ -1,26 +1,626 @@
 //! Keeps track of LuminS' progress
 
-use indicatif::{ProgressBar, ProgressStyle};
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 use lazy_static::lazy_static;
+use rayon::ThreadPoolBuilder;
+
+use crate::lumins::context::Context;
+use crate::lumins::locale;
 
 lazy_static! {
-    /// Provides a bar that shows the number of files
-    /// copied, synchronized, or deleted, out of the total number of files
-    pub static ref PROGRESS_BAR: ProgressBar = {
-        let progress_bar = ProgressBar::new(0);
-        progress_bar.set_style(
-            ProgressStyle::default_bar()
-                .template("[{elapsed_precise}] [{bar:40.green/blue}] {pos}/{len} ({eta})"),
-        );
-        progress_bar
-    };
-}
-
-/// Initializes PROGRESS_BAR with `length` and sets draw delta
+    /// Progress of everything that runs outside of a job of its own, such as a run of the
+    /// command line
+    static ref DEFAULT_JOB: Arc<Job> = Job::new(ProgressBar::new(0));
+}
+
+thread_local! {
+    /// Job that the progress made on this thread is credited to, None for the default job
+    static CURRENT_JOB: RefCell<Option<Arc<Job>>> = const { RefCell::new(None) };
+}
+
+/// Struct to represent the progress of a single job, such as a synchronization run through the
+/// library, which is reported independently of the other jobs of the same process
+///
+/// The bar of a job shows the number of files copied, synchronized, or deleted, out of the total
+/// number of files, along with the bytes of the files copied during transfers.
+pub struct Job {
+    bar: ProgressBar,
+    /// Whether the bar is shared by transfers that run at the same time
+    shared: AtomicBool,
+    /// Name shown in front of the bar, such as the destination that the job copies to
+    label: Option<String>,
+    /// Settings and records of the job
+    context: Arc<Context>,
+}
+
+impl Job {
+    /// Creates a job whose progress is shown on `bar`, with a context of its own
+    pub fn new(bar: ProgressBar) -> Arc<Self> {
+        Job::with_label(bar, None, Arc::new(Context::default()))
+    }
+
+    /// Creates a job whose progress is shown on `bar`, behind `label` if it is given, and that
+    /// has `context`
+    fn with_label(bar: ProgressBar, label: Option<String>, context: Arc<Context>) -> Arc<Self> {
+        bar.set_style(bar_style(label.as_deref()));
+        Arc::new(Job {
+            bar,
+            shared: AtomicBool::new(false),
+            label,
+            context,
+        })
+    }
+
+    /// Gets the settings and records of this job
+    pub fn context(&self) -> &Arc<Context> {
+        &self.context
+    }
+
+    /// Runs `f`, crediting all progress made on the current thread meanwhile to this job
+    pub fn enter<F, R>(self: &Arc<Self>, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        let previous = CURRENT_JOB.with(|job| job.replace(Some(self.clone())));
+        let result = f();
+        CURRENT_JOB.with(|job| *job.borrow_mut() = previous);
+        result
+    }
+
+    /// Credits all progress made on the current thread from now on to this job, such as on the
+    /// threads of a thread pool that only serves this job
+    pub fn attach(self: &Arc<Self>) {
+        CURRENT_JOB.with(|job| *job.borrow_mut() = Some(self.clone()));
+    }
+}
+
+/// Credits all progress made on the current thread from now on to the default job again, such as
+/// on the threads of a thread pool once the job they served is done
+pub fn detach() {
+    CURRENT_JOB.with(|job| *job.borrow_mut() = None);
+}
+
+/// Gets the job that the progress made on the current thread is credited to
+pub fn current_job() -> Arc<Job> {
+    CURRENT_JOB.with(|job| job.borrow().clone().unwrap_or_else(|| DEFAULT_JOB.clone()))
+}
+
+/// Checks whether the progress made on the current thread is credited to a job of its own,
+/// rather than to the default job
+pub fn in_job() -> bool {
+    CURRENT_JOB.with(|job| match &*job.borrow() {
+        Some(job) => !Arc::ptr_eq(job, &DEFAULT_JOB),
+        None => false,
+    })
+}
+
+/// Gets the progress bar of the job of the current thread
+pub fn bar() -> ProgressBar {
+    CURRENT_JOB.with(|job| match &*job.borrow() {
+        Some(job) => job.bar.clone(),
+        None => DEFAULT_JOB.bar.clone(),
+    })
+}
+
+/// Interval between two redraws of the spinner while scanning, in milliseconds
+const SCAN_TICK: u64 = 100;
+
+/// Style of the bar while files are copied, synchronized, or deleted
+fn bar_style(label: Option<&str>) -> ProgressStyle {
+    ProgressStyle::default_bar().template(&labeled(
+        label,
+        "[{elapsed_precise}] [{bar:40.green/blue}] {pos}/{len} ({eta}) {msg}",
+    ))
+}
+
+/// Style of the bar while files are transferred, where every entry counts as a byte on top
+/// of the bytes of the files copied
+fn transfer_style(label: Option<&str>) -> ProgressStyle {
+    ProgressStyle::default_bar().template(&labeled(
+        label,
+        "[{elapsed_precise}] [{bar:40.green/blue}] {bytes}/{total_bytes} ({binary_bytes_per_sec}, {eta}) {msg}",
+    ))
+}
+
+/// Style of the bar while scanning, where its length counts the entries found so far
+fn scan_style(label: Option<&str>) -> ProgressStyle {
+    ProgressStyle::default_spinner().template(&labeled(
+        label,
+        "{spinner:.green} [{elapsed_precise}] {prefix}... {len} entries found {msg}",
+    ))
+}
+
+/// Style of the bar during a stage before or after the transfer, such as hashing
+fn phase_style(label: Option<&str>) -> ProgressStyle {
+    ProgressStyle::default_bar().template(&labeled(
+        label,
+        "[{elapsed_precise}] {prefix}... {percent}% [{bar:40.cyan/blue}] {pos}/{len} ({eta}) {msg}",
+    ))
+}
+
+/// Style of the bar of all destinations together, whose stages are counted in different units,
+/// so it only shows how far they are as a whole
+fn total_style() -> ProgressStyle {
+    ProgressStyle::default_bar()
+        .template("[{elapsed_precise}] [{bar:40.green/blue}] {percent}% of all destinations")
+}
+
+/// Puts `label` in front of the bar of `template`, without braces that would be taken as keys
+fn labeled(label: Option<&str>, template: &str) -> String {
+    match label {
+        Some(label) => format!("{} {}", label.replace(['{', '}'], ""), template),
+        None => template.to_string(),
+    }
+}
+
+/// Gets the label of the job of the current thread
+fn label() -> Option<String> {
+    current_job().label.clone()
+}
+
+/// Turns the bar of the current job into a spinner whose length grows as scanning finds entries,
+/// until `progress_init` turns it back into a bar
+///
+/// # Arguments
+/// * `phase`: what is being scanned, such as "Scanning source"
+pub fn scan_init(phase: &'static str) {
+    let bar = bar();
+    bar.set_style(scan_style(label().as_deref()));
+    bar.set_prefix(phase);
+    bar.set_length(0);
+    bar.set_position(0);
+    bar.enable_steady_tick(SCAN_TICK);
+}
+
+/// Turns the bar of the current job into a bar for a stage before or after the transfer, such as hashing,
+/// which is shown by name so that a slow stage can be told apart from the transfer
+///
+/// # Arguments
+/// * `phase`: what the stage does, such as "Hashing"
+/// * `length`: number of files the stage goes through
+pub fn phase_init(phase: &'static str, length: u64) {
+    if current_job().shared.load(Ordering::Relaxed) {
+        return extend(length);
+    }
+    let bar = bar();
+    bar.disable_steady_tick();
+    bar.set_style(phase_style(label().as_deref()));
+    bar.set_prefix(phase);
+    bar.set_length(length);
+    bar.set_draw_delta(length / 1000);
+    bar.set_position(0);
+}
+
+/// Initializes the bar of the current job with `length` and sets draw delta
 /// # Arguments
 /// * `length`: Length fo the bar to set
 pub fn progress_init(length: u64) {
-    PROGRESS_BAR.set_length(length);
-    PROGRESS_BAR.set_draw_delta(length / 1000);
-    PROGRESS_BAR.set_position(0);
+    let bar = bar();
+    bar.disable_steady_tick();
+    bar.set_style(bar_style(label().as_deref()));
+    bar.set_length(length);
+    bar.set_draw_delta(length / 1000);
+    bar.set_position(0);
+}
+
+/// Initializes the bar of the current job for a transfer, so that a large file weighs as much as the many small
+/// files of the same total size
+///
+/// # Arguments
+/// * `entries`: number of files, directories, and symlinks the transfer goes through
+/// * `bytes`: total size of the files the transfer copies or compares
+pub fn transfer_init(entries: u64, bytes: u64) {
+    if current_job().shared.load(Ordering::Relaxed) {
+        return extend(entries + bytes);
+    }
+    progress_init(entries + bytes);
+    bar().set_style(transfer_style(label().as_deref()));
+}
+
+/// Struct to share the bar of the current job between transfers to several destinations that run
+/// at the same time, for as long as it lives
+///
+/// While it is shared, every transfer and stage adds what it goes through to the bar instead of
+/// starting it over, so that the bar shows the progress of all of them together.
+pub struct SharedProgress(Arc<Job>);
+
+impl SharedProgress {
+    /// Starts an empty transfer that all following transfers and stages add to
+    pub fn start() -> Self {
+        transfer_init(0, 0);
+        let job = current_job();
+        job.shared.store(true, Ordering::Relaxed);
+        SharedProgress(job)
+    }
+}
+
+impl Drop for SharedProgress {
+    fn drop(&mut self) {
+        self.0.shared.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Adds `length` to the bar of the current job while it is shared
+fn extend(length: u64) {
+    let bar = bar();
+    bar.inc_length(length);
+    bar.set_draw_delta(bar.length() / 1000);
+}
+
+/// Interval between two updates of the bar of all destinations together
+const TOTAL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Struct to show transfers to several destinations that run at the same time on bars of their
+/// own, one per destination, along with a bar of all of them together, for as long as it lives
+///
+/// Every destination is a job of its own, which runs in a thread pool of its own, so that the
+/// progress made for it is credited to its own bar.
+pub struct FanOut {
+    jobs: Vec<Arc<Job>>,
+    total: ProgressBar,
+    /// Number of threads of the thread pool of every destination
+    threads: usize,
+    stop: mpsc::Sender<bool>,
+    handles: Vec<thread::JoinHandle<()>>,
+}
+
+impl FanOut {
+    /// Starts showing a bar for every destination in `dests`, in place of the bar of the current
+    /// job, which is shown again once the bars are done
+    ///
+    /// The threads of the current thread pool are split between the destinations.
+    pub fn start(dests: &[&Path]) -> Self {
+        let outer = bar();
+        outer.disable_steady_tick();
+        let target = if outer.is_hidden() {
+            ProgressDrawTarget::hidden()
+        } else {
+            ProgressDrawTarget::stderr()
+        };
+        outer.finish_and_clear();
+
+        let multi = MultiProgress::with_draw_target(target);
+        // The destinations are part of the same run, so they share its settings and records
+        let context = current_job().context.clone();
+        let jobs: Vec<Arc<Job>> = dests
+            .iter()
+            .map(|dest| {
+                Job::with_label(
+                    multi.add(ProgressBar::new(0)),
+                    Some(dest.display().to_string()),
+                    context.clone(),
+                )
+            })
+            .collect();
+        let total = multi.add(ProgressBar::new(0));
+        total.set_style(total_style());
+
+        // The bars of a MultiProgress are only drawn while it is joined
+        let (stop, stopped) = mpsc::channel();
+        let drawer = thread::spawn(move || {
+            let _ = multi.join_and_clear();
+        });
+        let bars: Vec<ProgressBar> = jobs.iter().map(|job| job.bar.clone()).collect();
+        let total_bar = total.clone();
+        let updater = thread::spawn(move || {
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(TOTAL_INTERVAL) {
+                update_total(&total_bar, &bars);
+            }
+        });
+
+        FanOut {
+            threads: (rayon::current_num_threads() / dests.len().max(1)).max(1),
+            jobs,
+            total,
+            stop,
+            handles: vec![updater, drawer],
+        }
+    }
+
+    /// Runs `f` for the destination at `index`, in a thread pool of its own whose threads credit
+    /// their progress to the bar of the destination
+    ///
+    /// # Errors
+    /// This function will return an error if the thread pool cannot be built, or if `f` fails
+    pub fn run<F, R>(&self, index: usize, f: F) -> Result<R, io::Error>
+    where
+        F: FnOnce() -> Result<R, io::Error> + Send,
+        R: Send,
+    {
+        let job = self.jobs[index].clone();
+        ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .start_handler(move |_| job.attach())
+            .build()
+            .map_err(io::Error::other)?
+            .install(f)
+    }
+}
+
+impl Drop for FanOut {
+    fn drop(&mut self) {
+        let _ = self.stop.send(true);
+        let bars: Vec<ProgressBar> = self.jobs.iter().map(|job| job.bar.clone()).collect();
+        update_total(&self.total, &bars);
+
+        // The drawer returns once every bar is done
+        for bar in bars.iter() {
+            bar.finish_and_clear();
+        }
+        self.total.finish_and_clear();
+        for thread in self.handles.drain(..) {
+            let _ = thread.join();
+        }
+        bar().reset();
+    }
+}
+
+/// Sets the bar of all destinations together to the sum of the bars of the destinations
+fn update_total(total: &ProgressBar, bars: &[ProgressBar]) {
+    total.set_length(bars.iter().map(ProgressBar::length).sum());
+    total.set_position(bars.iter().map(ProgressBar::position).sum());
+}
+
+/// Struct to credit the bytes of a single file to the bar of the job it is copied in, never more
+/// than the size it was counted with
+///
+/// A file that is copied again, such as when it changed while being copied, is only credited
+/// once: each attempt credits what it copies beyond the bytes that earlier attempts credited.
+/// Whatever is not credited by the time it is dropped, such as the rest of a file that could not
+/// be copied, is credited then, so that every file is counted exactly once.
+pub struct FileProgress {
+    size: u64,
+    credited: u64,
+    copied: u64,
+    bar: ProgressBar,
+}
+
+impl FileProgress {
+    /// Creates the progress of a file counted with `size` bytes
+    pub fn new(size: u64) -> Self {
+        FileProgress {
+            size,
+            credited: 0,
+            copied: 0,
+            bar: bar(),
+        }
+    }
+
+    /// Credits `bytes` more bytes of the current attempt at copying the file
+    pub fn inc(&mut self, bytes: u64) {
+        self.copied += bytes;
+        let copied = self.copied.min(self.size);
+        if copied > self.credited {
+            self.bar.inc(copied - self.credited);
+            self.credited = copied;
+        }
+    }
+
+    /// Starts another attempt at copying the file, which is credited from where the earlier ones
+    /// got to
+    pub fn restart(&mut self) {
+        self.copied = 0;
+    }
+}
+
+impl Drop for FileProgress {
+    fn drop(&mut self) {
+        self.bar.inc(self.size - self.credited);
+    }
+}
+
+/// Shows the bar of the current job on stderr, or hides it while it still keeps track of the
+/// progress
+///
+/// # Arguments
+/// * `visible`: whether the bar is drawn
+pub fn set_visible(visible: bool) {
+    bar().set_draw_target(if visible {
+        ProgressDrawTarget::stderr()
+    } else {
+        ProgressDrawTarget::hidden()
+    });
+}
+
+/// Asks `question` on the terminal and waits for a yes or no answer, hiding the bar of the
+/// current job while waiting
+///
+/// # Arguments
+/// * `question`: question to ask, without the answers
+///
+/// # Returns
+/// True if the answer is yes, false if it is no or nothing can be read
+pub fn confirm(question: &str) -> bool {
+    let bar = bar();
+    bar.disable_steady_tick();
+    bar.finish_and_clear();
+
+    eprint!("{} {} ", question, locale::text("confirm-answers", &[]));
+    let mut answer = String::new();
+    let confirmed = io::stdin().read_line(&mut answer).is_ok() && locale::is_yes(&answer);
+
+    bar.reset();
+    confirmed
+}
+
+/// Interval between two progress records
+const REPORT_INTERVAL: Duration = Duration::from_millis(200);
+
+/// A struct that writes machine readable progress records to a file descriptor or named pipe,
+/// separately from the human readable output
+///
+/// Records are single lines of the form `progress <done> <total>`, written periodically
+/// while the progress changes, followed by a final `end <ok|error> <done> <total>`.
+pub struct ProgressReporter {
+    stop: mpsc::Sender<bool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl ProgressReporter {
+    /// Starts reporting the progress of the current job to `target`
+    ///
+    /// # Arguments
+    /// * `target`: number of a file descriptor open for writing, or path of a named pipe or file
+    ///
+    /// # Errors
+    /// This function will return an error if `target` cannot be opened
+    pub fn start(target: &str) -> Result<Self, io::Error> {
+        let mut output = open_target(target)?;
+        let (stop, stopped) = mpsc::channel();
+
+        let bar = bar();
+        let thread = thread::spawn(move || {
+            let mut last = None;
+            let success = loop {
+                match stopped.recv_timeout(REPORT_INTERVAL) {
+                    Ok(success) => break success,
+                    Err(mpsc::RecvTimeoutError::Timeout) => (),
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break false,
+                }
+
+                let current = (bar.position(), bar.length());
+                if last != Some(current) {
+                    last = Some(current);
+                    // Nobody may be listening anymore, which must not disturb the transfer
+                    let _ = writeln!(output, "progress {} {}", current.0, current.1);
+                }
+            };
+
+            let _ = writeln!(
+                output,
+                "end {} {} {}",
+                if success { "ok" } else { "error" },
+                bar.position(),
+                bar.length()
+            );
+        });
+
+        Ok(ProgressReporter {
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Stops reporting, after writing the final record
+    ///
+    /// # Arguments
+    /// * `success`: whether the run finished without errors
+    pub fn finish(mut self, success: bool) {
+        let _ = self.stop.send(success);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Opens the target of progress records
+///
+/// # Errors
+/// This function will return an error if `target` cannot be opened
+#[cfg(target_family = "unix")]
+fn open_target(target: &str) -> Result<fs::File, io::Error> {
+    use std::os::unix::io::FromRawFd;
+
+    match target.parse::<i32>() {
+        // The descriptor is handed over by whoever started lms, and is owned from now on
+        Ok(fd) if fd > 2 => Ok(unsafe { fs::File::from_raw_fd(fd) }),
+        Ok(fd) => Err(io::Error::other(format!(
+            "progress cannot be reported to standard stream {}",
+            fd
+        ))),
+        Err(_) => fs::OpenOptions::new().append(true).open(target),
+    }
+}
+
+#[cfg(target_family = "windows")]
+fn open_target(target: &str) -> Result<fs::File, io::Error> {
+    // Handles cannot be passed by number, named pipes are passed by path (\\.\pipe\name)
+    fs::OpenOptions::new().write(true).open(target)
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_progress_reporter {
+    use super::*;
+
+    #[test]
+    fn records() {
+        const TEST_FILE: &str = "test_progress_reporter_records";
+
+        fs::File::create(TEST_FILE).unwrap();
+        ProgressReporter::start(TEST_FILE).unwrap().finish(true);
+
+        let records = fs::read_to_string(TEST_FILE).unwrap();
+        assert_eq!(records.lines().last().unwrap().starts_with("end ok "), true);
+
+        fs::remove_file(TEST_FILE).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test_file_progress {
+    use super::*;
+
+    #[test]
+    fn attempts() {
+        let mut progress = FileProgress {
+            size: 10,
+            credited: 0,
+            copied: 0,
+            bar: ProgressBar::hidden(),
+        };
+        let bar = progress.bar.clone();
+        progress.inc(6);
+        assert_eq!(bar.position(), 6);
+
+        // Copying the file again only credits what goes beyond the first attempt
+        progress.restart();
+        progress.inc(4);
+        assert_eq!(bar.position(), 6);
+        progress.inc(6);
+        assert_eq!(bar.position(), 10);
+        progress.inc(5);
+        assert_eq!(bar.position(), 10);
+
+        drop(progress);
+        assert_eq!(bar.position(), 10);
+    }
+}
+
+#[cfg(test)]
+mod test_fan_out {
+    use super::*;
+
+    #[test]
+    fn separate_bars() {
+        let dests = [Path::new("a"), Path::new("b")];
+        let fan_out = FanOut::start(&dests);
+        for (index, bytes) in [(0, 10), (1, 30)].iter() {
+            let result = fan_out.run(*index, || {
+                transfer_init(0, *bytes);
+                FileProgress::new(*bytes).inc(*bytes);
+                Ok(bar().position())
+            });
+            assert_eq!(result.unwrap(), *bytes);
+        }
+        assert_eq!(fan_out.jobs[0].label.as_deref(), Some("a"));
+
+        // The bar of all destinations adds up the bars of every destination
+        let bars: Vec<ProgressBar> = fan_out.jobs.iter().map(|job| job.bar.clone()).collect();
+        update_total(&fan_out.total, &bars);
+        assert_eq!(fan_out.total.position(), 40);
+        assert_eq!(fan_out.total.length(), 40);
+    }
 }
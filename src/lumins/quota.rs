@@ -0,0 +1,127 @@
+//! Contains the space budget of a destination given with --max-dest-size, which stops a
+//! synchronization before the destination grows past it, instead of filling up its disk.
+
+use std::io;
+use std::path::PathBuf;
+
+use hashbrown::HashSet;
+use log::warn;
+
+use crate::lumins::events::{self, Action};
+use crate::lumins::file_ops::{File, FileOps};
+use crate::progress;
+
+/// Gets the size the files in the destination take up once the files to delete are deleted
+///
+/// # Arguments
+/// * `dest_files`: files in the destination
+/// * `files_to_delete`: paths of the files that are deleted from the destination
+pub fn remaining_size(dest_files: &HashSet<File>, files_to_delete: &HashSet<PathBuf>) -> u64 {
+    dest_files
+        .iter()
+        .filter(|file| !files_to_delete.contains(file.path()))
+        .map(FileOps::size)
+        .sum()
+}
+
+/// Selects the files that can be copied without the destination growing past `max_dest_size`
+///
+/// Files are taken in order until the first one that does not fit, so that files copied first
+/// with --priority are the ones that fit.
+///
+/// # Arguments
+/// * `files_to_copy`: files to copy, in the order they are copied
+/// * `used`: size the files in the destination take up before any file is copied
+/// * `max_dest_size`: size in bytes that the files in the destination may take up
+///
+/// # Returns
+/// The files to copy, and the files left unsynced
+pub fn fit(
+    mut files_to_copy: Vec<&File>,
+    mut used: u64,
+    max_dest_size: u64,
+) -> (Vec<&File>, Vec<&File>) {
+    let fitting = files_to_copy
+        .iter()
+        .take_while(|file| match used.checked_add(file.size()) {
+            Some(size) if size <= max_dest_size => {
+                used = size;
+                true
+            }
+            _ => false,
+        })
+        .count();
+
+    let unsynced = files_to_copy.split_off(fitting);
+    (files_to_copy, unsynced)
+}
+
+/// Reports the files left unsynced because the destination would have grown past its budget
+///
+/// # Arguments
+/// * `unsynced`: files left unsynced
+/// * `max_dest_size`: size in bytes that the files in the destination may take up
+///
+/// # Errors
+/// This function will return an error if any file was left unsynced
+pub fn report(unsynced: &[&File], max_dest_size: u64) -> Result<(), io::Error> {
+    if unsynced.is_empty() {
+        return Ok(());
+    }
+
+    let bytes: u64 = unsynced.iter().map(|file| file.size()).sum();
+    warn!(
+        "Warning -- {} files ({} bytes) were left unsynced, the destination would exceed {} bytes",
+        unsynced.len(),
+        bytes,
+        max_dest_size
+    );
+    for file in unsynced {
+        events::record(
+            Action::Skip,
+            file.path(),
+            format_args!("Skipping {:?}, it does not fit", file.path()),
+        );
+    }
+    // Unsynced files still count towards the progress
+    progress::bar().inc(unsynced.len() as u64 + bytes);
+
+    Err(io::Error::other(format!(
+        "{} files were left unsynced, the destination would exceed --max-dest-size {}",
+        unsynced.len(),
+        max_dest_size
+    )))
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_quota {
+    use super::*;
+
+    #[test]
+    fn fit_budget() {
+        let a = File::from("a.txt", 10);
+        let b = File::from("b.txt", 20);
+        let c = File::from("c.txt", 1);
+
+        let mut dest_files = HashSet::new();
+        dest_files.insert(File::from("old.txt", 50));
+        dest_files.insert(File::from("kept.txt", 5));
+        let files_to_delete: HashSet<PathBuf> =
+            vec![PathBuf::from("old.txt")].into_iter().collect();
+        assert_eq!(remaining_size(&dest_files, &files_to_delete), 5);
+
+        // Files after the first that does not fit are left, even if they would fit
+        let (files, unsynced) = fit(vec![&a, &b, &c], 5, 30);
+        assert_eq!(files, vec![&a]);
+        assert_eq!(unsynced, vec![&b, &c]);
+        assert_eq!(report(&unsynced, 30).is_ok(), false);
+
+        let (files, unsynced) = fit(vec![&a, &b, &c], 5, 36);
+        assert_eq!(files, vec![&a, &b, &c]);
+        assert_eq!(report(&unsynced, 36).is_ok(), true);
+    }
+}
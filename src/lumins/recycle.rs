@@ -0,0 +1,108 @@
+//! Contains the trash that deleted files and symlinks are moved to with --trash, and by `lms rm`
+//! unless --permanent is given, so that they can still be restored from the trash or recycle bin
+//! of the platform.
+
+use std::io;
+use std::path::Path;
+
+use crate::lumins::events::{self, Action};
+use crate::lumins::file_ops::FileOps;
+use crate::progress;
+
+/// Moves all given files from `location` to the trash, instead of deleting them
+///
+/// Files are moved one at a time, since the trash of some platforms is only reached through a
+/// single system service.
+///
+/// # Arguments
+/// * `files_to_trash`: files to move
+/// * `location`: base directory of the files to move, such that for all `file` in
+///   `files_to_trash`, `location + file.path()` is the absolute path of the file
+///
+/// # Returns
+/// The number of files that could not be moved, which are kept
+pub fn trash_files<'a, T, S>(files_to_trash: T, location: &Path) -> usize
+where
+    T: IntoIterator<Item = &'a S>,
+    S: FileOps + 'a,
+{
+    trash_files_with(files_to_trash, location, trash)
+}
+
+/// Moves all given files from `location` with `trash`, like `trash_files`
+///
+/// # Arguments
+/// * `files_to_trash`: files to move
+/// * `location`: base directory of the files to move
+/// * `trash`: moves the file at the given absolute path to the trash
+///
+/// # Returns
+/// The number of files that could not be moved, which are kept
+fn trash_files_with<'a, T, S, F>(files_to_trash: T, location: &Path, trash: F) -> usize
+where
+    T: IntoIterator<Item = &'a S>,
+    S: FileOps + 'a,
+    F: Fn(&Path) -> Result<(), io::Error>,
+{
+    files_to_trash
+        .into_iter()
+        .filter(|file| {
+            let path = location.join(file.path());
+            let result = trash(&path);
+            match &result {
+                Ok(_) => events::record(
+                    Action::Delete,
+                    &path,
+                    format_args!("Moving {:?} to the trash", path),
+                ),
+                Err(e) => events::record(
+                    Action::Error,
+                    &path,
+                    format_args!("Error -- Moving {:?} to the trash: {}", path, e),
+                ),
+            }
+            progress::bar().inc(1);
+            result.is_err()
+        })
+        .count()
+}
+
+/// Moves the file at `path` to the trash
+///
+/// # Errors
+/// This function will return an error if the platform has no trash that the file can be moved
+/// to, in which case it is left where it is
+pub fn trash(path: &Path) -> Result<(), io::Error> {
+    ::trash::delete(path).map_err(|e| io::Error::other(e.to_string()))
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_recycle {
+    use super::*;
+    use crate::lumins::file_ops::File;
+    use std::fs;
+
+    #[test]
+    fn no_trash() {
+        const TEST_DIR: &str = "test_recycle_no_trash";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::write([TEST_DIR, "a.txt"].join("/"), b"a").unwrap();
+        fs::write([TEST_DIR, "b.txt"].join("/"), b"b").unwrap();
+        let files = vec![File::from("a.txt", 1), File::from("b.txt", 1)];
+
+        // Files that cannot be moved to the trash are kept, and only counted as failures
+        let failed = trash_files_with(&files, Path::new(TEST_DIR), |_| {
+            Err(io::Error::other("no trash"))
+        });
+        assert_eq!(failed, 2);
+        assert_eq!(fs::read([TEST_DIR, "a.txt"].join("/")).unwrap(), b"a");
+        assert_eq!(fs::read([TEST_DIR, "b.txt"].join("/")).unwrap(), b"b");
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
@@ -0,0 +1,229 @@
+//! Contains utilities for turning hard links in the source into clones in the destination, as
+//! given with --reflink-hardlinks, so that hard linked source trees such as rsnapshot archives
+//! take no more space in the destination, while every copy keeps metadata of its own, or into
+//! hard links in the destination, as given with --hard-links, which also keep the files linked.
+
+use std::fs;
+use std::path::Path;
+
+use hashbrown::HashMap;
+use rayon::prelude::*;
+
+use crate::lumins::events::{self, Action};
+use crate::lumins::file_ops::{self, File, FileOps, HashStore};
+use crate::lumins::parse::Flag;
+use crate::progress;
+
+/// Splits `files_to_copy` into the files to copy, and the files hard linked to one of them,
+/// which are cloned from its copy once it is copied
+///
+/// Of every group of files hard linked to each other, the file with the smallest path is copied.
+///
+/// # Arguments
+/// * `files_to_copy`: files missing from the destination
+/// * `src`: base directory of the source files that the paths of `files_to_copy` are relative to
+///
+/// # Returns
+/// The files to copy, and the files to clone along with the file they are cloned from
+pub fn split<'a>(
+    files_to_copy: Vec<&'a File>,
+    src: &Path,
+) -> (Vec<&'a File>, Vec<(&'a File, &'a File)>) {
+    let inodes: Vec<Option<(u64, u64)>> = files_to_copy
+        .par_iter()
+        .map(|file| {
+            let src_file = src.join(file.path());
+            fs::symlink_metadata(src_file)
+                .ok()
+                .and_then(|metadata| linked_inode(&metadata))
+        })
+        .collect();
+
+    let mut originals: HashMap<(u64, u64), &File> = HashMap::new();
+    for (file, inode) in files_to_copy.iter().zip(inodes.iter()) {
+        if let Some(inode) = inode {
+            let original = originals.entry(*inode).or_insert(file);
+            if file.path() < original.path() {
+                *original = file;
+            }
+        }
+    }
+
+    let mut files = Vec::new();
+    let mut clones = Vec::new();
+    for (file, inode) in files_to_copy.into_iter().zip(inodes) {
+        match inode.and_then(|inode| originals.get(&inode)) {
+            Some(&original) if original.path() != file.path() => clones.push((file, original)),
+            _ => files.push(file),
+        }
+    }
+    (files, clones)
+}
+
+/// Clones every file in `files_to_clone` from the copy of the file it was hard linked to in
+/// `dest`, or hard links it to that copy if `flags` contains `HARD_LINKS`, in parallel
+///
+/// Files that cannot be cloned, such as on filesystems without copy-on-write support, or linked
+/// are copied from `src` instead.
+///
+/// # Arguments
+/// * `files_to_clone`: files to clone, along with the file they are cloned from
+/// * `src`: base directory of the files to copy from, like for `split`
+/// * `dest`: base directory of the copies that the paths of `files_to_clone` are relative to
+/// * `flags`: set for Flag's, of which `HARD_LINKS` links files instead of cloning them
+/// * `hashes`: store that the hashes of the copies are recorded in, if they are recorded
+///
+/// # Returns
+/// The number of files that could neither be cloned nor copied
+pub fn clone_files(
+    files_to_clone: Vec<(&File, &File)>,
+    src: &Path,
+    dest: &Path,
+    flags: Flag,
+    hashes: Option<&HashStore>,
+) -> usize {
+    let files_to_copy: Vec<&File> = files_to_clone
+        .into_par_iter()
+        .filter_map(|(file, original)| {
+            let from = dest.join(original.path());
+            let to = dest.join(file.path());
+            let (result, verb) = if flags.contains(Flag::HARD_LINKS) {
+                (fs::hard_link(&from, &to), "Linking")
+            } else {
+                (file_ops::reflink(&from, &to), "Cloning")
+            };
+            if result.is_err() {
+                return Some(file);
+            }
+
+            events::record(
+                Action::Copy,
+                &to,
+                format_args!("{} file {:?} -> {:?}", verb, from, to),
+            );
+            // A clone or link holds the same contents as the copy it was made from
+            if let Some(hashes) = hashes {
+                if let Some(hash) = hashes.get(original.path()) {
+                    hashes.insert(file.path().clone(), hash);
+                }
+            }
+            progress::bar().inc(1 + file.size());
+            None
+        })
+        .collect();
+
+    match hashes {
+        Some(hashes) => {
+            file_ops::copy_files_hashed(files_to_copy.into_par_iter(), src, dest, flags, hashes)
+        }
+        None => file_ops::copy_files(files_to_copy.into_par_iter(), src, dest, flags),
+    }
+}
+
+/// Gets the device and inode of a file that is hard linked to other files
+#[cfg(target_family = "unix")]
+fn linked_inode(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    if metadata.is_file() && metadata.nlink() > 1 {
+        Some((metadata.dev(), metadata.ino()))
+    } else {
+        None
+    }
+}
+
+#[cfg(target_family = "windows")]
+fn linked_inode(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(all(test, target_family = "unix"))]
+mod test_reflinks {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn hard_linked_files() {
+        const TEST_SRC: &str = "test_reflinks_hard_linked_files_src";
+        const TEST_DEST: &str = "test_reflinks_hard_linked_files_dest";
+
+        fs::create_dir_all(TEST_SRC).unwrap();
+        fs::create_dir_all(TEST_DEST).unwrap();
+        fs::write([TEST_SRC, "a.txt"].join("/"), b"linked").unwrap();
+        fs::hard_link([TEST_SRC, "a.txt"].join("/"), [TEST_SRC, "b.txt"].join("/")).unwrap();
+        fs::write([TEST_SRC, "c.txt"].join("/"), b"single").unwrap();
+
+        let a = File::from("a.txt", 6);
+        let b = File::from("b.txt", 6);
+        let c = File::from("c.txt", 6);
+        let (files, clones) = split(vec![&b, &c, &a], Path::new(TEST_SRC));
+        assert_eq!(files, vec![&c, &a]);
+        assert_eq!(clones, vec![(&b, &a)]);
+
+        // Clones fall back to copies where the filesystem cannot clone
+        file_ops::copy_files(
+            files.into_par_iter(),
+            Path::new(TEST_SRC),
+            Path::new(TEST_DEST),
+            Flag::empty(),
+        );
+        clone_files(
+            clones,
+            Path::new(TEST_SRC),
+            Path::new(TEST_DEST),
+            Flag::empty(),
+            None,
+        );
+        assert_eq!(fs::read([TEST_DEST, "b.txt"].join("/")).unwrap(), b"linked");
+        assert_ne!(
+            fs::metadata([TEST_DEST, "a.txt"].join("/")).unwrap().ino(),
+            fs::metadata([TEST_DEST, "b.txt"].join("/")).unwrap().ino()
+        );
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+
+    #[test]
+    fn hard_links() {
+        const TEST_SRC: &str = "test_reflinks_hard_links_src";
+        const TEST_DEST: &str = "test_reflinks_hard_links_dest";
+
+        fs::create_dir_all(TEST_SRC).unwrap();
+        fs::create_dir_all(TEST_DEST).unwrap();
+        fs::write([TEST_SRC, "a.txt"].join("/"), b"linked").unwrap();
+        fs::hard_link([TEST_SRC, "a.txt"].join("/"), [TEST_SRC, "b.txt"].join("/")).unwrap();
+
+        let a = File::from("a.txt", 6);
+        let b = File::from("b.txt", 6);
+        let (files, links) = split(vec![&a, &b], Path::new(TEST_SRC));
+        file_ops::copy_files(
+            files.into_par_iter(),
+            Path::new(TEST_SRC),
+            Path::new(TEST_DEST),
+            Flag::HARD_LINKS,
+        );
+        assert_eq!(
+            clone_files(
+                links,
+                Path::new(TEST_SRC),
+                Path::new(TEST_DEST),
+                Flag::HARD_LINKS,
+                None,
+            ),
+            0
+        );
+        assert_eq!(fs::read([TEST_DEST, "b.txt"].join("/")).unwrap(), b"linked");
+        assert_eq!(
+            fs::metadata([TEST_DEST, "a.txt"].join("/")).unwrap().ino(),
+            fs::metadata([TEST_DEST, "b.txt"].join("/")).unwrap().ino()
+        );
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+}
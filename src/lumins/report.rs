@@ -0,0 +1,252 @@
+//! Contains the report given with --report, which writes what a dry run planned, or what a run
+//! did and what failed, to a JSON file with entries of the same shape, so that the plan of a run
+//! can be diffed against what it actually did by audit pipelines.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+use crate::lumins::events::{self, json_string};
+use crate::lumins::plan::{self, Plan};
+
+lazy_static! {
+    /// Report of the current run, once it is configured
+    static ref REPORT: Mutex<Option<Report>> = Mutex::new(None);
+}
+
+/// Struct to represent an entry of a report, which is the same for planned and actual changes
+#[derive(Eq, PartialEq, Ord, PartialOrd, Debug, Clone)]
+struct Entry {
+    /// Path relative to the source or destination
+    path: PathBuf,
+    /// Name of the action, as in the JSON output of --output json
+    action: &'static str,
+    /// Why the action failed, for failed entries
+    message: Option<String>,
+}
+
+/// Struct to represent the report of a run
+#[derive(Debug, Default)]
+struct Report {
+    /// File that the report is written to
+    file: PathBuf,
+    /// Source and destination directories, which the paths of the entries are relative to
+    roots: Vec<PathBuf>,
+    /// Changes planned by a dry run
+    planned: Vec<Entry>,
+    /// Changes made by the run
+    done: Vec<Entry>,
+    /// Changes that failed
+    failed: Vec<Entry>,
+}
+
+impl Report {
+    /// Gets `path` relative to the root it is in
+    fn relative(&self, path: &Path) -> PathBuf {
+        self.roots
+            .iter()
+            .filter_map(|root| path.strip_prefix(root).ok())
+            .min_by_key(|relative| relative.components().count())
+            .unwrap_or(path)
+            .to_path_buf()
+    }
+}
+
+/// Configures the report of the current run
+///
+/// # Arguments
+/// * `file`: file to write the report to, None to write no report
+/// * `roots`: source and destination directories of the run
+pub fn configure(file: Option<PathBuf>, roots: Vec<PathBuf>) {
+    *REPORT.lock().unwrap() = file.map(|file| Report {
+        file,
+        roots,
+        ..Report::default()
+    });
+}
+
+/// Adds an action taken on `path` to the report, if one is configured
+///
+/// # Arguments
+/// * `action`: action taken
+/// * `path`: path the action was taken on
+/// * `message`: description of the action, which is kept for failed actions
+pub fn record(action: events::Action, path: &Path, message: &str) {
+    let mut report = REPORT.lock().unwrap();
+    let report = match report.as_mut() {
+        Some(report) => report,
+        None => return,
+    };
+
+    let entry = Entry {
+        path: report.relative(path),
+        action: action.name(),
+        message: None,
+    };
+    match action {
+        events::Action::Copy | events::Action::Rename | events::Action::Delete => {
+            report.done.push(entry)
+        }
+        events::Action::Error => report.failed.push(Entry {
+            message: Some(message.to_string()),
+            ..entry
+        }),
+        events::Action::Skip | events::Action::Warning => (),
+    }
+}
+
+/// Adds the changes of `plan` to the report, if one is configured
+pub fn record_plan(plan: &Plan) {
+    if let Some(report) = REPORT.lock().unwrap().as_mut() {
+        report.planned.extend(planned_entries(plan));
+    }
+}
+
+/// Gets the entries of the changes of `plan`
+///
+/// Changes that a run does not record as actions, such as backups and metadata, are left out,
+/// and the others are named like the actions that carry them out.
+fn planned_entries(plan: &Plan) -> Vec<Entry> {
+    plan.actions()
+        .iter()
+        .filter_map(|(action, path)| {
+            let action = match action {
+                plan::Action::CreateDir
+                | plan::Action::Copy
+                | plan::Action::Update
+                | plan::Action::Symlink => events::Action::Copy,
+                plan::Action::Rename => events::Action::Rename,
+                plan::Action::Delete | plan::Action::RemoveSource => events::Action::Delete,
+                plan::Action::Backup | plan::Action::Metadata => return None,
+            };
+            Some(Entry {
+                path: path.clone(),
+                action: action.name(),
+                message: None,
+            })
+        })
+        .collect()
+}
+
+/// Writes the report of the current run, if one is configured
+///
+/// # Arguments
+/// * `error`: error that the run failed with, None if it succeeded
+///
+/// # Errors
+/// This function will return an error if the report cannot be written
+pub fn write(error: Option<&io::Error>) -> Result<(), io::Error> {
+    let mut report = REPORT.lock().unwrap();
+    match report.as_mut() {
+        Some(report) => {
+            let json = to_json(report, error);
+            fs::write(&report.file, json)
+        }
+        None => Ok(()),
+    }
+}
+
+/// Formats `report` as JSON, with one entry per line and the entries of every list sorted by
+/// path, so that the lists of different runs can be diffed line by line
+fn to_json(report: &mut Report, error: Option<&io::Error>) -> String {
+    let mut json = String::from("{\n");
+    for (name, entries) in [
+        ("planned", &mut report.planned),
+        ("done", &mut report.done),
+        ("failed", &mut report.failed),
+    ] {
+        entries.sort();
+        json.push_str(&format!("{}:[\n", json_string(name)));
+        for (i, entry) in entries.iter().enumerate() {
+            json.push_str(&format!(
+                "{{\"action\":{},\"path\":{}",
+                json_string(entry.action),
+                json_string(&path_name(&entry.path))
+            ));
+            if let Some(message) = &entry.message {
+                json.push_str(&format!(",\"message\":{}", json_string(message)));
+            }
+            json.push_str(if i + 1 < entries.len() { "},\n" } else { "}\n" });
+        }
+        json.push_str("],\n");
+    }
+    json.push_str(&format!(
+        "\"result\":{}\n}}\n",
+        json_string(&match error {
+            Some(e) => format!("error: {}", e),
+            None => String::from("ok"),
+        })
+    ));
+    json
+}
+
+/// Gets the name of `path` in a report, where the base directory itself is "."
+fn path_name(path: &Path) -> String {
+    if path.as_os_str().is_empty() {
+        String::from(".")
+    } else {
+        path.to_string_lossy().into_owned()
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_report {
+    use super::*;
+
+    #[test]
+    fn diffable() {
+        let mut plan = Plan::default();
+        plan.add(plan::Action::Update, vec!["b.txt"]);
+        plan.add(plan::Action::CreateDir, vec!["dir"]);
+        plan.add(plan::Action::Backup, vec!["b.txt"]);
+
+        let mut report = Report {
+            roots: vec![PathBuf::from("/src"), PathBuf::from("/dest")],
+            ..Report::default()
+        };
+        assert_eq!(
+            report.relative(Path::new("/dest/dir")),
+            PathBuf::from("dir")
+        );
+
+        // Planned and actual changes of the same paths read the same
+        report.planned = planned_entries(&plan);
+        for path in ["/dest/dir", "/dest/b.txt"].iter() {
+            report.done.push(Entry {
+                path: report.relative(Path::new(path)),
+                action: events::Action::Copy.name(),
+                message: None,
+            });
+        }
+        report.failed.push(Entry {
+            path: report.relative(Path::new("/src/c.txt")),
+            action: events::Action::Error.name(),
+            message: Some(String::from("denied")),
+        });
+
+        assert_eq!(
+            to_json(&mut report, None),
+            "{\n\
+             \"planned\":[\n\
+             {\"action\":\"copy\",\"path\":\"b.txt\"},\n\
+             {\"action\":\"copy\",\"path\":\"dir\"}\n\
+             ],\n\
+             \"done\":[\n\
+             {\"action\":\"copy\",\"path\":\"b.txt\"},\n\
+             {\"action\":\"copy\",\"path\":\"dir\"}\n\
+             ],\n\
+             \"failed\":[\n\
+             {\"action\":\"error\",\"path\":\"c.txt\",\"message\":\"denied\"}\n\
+             ],\n\
+             \"result\":\"ok\"\n\
+             }\n"
+        );
+    }
+}
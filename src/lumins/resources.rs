@@ -0,0 +1,155 @@
+//! Contains the resources used by a run, namely its peak memory, the bytes it read from and wrote
+//! to storage, and its CPU time, which are reported in its summary so that the effect of tuning
+//! options such as --threads and --block-size can be measured.
+
+use std::fs;
+use std::time::Duration;
+
+use indicatif::HumanBytes;
+
+/// Struct to represent the resources used by this process so far, leaving out those that cannot
+/// be read on this platform
+#[derive(Eq, PartialEq, Debug, Default, Clone, Copy)]
+pub struct Resources {
+    /// Largest resident set size in bytes
+    pub peak_rss: Option<u64>,
+    /// Bytes read from storage, which leaves out reads served from the page cache
+    pub read_bytes: Option<u64>,
+    /// Bytes written to storage
+    pub write_bytes: Option<u64>,
+    /// Time spent on a CPU, in user and kernel mode
+    pub cpu_time: Option<Duration>,
+}
+
+impl Resources {
+    /// Formats the resources as the fields of a JSON object, without braces, such as
+    /// `"peak_rss":1024,"cpu_seconds":0.52`
+    pub fn to_json_fields(&self) -> String {
+        let numbers = [
+            ("peak_rss", self.peak_rss),
+            ("read_bytes", self.read_bytes),
+            ("write_bytes", self.write_bytes),
+        ];
+        numbers
+            .iter()
+            .filter_map(|(name, value)| value.map(|value| format!("\"{}\":{}", name, value)))
+            .chain(
+                self.cpu_time
+                    .map(|cpu_time| format!("\"cpu_seconds\":{:.3}", cpu_time.as_secs_f64())),
+            )
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+
+    /// Formats the resources as the fields of a summary meant for people
+    pub fn to_fields(&self) -> Vec<(&'static str, String)> {
+        let bytes = |bytes: Option<u64>| bytes.map(|bytes| HumanBytes(bytes).to_string());
+        [
+            ("peak memory", bytes(self.peak_rss)),
+            ("read", bytes(self.read_bytes)),
+            ("written", bytes(self.write_bytes)),
+            (
+                "cpu time",
+                self.cpu_time
+                    .map(|cpu_time| format!("{:.1}s", cpu_time.as_secs_f64())),
+            ),
+        ]
+        .iter()
+        .filter_map(|(name, value)| value.clone().map(|value| (*name, value)))
+        .collect()
+    }
+}
+
+/// Gets the resources used by this process so far
+pub fn current() -> Resources {
+    let (peak_rss, cpu_time) = rusage().unzip();
+    let (read_bytes, write_bytes) = storage_io().unzip();
+    Resources {
+        peak_rss,
+        read_bytes,
+        write_bytes,
+        cpu_time,
+    }
+}
+
+/// Gets the peak resident set size in bytes and the CPU time of this process
+#[cfg(target_family = "unix")]
+fn rusage() -> Option<(u64, Duration)> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // This is safe, since `getrusage` only writes to `usage`, which is only read if it succeeded
+    let usage = unsafe {
+        if libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) != 0 {
+            return None;
+        }
+        usage.assume_init()
+    };
+
+    // macOS reports the peak resident set size in bytes, and every other system in KiB
+    let peak_rss = if cfg!(target_os = "macos") {
+        usage.ru_maxrss as u64
+    } else {
+        usage.ru_maxrss as u64 * 1024
+    };
+    let time = |time: libc::timeval| {
+        Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+    };
+    Some((peak_rss, time(usage.ru_utime) + time(usage.ru_stime)))
+}
+
+#[cfg(target_family = "windows")]
+fn rusage() -> Option<(u64, Duration)> {
+    None
+}
+
+/// Gets the bytes this process read from and wrote to storage, from `/proc/self/io`
+fn storage_io() -> Option<(u64, u64)> {
+    parse_io(&fs::read_to_string("/proc/self/io").ok()?)
+}
+
+/// Parses the bytes read from and written to storage out of the contents of `/proc/self/io`
+fn parse_io(io: &str) -> Option<(u64, u64)> {
+    let field = |name: &str| {
+        io.lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(key, _)| *key == name)
+            .and_then(|(_, value)| value.trim().parse().ok())
+    };
+    Some((field("read_bytes")?, field("write_bytes")?))
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_resources {
+    use super::*;
+
+    #[test]
+    fn formats() {
+        let io = "rchar: 5000\nwchar: 300\nsyscr: 12\nsyscw: 3\nread_bytes: 4096\n\
+                  write_bytes: 8192\ncancelled_write_bytes: 0\n";
+        assert_eq!(parse_io(io), Some((4096, 8192)));
+        assert_eq!(parse_io("rchar: 5000\n"), None);
+
+        let resources = Resources {
+            peak_rss: Some(2048),
+            read_bytes: None,
+            write_bytes: Some(10),
+            cpu_time: Some(Duration::from_millis(1500)),
+        };
+        assert_eq!(
+            resources.to_json_fields(),
+            "\"peak_rss\":2048,\"write_bytes\":10,\"cpu_seconds\":1.500"
+        );
+        assert_eq!(
+            resources.to_fields(),
+            vec![
+                ("peak memory", String::from("2.00KiB")),
+                ("written", String::from("10B")),
+                ("cpu time", String::from("1.5s")),
+            ]
+        );
+        assert_eq!(Resources::default().to_json_fields(), "");
+    }
+}
@@ -0,0 +1,469 @@
+//! Contains utilities for reusing contents that already exist in the destination.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use hashbrown::{HashMap, HashSet};
+use log::{error, info};
+use rayon::prelude::*;
+
+use crate::lumins::backups;
+use crate::lumins::events::{self, Action};
+use crate::lumins::file_ops::{self, File, FileHash, FileOps, HashStore};
+use crate::lumins::parse::Flag;
+use crate::lumins::rollback;
+use crate::progress;
+
+/// Copies files that are missing from `dest` from files with identical contents
+/// elsewhere in `dest`, instead of reading them from `src`, in parallel
+///
+/// Hashes of the source files are taken from `hashes` when known, and are only
+/// computed when a file of the same size exists in `dest`. Files are cloned where the
+/// filesystem supports it, and files that were merely moved are hard linked to the file
+/// they were moved from, so neither rewrites any data.
+///
+/// # Arguments
+/// * `files_to_copy`: files missing from `dest`
+/// * `dest_files`: files that currently exist in `dest`
+/// * `deleted`: paths of the files in `dest` that are deleted after copying
+/// * `src`: base directory of the files to copy from, such that for all `file` in
+/// `files_to_copy`, `src + file.path()` is the absolute path of the source file
+/// * `dest`: base directory of the files to copy to, such that for all `file` in
+/// `files_to_copy`, `dest + file.path()` is the absolute path of the destination file
+/// * `flags`: set for Flag's
+/// * `hashes`: hashes of the source files, which are recorded in it as they are computed
+///
+/// # Returns
+/// The files that could not be copied from `dest`, which still have to be copied from `src`
+pub fn copy_files_from_dest<'a>(
+    files_to_copy: Vec<&'a File>,
+    dest_files: &HashSet<File>,
+    deleted: &HashSet<PathBuf>,
+    src: &Path,
+    dest: &Path,
+    flags: Flag,
+    hashes: &HashStore,
+) -> Vec<&'a File> {
+    // Only files of equal size can be identical, and empty files are cheap to copy anyway
+    let mut dest_files_by_size: HashMap<u64, Vec<&File>> = HashMap::new();
+    for file in dest_files.iter().filter(|file| file.size() > 0) {
+        dest_files_by_size
+            .entry(file.size())
+            .or_default()
+            .push(file);
+    }
+    let dest_hashes = HashStore::default();
+    let moved = Mutex::new(HashSet::new());
+
+    files_to_copy
+        .into_par_iter()
+        .filter(|file| {
+            let candidates = match dest_files_by_size.get(&file.size()) {
+                Some(candidates) => candidates,
+                None => return true,
+            };
+
+            let hash = match hashes
+                .get(file.path())
+                .or_else(|| file_ops::hash_file_with_flags(*file, src, flags))
+            {
+                Some(hash) => hash,
+                None => return true,
+            };
+            hashes.insert(file.path().clone(), hash.clone());
+
+            let original = match find_identical(&hash, candidates, dest, flags, &dest_hashes) {
+                Some(original) => original,
+                None => return true,
+            };
+
+            // A file that is about to be deleted can become a single new file without copying
+            let renamed = deleted.contains(original.path())
+                && moved.lock().unwrap().insert(original.path().clone());
+
+            let from = dest.join(original.path());
+            let to = dest.join(file.path());
+            match copy_within(&from, &to, renamed) {
+                Ok(_) => {
+                    progress::bar().inc(1 + file.size());
+                    false
+                }
+                Err(e) => {
+                    error!("Error -- Copying file {:?}: {}", from, e);
+                    true
+                }
+            }
+        })
+        .collect()
+}
+
+/// Finds the files missing from `dest` that were only moved in the source, as files with
+/// identical contents that are about to be deleted from `dest`
+///
+/// Hashes of the source files are taken from `hashes` when known, and are only computed when a
+/// file of the same size is deleted from `dest`. Files are only matched to paths that do not
+/// exist in `dest` yet, so that no rename replaces another file.
+///
+/// # Arguments
+/// * `files_to_copy`: files missing from `dest`
+/// * `dest_files`: files that currently exist in `dest`
+/// * `files_to_delete`: paths of the files in `dest` that are deleted
+/// * `src`: base directory of the files to copy from, like for `copy_files_from_dest`
+/// * `dest`: base directory of the files to copy to, like for `copy_files_from_dest`
+/// * `flags`: set for Flag's
+/// * `hashes`: hashes of the source files, which are recorded in it as they are computed
+///
+/// # Returns
+/// The files that still have to be copied, and the files to rename along with the file in
+/// `dest` they are renamed from
+pub fn find_renames<'a, 'b>(
+    files_to_copy: Vec<&'a File>,
+    dest_files: &'b HashSet<File>,
+    files_to_delete: &HashSet<PathBuf>,
+    src: &Path,
+    dest: &Path,
+    flags: Flag,
+    hashes: &HashStore,
+) -> (Vec<&'a File>, Vec<(&'a File, &'b File)>) {
+    // Empty files are cheap to copy anyway
+    let dest_paths: HashSet<&PathBuf> = dest_files.iter().map(FileOps::path).collect();
+    let deleted: Vec<&File> = dest_files
+        .iter()
+        .filter(|file| file.size() > 0 && files_to_delete.contains(file.path()))
+        .collect();
+    let deleted_sizes: HashSet<u64> = deleted.iter().map(|file| file.size()).collect();
+
+    let src_hashes: Vec<Option<FileHash>> = files_to_copy
+        .par_iter()
+        .map(|file| {
+            if !deleted_sizes.contains(&file.size()) || dest_paths.contains(file.path()) {
+                return None;
+            }
+            let hash = hashes
+                .get(file.path())
+                .or_else(|| file_ops::hash_file_with_flags(*file, src, flags))?;
+            hashes.insert(file.path().clone(), hash.clone());
+            Some(hash)
+        })
+        .collect();
+
+    // Deleted files are only hashed if a source file of the same size was hashed
+    let hashed_sizes: HashSet<u64> = files_to_copy
+        .iter()
+        .zip(src_hashes.iter())
+        .filter(|(_, hash)| hash.is_some())
+        .map(|(file, _)| file.size())
+        .collect();
+    let dest_hashes: Vec<Option<FileHash>> = deleted
+        .par_iter()
+        .map(|file| {
+            if hashed_sizes.contains(&file.size()) {
+                file_ops::hash_file_with_flags(*file, dest, flags)
+            } else {
+                None
+            }
+        })
+        .collect();
+    let mut originals: HashMap<(u64, FileHash), Vec<&File>> = HashMap::new();
+    for (file, hash) in deleted.into_iter().zip(dest_hashes) {
+        if let Some(hash) = hash {
+            originals.entry((file.size(), hash)).or_default().push(file);
+        }
+    }
+
+    // Every file in dest is renamed at most once, further moved copies are copied
+    let mut files = Vec::new();
+    let mut renames = Vec::new();
+    for (file, hash) in files_to_copy.into_iter().zip(src_hashes) {
+        match hash.and_then(|hash| originals.get_mut(&(file.size(), hash))?.pop()) {
+            Some(original) => renames.push((file, original)),
+            None => files.push(file),
+        }
+    }
+    (files, renames)
+}
+
+/// Renames every file in `files_to_rename` in `dest` from the file it was moved from, in
+/// parallel, recording the renames to roll back with --backup-dir
+///
+/// # Arguments
+/// * `files_to_rename`: files to rename, along with the file in `dest` they are renamed from
+/// * `dest`: base directory of the files to rename
+///
+/// # Returns
+/// The paths of the files in `dest` that were renamed, and the files that could not be renamed,
+/// which still have to be copied
+pub fn rename_files<'a>(
+    files_to_rename: Vec<(&'a File, &File)>,
+    dest: &Path,
+) -> (HashSet<PathBuf>, Vec<&'a File>) {
+    let renamed = Mutex::new(HashSet::new());
+    let files_to_copy = files_to_rename
+        .into_par_iter()
+        .filter_map(|(file, original)| {
+            let from = dest.join(original.path());
+            let to = dest.join(file.path());
+            let result = match to.parent() {
+                Some(parent) => fs::create_dir_all(parent),
+                None => Ok(()),
+            }
+            .and_then(|_| fs::rename(&from, &to));
+            match result {
+                Ok(_) => {
+                    events::record(
+                        Action::Rename,
+                        &to,
+                        format_args!("Renaming file {:?} -> {:?}", from, to),
+                    );
+                    if backups::is_configured() {
+                        rollback::renamed(dest, original.path(), file.path());
+                    }
+                    renamed.lock().unwrap().insert(original.path().clone());
+                    // The rename stands in for both copying the file and deleting the original
+                    progress::bar().inc(2 + file.size());
+                    None
+                }
+                Err(e) => {
+                    events::record(
+                        Action::Error,
+                        &from,
+                        format_args!("Error -- Renaming file {:?} -> {:?}: {}", from, to, e),
+                    );
+                    Some(file)
+                }
+            }
+        })
+        .collect();
+    (renamed.into_inner().unwrap(), files_to_copy)
+}
+
+/// Finds a file among `candidates` whose contents hash to `hash`
+///
+/// # Arguments
+/// * `hash`: hash of the contents to look for
+/// * `candidates`: files in `dest` of the same size as the contents to look for
+/// * `dest`: base directory of the candidates
+/// * `flags`: set for Flag's
+/// * `dest_hashes`: hashes of the candidates, which are recorded in it as they are computed
+///
+/// # Returns
+/// * Some: A file with the given contents
+/// * None: If no candidate has the given contents
+fn find_identical<'a>(
+    hash: &FileHash,
+    candidates: &[&'a File],
+    dest: &Path,
+    flags: Flag,
+    dest_hashes: &HashStore,
+) -> Option<&'a File> {
+    candidates.iter().copied().find(|candidate| {
+        let candidate_hash = match dest_hashes.get(candidate.path()) {
+            Some(candidate_hash) => candidate_hash,
+            None => match file_ops::hash_file_with_flags(*candidate, dest, flags) {
+                Some(candidate_hash) => {
+                    dest_hashes.insert(candidate.path().clone(), candidate_hash.clone());
+                    candidate_hash
+                }
+                None => return false,
+            },
+        };
+        candidate_hash == *hash
+    })
+}
+
+/// Copies the file `from` to `to` within the destination, creating the parent directory of `to`
+///
+/// The file is cloned if possible, otherwise it is hard linked if `renamed`, or copied
+///
+/// # Arguments
+/// * `from`: absolute path of the file to copy
+/// * `to`: absolute path of the copy
+/// * `renamed`: whether `from` is deleted afterwards, so that `to` may share its inode
+///
+/// # Errors
+/// This function will return an error if the parent directory cannot be created
+/// or the file cannot be copied
+fn copy_within(from: &Path, to: &Path, renamed: bool) -> Result<(), io::Error> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    if file_ops::reflink(from, to).is_ok() {
+        info!("Cloning file {:?} -> {:?}", from, to);
+    } else if renamed && fs::hard_link(from, to).is_ok() {
+        info!("Linking file {:?} -> {:?}", to, from);
+    } else {
+        fs::copy(from, to)?;
+        info!("Copying file {:?} -> {:?}", from, to);
+    }
+
+    Ok(())
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_copy_files_from_dest {
+    use super::*;
+
+    #[test]
+    fn moved_file() {
+        const TEST_SRC: &str = "test_copy_files_from_dest_moved_file_src";
+        const TEST_DEST: &str = "test_copy_files_from_dest_moved_file_dest";
+        const TEST_DATA: [&[u8]; 2] = [b"moved contents", b"new contents!!"];
+
+        fs::create_dir_all([TEST_SRC, "sub"].join("/")).unwrap();
+        fs::create_dir_all(TEST_DEST).unwrap();
+
+        // The first file moved from the root of dest into a subdirectory,
+        // the second file has the same size but different contents
+        fs::write([TEST_SRC, "sub/moved.txt"].join("/"), TEST_DATA[0]).unwrap();
+        fs::write([TEST_SRC, "new.txt"].join("/"), TEST_DATA[1]).unwrap();
+        fs::write([TEST_DEST, "old.txt"].join("/"), TEST_DATA[0]).unwrap();
+
+        let src_files = vec![
+            File::from("sub/moved.txt", TEST_DATA[0].len() as u64),
+            File::from("new.txt", TEST_DATA[1].len() as u64),
+        ];
+        let mut dest_files = HashSet::new();
+        dest_files.insert(File::from("old.txt", TEST_DATA[0].len() as u64));
+
+        let hashes = HashStore::default();
+        let remaining = copy_files_from_dest(
+            src_files.iter().collect(),
+            &dest_files,
+            &HashSet::new(),
+            Path::new(TEST_SRC),
+            Path::new(TEST_DEST),
+            Flag::empty(),
+            &hashes,
+        );
+
+        assert_eq!(remaining, vec![&src_files[1]]);
+        assert_eq!(
+            fs::read([TEST_DEST, "sub/moved.txt"].join("/")).unwrap(),
+            TEST_DATA[0]
+        );
+        assert_eq!(hashes.get(Path::new("sub/moved.txt")).is_some(), true);
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn renamed_file() {
+        use std::os::unix::fs::MetadataExt;
+
+        const TEST_SRC: &str = "test_copy_files_from_dest_renamed_file_src";
+        const TEST_DEST: &str = "test_copy_files_from_dest_renamed_file_dest";
+        const TEST_DATA: &[u8] = b"renamed contents";
+
+        fs::create_dir_all(TEST_SRC).unwrap();
+        fs::create_dir_all(TEST_DEST).unwrap();
+
+        // The file was renamed and also copied once in src
+        fs::write([TEST_SRC, "new.txt"].join("/"), TEST_DATA).unwrap();
+        fs::write([TEST_SRC, "new_copy.txt"].join("/"), TEST_DATA).unwrap();
+        fs::write([TEST_DEST, "old.txt"].join("/"), TEST_DATA).unwrap();
+
+        let src_files = vec![
+            File::from("new.txt", TEST_DATA.len() as u64),
+            File::from("new_copy.txt", TEST_DATA.len() as u64),
+        ];
+        let mut dest_files = HashSet::new();
+        dest_files.insert(File::from("old.txt", TEST_DATA.len() as u64));
+        let mut deleted = HashSet::new();
+        deleted.insert(PathBuf::from("old.txt"));
+
+        let remaining = copy_files_from_dest(
+            src_files.iter().collect(),
+            &dest_files,
+            &deleted,
+            Path::new(TEST_SRC),
+            Path::new(TEST_DEST),
+            Flag::empty(),
+            &HashStore::default(),
+        );
+
+        let inode = |file: &str| fs::metadata([TEST_DEST, file].join("/")).unwrap().ino();
+        let old_inode = inode("old.txt");
+        let new_inodes = [inode("new.txt"), inode("new_copy.txt")];
+
+        // Only one of the new files may take over the inode of the old file
+        assert_eq!(remaining.is_empty(), true);
+        assert_ne!(new_inodes[0], new_inodes[1]);
+        assert_eq!(
+            new_inodes.iter().filter(|&&ino| ino == old_inode).count() <= 1,
+            true
+        );
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test_renames {
+    use super::*;
+
+    #[test]
+    fn moved_files() {
+        const TEST_SRC: &str = "test_renames_moved_files_src";
+        const TEST_DEST: &str = "test_renames_moved_files_dest";
+        const TEST_DATA: [&[u8]; 2] = [b"moved contents", b"other contents"];
+
+        fs::create_dir_all([TEST_SRC, "new_dir"].join("/")).unwrap();
+        fs::create_dir_all([TEST_DEST, "old_dir"].join("/")).unwrap();
+
+        // The first file was moved along with its directory, the second one has the same size as
+        // a deleted file but different contents, and the third one is a second copy
+        fs::write([TEST_SRC, "new_dir/moved.txt"].join("/"), TEST_DATA[0]).unwrap();
+        fs::write([TEST_SRC, "other.txt"].join("/"), TEST_DATA[1]).unwrap();
+        fs::write([TEST_SRC, "copy.txt"].join("/"), TEST_DATA[0]).unwrap();
+        fs::write([TEST_DEST, "old_dir/moved.txt"].join("/"), TEST_DATA[0]).unwrap();
+
+        let size = TEST_DATA[0].len() as u64;
+        let src_files = vec![
+            File::from("new_dir/moved.txt", size),
+            File::from("other.txt", size),
+            File::from("copy.txt", size),
+        ];
+        let mut dest_files = HashSet::new();
+        dest_files.insert(File::from("old_dir/moved.txt", size));
+        let mut deleted = HashSet::new();
+        deleted.insert(PathBuf::from("old_dir/moved.txt"));
+
+        let (files, renames) = find_renames(
+            src_files.iter().collect(),
+            &dest_files,
+            &deleted,
+            Path::new(TEST_SRC),
+            Path::new(TEST_DEST),
+            Flag::empty(),
+            &HashStore::default(),
+        );
+        assert_eq!(files, vec![&src_files[1], &src_files[2]]);
+        assert_eq!(renames.len(), 1);
+        assert_eq!(renames[0].0, &src_files[0]);
+
+        let (renamed, remaining) = rename_files(renames, Path::new(TEST_DEST));
+        assert_eq!(remaining.is_empty(), true);
+        assert_eq!(renamed.contains(Path::new("old_dir/moved.txt")), true);
+        assert_eq!(
+            fs::read([TEST_DEST, "new_dir/moved.txt"].join("/")).unwrap(),
+            TEST_DATA[0]
+        );
+        assert_eq!(
+            Path::new(TEST_DEST).join("old_dir/moved.txt").exists(),
+            false
+        );
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+}
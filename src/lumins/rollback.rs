@@ -0,0 +1,366 @@
+//! Contains the record of the last synchronization with --backup-dir, which lists the files it
+//! backed up, renamed, and the paths it created as it goes, and `lms rollback`, which renames the
+//! files back, restores the backed up files, and deletes the created paths, so that a destination
+//! can be taken back to its state before a sync, even if the sync failed midway.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use log::info;
+
+use crate::lumins::events::{self, Action};
+use crate::lumins::journal::{escape, unescape};
+use crate::lumins::state::{self, STATE_DIR};
+
+/// Name of the record of the last synchronization inside `STATE_DIR`
+const ROLLBACK_FILE: &str = "rollback";
+
+/// First line of a record, followed by the backup directory of the synchronization
+const HEADER: &str = "lms-rollback ";
+
+lazy_static! {
+    /// Lock so that the lines appended by parallel file operations are not interleaved
+    static ref APPENDING: Mutex<()> = Mutex::new(());
+}
+
+/// Struct to represent what the last synchronization of a destination changed
+#[derive(Eq, PartialEq, Debug, Default)]
+struct Record {
+    /// Directory that the old versions of the changed files were kept in
+    backup_dir: PathBuf,
+    /// Files that were deleted or overwritten, once they were backed up
+    backed_up: Vec<PathBuf>,
+    /// Files, dirs, and symlinks that did not exist in the destination before
+    created: Vec<PathBuf>,
+    /// Files that were renamed, from their old path to their new one
+    renamed: Vec<(PathBuf, PathBuf)>,
+}
+
+/// Starts the record of the synchronization of `dest`, replacing the record of the one before
+///
+/// The paths it creates are recorded up front, and what it backs up and renames is appended once
+/// it is done, so that a synchronization that fails midway can be rolled back as well.
+///
+/// # Arguments
+/// * `dest`: Destination directory
+/// * `backup_dir`: directory the old versions of the changed files are kept in
+/// * `created`: paths, relative to `dest`, that do not exist before the synchronization
+///
+/// # Errors
+/// This function will return an error if the record cannot be written
+pub fn start(dest: &Path, backup_dir: &Path, created: &[PathBuf]) -> Result<(), io::Error> {
+    let mut records = HEADER.as_bytes().to_vec();
+    records.extend(escape(&state::path_key(backup_dir)));
+    records.push(b'\n');
+    for path in created.iter() {
+        push_line(&mut records, b'C', path);
+    }
+
+    let _lock = APPENDING.lock().unwrap();
+    fs::create_dir_all(dest.join(STATE_DIR))?;
+    fs::File::create(rollback_path(dest))?.write_all(&records)
+}
+
+/// Records that the file at `path` in `dest` was moved or copied into the backup directory
+pub fn backed_up(dest: &Path, path: &Path) {
+    let mut records = Vec::new();
+    push_line(&mut records, b'B', path);
+    append(dest, path, &records);
+}
+
+/// Records that the file at `from` in `dest` was renamed to `to`
+pub fn renamed(dest: &Path, from: &Path, to: &Path) {
+    let mut records = Vec::new();
+    push_line(&mut records, b'R', from);
+    push_line(&mut records, b'T', to);
+    append(dest, from, &records);
+}
+
+/// Appends a line of kind `kind` for `path` to `records`
+fn push_line(records: &mut Vec<u8>, kind: u8, path: &Path) {
+    records.extend_from_slice(&[kind, b' ']);
+    records.extend(escape(&state::path_key(path)));
+    records.push(b'\n');
+}
+
+/// Appends `records` about `path` to the record of the synchronization of `dest`, if one was
+/// started
+fn append(dest: &Path, path: &Path, records: &[u8]) {
+    let _lock = APPENDING.lock().unwrap();
+    let result = fs::OpenOptions::new()
+        .append(true)
+        .open(rollback_path(dest))
+        .and_then(|mut file| file.write_all(records));
+    match result {
+        Ok(_) => (),
+        // Without --backup-dir, no record is started
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => {
+            let path = dest.join(path);
+            events::record(
+                Action::Error,
+                &path,
+                format_args!(
+                    "Error -- Recording {:?} to roll back, it cannot be rolled back: {}",
+                    path, e
+                ),
+            )
+        }
+    }
+}
+
+/// Discards the record of an earlier synchronization of `dest`, which can no longer be rolled
+/// back once the destination was synchronized without a backup directory
+///
+/// # Errors
+/// This function will return an error if the record exists but cannot be deleted
+pub fn discard(dest: &Path) -> Result<(), io::Error> {
+    match fs::remove_file(rollback_path(dest)) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Undoes the last synchronization of `dest` with --backup-dir, like `lms rollback`
+///
+/// The files it renamed are renamed back, newest first, the paths it created are deleted, where
+/// dirs are only deleted once they are empty, and the files it deleted or overwrote are moved
+/// back out of the backup directory.
+///
+/// # Arguments
+/// * `dest`: Destination directory
+///
+/// # Errors
+/// This function will return an error in the following situations,
+/// but is not limited to just these cases:
+/// * No synchronization of `dest` was recorded
+/// * Any path could not be restored or deleted, in which case the record is kept to retry
+pub fn rollback(dest: &Path) -> Result<(), io::Error> {
+    let record = read(dest)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "no synchronization with --backup-dir of {:?} to roll back",
+                dest
+            ),
+        )
+    })?;
+
+    let mut failed = 0;
+    for (from, to) in record.renamed.iter().rev() {
+        let (from, to) = (dest.join(from), dest.join(to));
+        match fs::rename(&to, &from) {
+            Ok(_) => events::record(
+                Action::Rename,
+                &from,
+                format_args!("Renaming file {:?} -> {:?}", to, from),
+            ),
+            // A rollback that is retried finds the files it already renamed back gone
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                info!("Skipping {:?}, it is gone", to)
+            }
+            Err(e) => {
+                events::record(
+                    Action::Error,
+                    &to,
+                    format_args!("Error -- Renaming file {:?} -> {:?}: {}", to, from, e),
+                );
+                failed += 1;
+            }
+        }
+    }
+
+    let (dirs, created): (Vec<&PathBuf>, Vec<&PathBuf>) = record
+        .created
+        .iter()
+        .partition(|path| fs::symlink_metadata(dest.join(path)).is_ok_and(|m| m.is_dir()));
+    for path in created {
+        let path = dest.join(path);
+        match fs::remove_file(&path) {
+            Ok(_) => events::record(Action::Delete, &path, format_args!("Deleting {:?}", path)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => {
+                events::record(
+                    Action::Error,
+                    &path,
+                    format_args!("Error -- Deleting {:?}: {}", path, e),
+                );
+                failed += 1;
+            }
+        }
+    }
+
+    // Dirs are deleted deepest first, and kept if anything that existed before is left in them
+    let mut dirs = dirs;
+    dirs.sort_by_key(|path| std::cmp::Reverse(path.components().count()));
+    for path in dirs {
+        let path = dest.join(path);
+        if fs::remove_dir(&path).is_ok() {
+            events::record(Action::Delete, &path, format_args!("Deleting {:?}", path));
+        }
+    }
+
+    for path in record.backed_up.iter() {
+        let backup = record.backup_dir.join(path);
+        let path = dest.join(path);
+        match restore(&backup, &path) {
+            Ok(_) => events::record(
+                Action::Copy,
+                &path,
+                format_args!("Restoring {:?} from {:?}", path, backup),
+            ),
+            // A rollback that is retried finds the files it already restored gone
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                info!("Skipping {:?}, its backup {:?} is gone", path, backup)
+            }
+            Err(e) => {
+                events::record(
+                    Action::Error,
+                    &path,
+                    format_args!("Error -- Restoring {:?} from {:?}: {}", path, backup, e),
+                );
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(io::Error::other(format!(
+            "{} paths could not be rolled back, run lms rollback again to retry",
+            failed
+        )));
+    }
+    discard(dest)?;
+    // The state dir is only left behind if it holds more than the record
+    let _ = fs::remove_dir(dest.join(STATE_DIR));
+    info!("Rolled back the last synchronization of {:?}", dest);
+    Ok(())
+}
+
+/// Moves the backup at `backup` back to `path`, replacing whatever is there
+///
+/// # Errors
+/// This function will return an error if the backup cannot be moved or copied back
+fn restore(backup: &Path, path: &Path) -> Result<(), io::Error> {
+    let metadata = fs::symlink_metadata(backup)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    if let Err(e) = fs::rename(backup, path) {
+        // Files cannot be renamed from another filesystem, so they are copied from there instead
+        if metadata.file_type().is_symlink() {
+            return Err(e);
+        }
+        fs::copy(backup, path)?;
+        fs::remove_file(backup)?;
+    }
+    Ok(())
+}
+
+/// Reads the record of the last synchronization of `dest`
+///
+/// # Returns
+/// * Some: The record
+/// * None: If no synchronization is recorded, or the record is incomplete
+///
+/// # Errors
+/// This function will return an error if the record cannot be read
+fn read(dest: &Path) -> Result<Option<Record>, io::Error> {
+    let file = match fs::File::open(rollback_path(dest)) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let mut record = Record::default();
+    let mut header = true;
+    let mut renamed_from = None;
+    for line in BufReader::new(file).split(b'\n') {
+        let line = line?;
+        if header {
+            match line.strip_prefix(HEADER.as_bytes()) {
+                Some(backup_dir) => record.backup_dir = state::key_path(&unescape(backup_dir)),
+                None => return Ok(None),
+            }
+            header = false;
+            continue;
+        }
+
+        let path = line.get(2..).map(|path| state::key_path(&unescape(path)));
+        match (line.first(), path, renamed_from.take()) {
+            (Some(b'B'), Some(path), None) => record.backed_up.push(path),
+            (Some(b'C'), Some(path), None) => record.created.push(path),
+            (Some(b'R'), Some(path), None) => renamed_from = Some(path),
+            (Some(b'T'), Some(path), Some(from)) => record.renamed.push((from, path)),
+            _ => return Ok(None),
+        }
+    }
+
+    Ok(Some(record).filter(|_| !header && renamed_from.is_none()))
+}
+
+/// Gets the path of the record of the last synchronization of `dest`
+fn rollback_path(dest: &Path) -> PathBuf {
+    dest.join(STATE_DIR).join(ROLLBACK_FILE)
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_rollback {
+    use super::*;
+
+    #[test]
+    fn restores() {
+        const TEST_DIR: &str = "test_rollback_restores";
+        const TEST_BACKUP: &str = "test_rollback_restores_backup";
+
+        // The sync overwrote changed.txt, deleted deleted.txt, renamed renamed.txt into new, and
+        // created new/created.txt
+        fs::create_dir_all([TEST_DIR, "new"].join("/")).unwrap();
+        fs::create_dir_all([TEST_BACKUP, "old"].join("/")).unwrap();
+        fs::write([TEST_DIR, "changed.txt"].join("/"), b"new").unwrap();
+        fs::write([TEST_DIR, "new/created.txt"].join("/"), b"created").unwrap();
+        fs::write([TEST_DIR, "new/renamed.txt"].join("/"), b"renamed").unwrap();
+        fs::write([TEST_BACKUP, "changed.txt"].join("/"), b"old").unwrap();
+        fs::write([TEST_BACKUP, "old/deleted.txt"].join("/"), b"deleted").unwrap();
+
+        let dest = Path::new(TEST_DIR);
+        assert_eq!(
+            start(
+                dest,
+                Path::new(TEST_BACKUP),
+                &[PathBuf::from("new"), PathBuf::from("new/created.txt")],
+            )
+            .is_ok(),
+            true
+        );
+        backed_up(dest, Path::new("changed.txt"));
+        renamed(dest, Path::new("renamed.txt"), Path::new("new/renamed.txt"));
+        backed_up(dest, Path::new("old/deleted.txt"));
+        assert_eq!(
+            read(dest).unwrap().unwrap().backup_dir,
+            PathBuf::from(TEST_BACKUP)
+        );
+
+        assert_eq!(rollback(dest).is_ok(), true);
+        assert_eq!(fs::read(dest.join("changed.txt")).unwrap(), b"old");
+        assert_eq!(fs::read(dest.join("old/deleted.txt")).unwrap(), b"deleted");
+        assert_eq!(fs::read(dest.join("renamed.txt")).unwrap(), b"renamed");
+        assert_eq!(dest.join("new").exists(), false);
+        assert_eq!(Path::new(TEST_BACKUP).join("changed.txt").exists(), false);
+
+        // The record is discarded, so the same sync cannot be rolled back twice
+        assert_eq!(read(dest).unwrap(), None);
+        assert_eq!(rollback(dest).is_ok(), false);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+        fs::remove_dir_all(TEST_BACKUP).unwrap();
+    }
+}
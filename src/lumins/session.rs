@@ -0,0 +1,218 @@
+//! Contains the session of a run, which gives every run a unique ID and, with --log-dir, records
+//! its log and a summary under that ID, so that overlapping scheduled runs can be told apart.
+
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+use log::Level;
+
+use crate::lumins::resources;
+
+lazy_static! {
+    /// Unique ID of this run, in the form of a random (version 4) UUID
+    static ref RUN_ID: String = generate_id();
+    /// Log file and directory of this run, once it is started with a log directory
+    static ref SESSION: Mutex<Option<Session>> = Mutex::new(None);
+}
+
+/// Number of warnings logged during this run
+static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+/// Number of errors logged during this run
+static ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+/// Struct to represent the files a run is recorded in
+#[derive(Debug)]
+struct Session {
+    log_dir: PathBuf,
+    log: fs::File,
+    started: SystemTime,
+    timer: Instant,
+}
+
+/// Gets the unique ID of this run
+pub fn run_id() -> &'static str {
+    &RUN_ID
+}
+
+/// Starts recording this run in `log_dir`, where every log record is written to `<ID>.log`
+///
+/// # Arguments
+/// * `log_dir`: directory of the log and summary files, which is created if it does not exist
+///
+/// # Errors
+/// This function will return an error if the log file cannot be created
+pub fn start(log_dir: &Path) -> Result<(), io::Error> {
+    fs::create_dir_all(log_dir)?;
+    let log_file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_dir.join(format!("{}.log", run_id())))?;
+
+    *SESSION.lock().unwrap() = Some(Session {
+        log_dir: log_dir.to_path_buf(),
+        log: log_file,
+        started: SystemTime::now(),
+        timer: Instant::now(),
+    });
+    log(Level::Info, &format!("Starting {}", command_line()));
+    Ok(())
+}
+
+/// Checks whether this run is recorded in a log directory
+pub fn is_recorded() -> bool {
+    SESSION.lock().unwrap().is_some()
+}
+
+/// Records a log record of this run, if it is recorded in a log directory
+///
+/// # Arguments
+/// * `level`: level of the record
+/// * `message`: message of the record
+pub fn log(level: Level, message: &str) {
+    match level {
+        Level::Error => ERRORS.fetch_add(1, Ordering::Relaxed),
+        Level::Warn => WARNINGS.fetch_add(1, Ordering::Relaxed),
+        _ => 0,
+    };
+
+    if let Some(session) = SESSION.lock().unwrap().as_mut() {
+        // A log that cannot be written must not disturb the run itself
+        let _ = writeln!(
+            session.log,
+            "{} {} {:<5} {}",
+            humantime::format_rfc3339_millis(SystemTime::now()),
+            run_id(),
+            level,
+            message
+        );
+    }
+}
+
+/// Finishes recording this run by writing its summary to `<ID>.summary` in the log directory
+///
+/// # Arguments
+/// * `error`: error that the run failed with, None if it succeeded
+///
+/// # Errors
+/// This function will return an error if the summary cannot be written
+pub fn finish(error: Option<&io::Error>) -> Result<(), io::Error> {
+    let result = match error {
+        Some(e) => format!("error: {}", e),
+        None => String::from("ok"),
+    };
+    log(Level::Info, &format!("Finished with {}", result));
+
+    let session = match SESSION.lock().unwrap().take() {
+        Some(session) => session,
+        None => return Ok(()),
+    };
+    let mut fields = vec![
+        ("run", run_id().to_string()),
+        ("command", command_line()),
+        (
+            "started",
+            humantime::format_rfc3339_seconds(session.started).to_string(),
+        ),
+        (
+            "finished",
+            humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        ),
+        (
+            "duration",
+            format!("{:.1}s", session.timer.elapsed().as_secs_f64()),
+        ),
+        ("warnings", WARNINGS.load(Ordering::Relaxed).to_string()),
+        ("errors", ERRORS.load(Ordering::Relaxed).to_string()),
+    ];
+    fields.extend(resources::current().to_fields());
+    fields.push(("result", result));
+    let summary = summary(&fields);
+    fs::write(
+        session.log_dir.join(format!("{}.summary", run_id())),
+        summary,
+    )
+}
+
+/// Formats the fields of a summary, one `name: value` line per field
+fn summary(fields: &[(&str, String)]) -> String {
+    fields
+        .iter()
+        .fold(String::new(), |mut summary, (name, value)| {
+            let _ = writeln!(summary, "{}: {}", name, value);
+            summary
+        })
+}
+
+/// Gets the command line this run was started with
+fn command_line() -> String {
+    env::args().collect::<Vec<_>>().join(" ")
+}
+
+/// Generates a random (version 4) UUID
+///
+/// If no random bytes are available, the ID is derived from the time and process ID instead,
+/// which still tells apart runs that overlap.
+fn generate_id() -> String {
+    let mut bytes = [0u8; 16];
+    if getrandom::getrandom(&mut bytes).is_err() {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos())
+            .unwrap_or_default();
+        bytes[..8].copy_from_slice(&(nanos as u64).to_le_bytes());
+        bytes[8..12].copy_from_slice(&process::id().to_le_bytes());
+    }
+    format_uuid(bytes)
+}
+
+/// Formats 16 random bytes as a version 4 UUID, such as `0f8fad5b-d9cb-469f-a165-70867728950e`
+fn format_uuid(mut bytes: [u8; 16]) -> String {
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_session {
+    use super::*;
+
+    #[test]
+    fn uuid() {
+        assert_eq!(
+            format_uuid([0xff; 16]),
+            "ffffffff-ffff-4fff-bfff-ffffffffffff"
+        );
+        assert_eq!(format_uuid([0; 16]), "00000000-0000-4000-8000-000000000000");
+        assert_eq!(run_id().len(), 36);
+        assert_eq!(run_id(), run_id());
+    }
+
+    #[test]
+    fn summary_lines() {
+        assert_eq!(
+            summary(&[("run", String::from("id")), ("result", String::from("ok"))]),
+            "run: id\nresult: ok\n"
+        );
+    }
+}
@@ -0,0 +1,384 @@
+//! Contains utilities for reading a source from a temporary read-only snapshot,
+//! so that a live filesystem is copied as it was at a single point in time,
+//! and files held open by other programs can still be read.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+#[cfg(target_family = "unix")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{error, info};
+
+#[cfg(target_family = "unix")]
+use crate::lumins::mounts::{Mount, MountTable};
+
+/// Prefix of the names of snapshots, and of the directories they are accessible at
+pub const SNAPSHOT_PREFIX: &str = "lms-snapshot-";
+
+/// Kinds of snapshots, depending on the filesystem or volume manager holding the source
+#[derive(Eq, PartialEq, Debug, Clone)]
+enum SnapshotKind {
+    /// A read-only Btrfs snapshot of the subvolume mounted at the mount point
+    #[cfg(target_family = "unix")]
+    Btrfs,
+    /// A ZFS snapshot of the dataset mounted at the mount point
+    #[cfg(target_family = "unix")]
+    Zfs,
+    /// An LVM snapshot of the logical volume mounted at the mount point
+    #[cfg(target_family = "unix")]
+    Lvm,
+    /// A Volume Shadow Copy of the volume holding the source
+    #[cfg(target_family = "windows")]
+    Vss,
+}
+
+/// A struct that represents a temporary snapshot of a source directory,
+/// which is removed again when dropped
+#[derive(Debug)]
+pub struct Snapshot {
+    kind: SnapshotKind,
+    /// Device, dataset or shadow copy ID of the snapshot
+    device: String,
+    /// Where the root of the snapshot is accessible
+    root: PathBuf,
+    /// Where the source directory is accessible inside of the snapshot
+    source: PathBuf,
+}
+
+impl Snapshot {
+    /// Creates a read-only snapshot of the filesystem holding `src`
+    ///
+    /// Btrfs subvolumes, ZFS datasets and LVM logical volumes can be snapshotted.
+    /// Creating snapshots generally requires root privileges.
+    ///
+    /// # Arguments
+    /// * `src`: source directory to snapshot
+    ///
+    /// # Errors
+    /// This function will return an error if the filesystem holding `src` cannot be snapshotted
+    #[cfg(target_family = "unix")]
+    pub fn create(src: &Path) -> Result<Self, io::Error> {
+        let src = fs::canonicalize(src)?;
+        let mounts = MountTable::load();
+        let (point, mount) = mounts
+            .containing_mount(&src)
+            .ok_or_else(|| io::Error::other(format!("cannot find the filesystem of {:?}", src)))?;
+        let kind = snapshot_kind(mount).ok_or_else(|| {
+            io::Error::other(format!(
+                "cannot snapshot {:?}, {} on {} is not Btrfs, ZFS or LVM",
+                src, mount.device, mount.fs_type
+            ))
+        })?;
+
+        // This is safe to unwrap, since `point` is an ancestor of `src`
+        let relative_path = src.strip_prefix(point).unwrap();
+        let name = format!(
+            "{}{}-{}",
+            SNAPSHOT_PREFIX,
+            std::process::id(),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        );
+
+        let (device, root) = match kind {
+            SnapshotKind::Btrfs => {
+                let root = point.join(format!(".{}", name));
+                run(Command::new("btrfs")
+                    .args(["subvolume", "snapshot", "-r"])
+                    .arg(point)
+                    .arg(&root))?;
+                (root.to_string_lossy().to_string(), root)
+            }
+            SnapshotKind::Zfs => {
+                let device = format!("{}@{}", mount.device, name);
+                run(Command::new("zfs").arg("snapshot").arg(&device))?;
+                (device, point.join(".zfs/snapshot").join(&name))
+            }
+            SnapshotKind::Lvm => {
+                let device = create_lvm_snapshot(mount, &name)?;
+                let root = std::env::temp_dir().join(&name);
+                let mounted = fs::create_dir(&root).and_then(|_| {
+                    // XFS refuses to mount a second filesystem with the same UUID
+                    let options = if mount.fs_type == "xfs" {
+                        "ro,nouuid"
+                    } else {
+                        "ro"
+                    };
+                    run(Command::new("mount")
+                        .args(["-o", options, &device])
+                        .arg(&root))
+                });
+                if let Err(e) = mounted {
+                    let _ = fs::remove_dir(&root);
+                    let _ = run(Command::new("lvremove").args(["-f", &device]));
+                    return Err(e);
+                }
+                (device, root)
+            }
+        };
+
+        info!("Created snapshot {} of {:?}", device, point);
+        Ok(Snapshot {
+            kind,
+            device,
+            source: root.join(relative_path),
+            root,
+        })
+    }
+
+    /// Creates a Volume Shadow Copy of the volume holding `src`
+    ///
+    /// Files that other programs hold open without sharing them, such as mailbox files and
+    /// registry hives, can be read from the shadow copy. This requires administrator privileges.
+    ///
+    /// # Arguments
+    /// * `src`: source directory to snapshot
+    ///
+    /// # Errors
+    /// This function will return an error if `src` is not on a local volume,
+    /// or the shadow copy cannot be created
+    #[cfg(target_family = "windows")]
+    pub fn create(src: &Path) -> Result<Self, io::Error> {
+        use std::path::{Component, Prefix};
+
+        let src = fs::canonicalize(src)?;
+        let mut components = src.components();
+        let volume = match components.next() {
+            Some(Component::Prefix(prefix)) => match prefix.kind() {
+                Prefix::VerbatimDisk(disk) | Prefix::Disk(disk) => {
+                    Some(format!("{}:\\", disk as char))
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+        .ok_or_else(|| {
+            io::Error::other(format!(
+                "cannot snapshot {:?}, it is not on a local volume",
+                src
+            ))
+        })?;
+        let relative_path: PathBuf = components
+            .filter(|component| *component != Component::RootDir)
+            .collect();
+
+        let script = format!(
+            "$result = (Get-WmiObject -List Win32_ShadowCopy).Create('{}', 'ClientAccessible'); \
+             if ($result.ReturnValue -ne 0) {{ \
+                 Write-Error \"Win32_ShadowCopy.Create returned $($result.ReturnValue)\"; exit 1 \
+             }}; \
+             $shadow = Get-WmiObject Win32_ShadowCopy -Filter \"ID='$($result.ShadowID)'\"; \
+             $shadow.ID; $shadow.DeviceObject",
+            volume
+        );
+        let output = run(Command::new("powershell").args([
+            "-NoProfile",
+            "-NonInteractive",
+            "-Command",
+            &script,
+        ]))?;
+        let mut lines = output.lines().map(str::trim);
+        let (device, root) = match (lines.next(), lines.next()) {
+            (Some(id), Some(device_object)) => (
+                id.to_string(),
+                PathBuf::from(format!("{}\\", device_object)),
+            ),
+            _ => {
+                return Err(io::Error::other(
+                    "Win32_ShadowCopy.Create returned no shadow copy",
+                ))
+            }
+        };
+
+        info!("Created shadow copy {} of {}", device, volume);
+        Ok(Snapshot {
+            kind: SnapshotKind::Vss,
+            device,
+            source: root.join(relative_path),
+            root,
+        })
+    }
+
+    /// Gets the path of the source directory inside of the snapshot
+    pub fn source(&self) -> &Path {
+        &self.source
+    }
+
+    /// Removes the snapshot
+    ///
+    /// # Errors
+    /// This function will return an error if the snapshot cannot be removed
+    fn remove(&self) -> Result<(), io::Error> {
+        match self.kind {
+            #[cfg(target_family = "unix")]
+            SnapshotKind::Btrfs => {
+                run(Command::new("btrfs")
+                    .args(["subvolume", "delete"])
+                    .arg(&self.root))?;
+            }
+            #[cfg(target_family = "unix")]
+            SnapshotKind::Zfs => {
+                run(Command::new("zfs").args(["destroy", &self.device]))?;
+            }
+            #[cfg(target_family = "unix")]
+            SnapshotKind::Lvm => {
+                run(Command::new("umount").arg(&self.root))?;
+                fs::remove_dir(&self.root)?;
+                run(Command::new("lvremove").args(["-f", &self.device]))?;
+            }
+            #[cfg(target_family = "windows")]
+            SnapshotKind::Vss => {
+                let script = format!(
+                    "Get-WmiObject Win32_ShadowCopy -Filter \"ID='{}'\" | \
+                     ForEach-Object {{ $_.Delete() }}",
+                    self.device
+                );
+                run(Command::new("powershell").args([
+                    "-NoProfile",
+                    "-NonInteractive",
+                    "-Command",
+                    &script,
+                ]))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        match self.remove() {
+            Ok(_) => info!("Removed snapshot {} at {:?}", self.device, self.root),
+            Err(e) => error!("Error -- Removing snapshot {}: {}", self.device, e),
+        }
+    }
+}
+
+/// Checks whether an error means that a file could not be read because another program holds it
+/// open without sharing it, such as a mailbox file or a database, which can only be read from a
+/// shadow copy
+///
+/// # Arguments
+/// * `e`: error of reading the file
+///
+/// # Returns
+/// True if the file is held open by another program
+#[cfg(target_family = "windows")]
+pub fn is_held_open(e: &io::Error) -> bool {
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    const ERROR_LOCK_VIOLATION: i32 = 33;
+
+    matches!(
+        e.raw_os_error(),
+        Some(ERROR_SHARING_VIOLATION) | Some(ERROR_LOCK_VIOLATION)
+    )
+}
+
+#[cfg(target_family = "unix")]
+pub fn is_held_open(_e: &io::Error) -> bool {
+    false
+}
+
+/// Determines how the given mount can be snapshotted
+///
+/// # Arguments
+/// * `mount`: mount holding the source
+///
+/// # Returns
+/// * Some: The kind of snapshot to take
+/// * None: If the mount cannot be snapshotted
+#[cfg(target_family = "unix")]
+fn snapshot_kind(mount: &Mount) -> Option<SnapshotKind> {
+    match mount.fs_type.as_str() {
+        "btrfs" => Some(SnapshotKind::Btrfs),
+        "zfs" => Some(SnapshotKind::Zfs),
+        _ if mount.device.starts_with("/dev/mapper/") => Some(SnapshotKind::Lvm),
+        _ => None,
+    }
+}
+
+/// Creates an LVM snapshot of the logical volume holding the given mount
+///
+/// The snapshot is given a tenth of the size of the volume, to hold the changes
+/// made to the volume while it exists
+///
+/// # Arguments
+/// * `mount`: mount of the logical volume
+/// * `name`: name of the snapshot
+///
+/// # Returns
+/// The device of the snapshot
+///
+/// # Errors
+/// This function will return an error if the device is not a logical volume,
+/// or the snapshot cannot be created
+#[cfg(target_family = "unix")]
+fn create_lvm_snapshot(mount: &Mount, name: &str) -> Result<String, io::Error> {
+    let volume =
+        run(Command::new("lvs").args(["--noheadings", "--options", "vg_name", &mount.device]))?;
+    let volume_group = volume.trim();
+
+    run(Command::new("lvcreate").args([
+        "--snapshot",
+        "--extents",
+        "10%ORIGIN",
+        "--name",
+        name,
+        &mount.device,
+    ]))?;
+    Ok(format!("/dev/{}/{}", volume_group, name))
+}
+
+/// Runs the given command, and fails unless it succeeds
+///
+/// # Returns
+/// The standard output of the command
+///
+/// # Errors
+/// This function will return an error if the command cannot be run or fails
+fn run(command: &mut Command) -> Result<String, io::Error> {
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{:?} failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(all(test, target_family = "unix"))]
+mod test_snapshot {
+    use super::*;
+
+    #[test]
+    fn kind() {
+        let mount = |device: &str, fs_type: &str| Mount {
+            device: device.to_string(),
+            fs_type: fs_type.to_string(),
+        };
+
+        assert_eq!(
+            snapshot_kind(&mount("/dev/sda2", "btrfs")),
+            Some(SnapshotKind::Btrfs)
+        );
+        assert_eq!(
+            snapshot_kind(&mount("tank/home", "zfs")),
+            Some(SnapshotKind::Zfs)
+        );
+        assert_eq!(
+            snapshot_kind(&mount("/dev/mapper/vg-root", "ext4")),
+            Some(SnapshotKind::Lvm)
+        );
+        assert_eq!(snapshot_kind(&mount("/dev/sda1", "ext4")), None);
+    }
+}
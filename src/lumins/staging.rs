@@ -0,0 +1,207 @@
+//! Contains the staging area that deleted destination files and symlinks are moved to with
+//! --stage-deletions, which is only purged once the synchronization succeeded, so that a failed
+//! synchronization can be reverted by moving the staged files back.
+//!
+//! Every run stages its deletions in a directory of its own inside the staging area, named after
+//! the run, so that the files kept by a run that failed or was interrupted are neither overwritten
+//! nor purged by the runs after it, which report them instead.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use rayon::prelude::*;
+
+use crate::lumins::events::{self, Action};
+use crate::lumins::file_ops::FileOps;
+use crate::lumins::session;
+use crate::lumins::state::STATE_DIR;
+use crate::progress;
+
+/// Name of the staging area inside `STATE_DIR`, whose contents traversal never visits
+const STAGING_DIR: &str = "staged-deletions";
+
+/// Number of staging areas of runs handed out so far by this process
+static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+/// Gets the staging area of the destination `dest`
+pub fn staging_dir(dest: &Path) -> PathBuf {
+    dest.join(STATE_DIR).join(STAGING_DIR)
+}
+
+/// Gets a staging area of its own for a run synchronizing `dest`, inside the staging area of
+/// `dest`, which is only created once a file is staged in it
+///
+/// The area is named after the time and ID of the run, so that the areas of the runs are listed
+/// in the order they ran, along with a count for the runs of a process that uses the library.
+pub fn run_dir(dest: &Path) -> PathBuf {
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    staging_dir(dest).join(format!(
+        "{}-{}-{}",
+        started,
+        session::run_id(),
+        RUNS.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Moves all given files from `location` into the staging area `run_dir` in parallel, at the same
+/// relative path, instead of deleting them
+///
+/// # Arguments
+/// * `files_to_stage`: files to move
+/// * `location`: base directory of the files to move, such that for all `file` in
+///   `files_to_stage`, `location + file.path()` is the absolute path of the file
+/// * `run_dir`: staging area of the run, from `run_dir`
+///
+/// # Returns
+/// The number of files that could not be moved, which are kept
+pub fn stage_files<'a, T, S>(files_to_stage: T, location: &Path, run_dir: &Path) -> usize
+where
+    T: ParallelIterator<Item = &'a S>,
+    S: FileOps + Sync + 'a,
+{
+    files_to_stage
+        .filter(|file| {
+            let path = location.join(file.path());
+            let result = stage(&path, &run_dir.join(file.path()));
+            match &result {
+                Ok(_) => events::record(
+                    Action::Delete,
+                    &path,
+                    format_args!("Staging {:?} for deletion", path),
+                ),
+                Err(e) => events::record(
+                    Action::Error,
+                    &path,
+                    format_args!("Error -- Staging {:?} for deletion: {}", path, e),
+                ),
+            }
+            progress::bar().inc(1);
+            result.is_err()
+        })
+        .count()
+}
+
+/// Moves the file at `path` to `staged`
+///
+/// # Errors
+/// This function will return an error if the file cannot be moved, in which case it is left
+/// where it is
+fn stage(path: &Path, staged: &Path) -> Result<(), io::Error> {
+    if let Some(parent) = staged.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(path, staged)
+}
+
+/// Purges the staging area `run_dir` of a synchronization of `dest` if it succeeded, and keeps it
+/// otherwise, reporting the files that earlier runs kept in the staging area of `dest`
+///
+/// # Arguments
+/// * `dest`: Destination directory
+/// * `run_dir`: staging area of the synchronization, from `run_dir`
+/// * `failed`: number of file operations of the synchronization that failed
+///
+/// # Errors
+/// This function will return an error if the staging area of the run cannot be purged
+pub fn finish(dest: &Path, run_dir: &Path, failed: usize) -> Result<(), io::Error> {
+    if run_dir.exists() {
+        if failed > 0 {
+            warn!(
+                "Warning -- Keeping the files deleted from {:?} in {:?}, since {} file operations \
+                 failed, move them back to revert the deletions",
+                dest, run_dir, failed
+            );
+        } else {
+            fs::remove_dir_all(run_dir)?;
+            info!("Purged the files staged for deletion in {:?}", run_dir);
+        }
+    }
+
+    let staging_dir = staging_dir(dest);
+    let leftovers = match fs::read_dir(&staging_dir) {
+        Ok(entries) => entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path != run_dir)
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    for leftover in leftovers.iter() {
+        warn!(
+            "Warning -- {:?} holds files deleted from {:?} by an earlier run that did not \
+             succeed, move them back to revert those deletions, or remove them",
+            leftover, dest
+        );
+    }
+
+    // The staging area and the state dir are only left behind if they hold more than this run
+    if fs::remove_dir(&staging_dir).is_ok() {
+        if let Some(state_dir) = staging_dir.parent() {
+            let _ = fs::remove_dir(state_dir);
+        }
+    }
+    Ok(())
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_staging {
+    use super::*;
+    use crate::lumins::file_ops::File;
+    use hashbrown::HashSet;
+
+    #[test]
+    fn staged_files() {
+        const TEST_DIR: &str = "test_staging_staged_files";
+
+        fs::create_dir_all([TEST_DIR, "dir"].join("/")).unwrap();
+        fs::write([TEST_DIR, "dir/old.txt"].join("/"), b"old").unwrap();
+
+        let mut files = HashSet::new();
+        files.insert(File::from("dir/old.txt", 3));
+        let failed_run = run_dir(Path::new(TEST_DIR));
+        assert_eq!(
+            stage_files(files.par_iter(), Path::new(TEST_DIR), &failed_run),
+            0
+        );
+        assert_eq!(
+            Path::new(&[TEST_DIR, "dir/old.txt"].join("/")).exists(),
+            false
+        );
+        let staged = failed_run.join("dir/old.txt");
+        assert_eq!(fs::read(&staged).unwrap(), b"old");
+
+        // A failed synchronization keeps the staged files
+        assert_eq!(finish(Path::new(TEST_DIR), &failed_run, 1).is_ok(), true);
+        assert_eq!(staged.exists(), true);
+
+        // The next run stages the same path in its own area, and only purges that area
+        fs::write([TEST_DIR, "dir/old.txt"].join("/"), b"newer").unwrap();
+        let next_run = run_dir(Path::new(TEST_DIR));
+        assert_ne!(next_run, failed_run);
+        assert_eq!(
+            stage_files(files.par_iter(), Path::new(TEST_DIR), &next_run),
+            0
+        );
+        assert_eq!(finish(Path::new(TEST_DIR), &next_run, 0).is_ok(), true);
+        assert_eq!(next_run.exists(), false);
+        assert_eq!(fs::read(&staged).unwrap(), b"old");
+
+        // Once nothing is left, the staging area and the state dir are removed too
+        fs::remove_dir_all(&failed_run).unwrap();
+        assert_eq!(finish(Path::new(TEST_DIR), &next_run, 0).is_ok(), true);
+        assert_eq!(staging_dir(Path::new(TEST_DIR)).exists(), false);
+        assert_eq!(Path::new(&[TEST_DIR, STATE_DIR].join("/")).exists(), false);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
@@ -0,0 +1,258 @@
+//! Contains utilities for noticing copies that stop making progress, such as copies from a dead
+//! network server or a failing disk, so that they do not hang a run silently.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hashbrown::HashMap;
+use lazy_static::lazy_static;
+use log::warn;
+
+use crate::lumins::context;
+use crate::lumins::file_ops::ContentHasher;
+use crate::lumins::stream;
+use crate::progress::FileProgress;
+
+/// Interval between two checks for stalled copies
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a copy may make no progress, and whether it is aborted after that
+type Config = (Duration, bool);
+
+lazy_static! {
+    /// Copies in progress that are being monitored, by ID, with the configuration of their job
+    static ref TRANSFERS: Mutex<HashMap<usize, (Arc<Transfer>, Config)>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Struct to represent a single monitored copy
+#[derive(Debug)]
+struct Transfer {
+    path: PathBuf,
+    /// Last time any data was copied
+    progress: Mutex<Instant>,
+    /// Whether a stall was reported since the last progress
+    stalled: AtomicBool,
+    /// Whether the copy must give up
+    aborted: AtomicBool,
+}
+
+impl Transfer {
+    fn touch(&self) {
+        *self.progress.lock().unwrap() = Instant::now();
+        self.stalled.store(false, Ordering::Relaxed);
+    }
+
+    /// Checks whether the copy stalled at `now`, reporting and aborting it once per stall
+    ///
+    /// # Returns
+    /// True if the copy stalled
+    fn check(&self, now: Instant, timeout: Duration, abort: bool) -> bool {
+        let idle = now.saturating_duration_since(*self.progress.lock().unwrap());
+        if idle < timeout || self.stalled.swap(true, Ordering::Relaxed) {
+            return false;
+        }
+
+        if abort {
+            warn!(
+                "Warning -- Copying {:?} made no progress for {}s, aborting it",
+                self.path,
+                idle.as_secs()
+            );
+            self.aborted.store(true, Ordering::Relaxed);
+        } else {
+            warn!(
+                "Warning -- Copying {:?} made no progress for {}s",
+                self.path,
+                idle.as_secs()
+            );
+        }
+        true
+    }
+}
+
+/// Removes a transfer from the monitored copies when the copy ends
+struct Registration(usize);
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        TRANSFERS.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Enables monitoring of all following copies of the current job
+///
+/// # Arguments
+/// * `timeout`: how long a copy may make no progress before it is reported, None to not monitor
+/// * `abort`: whether to give up on copies that made no progress for `timeout`
+pub fn configure(timeout: Option<Duration>, abort: bool) {
+    *context::current().stall.lock().unwrap() = timeout.map(|timeout| (timeout, abort));
+}
+
+/// Copies the contents and permissions of the file `src` to `dest`, like `stream::copy`,
+/// reporting the copy if it makes no progress for the configured timeout
+///
+/// A stalled copy can only be aborted once the read or write it is stuck in returns
+///
+/// # Arguments
+/// * `src`: absolute path of the file to copy
+/// * `dest`: absolute path of the copy
+/// * `hasher`: hasher to feed the contents copied to, which streams files of any size
+/// * `progress`: progress of the file, which streamed files are credited to chunk by chunk
+///
+/// # Returns
+/// The number of bytes copied
+///
+/// # Errors
+/// This function will return an error if `src` cannot be copied, or the copy was aborted
+pub fn copy(
+    src: &Path,
+    dest: &Path,
+    hasher: Option<&mut ContentHasher>,
+    progress: &mut FileProgress,
+) -> Result<u64, io::Error> {
+    let config = match *context::current().stall.lock().unwrap() {
+        Some(config) => config,
+        None => {
+            return match hasher {
+                Some(hasher) => stream::copy_with(src, dest, |chunk, _| {
+                    hasher.update(chunk);
+                    progress.inc(chunk.len() as u64);
+                    Ok(())
+                }),
+                None => stream::copy(src, dest, progress),
+            }
+        }
+    };
+
+    static MONITOR: Once = Once::new();
+    MONITOR.call_once(|| {
+        thread::spawn(|| loop {
+            thread::sleep(CHECK_INTERVAL);
+            let now = Instant::now();
+            for (transfer, (timeout, abort)) in TRANSFERS.lock().unwrap().values() {
+                transfer.check(now, *timeout, *abort);
+            }
+        });
+    });
+
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    let transfer = Arc::new(Transfer {
+        path: src.to_path_buf(),
+        progress: Mutex::new(Instant::now()),
+        stalled: AtomicBool::new(false),
+        aborted: AtomicBool::new(false),
+    });
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    TRANSFERS
+        .lock()
+        .unwrap()
+        .insert(id, (transfer.clone(), config));
+    let _registration = Registration(id);
+
+    let result = copy_chunks(src, dest, &transfer, hasher, progress);
+    if result.is_err() && transfer.aborted.load(Ordering::Relaxed) {
+        let _ = fs::remove_file(dest);
+    }
+    result
+}
+
+/// Copies `src` to `dest` chunk by chunk, recording the progress in `transfer` and `progress`,
+/// and feeding the contents to `hasher`
+///
+/// # Errors
+/// This function will return an error if `src` cannot be copied, or `transfer` was aborted
+fn copy_chunks(
+    src: &Path,
+    dest: &Path,
+    transfer: &Transfer,
+    mut hasher: Option<&mut ContentHasher>,
+    progress: &mut FileProgress,
+) -> Result<u64, io::Error> {
+    stream::copy_with(src, dest, |chunk, _| {
+        if transfer.aborted.load(Ordering::Relaxed) {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "the copy made no progress",
+            ));
+        }
+        transfer.touch();
+        if let Some(hasher) = hasher.as_deref_mut() {
+            hasher.update(chunk);
+        }
+        progress.inc(chunk.len() as u64);
+        Ok(())
+    })
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_stall {
+    use super::*;
+    use crate::lumins::stream::CHUNK_SIZE;
+
+    #[test]
+    fn check() {
+        let transfer = Transfer {
+            path: PathBuf::from("stalled.txt"),
+            progress: Mutex::new(Instant::now()),
+            stalled: AtomicBool::new(false),
+            aborted: AtomicBool::new(false),
+        };
+        let timeout = Duration::from_secs(10);
+        let now = Instant::now();
+
+        assert_eq!(transfer.check(now, timeout, true), false);
+
+        // A stall is reported once, until the copy makes progress again
+        assert_eq!(transfer.check(now + timeout * 2, timeout, false), true);
+        assert_eq!(transfer.check(now + timeout * 3, timeout, false), false);
+        assert_eq!(transfer.aborted.load(Ordering::Relaxed), false);
+
+        transfer.touch();
+        assert_eq!(
+            transfer.check(Instant::now() + timeout * 2, timeout, true),
+            true
+        );
+        assert_eq!(transfer.aborted.load(Ordering::Relaxed), true);
+    }
+
+    #[test]
+    fn copy_chunks_contents() {
+        const TEST_DIR: &str = "test_stall_copy_chunks_contents";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        let src = PathBuf::from([TEST_DIR, "src.bin"].join("/"));
+        let dest = PathBuf::from([TEST_DIR, "dest.bin"].join("/"));
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 7).map(|i| i as u8).collect();
+        fs::write(&src, &data).unwrap();
+
+        let transfer = Transfer {
+            path: src.clone(),
+            progress: Mutex::new(Instant::now()),
+            stalled: AtomicBool::new(false),
+            aborted: AtomicBool::new(false),
+        };
+        assert_eq!(
+            copy_chunks(&src, &dest, &transfer, None, &mut FileProgress::new(0)).unwrap(),
+            data.len() as u64
+        );
+        assert_eq!(fs::read(&dest).unwrap(), data);
+
+        transfer.aborted.store(true, Ordering::Relaxed);
+        assert_eq!(
+            copy_chunks(&src, &dest, &transfer, None, &mut FileProgress::new(0)).is_err(),
+            true
+        );
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
@@ -0,0 +1,885 @@
+//! Contains the state that is recorded in the destination between runs.
+
+use std::convert::TryInto;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hashbrown::HashSet;
+use log::{error, info, warn};
+
+use crate::lumins::context;
+use crate::lumins::file_ops::{FileHash, HashStore};
+use crate::lumins::journal::{self, JournalChanges, JournalCursor};
+use crate::lumins::parse::Flag;
+use crate::lumins::paths;
+
+/// Name of the directory in the root of the destination that holds the state
+pub const STATE_DIR: &str = ".lumins";
+
+/// Name of the database inside `STATE_DIR`
+const STATE_DB: &str = "state";
+
+/// Name of the directory of the hash caches inside the cache directory of the user
+const CACHE_DIR: &str = "lms";
+
+/// Key of the canonical source path that the state was recorded for
+const SOURCE_KEY: &[u8] = b"source";
+
+/// Key of the position in the journal that the last run read up to
+const JOURNAL_KEY: &[u8] = b"journal";
+
+/// Size in bytes of the digest of a single chunk of a secure hash
+const CHUNK_DIGEST_SIZE: usize = 64;
+
+/// Struct to represent the recorded state of a directory
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct DirState {
+    /// Time the directory was last modified
+    pub modified: SystemTime,
+    /// Number of entries in the directory
+    pub children: u64,
+}
+
+impl DirState {
+    /// Reads the current state of the directory at `path`
+    ///
+    /// # Errors
+    /// This function will return an error if the directory cannot be read
+    pub fn read(path: &Path) -> Result<Self, io::Error> {
+        let modified = fs::metadata(path)?.modified()?;
+        let children = fs::read_dir(path)?.count() as u64;
+        Ok(DirState { modified, children })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = time_to_bytes(self.modified);
+        bytes.extend_from_slice(&self.children.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 20 {
+            return None;
+        }
+
+        Some(DirState {
+            modified: time_from_bytes(&bytes[0..12])?,
+            children: u64::from_le_bytes(bytes[12..20].try_into().ok()?),
+        })
+    }
+}
+
+/// Struct to represent the recorded state of a file
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct FileRecord {
+    /// Size of the file in bytes
+    pub size: u64,
+    /// Time the file was last modified
+    pub modified: SystemTime,
+    /// Inode number of the file, or 0 where there are no inodes
+    pub inode: u64,
+    /// Hash of the contents of the file, if it was hashed
+    pub hash: Option<FileHash>,
+}
+
+impl FileRecord {
+    /// Creates a record without a hash from the metadata of a file
+    ///
+    /// # Arguments
+    /// * `metadata`: metadata of the file
+    ///
+    /// # Returns
+    /// The record of the file
+    pub fn from_metadata(metadata: &fs::Metadata) -> Self {
+        FileRecord {
+            size: metadata.len(),
+            modified: metadata.modified().unwrap_or(UNIX_EPOCH),
+            inode: inode(metadata),
+            hash: None,
+        }
+    }
+
+    /// Checks whether two records describe the same, unmodified file, ignoring hashes
+    pub fn is_same_file(&self, other: &FileRecord) -> bool {
+        self.size == other.size && self.modified == other.modified && self.inode == other.inode
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(37);
+        bytes.extend_from_slice(&self.size.to_le_bytes());
+        bytes.extend(time_to_bytes(self.modified));
+        bytes.extend_from_slice(&self.inode.to_le_bytes());
+        match &self.hash {
+            None => bytes.push(0),
+            Some(FileHash::Fast(hash)) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&hash.to_le_bytes());
+            }
+            Some(FileHash::Secure(hash)) => {
+                bytes.push(3);
+                bytes.extend_from_slice(hash);
+            }
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 29 {
+            return None;
+        }
+
+        let hash = match bytes[28] {
+            0 => None,
+            1 => Some(FileHash::Fast(u64::from_le_bytes(
+                bytes.get(29..37)?.try_into().ok()?,
+            ))),
+            // Secure hashes recorded before they were computed chunk by chunk cannot be compared
+            2 => None,
+            3 => Some(FileHash::Secure(bytes[29..].to_vec())),
+            _ => return None,
+        };
+        Some(FileRecord {
+            size: u64::from_le_bytes(bytes[0..8].try_into().ok()?),
+            modified: time_from_bytes(&bytes[8..20])?,
+            inode: u64::from_le_bytes(bytes[20..28].try_into().ok()?),
+            hash,
+        })
+    }
+}
+
+/// A struct that represents the persistent state of a destination,
+/// recorded from its source at the end of each successful run
+pub struct State {
+    db: sled::Db,
+    dirs: sled::Tree,
+    files: sled::Tree,
+    /// Source directory whose state is observed during the current run
+    src: PathBuf,
+    /// Whether directories whose modification time is unchanged are considered unchanged
+    compare_dir_times: bool,
+    /// Changes read from the journal, if the journal knows all changes since the last run
+    changed: Option<HashSet<PathBuf>>,
+    /// Position in the journal read up to during the current run, recorded on `commit`
+    journal_cursor: Option<JournalCursor>,
+    /// Directories observed during the current run, recorded on `commit`
+    observed_dirs: Mutex<Vec<(PathBuf, DirState)>>,
+    /// Files observed during the current run, recorded on `commit`
+    observed_files: Mutex<Vec<(PathBuf, FileRecord)>>,
+    /// Progress of hashing large files, kept across runs
+    hash_progress: HashProgress,
+}
+
+impl State {
+    /// Opens the state of `dest` for synchronizing from `src`, creating it if it does not exist
+    ///
+    /// State recorded for a different source is discarded. If `flags` contains `JOURNAL`,
+    /// the changes recorded in the journal since the last run are read.
+    ///
+    /// # Arguments
+    /// * `src`: Source directory
+    /// * `dest`: Destination directory
+    /// * `flags`: set for Flag's, which select how unchanged directories are detected
+    ///
+    /// # Errors
+    /// This function will return an error if the state cannot be opened or created
+    pub fn open(src: &Path, dest: &Path, flags: Flag) -> Result<Self, io::Error> {
+        let db = sled::open(dest.join(STATE_DIR).join(STATE_DB)).map_err(to_io_error)?;
+        let dirs = db.open_tree("dirs").map_err(to_io_error)?;
+        let files = db.open_tree("files").map_err(to_io_error)?;
+        let hash_progress = HashProgress {
+            tree: db.open_tree("hash_progress").map_err(to_io_error)?,
+        };
+        *context::current().hash_progress.lock().unwrap() = Some(hash_progress.clone());
+
+        let source = path_key(&paths::canonicalize(src)?);
+        if db.get(SOURCE_KEY).map_err(to_io_error)?.as_deref() != Some(&source[..]) {
+            dirs.clear().map_err(to_io_error)?;
+            files.clear().map_err(to_io_error)?;
+            db.insert(SOURCE_KEY, source).map_err(to_io_error)?;
+        }
+
+        let mut changes = None;
+        if flags.contains(Flag::JOURNAL) {
+            let cursor = db
+                .get(JOURNAL_KEY)
+                .map_err(to_io_error)?
+                .and_then(|cursor| cursor_from_bytes(&cursor));
+            changes = journal::read(dest, cursor)?;
+
+            match changes {
+                None => warn!("Warning -- No journal is being recorded for {:?}", dest),
+                Some(JournalChanges { changed: None, .. }) => {
+                    info!(
+                        "Journal of {:?} is incomplete, reading the whole source",
+                        dest
+                    )
+                }
+                _ => (),
+            }
+        }
+
+        Ok(State {
+            db,
+            dirs,
+            files,
+            src: PathBuf::from(src),
+            compare_dir_times: flags.contains(Flag::SKIP_UNCHANGED_DIRS),
+            changed: changes.as_ref().and_then(|changes| changes.changed.clone()),
+            journal_cursor: changes.map(|changes| changes.cursor),
+            observed_dirs: Mutex::new(Vec::new()),
+            observed_files: Mutex::new(Vec::new()),
+            hash_progress,
+        })
+    }
+
+    /// Gets the progress of hashing large files kept in this state
+    pub fn hash_progress(&self) -> &HashProgress {
+        &self.hash_progress
+    }
+
+    /// Checks whether nothing in the directory at `path` changed since the last run
+    ///
+    /// If the journal knows all changes since the last run, the directory is unchanged
+    /// if no change was recorded inside of it. Otherwise, the directory and all its recorded
+    /// subdirectories must have the same modification time and number of entries as recorded,
+    /// which means that files modified in place go unnoticed.
+    ///
+    /// # Arguments
+    /// * `path`: path of the directory relative to the source
+    ///
+    /// # Returns
+    /// True if the directory is recorded and did not change
+    pub fn is_unchanged(&self, path: &Path) -> bool {
+        let key = path_key(path);
+        let mut prefix = key.clone();
+        prefix.push(b'/');
+
+        let recorded = match self.dirs.get(&key) {
+            Ok(Some(recorded)) => recorded,
+            _ => return false,
+        };
+
+        if let Some(changed) = &self.changed {
+            return !changed.iter().any(|changed| changed.starts_with(path));
+        }
+        if !self.compare_dir_times {
+            return false;
+        }
+
+        std::iter::once(Ok((key.into(), recorded)))
+            .chain(self.dirs.scan_prefix(&prefix))
+            .all(|entry| {
+                let (key, recorded) = match entry {
+                    Ok(entry) => entry,
+                    Err(_) => return false,
+                };
+                let current = DirState::read(&self.src.join(key_path(&key)));
+                current.ok() == DirState::from_bytes(&recorded)
+            })
+    }
+
+    /// Records the state of a directory seen in the source during the current run
+    ///
+    /// # Arguments
+    /// * `path`: path of the directory relative to the source
+    /// * `dir_state`: state of the directory from before its entries were read
+    pub fn observe_dir(&self, path: &Path, dir_state: DirState) {
+        self.observed_dirs
+            .lock()
+            .unwrap()
+            .push((path.to_path_buf(), dir_state));
+    }
+
+    /// Records the state of a file seen in the source during the current run
+    ///
+    /// # Arguments
+    /// * `path`: path of the file relative to the source
+    /// * `record`: state of the file
+    pub fn observe_file(&self, path: &Path, record: FileRecord) {
+        self.observed_files
+            .lock()
+            .unwrap()
+            .push((path.to_path_buf(), record));
+    }
+
+    /// Gets the state of a file recorded by the last run
+    ///
+    /// # Arguments
+    /// * `path`: path of the file relative to the source
+    ///
+    /// # Returns
+    /// * Some: The recorded state of the file
+    /// * None: If the file was not recorded
+    pub fn file(&self, path: &Path) -> Option<FileRecord> {
+        match self.files.get(path_key(path)) {
+            Ok(Some(record)) => FileRecord::from_bytes(&record),
+            _ => None,
+        }
+    }
+
+    /// Adds the hashes recorded by the last run of the files observed during the current run
+    /// to `hashes`, for all files that were not modified since
+    ///
+    /// # Arguments
+    /// * `flags`: set for Flag's, of which `SECURE` selects the kind of hashes to add
+    /// * `hashes`: store to add the hashes to
+    pub fn load_hashes(&self, flags: Flag, hashes: &HashStore) {
+        for (path, record) in self.observed_files.lock().unwrap().iter() {
+            let hash = match self.file(path) {
+                Some(recorded) if recorded.is_same_file(record) => recorded.hash,
+                _ => continue,
+            };
+
+            match hash {
+                Some(FileHash::Fast(hash)) if !flags.contains(Flag::SECURE) => {
+                    hashes.insert(path.clone(), FileHash::Fast(hash))
+                }
+                Some(FileHash::Secure(hash)) if flags.contains(Flag::SECURE) => {
+                    hashes.insert(path.clone(), FileHash::Secure(hash))
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Persists the directories and files observed during the current run, which must have
+    /// finished successfully, and forgets the ones that no longer exist
+    ///
+    /// # Arguments
+    /// * `skipped`: paths relative to the source that were not traversed, and stay recorded
+    /// * `hashes`: hashes of the files computed during the current run
+    ///
+    /// # Errors
+    /// This function will return an error if the state cannot be written
+    pub fn commit(&self, skipped: &HashSet<PathBuf>, hashes: &HashStore) -> Result<(), io::Error> {
+        let observed_dirs = std::mem::take(&mut *self.observed_dirs.lock().unwrap());
+        let observed_files = std::mem::take(&mut *self.observed_files.lock().unwrap());
+
+        let mut dirs = forget_unobserved(&self.dirs, &observed_dirs, skipped)?;
+        for (path, dir_state) in observed_dirs {
+            dirs.insert(path_key(&path), dir_state.to_bytes());
+        }
+
+        let mut files = forget_unobserved(&self.files, &observed_files, skipped)?;
+        for (path, mut record) in observed_files {
+            record.hash = match hashes.get(&path) {
+                Some(hash) => Some(hash),
+                None => self
+                    .file(&path)
+                    .filter(|recorded| recorded.is_same_file(&record))
+                    .and_then(|recorded| recorded.hash),
+            };
+            files.insert(path_key(&path), record.to_bytes());
+        }
+
+        self.dirs.apply_batch(dirs).map_err(to_io_error)?;
+        self.files.apply_batch(files).map_err(to_io_error)?;
+        if let Some(cursor) = self.journal_cursor {
+            self.db
+                .insert(JOURNAL_KEY, cursor_to_bytes(cursor))
+                .map_err(to_io_error)?;
+        }
+        self.db.flush().map_err(to_io_error)?;
+        Ok(())
+    }
+}
+
+impl Drop for State {
+    fn drop(&mut self) {
+        // The progress must not keep the database open once the state is closed
+        *context::current().hash_progress.lock().unwrap() = None;
+    }
+}
+
+/// A struct that represents the progress of hashing large files chunk by chunk, which is kept
+/// in the state so that a hash interrupted by the end of a run is resumed by the next run
+#[derive(Clone)]
+pub struct HashProgress {
+    tree: sled::Tree,
+}
+
+impl HashProgress {
+    /// Gets the progress kept in the state opened last by the current job, if it opened any state
+    pub fn current() -> Option<Self> {
+        context::current().hash_progress.lock().unwrap().clone()
+    }
+
+    /// Gets the digests of the chunks of the file at `path` saved by an interrupted hash
+    ///
+    /// # Arguments
+    /// * `path`: path of the file
+    /// * `record`: current state of the file, without a hash
+    ///
+    /// # Returns
+    /// The digests of the first chunks of the file, in order, or none if the file changed
+    pub fn load(&self, path: &Path, record: &FileRecord) -> Vec<Vec<u8>> {
+        let saved = match progress_key(path).map(|key| self.tree.get(key)) {
+            Some(Ok(Some(saved))) => saved,
+            _ => return Vec::new(),
+        };
+
+        let header = record.to_bytes();
+        if !saved.starts_with(&header)
+            || !(saved.len() - header.len()).is_multiple_of(CHUNK_DIGEST_SIZE)
+        {
+            return Vec::new();
+        }
+        saved[header.len()..]
+            .chunks(CHUNK_DIGEST_SIZE)
+            .map(|digest| digest.to_vec())
+            .collect()
+    }
+
+    /// Saves the digests of the first chunks of the file at `path`
+    ///
+    /// # Arguments
+    /// * `path`: path of the file
+    /// * `record`: current state of the file, without a hash
+    /// * `digests`: digests of the first chunks of the file, in order
+    pub fn save(&self, path: &Path, record: &FileRecord, digests: &[Vec<u8>]) {
+        let key = match progress_key(path) {
+            Some(key) => key,
+            None => return,
+        };
+
+        let mut saved = record.to_bytes();
+        for digest in digests {
+            saved.extend_from_slice(digest);
+        }
+        if let Err(e) = self.tree.insert(key, saved).and(self.tree.flush()) {
+            warn!("Warning -- Saving progress of hashing {:?}: {}", path, e);
+        }
+    }
+
+    /// Forgets the progress of hashing the file at `path`, once it is completely hashed
+    pub fn clear(&self, path: &Path) {
+        if let Some(key) = progress_key(path) {
+            let _ = self.tree.remove(key);
+        }
+    }
+}
+
+/// A struct that represents the cache of the hashes of files in both the source and the
+/// destination computed by earlier runs
+///
+/// Every destination has its own cache in the cache directory of the user, so that the destination
+/// itself stays an exact copy and runs to different destinations do not wait for each other.
+/// The cache is used by all hashes for comparing files, for as long as the `HashCache` is open.
+pub struct HashCache {
+    db: sled::Db,
+    hashes: CachedHashes,
+}
+
+impl HashCache {
+    /// Opens the cache of `dest`, creating it if it does not exist
+    ///
+    /// # Errors
+    /// This function will return an error if `dest` does not exist, there is no cache directory,
+    /// or the cache cannot be opened or created
+    pub fn open(dest: &Path) -> Result<Self, io::Error> {
+        let dest = paths::canonicalize(dest)?;
+        let cache_dir = cache_dir().ok_or_else(|| io::Error::other("no cache directory"))?;
+        let name = format!("{:016x}", seahash::hash(&path_key(&dest)));
+        let db = sled::open(cache_dir.join(CACHE_DIR).join(name)).map_err(to_io_error)?;
+        let hashes = CachedHashes {
+            tree: db.open_tree("hashes").map_err(to_io_error)?,
+        };
+        *context::current().hash_cache.lock().unwrap() = Some(hashes.clone());
+        Ok(HashCache { db, hashes })
+    }
+
+    /// Gets the hashes kept in this cache
+    pub fn hashes(&self) -> &CachedHashes {
+        &self.hashes
+    }
+}
+
+impl Drop for HashCache {
+    fn drop(&mut self) {
+        *context::current().hash_cache.lock().unwrap() = None;
+        if let Err(e) = self.db.flush() {
+            warn!("Warning -- Saving the hash cache: {}", e);
+        }
+    }
+}
+
+/// A struct that represents the hashes kept in a `HashCache`, which are reused as long as the
+/// size, modification time and inode of their file stay the same
+#[derive(Clone)]
+pub struct CachedHashes {
+    tree: sled::Tree,
+}
+
+impl CachedHashes {
+    /// Gets the hashes kept in the cache opened last by the current job, if a cache is open
+    pub fn current() -> Option<Self> {
+        context::current().hash_cache.lock().unwrap().clone()
+    }
+
+    /// Gets the cached hash of the file at `path`, if the file did not change since it was hashed
+    ///
+    /// # Arguments
+    /// * `path`: path of the file
+    /// * `metadata`: current metadata of the file
+    /// * `flags`: set for Flag's, of which `SECURE` selects the kind of hash
+    ///
+    /// # Returns
+    /// * Some: The cached hash
+    /// * None: If no hash of the current file of the right kind is cached
+    pub fn get(&self, path: &Path, metadata: &fs::Metadata, flags: Flag) -> Option<FileHash> {
+        let cached = self.tree.get(progress_key(path)?).ok()??;
+        let cached = FileRecord::from_bytes(&cached)?;
+        if !cached.is_same_file(&FileRecord::from_metadata(metadata)) {
+            return None;
+        }
+
+        match cached.hash? {
+            FileHash::Fast(hash) if !flags.contains(Flag::SECURE) => Some(FileHash::Fast(hash)),
+            FileHash::Secure(hash) if flags.contains(Flag::SECURE) => Some(FileHash::Secure(hash)),
+            _ => None,
+        }
+    }
+
+    /// Caches the hash of the file at `path`
+    ///
+    /// # Arguments
+    /// * `path`: path of the file
+    /// * `metadata`: metadata of the file from before it was hashed
+    /// * `hash`: hash of the file
+    pub fn insert(&self, path: &Path, metadata: &fs::Metadata, hash: &FileHash) {
+        let key = match progress_key(path) {
+            Some(key) => key,
+            None => return,
+        };
+
+        let record = FileRecord {
+            hash: Some(hash.clone()),
+            ..FileRecord::from_metadata(metadata)
+        };
+        if let Err(e) = self.tree.insert(key, record.to_bytes()) {
+            warn!("Warning -- Caching the hash of {:?}: {}", path, e);
+        }
+    }
+}
+
+/// Gets the cache directory of the user, such as `~/.cache`
+fn cache_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CACHE_HOME")
+        .or_else(|| env::var_os("LOCALAPPDATA"))
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+}
+
+/// Gets the key of the progress of hashing the file at `path`, which is its absolute path
+fn progress_key(path: &Path) -> Option<Vec<u8>> {
+    paths::canonicalize(path).ok().map(|path| path_key(&path))
+}
+
+/// Reads the files recorded in the state of `dir`, which make up a manifest of the source
+/// that `dir` was last synchronized from
+///
+/// # Arguments
+/// * `dir`: directory holding the state, such as a backup
+///
+/// # Returns
+/// * Some: The recorded files, with their paths relative to the source
+/// * None: If no state is recorded in `dir`
+///
+/// # Errors
+/// This function will return an error if the state cannot be read
+pub fn recorded_files(dir: &Path) -> Result<Option<Vec<(PathBuf, FileRecord)>>, io::Error> {
+    let path = dir.join(STATE_DIR).join(STATE_DB);
+    if !path.is_dir() {
+        return Ok(None);
+    }
+
+    let db = sled::open(path).map_err(to_io_error)?;
+    let mut files = Vec::new();
+    for entry in db.open_tree("files").map_err(to_io_error)?.iter() {
+        let (key, record) = entry.map_err(to_io_error)?;
+        if let Some(record) = FileRecord::from_bytes(&record) {
+            files.push((key_path(&key), record));
+        }
+    }
+    Ok(Some(files))
+}
+
+/// Creates a batch that removes all entries of `tree` that were neither observed nor skipped
+///
+/// # Arguments
+/// * `tree`: tree of recorded entries
+/// * `observed`: entries observed during the current run
+/// * `skipped`: paths that were not traversed, whose entries stay recorded
+///
+/// # Errors
+/// This function will return an error if `tree` cannot be read
+fn forget_unobserved<T>(
+    tree: &sled::Tree,
+    observed: &[(PathBuf, T)],
+    skipped: &HashSet<PathBuf>,
+) -> Result<sled::Batch, io::Error> {
+    let observed: HashSet<Vec<u8>> = observed.iter().map(|(path, _)| path_key(path)).collect();
+
+    let mut batch = sled::Batch::default();
+    for entry in tree.iter() {
+        let (key, _) = entry.map_err(to_io_error)?;
+        let path = key_path(&key);
+        if !observed.contains(&key[..]) && !skipped.iter().any(|s| path.starts_with(s)) {
+            batch.remove(key);
+        }
+    }
+    Ok(batch)
+}
+
+fn time_to_bytes(time: SystemTime) -> Vec<u8> {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+    let mut bytes = Vec::with_capacity(12);
+    bytes.extend_from_slice(&since_epoch.as_secs().to_le_bytes());
+    bytes.extend_from_slice(&since_epoch.subsec_nanos().to_le_bytes());
+    bytes
+}
+
+fn time_from_bytes(bytes: &[u8]) -> Option<SystemTime> {
+    let secs = u64::from_le_bytes(bytes.get(0..8)?.try_into().ok()?);
+    let nanos = u32::from_le_bytes(bytes.get(8..12)?.try_into().ok()?);
+    Some(UNIX_EPOCH + Duration::new(secs, nanos))
+}
+
+#[cfg(target_family = "unix")]
+fn inode(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+
+    metadata.ino()
+}
+
+#[cfg(target_family = "windows")]
+fn inode(_metadata: &fs::Metadata) -> u64 {
+    0
+}
+
+fn cursor_to_bytes(cursor: JournalCursor) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(16);
+    bytes.extend_from_slice(&cursor.id.to_le_bytes());
+    bytes.extend_from_slice(&cursor.offset.to_le_bytes());
+    bytes
+}
+
+fn cursor_from_bytes(bytes: &[u8]) -> Option<JournalCursor> {
+    if bytes.len() != 16 {
+        return None;
+    }
+
+    Some(JournalCursor {
+        id: u64::from_le_bytes(bytes[0..8].try_into().ok()?),
+        offset: u64::from_le_bytes(bytes[8..16].try_into().ok()?),
+    })
+}
+
+/// Encodes a relative path as a key, with components separated by `/`
+#[cfg(target_family = "unix")]
+pub fn path_key(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(target_family = "windows")]
+pub fn path_key(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().replace('\\', "/").into_bytes()
+}
+
+/// Decodes a key created by `path_key`
+#[cfg(target_family = "unix")]
+pub fn key_path(key: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+
+    PathBuf::from(std::ffi::OsStr::from_bytes(key))
+}
+
+#[cfg(target_family = "windows")]
+pub fn key_path(key: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(key).into_owned())
+}
+
+fn to_io_error(e: sled::Error) -> io::Error {
+    match e {
+        sled::Error::Io(e) => e,
+        e => {
+            error!("Error -- State database: {}", e);
+            io::Error::other(e.to_string())
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_state {
+    use super::*;
+
+    #[test]
+    fn dir_state_bytes() {
+        let dir_state = DirState {
+            modified: UNIX_EPOCH + Duration::new(1_600_000_000, 123),
+            children: 42,
+        };
+
+        assert_eq!(DirState::from_bytes(&dir_state.to_bytes()), Some(dir_state));
+        assert_eq!(DirState::from_bytes(b"garbage"), None);
+    }
+
+    #[test]
+    fn file_record_bytes() {
+        let mut record = FileRecord {
+            size: 1024,
+            modified: UNIX_EPOCH + Duration::new(1_600_000_000, 456),
+            inode: 7,
+            hash: None,
+        };
+
+        for hash in [
+            None,
+            Some(FileHash::Fast(99)),
+            Some(FileHash::Secure(vec![1; 64])),
+        ]
+        .iter()
+        {
+            record.hash = hash.clone();
+            assert_eq!(
+                FileRecord::from_bytes(&record.to_bytes()),
+                Some(record.clone())
+            );
+        }
+        assert_eq!(FileRecord::from_bytes(b"garbage"), None);
+    }
+
+    #[test]
+    fn recorded_hashes() {
+        const TEST_SRC: &str = "test_state_recorded_hashes_src";
+        const TEST_DEST: &str = "test_state_recorded_hashes_dest";
+        const TEST_FILES: [&str; 2] = ["a.txt", "b.txt"];
+
+        fs::create_dir_all(TEST_SRC).unwrap();
+        fs::create_dir_all(TEST_DEST).unwrap();
+
+        let state = State::open(Path::new(TEST_SRC), Path::new(TEST_DEST), Flag::STATE).unwrap();
+        let hashes = HashStore::default();
+        for (i, file) in TEST_FILES.iter().enumerate() {
+            let record = FileRecord {
+                size: 4,
+                modified: UNIX_EPOCH,
+                inode: i as u64,
+                hash: None,
+            };
+            state.observe_file(Path::new(file), record);
+            hashes.insert(PathBuf::from(file), FileHash::Fast(i as u64));
+        }
+        state.commit(&HashSet::new(), &hashes).unwrap();
+
+        assert_eq!(
+            state.file(Path::new("a.txt")).unwrap().hash,
+            Some(FileHash::Fast(0))
+        );
+
+        // Only the hash of the file that did not change since is reused
+        for (i, file) in TEST_FILES.iter().enumerate() {
+            let record = FileRecord {
+                size: 4 + i as u64,
+                modified: UNIX_EPOCH,
+                inode: i as u64,
+                hash: None,
+            };
+            state.observe_file(Path::new(file), record);
+        }
+        let hashes = HashStore::default();
+        state.load_hashes(Flag::empty(), &hashes);
+
+        assert_eq!(hashes.get(Path::new("a.txt")), Some(FileHash::Fast(0)));
+        assert_eq!(hashes.get(Path::new("b.txt")), None);
+
+        // Fast hashes are useless to secure runs
+        let hashes = HashStore::default();
+        state.load_hashes(Flag::SECURE, &hashes);
+
+        assert_eq!(hashes.get(Path::new("a.txt")), None);
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+
+    #[test]
+    fn unchanged_dirs() {
+        const TEST_SRC: &str = "test_state_unchanged_dirs_src";
+        const TEST_DEST: &str = "test_state_unchanged_dirs_dest";
+        const TEST_DIRS: [&str; 3] = ["a", "a/b", "c"];
+
+        for dir in TEST_DIRS.iter() {
+            fs::create_dir_all([TEST_SRC, dir].join("/")).unwrap();
+        }
+        fs::create_dir_all(TEST_DEST).unwrap();
+
+        let state = State::open(
+            Path::new(TEST_SRC),
+            Path::new(TEST_DEST),
+            Flag::SKIP_UNCHANGED_DIRS,
+        )
+        .unwrap();
+        for dir in TEST_DIRS.iter() {
+            let dir_state = DirState::read(Path::new(&[TEST_SRC, dir].join("/"))).unwrap();
+            state.observe_dir(Path::new(dir), dir_state);
+        }
+
+        // Nothing is recorded until the run is committed
+        assert_eq!(state.is_unchanged(Path::new("a")), false);
+        state
+            .commit(&HashSet::new(), &HashStore::default())
+            .unwrap();
+        assert_eq!(state.is_unchanged(Path::new("a")), true);
+
+        // A new entry deep inside `a` changes `a` as a whole, but not `c`
+        fs::File::create([TEST_SRC, "a/b/file.txt"].join("/")).unwrap();
+
+        assert_eq!(state.is_unchanged(Path::new("a")), false);
+        assert_eq!(state.is_unchanged(Path::new("c")), true);
+        assert_eq!(state.is_unchanged(Path::new("d")), false);
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+
+    #[test]
+    fn cached_hashes() {
+        const TEST_DIR: &str = "test_state_cached_hashes";
+        const TEST_FILE: &str = "test_state_cached_hashes/file.txt";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        fs::write(TEST_FILE, b"cached").unwrap();
+        let path = Path::new(TEST_FILE);
+
+        let cache = HashCache::open(Path::new(TEST_DIR)).unwrap();
+        let metadata = fs::metadata(path).unwrap();
+        assert_eq!(cache.hashes().get(path, &metadata, Flag::empty()), None);
+
+        cache.hashes().insert(path, &metadata, &FileHash::Fast(42));
+        assert_eq!(
+            cache.hashes().get(path, &metadata, Flag::empty()),
+            Some(FileHash::Fast(42))
+        );
+        assert_eq!(cache.hashes().get(path, &metadata, Flag::SECURE), None);
+
+        // A modified file must be hashed again
+        fs::write(TEST_FILE, b"modified").unwrap();
+        let metadata = fs::metadata(path).unwrap();
+        assert_eq!(cache.hashes().get(path, &metadata, Flag::empty()), None);
+
+        drop(cache);
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
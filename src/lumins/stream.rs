@@ -0,0 +1,354 @@
+//! Contains a streaming copy for large files, which preallocates the destination, flushes it to
+//! disk periodically and shows the progress of the file in bytes.
+
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use log::info;
+
+use crate::lumins::throttle;
+use crate::progress::{self, FileProgress};
+
+/// Size of the chunks that streaming copies read and write at a time
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Size in bytes from which files are copied with a streaming copy
+pub const LARGE_FILE_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Number of bytes written to a large file between two flushes to disk
+const SYNC_INTERVAL: u64 = 256 * 1024 * 1024;
+
+/// Number of bytes in a MiB, in which sizes are shown
+const MIB: f64 = 1024.0 * 1024.0;
+
+/// Copies the contents and permissions of the file `src` to `dest`, like `fs::copy`,
+/// streaming files of at least `LARGE_FILE_SIZE` bytes, and every file if reads are throttled
+///
+/// # Arguments
+/// * `src`: absolute path of the file to copy
+/// * `dest`: absolute path of the copy
+/// * `progress`: progress of the file, which streamed files are credited to chunk by chunk
+///
+/// # Returns
+/// The number of bytes copied
+///
+/// # Errors
+/// This function will return an error if `src` cannot be copied
+pub fn copy(src: &Path, dest: &Path, progress: &mut FileProgress) -> Result<u64, io::Error> {
+    if fs::metadata(src)?.len() < LARGE_FILE_SIZE && !throttle::is_limited() {
+        return fs::copy(src, dest);
+    }
+
+    copy_with(src, dest, |chunk, _| {
+        progress.inc(chunk.len() as u64);
+        Ok(())
+    })
+}
+
+/// Copies the contents and permissions of the file `src` to `dest` chunk by chunk,
+/// calling `on_chunk` with every chunk and the number of bytes copied so far
+///
+/// Files of at least `LARGE_FILE_SIZE` bytes are preallocated, flushed to disk every
+/// `SYNC_INTERVAL` bytes, and their progress and throughput are shown.
+///
+/// # Arguments
+/// * `src`: absolute path of the file to copy
+/// * `dest`: absolute path of the copy
+/// * `on_chunk`: called after every chunk, which stops the copy by returning an error
+///
+/// # Returns
+/// The number of bytes copied
+///
+/// # Errors
+/// This function will return an error if `src` cannot be copied, or `on_chunk` fails
+pub fn copy_with<F>(src: &Path, dest: &Path, mut on_chunk: F) -> Result<u64, io::Error>
+where
+    F: FnMut(&[u8], u64) -> Result<(), io::Error>,
+{
+    let mut reader = fs::File::open(src)?;
+    let metadata = reader.metadata()?;
+    let mut writer = fs::File::create(dest)?;
+
+    let large = metadata.len() >= LARGE_FILE_SIZE;
+    if large {
+        preallocate(&writer, metadata.len())?;
+    }
+
+    let started = Instant::now();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut copied = 0;
+    let mut unsynced = 0;
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read as u64,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        throttle::read(read);
+        writer.write_all(&buffer[..read as usize])?;
+        copied += read;
+        on_chunk(&buffer[..read as usize], copied)?;
+
+        if large {
+            unsynced += read;
+            if unsynced >= SYNC_INTERVAL {
+                writer.sync_data()?;
+                unsynced = 0;
+                progress::bar().set_message(format!(
+                    "{:?} {:.0}/{:.0} MiB",
+                    src.file_name().unwrap_or_default(),
+                    copied as f64 / MIB,
+                    metadata.len() as f64 / MIB
+                ));
+            }
+        }
+    }
+
+    if large {
+        // The source may have shrunk since it was preallocated for
+        writer.set_len(copied)?;
+        writer.sync_data()?;
+        progress::bar().set_message("");
+
+        let seconds = started.elapsed().as_secs_f64();
+        info!(
+            "Streamed {:?}: {:.0} MiB in {:.1}s ({:.1} MiB/s)",
+            src,
+            copied as f64 / MIB,
+            seconds,
+            copied as f64 / MIB / seconds.max(0.001)
+        );
+    }
+
+    writer.set_permissions(metadata.permissions())?;
+    Ok(copied)
+}
+
+/// Copies the contents and permissions of the file `src` to `dest` like `copy_with`, leaving the
+/// holes of a sparse `src` unwritten, so that `dest` takes up as many blocks on disk as `src`
+///
+/// `on_chunk` is still called with the zeros that every hole reads as, so that holes are hashed
+/// and credited like the rest of the contents. Where holes cannot be found, `src` is copied whole.
+///
+/// # Arguments
+/// * `src`: absolute path of the file to copy
+/// * `dest`: absolute path of the copy
+/// * `on_chunk`: called after every chunk, which stops the copy by returning an error
+///
+/// # Returns
+/// The number of bytes copied, including holes
+///
+/// # Errors
+/// This function will return an error if `src` cannot be copied, or `on_chunk` fails
+pub fn copy_sparse<F>(src: &Path, dest: &Path, mut on_chunk: F) -> Result<u64, io::Error>
+where
+    F: FnMut(&[u8], u64) -> Result<(), io::Error>,
+{
+    let mut reader = fs::File::open(src)?;
+    let metadata = reader.metadata()?;
+    let mut writer = fs::File::create(dest)?;
+    let len = metadata.len();
+
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let zeros = vec![0; CHUNK_SIZE];
+    let mut copied = 0;
+    'regions: while copied < len {
+        let (data, hole) = next_data(&reader, copied)?.unwrap_or((len, len));
+        let (data, hole) = (data.min(len), hole.min(len));
+
+        // Holes are only passed on as zeros, and are left by seeking past them
+        while copied < data {
+            let zeros = &zeros[..(data - copied).min(CHUNK_SIZE as u64) as usize];
+            copied += zeros.len() as u64;
+            on_chunk(zeros, copied)?;
+        }
+
+        reader.seek(SeekFrom::Start(data))?;
+        writer.seek(SeekFrom::Start(data))?;
+        while copied < hole {
+            let chunk = (hole - copied).min(CHUNK_SIZE as u64) as usize;
+            let read = match reader.read(&mut buffer[..chunk]) {
+                // The source shrank since its length was read
+                Ok(0) => break 'regions,
+                Ok(read) => read,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            throttle::read(read as u64);
+            writer.write_all(&buffer[..read])?;
+            copied += read as u64;
+            on_chunk(&buffer[..read], copied)?;
+        }
+    }
+
+    // A trailing hole is only left by extending the copy
+    writer.set_len(copied)?;
+    writer.set_permissions(metadata.permissions())?;
+    Ok(copied)
+}
+
+/// Finds the next region of data in `file` at or after `offset`
+///
+/// # Returns
+/// * Some: The offsets of the start of the data and of the hole after it
+/// * None: If there is only a hole after `offset`
+///
+/// # Errors
+/// This function will return an error if `file` cannot be searched
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "macos"
+))]
+fn next_data(file: &fs::File, offset: u64) -> Result<Option<(u64, u64)>, io::Error> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let data = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+    if data < 0 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::ENXIO) => Ok(None),
+            // Filesystems that cannot find holes are read whole
+            Some(libc::EINVAL) => Ok(Some((offset, u64::MAX))),
+            _ => Err(e),
+        };
+    }
+    let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+    if hole < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Some((data as u64, hole as u64)))
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "macos"
+)))]
+fn next_data(_file: &fs::File, offset: u64) -> Result<Option<(u64, u64)>, io::Error> {
+    Ok(Some((offset, u64::MAX)))
+}
+
+/// Reserves `len` bytes of disk space for `file`, so that running out of space fails right away
+/// instead of after most of the file was copied, and the file is laid out contiguously
+///
+/// # Errors
+/// This function will return an error if the space cannot be reserved
+#[cfg(target_os = "linux")]
+fn preallocate(file: &fs::File, len: u64) -> Result<(), io::Error> {
+    use std::os::unix::io::AsRawFd;
+
+    // posix_fallocate returns the error instead of setting errno
+    match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, len as libc::off_t) } {
+        0 => Ok(()),
+        code => Err(io::Error::from_raw_os_error(code)),
+    }
+}
+
+#[cfg(target_family = "windows")]
+fn preallocate(file: &fs::File, len: u64) -> Result<(), io::Error> {
+    // Extending a file allocates its space, since files are not sparse by default
+    file.set_len(len)
+}
+
+#[cfg(not(any(target_os = "linux", target_family = "windows")))]
+fn preallocate(_file: &fs::File, _len: u64) -> Result<(), io::Error> {
+    Ok(())
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_stream {
+    use super::*;
+
+    #[test]
+    fn copy_with_chunks() {
+        const TEST_DIR: &str = "test_stream_copy_with_chunks";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        let src = Path::new(TEST_DIR).join("src.bin");
+        let dest = Path::new(TEST_DIR).join("dest.bin");
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 7).map(|i| i as u8).collect();
+        fs::write(&src, &data).unwrap();
+
+        let mut progress = Vec::new();
+        let copied = copy_with(&src, &dest, |_, copied| {
+            progress.push(copied);
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(copied, data.len() as u64);
+        assert_eq!(fs::read(&dest).unwrap(), data);
+        assert_eq!(
+            progress,
+            vec![CHUNK_SIZE as u64, 2 * CHUNK_SIZE as u64, data.len() as u64]
+        );
+
+        // The copy stops as soon as a chunk fails
+        let result = copy_with(&src, &dest, |_, _| {
+            Err(io::Error::from(io::ErrorKind::TimedOut))
+        });
+        assert_eq!(result.is_err(), true);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[test]
+    fn sparse() {
+        const TEST_DIR: &str = "test_stream_sparse";
+
+        // A hole, a chunk of data and a trailing hole
+        fs::create_dir_all(TEST_DIR).unwrap();
+        let src = Path::new(TEST_DIR).join("src.bin");
+        let dest = Path::new(TEST_DIR).join("dest.bin");
+        let mut file = fs::File::create(&src).unwrap();
+        file.set_len(4 * CHUNK_SIZE as u64).unwrap();
+        file.seek(SeekFrom::Start(2 * CHUNK_SIZE as u64)).unwrap();
+        file.write_all(&[7; 4096]).unwrap();
+        drop(file);
+
+        let mut passed_on = 0;
+        let copied = copy_sparse(&src, &dest, |chunk, _| {
+            passed_on += chunk.len() as u64;
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(copied, 4 * CHUNK_SIZE as u64);
+        assert_eq!(passed_on, copied);
+        assert_eq!(fs::read(&dest).unwrap(), fs::read(&src).unwrap());
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            let blocks = |path: &Path| fs::metadata(path).unwrap().blocks();
+            assert_eq!(blocks(&dest), blocks(&src));
+        }
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn preallocated() {
+        const TEST_DIR: &str = "test_stream_preallocated";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        let file = fs::File::create(Path::new(TEST_DIR).join("file.bin")).unwrap();
+
+        assert_eq!(preallocate(&file, 4096).is_ok(), true);
+        assert_eq!(file.metadata().unwrap().len(), 4096);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
@@ -0,0 +1,105 @@
+//! Contains the read throttling given with --read-bwlimit, which limits the rate at which copies
+//! read from the source, and at which files are read to hash them, so that a busy production disk
+//! is not saturated by a run, while the destination still takes whatever the page cache hands it
+//! at once.
+
+use std::io::{self, Read};
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::lumins::context;
+
+/// Configures the read throttling of all following copies of the current job
+///
+/// # Arguments
+/// * `read_bwlimit`: bytes per second that copies read from the source, None for no limit
+pub fn configure(read_bwlimit: Option<u64>) {
+    let context = context::current();
+    context
+        .read_limit
+        .store(read_bwlimit.unwrap_or(0), Ordering::Relaxed);
+    *context.read_schedule.lock().unwrap() = None;
+}
+
+/// Checks whether reads from the source are throttled, in which case files must be copied chunk
+/// by chunk instead of by the kernel
+pub fn is_limited() -> bool {
+    context::current().read_limit.load(Ordering::Relaxed) > 0
+}
+
+/// Accounts for `bytes` read from the source, blocking the current thread for as long as it
+/// takes to keep all reads of the current job together within its limit
+pub fn read(bytes: u64) {
+    let context = context::current();
+    let limit = context.read_limit.load(Ordering::Relaxed);
+    if limit == 0 {
+        return;
+    }
+
+    let wait = schedule(
+        &mut context.read_schedule.lock().unwrap(),
+        Instant::now(),
+        bytes,
+        limit,
+    );
+    if !wait.is_zero() {
+        thread::sleep(wait);
+    }
+}
+
+/// Struct to wrap a reader, such as a file being hashed, whose reads are throttled like the reads
+/// of copies
+pub struct Reader<R>(pub R);
+
+impl<R: Read> Read for Reader<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, io::Error> {
+        let bytes = self.0.read(buffer)?;
+        read(bytes as u64);
+        Ok(bytes)
+    }
+}
+
+/// Schedules a read of `bytes` at `now`, moving `next` on by the time the read takes at `limit`
+/// bytes per second, where time that no thread read in is not saved up for bursts
+///
+/// # Returns
+/// How long the reading thread must wait for the read to be paid for
+fn schedule(next: &mut Option<Instant>, now: Instant, bytes: u64, limit: u64) -> Duration {
+    let start = next.map_or(now, |next| next.max(now));
+    let end = start + Duration::from_secs_f64(bytes as f64 / limit as f64);
+    *next = Some(end);
+    end.saturating_duration_since(now)
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_throttle {
+    use super::*;
+
+    #[test]
+    fn schedules() {
+        let now = Instant::now();
+        let mut next = None;
+
+        assert_eq!(
+            schedule(&mut next, now, 500, 1000),
+            Duration::from_millis(500)
+        );
+        // Reads of other threads queue up behind the ones before them
+        assert_eq!(
+            schedule(&mut next, now, 1000, 1000),
+            Duration::from_millis(1500)
+        );
+
+        // Idle time is not saved up for bursts
+        let later = now + Duration::from_secs(10);
+        assert_eq!(
+            schedule(&mut next, later, 250, 1000),
+            Duration::from_millis(250)
+        );
+    }
+}
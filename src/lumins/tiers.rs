@@ -0,0 +1,136 @@
+//! Contains the size tiers of copies, which copy large files with fewer threads than small files,
+//! so that bandwidth-bound copies of large files do not thrash a disk by running many at once,
+//! while metadata-bound copies of small files keep all threads busy.
+
+use std::sync::Arc;
+
+use log::warn;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use crate::lumins::context;
+use crate::progress;
+
+/// Size in bytes from which files are copied as large files, unless given with --large-file-size
+pub const DEFAULT_LARGE_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Number of large files copied at once, unless given with --large-file-jobs
+pub const DEFAULT_LARGE_FILE_JOBS: usize = 2;
+
+/// Configures the tiers of all following copies of the current job
+///
+/// # Arguments
+/// * `large_file_size`: size in bytes from which files are large, None for the default
+/// * `large_file_jobs`: number of large files copied at once, None for the default
+pub fn configure(large_file_size: Option<u64>, large_file_jobs: Option<usize>) {
+    let context = context::current();
+    *context.tiers.lock().unwrap() = (
+        large_file_size.unwrap_or(DEFAULT_LARGE_FILE_SIZE),
+        large_file_jobs.unwrap_or(DEFAULT_LARGE_FILE_JOBS).max(1),
+    );
+    *context.large_pool.lock().unwrap() = None;
+}
+
+/// Checks whether a file of `size` bytes is copied as a large file
+pub fn is_large(size: u64) -> bool {
+    size >= context::current().tiers.lock().unwrap().0
+}
+
+/// Gets the number of large files copied at once
+pub fn large_file_jobs() -> usize {
+    context::current().tiers.lock().unwrap().1
+}
+
+/// Runs `large`, which copies large files, with the threads for large files, while `small`,
+/// which copies small files, runs in the current thread pool
+///
+/// If the current thread pool has a single thread, as with --sequential, both run one after the
+/// other in it, so that only one file is copied at a time.
+///
+/// # Arguments
+/// * `large`: copies the large files
+/// * `small`: copies the small files
+pub fn join<L, S>(large: L, small: S)
+where
+    L: FnOnce() + Send,
+    S: FnOnce() + Send,
+{
+    if rayon::current_num_threads() == 1 {
+        small();
+        large();
+        return;
+    }
+
+    match large_pool() {
+        Some(pool) => {
+            rayon::join(|| pool.install(large), small);
+        }
+        None => {
+            rayon::join(large, small);
+        }
+    }
+}
+
+/// Gets the threads for large files, creating them if they do not exist yet
+///
+/// Within a job of its own, the threads only serve that job, so that they credit their progress
+/// to it.
+fn large_pool() -> Option<Arc<ThreadPool>> {
+    if progress::in_job() {
+        let job = progress::current_job();
+        return build_large_pool(move |_| job.attach()).map(Arc::new);
+    }
+
+    let context = context::current();
+    let mut pool = context.large_pool.lock().unwrap();
+    if pool.is_none() {
+        *pool = build_large_pool(|_| ()).map(Arc::new);
+    }
+    pool.clone()
+}
+
+/// Creates the threads for large files, which run `start_handler` when they start
+fn build_large_pool<H>(start_handler: H) -> Option<ThreadPool>
+where
+    H: Fn(usize) + Send + Sync + 'static,
+{
+    match ThreadPoolBuilder::new()
+        .num_threads(large_file_jobs())
+        .thread_name(|i| format!("lms-large-{}", i))
+        .start_handler(start_handler)
+        .build()
+    {
+        Ok(pool) => Some(pool),
+        // Large files are then copied with the threads for small files
+        Err(e) => {
+            warn!("Warning -- Creating threads for large files: {}", e);
+            None
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_tiers {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn join_both() {
+        let copied = AtomicUsize::new(0);
+        join(
+            || {
+                copied.fetch_add(1, Ordering::Relaxed);
+            },
+            || {
+                copied.fetch_add(2, Ordering::Relaxed);
+            },
+        );
+        assert_eq!(copied.load(Ordering::Relaxed), 3);
+
+        assert_eq!(is_large(DEFAULT_LARGE_FILE_SIZE), true);
+        assert_eq!(is_large(DEFAULT_LARGE_FILE_SIZE - 1), false);
+    }
+}
@@ -0,0 +1,267 @@
+//! Contains the transformations given with --transform, which copy files through an external
+//! command instead of as they are, such as to strip GPS data from images or normalize the line
+//! endings of text.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use log::LevelFilter;
+
+use crate::lumins::context;
+use crate::lumins::filter::Pattern;
+
+/// Size of the chunks that the output of commands is read in
+const CHUNK_SIZE: usize = 64 * 1024;
+
+lazy_static! {
+    /// Level of the log records shown by the command line, which commands are run with
+    static ref LOG_LEVEL: Mutex<Option<LevelFilter>> = Mutex::new(None);
+}
+
+/// Struct to represent a command that the files whose name matches a glob pattern are copied
+/// through, given as `PATTERN=COMMAND`
+///
+/// The command reads the source file on stdin and writes the contents of the copy to stdout.
+#[derive(Clone, Debug)]
+pub struct Transform {
+    pattern: Pattern,
+    glob: String,
+    command: String,
+}
+
+impl FromStr for Transform {
+    type Err = String;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let (glob, command) = match rule.split_once('=') {
+            Some((glob, command)) if !glob.is_empty() && !command.trim().is_empty() => {
+                (glob, command)
+            }
+            _ => return Err(String::from("expected PATTERN=COMMAND")),
+        };
+        if glob.contains('/') {
+            return Err(String::from(
+                "patterns match file names, so they cannot contain /",
+            ));
+        }
+
+        Ok(Transform {
+            pattern: Pattern::new(glob),
+            glob: glob.to_string(),
+            command: command.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Transform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.glob, self.command)
+    }
+}
+
+/// Configures the transformations of all following copies of the current job
+///
+/// # Arguments
+/// * `transforms`: transformations, of which the first one matching a file applies to it
+pub fn configure(transforms: Vec<Transform>) {
+    *context::current().transforms.lock().unwrap() = transforms;
+}
+
+/// Checks whether any transformation is configured
+pub fn is_configured() -> bool {
+    !context::current().transforms.lock().unwrap().is_empty()
+}
+
+/// Runs all following commands with RUST_LOG set to `level`, like the log of the command line,
+/// without setting it for the whole process
+pub fn set_log_level(level: LevelFilter) {
+    *LOG_LEVEL.lock().unwrap() = Some(level);
+}
+
+/// Gets the command that the file at `path` is copied through, if any
+///
+/// # Arguments
+/// * `path`: path of the file, of which only the name is matched
+pub fn command_for(path: &Path) -> Option<String> {
+    let name = Path::new(path.file_name()?);
+    context::current()
+        .transforms
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|transform| transform.pattern.matches(name, false))
+        .map(|transform| transform.command.clone())
+}
+
+/// Runs `command` with the file `src` on stdin, calling `on_chunk` with every chunk it outputs
+///
+/// # Arguments
+/// * `command`: shell command to run
+/// * `src`: absolute path of the file to transform
+/// * `on_chunk`: called with every chunk output, which stops the command by returning an error
+///
+/// # Returns
+/// The number of bytes output
+///
+/// # Errors
+/// This function will return an error if `src` cannot be read, the command cannot be run or
+/// fails, or `on_chunk` fails
+pub fn run<F>(command: &str, src: &Path, mut on_chunk: F) -> Result<u64, io::Error>
+where
+    F: FnMut(&[u8]) -> Result<(), io::Error>,
+{
+    let mut command = shell(command);
+    if let Some(level) = *LOG_LEVEL.lock().unwrap() {
+        command.env("RUST_LOG", level.to_string().to_lowercase());
+    }
+    let mut child = command
+        .stdin(Stdio::from(fs::File::open(src)?))
+        .stdout(Stdio::piped())
+        .spawn()?;
+    // The pipe is taken so that it is closed before waiting, whether or not it was read to the end
+    let mut stdout = child.stdout.take().unwrap();
+
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut output = 0;
+    let result = loop {
+        match stdout.read(&mut buffer) {
+            Ok(0) => break Ok(()),
+            Ok(read) => {
+                output += read as u64;
+                if let Err(e) = on_chunk(&buffer[..read]) {
+                    break Err(e);
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => break Err(e),
+        }
+    };
+    drop(stdout);
+
+    let status = child.wait()?;
+    result?;
+    if !status.success() {
+        return Err(io::Error::other(format!("{:?} {}", command, status)));
+    }
+    Ok(output)
+}
+
+/// Copies the file `src` to `dest` through `command`, calling `on_chunk` with every chunk written
+///
+/// # Arguments
+/// * `command`: shell command to copy through
+/// * `src`: absolute path of the file to copy
+/// * `dest`: absolute path of the copy
+/// * `on_chunk`: called with every chunk written to `dest`
+///
+/// # Returns
+/// The number of bytes written to `dest`
+///
+/// # Errors
+/// This function will return an error if the command fails, or `dest` cannot be written
+pub fn copy_with<F>(
+    command: &str,
+    src: &Path,
+    dest: &Path,
+    mut on_chunk: F,
+) -> Result<u64, io::Error>
+where
+    F: FnMut(&[u8]) -> Result<(), io::Error>,
+{
+    let mut writer = fs::File::create(dest)?;
+    let written = run(command, src, |chunk| {
+        writer.write_all(chunk)?;
+        on_chunk(chunk)
+    })?;
+    writer.set_permissions(fs::metadata(src)?.permissions())?;
+    Ok(written)
+}
+
+/// Creates a command that runs `command` in the shell of the platform
+#[cfg(target_family = "unix")]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(target_family = "windows")]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_transform {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let transform: Transform = "*.txt=tr a b".parse().unwrap();
+        assert_eq!(transform.to_string(), "*.txt=tr a b");
+        assert_eq!(transform.command, "tr a b");
+        assert_eq!(transform.pattern.matches(Path::new("a.txt"), false), true);
+
+        assert_eq!("*.txt".parse::<Transform>().is_ok(), false);
+        assert_eq!("*.txt=".parse::<Transform>().is_ok(), false);
+        assert_eq!("=cat".parse::<Transform>().is_ok(), false);
+        assert_eq!("docs/*.txt=cat".parse::<Transform>().is_ok(), false);
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn copy_through_command() {
+        const TEST_DIR: &str = "test_transform_copy_through_command";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        let src = Path::new(TEST_DIR).join("src.txt");
+        let dest = Path::new(TEST_DIR).join("dest.txt");
+        fs::write(&src, b"a\r\nb\r\n").unwrap();
+
+        let mut output = Vec::new();
+        let written = copy_with("tr -d '\\r'", &src, &dest, |chunk| {
+            output.extend_from_slice(chunk);
+            Ok(())
+        });
+        assert_eq!(written.unwrap(), 4);
+        assert_eq!(output, b"a\nb\n");
+        assert_eq!(fs::read(&dest).unwrap(), b"a\nb\n");
+
+        assert_eq!(run("exit 3", &src, |_| Ok(())).is_ok(), false);
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn log_level() {
+        const TEST_DIR: &str = "test_transform_log_level";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        let src = Path::new(TEST_DIR).join("src.txt");
+        fs::write(&src, b"").unwrap();
+
+        // Commands get the log level of the command line, which the process itself does not
+        set_log_level(LevelFilter::Error);
+        let mut output = Vec::new();
+        run("printf %s \"$RUST_LOG\"", &src, |chunk| {
+            output.extend_from_slice(chunk);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(output, b"error");
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
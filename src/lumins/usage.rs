@@ -0,0 +1,303 @@
+//! Contains the usage report of `lms du`, which classifies the files of a directory by how long
+//! ago they were last modified or accessed and by their size, to help decide what to exclude
+//! from synchronization or move to colder storage.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use indicatif::HumanBytes;
+use log::warn;
+use rayon::prelude::*;
+
+use crate::lumins::events;
+use crate::lumins::file_ops::{self, FileOps};
+use crate::lumins::parse::{AgeBy, Options, Output};
+use crate::progress;
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// Ages that files are classified by, each holding the files younger than it and no younger
+/// than the one before, followed by the files older than all of them
+const AGE_CLASSES: [(&str, u64); 5] = [
+    ("1 day", DAY),
+    ("1 week", 7 * DAY),
+    ("1 month", 30 * DAY),
+    ("3 months", 90 * DAY),
+    ("1 year", 365 * DAY),
+];
+
+/// Sizes that files are classified by, like `AGE_CLASSES`
+const SIZE_CLASSES: [(&str, u64); 3] = [
+    ("1 MiB", 1 << 20),
+    ("100 MiB", 100 << 20),
+    ("1 GiB", 1 << 30),
+];
+
+/// Struct to represent the number of files and bytes in a class
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Tally {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl Tally {
+    fn add(&mut self, other: Tally) {
+        self.files += other.files;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Struct to represent the files of a directory, classified by age and size
+#[derive(Clone, Debug, Default)]
+pub struct Usage {
+    /// Files of every size class, for every age class
+    classes: [[Tally; SIZE_CLASSES.len() + 1]; AGE_CLASSES.len() + 1],
+}
+
+impl Usage {
+    /// Adds a file of `size` bytes that was last modified or accessed `age` ago
+    pub fn add(&mut self, age: Duration, size: u64) {
+        let age_class = class_of(age.as_secs(), &AGE_CLASSES);
+        let size_class = class_of(size, &SIZE_CLASSES);
+        self.classes[age_class][size_class].add(Tally {
+            files: 1,
+            bytes: size,
+        });
+    }
+
+    /// Adds all files of `other`
+    fn merge(mut self, other: Usage) -> Self {
+        for (ages, other_ages) in self.classes.iter_mut().zip(other.classes.iter()) {
+            for (tally, other_tally) in ages.iter_mut().zip(other_ages.iter()) {
+                tally.add(*other_tally);
+            }
+        }
+        self
+    }
+
+    /// Gets the files of every age class, of all sizes
+    pub fn ages(&self) -> Vec<Tally> {
+        self.classes
+            .iter()
+            .map(|sizes| {
+                let mut total = Tally::default();
+                sizes.iter().for_each(|tally| total.add(*tally));
+                total
+            })
+            .collect()
+    }
+
+    /// Gets all files
+    pub fn total(&self) -> Tally {
+        let mut total = Tally::default();
+        self.ages().into_iter().for_each(|tally| total.add(tally));
+        total
+    }
+
+    /// Formats the usage as a table of the bytes in every age and size class, meant for people
+    ///
+    /// # Arguments
+    /// * `target`: directory the usage is of
+    /// * `age_by`: time that the ages of the files were taken from
+    pub fn to_text(&self, target: &Path, age_by: AgeBy) -> String {
+        let total = self.total();
+        let mut text = format!(
+            "{} files, {} in {} by last {}\n",
+            total.files,
+            HumanBytes(total.bytes),
+            target.display(),
+            age_by
+        );
+
+        let _ = write!(text, "{:<12} {:>10} {:>12}", "age", "files", "size");
+        for label in labels(&SIZE_CLASSES) {
+            let _ = write!(text, " {:>12}", label);
+        }
+        text.push('\n');
+
+        for ((label, sizes), age) in labels(&AGE_CLASSES)
+            .into_iter()
+            .zip(self.classes.iter())
+            .zip(self.ages())
+        {
+            let _ = write!(
+                text,
+                "{:<12} {:>10} {:>12}",
+                label,
+                age.files,
+                HumanBytes(age.bytes).to_string()
+            );
+            for tally in sizes.iter() {
+                let _ = write!(text, " {:>12}", HumanBytes(tally.bytes).to_string());
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Formats the usage as a single JSON object, meant for programs
+    ///
+    /// Classes are given by their upper bound in seconds or bytes, which is null for the last one.
+    ///
+    /// # Arguments
+    /// * `target`: directory the usage is of
+    /// * `age_by`: time that the ages of the files were taken from
+    pub fn to_json(&self, target: &Path, age_by: AgeBy) -> String {
+        let total = self.total();
+        let mut json = format!(
+            "{{\"path\":{},\"age_by\":{},\"files\":{},\"bytes\":{},\"ages\":[",
+            events::json_string(&target.to_string_lossy()),
+            events::json_string(&age_by.to_string()),
+            total.files,
+            total.bytes
+        );
+
+        for (i, (sizes, age)) in self.classes.iter().zip(self.ages()).enumerate() {
+            let _ = write!(
+                json,
+                "{}{{\"max_age_secs\":{},\"files\":{},\"bytes\":{},\"sizes\":[",
+                if i == 0 { "" } else { "," },
+                bound(&AGE_CLASSES, i),
+                age.files,
+                age.bytes
+            );
+            for (j, tally) in sizes.iter().enumerate() {
+                let _ = write!(
+                    json,
+                    "{}{{\"max_size_bytes\":{},\"files\":{},\"bytes\":{}}}",
+                    if j == 0 { "" } else { "," },
+                    bound(&SIZE_CLASSES, j),
+                    tally.files,
+                    tally.bytes
+                );
+            }
+            json.push_str("]}");
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+/// Classifies all files in `target` by age and size, and prints the result to stdout
+///
+/// # Arguments
+/// * `target`: directory to classify the files of
+/// * `options`: command line options, of which `age_by` selects the time that ages are taken
+///   from, and `output` the format of the result
+///
+/// # Errors
+/// This function will return an error if `target` cannot be read
+pub fn report(target: &Path, options: &Options) -> Result<(), io::Error> {
+    progress::scan_init("Scanning");
+    let file_sets = file_ops::get_all_files(target, options)?;
+
+    let now = SystemTime::now();
+    let usage = file_sets
+        .files()
+        .par_iter()
+        .fold(Usage::default, |mut usage, file| {
+            let path = target.join(file.path());
+            let time = fs::metadata(&path).and_then(|metadata| match options.age_by {
+                AgeBy::Modify => metadata.modified(),
+                AgeBy::Access => metadata.accessed(),
+            });
+            match time {
+                // Times in the future count as no age at all
+                Ok(time) => usage.add(now.duration_since(time).unwrap_or_default(), file.size()),
+                Err(e) => warn!(
+                    "Warning -- Skipping {:?}, it cannot be classified: {}",
+                    path, e
+                ),
+            }
+            usage
+        })
+        .reduce(Usage::default, Usage::merge);
+
+    // The scanning spinner must not be drawn over the result
+    progress::bar().finish_and_clear();
+    match options.output {
+        Output::Text => print!("{}", usage.to_text(target, options.age_by)),
+        Output::Json => println!("{}", usage.to_json(target, options.age_by)),
+    }
+    Ok(())
+}
+
+/// Gets the index of the class of `value` among `classes`, which is past the last class if it
+/// is in none of them
+fn class_of(value: u64, classes: &[(&str, u64)]) -> usize {
+    classes
+        .iter()
+        .position(|&(_, max)| value < max)
+        .unwrap_or(classes.len())
+}
+
+/// Gets the labels of `classes`, followed by the label of everything past them
+fn labels(classes: &[(&str, u64)]) -> Vec<String> {
+    let mut labels: Vec<String> = classes
+        .iter()
+        .map(|(label, _)| format!("< {}", label))
+        .collect();
+    labels.push(format!(">= {}", classes[classes.len() - 1].0));
+    labels
+}
+
+/// Gets the upper bound of the class `i` of `classes` as JSON
+fn bound(classes: &[(&str, u64)], i: usize) -> String {
+    match classes.get(i) {
+        Some((_, max)) => max.to_string(),
+        None => String::from("null"),
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_usage {
+    use super::*;
+
+    #[test]
+    fn classes() {
+        let mut usage = Usage::default();
+        usage.add(Duration::from_secs(60), 10);
+        usage.add(Duration::from_secs(2 * DAY), 2 << 20);
+        usage.add(Duration::from_secs(400 * DAY), 2 << 30);
+
+        assert_eq!(
+            usage.ages(),
+            vec![
+                Tally {
+                    files: 1,
+                    bytes: 10
+                },
+                Tally {
+                    files: 1,
+                    bytes: 2 << 20
+                },
+                Tally::default(),
+                Tally::default(),
+                Tally::default(),
+                Tally {
+                    files: 1,
+                    bytes: 2 << 30
+                },
+            ]
+        );
+        assert_eq!(usage.classes[1][1].files, 1);
+        assert_eq!(usage.classes[5][3].files, 1);
+
+        let merged = usage.clone().merge(usage);
+        assert_eq!(merged.total().files, 6);
+
+        let json = merged.to_json(Path::new("dir"), AgeBy::Modify);
+        assert_eq!(
+            json.starts_with("{\"path\":\"dir\",\"age_by\":\"modification\",\"files\":6,"),
+            true
+        );
+        assert_eq!(json.contains("{\"max_size_bytes\":null,\"files\":2,"), true);
+    }
+}
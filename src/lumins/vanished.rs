@@ -0,0 +1,120 @@
+//! Collects the source files that vanished between reading the source and copying them,
+//! which routinely happens in live source trees and is not a failure of the run.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+
+use crate::lumins::context;
+use crate::lumins::events::{self, Action};
+
+/// Checks whether an operation on the source path `path` failed because `path` no longer exists,
+/// rather than because of anything else that could not be found, such as a destination directory
+///
+/// # Arguments
+/// * `path`: path in the source that the operation read
+/// * `e`: error of the operation
+///
+/// # Returns
+/// True if `path` vanished
+pub fn is_vanished(path: &Path, e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::NotFound && fs::symlink_metadata(path).is_err()
+}
+
+/// Records that `path` vanished after the source was read
+///
+/// # Arguments
+/// * `path`: path in the source that vanished
+pub fn record(path: &Path) {
+    events::record(Action::Skip, path, format_args!("Vanished {:?}", path));
+    context::current()
+        .vanished
+        .lock()
+        .unwrap()
+        .insert(path.to_path_buf());
+}
+
+/// Takes everything recorded so far, sorted by path
+///
+/// # Returns
+/// All paths that vanished since the last call
+pub fn take() -> Vec<PathBuf> {
+    let mut vanished: Vec<PathBuf> =
+        std::mem::take(&mut *context::current().vanished.lock().unwrap())
+            .into_iter()
+            .collect();
+    vanished.sort();
+    vanished
+}
+
+/// Reports how many source paths vanished during the run
+///
+/// # Arguments
+/// * `strict`: whether anything vanishing fails the run
+///
+/// # Errors
+/// This function will return an error if `strict` is set and anything vanished
+pub fn report(strict: bool) -> Result<(), io::Error> {
+    let vanished = take();
+    if vanished.is_empty() {
+        return Ok(());
+    }
+
+    warn!(
+        "Warning -- {} source paths vanished before they could be copied",
+        vanished.len()
+    );
+    for path in vanished.iter() {
+        info!("  {:?}", path);
+    }
+
+    if strict {
+        return Err(io::Error::other(format!(
+            "{} source paths vanished during the run",
+            vanished.len()
+        )));
+    }
+    Ok(())
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_vanished {
+    use super::*;
+
+    #[test]
+    fn record_and_report() {
+        record(Path::new("test_vanished_b"));
+        record(Path::new("test_vanished_a"));
+        record(Path::new("test_vanished_b"));
+
+        // Other tests may record concurrently, so only the paths of this test are looked at
+        let vanished: Vec<PathBuf> = take()
+            .into_iter()
+            .filter(|path| path.to_string_lossy().starts_with("test_vanished_"))
+            .collect();
+        assert_eq!(
+            vanished,
+            vec![
+                PathBuf::from("test_vanished_a"),
+                PathBuf::from("test_vanished_b")
+            ]
+        );
+
+        let not_found = io::Error::from(io::ErrorKind::NotFound);
+        assert_eq!(is_vanished(Path::new("test_vanished_a"), &not_found), true);
+        assert_eq!(is_vanished(Path::new("src"), &not_found), false);
+        assert_eq!(
+            is_vanished(
+                Path::new("test_vanished_a"),
+                &io::Error::from(io::ErrorKind::PermissionDenied)
+            ),
+            false
+        );
+    }
+}
@@ -0,0 +1,670 @@
+//! Contains utilities for verifying a restored tree against the manifest of the backup it was
+//! restored from, which is the state recorded in the backup by `lms sync --state`, for verifying
+//! a seeded destination against its source before recording that state, and for verifying and
+//! repairing a source and a destination with `lms verify`.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use hashbrown::{HashMap, HashSet};
+use log::{info, warn};
+use rayon::prelude::*;
+
+use crate::lumins::core;
+use crate::lumins::events::{self, Action};
+use crate::lumins::file_ops::{self, File, FileHash, FileOps, FileSets, HashStore};
+use crate::lumins::parse::{Flag, Options, Side};
+use crate::lumins::state::{self, FileRecord, HashCache, State};
+use crate::progress;
+
+/// Enum to represent how a restored file differs from its manifest
+#[derive(Eq, PartialEq, Ord, PartialOrd, Debug, Clone)]
+pub enum Mismatch {
+    /// The file was not restored
+    Missing,
+    /// The file was restored with a different size
+    Size { expected: u64, actual: u64 },
+    /// The file was restored with different contents
+    Contents,
+    /// The entry exists in the destination, but not in the source
+    Extra,
+    /// The symlink points to a different target
+    Target,
+    /// The entry is of a different type, such as a directory instead of a file
+    Type,
+    /// The entry has different permission bits
+    Mode { expected: u32, actual: u32 },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mismatch::Missing => write!(f, "is missing"),
+            Mismatch::Size { expected, actual } => {
+                write!(f, "has {} bytes instead of {}", actual, expected)
+            }
+            Mismatch::Contents => write!(f, "has different contents"),
+            Mismatch::Extra => write!(f, "is not in the source"),
+            Mismatch::Target => write!(f, "points to a different target"),
+            Mismatch::Type => write!(f, "is of a different type"),
+            Mismatch::Mode { expected, actual } => {
+                write!(f, "has mode {:04o} instead of {:04o}", actual, expected)
+            }
+        }
+    }
+}
+
+/// Verifies every file in `dest` against the manifest recorded in `src`, and reports all
+/// mismatches
+///
+/// # Arguments
+/// * `src`: Backup directory that was restored from, holding the manifest
+/// * `dest`: Directory that was restored to
+/// * `flags`: set for Flag's, of which `STRICT` makes any mismatch fail
+///
+/// # Errors
+/// This function will return an error if `src` holds no manifest, or if `flags` contains `STRICT`
+/// and any file does not match
+pub fn verify_restore(src: &Path, dest: &Path, flags: Flag) -> Result<(), io::Error> {
+    let records = state::recorded_files(src)?.ok_or_else(|| {
+        io::Error::other(format!(
+            "{} holds no manifest to verify against, it must be synchronized with --state",
+            src.display()
+        ))
+    })?;
+
+    progress::phase_init("Verifying restore", records.len() as u64);
+    let mismatches = compare(&records, dest);
+    info!(
+        "Verified {} restored files in {:?} against the manifest of {:?}",
+        records.len(),
+        dest,
+        src
+    );
+    if mismatches.is_empty() {
+        return Ok(());
+    }
+
+    warn!(
+        "Warning -- {} restored files do not match the manifest:",
+        mismatches.len()
+    );
+    for (path, mismatch) in mismatches.iter() {
+        warn!("  {:?} {}", path, mismatch);
+    }
+
+    if flags.contains(Flag::STRICT) {
+        return Err(io::Error::other(format!(
+            "{} restored files do not match the manifest",
+            mismatches.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Verifies that `dest`, such as a copy seeded from a disk that was shipped, holds exactly what
+/// is in `src`, and records the state of `src` in `dest` if it does
+///
+/// Once the state and the hashes of both sides are recorded, the next `lms sync --state` of `src`
+/// to `dest` finds nothing to copy and need not hash unchanged files again.
+///
+/// # Arguments
+/// * `src`: Source directory
+/// * `dest`: Destination directory that was seeded from `src`
+/// * `options`: command line options, of which `SECURE` selects the hash function
+///
+/// # Errors
+/// This function will return an error if either directory cannot be read, the state cannot be
+/// recorded, or anything in `dest` does not match `src`
+pub fn seed_check(src: &Path, dest: &Path, options: &Options) -> Result<(), io::Error> {
+    let state = State::open(src, dest, options.flags | Flag::STATE)?;
+    let _cache = if options.flags.contains(Flag::NO_CACHE) {
+        None
+    } else {
+        Some(HashCache::open(dest)?)
+    };
+
+    progress::scan_init("Scanning source");
+    let src_file_sets = file_ops::get_all_files_with_state(src, options, Some(&state))?;
+    progress::scan_init("Scanning destination");
+    let dest_file_sets = file_ops::get_all_files(dest, options)?;
+
+    let (mut mismatches, files_to_compare) = compare_entries(&src_file_sets, &dest_file_sets);
+
+    progress::phase_init("Comparing", files_to_compare.len() as u64);
+    let hashes = HashStore::default();
+    // Seeding records the hashes of all files, so every file is hashed
+    let differing = file_ops::differing_files(
+        files_to_compare.into_par_iter(),
+        src,
+        dest,
+        options.flags | Flag::CHECKSUM,
+        &hashes,
+    );
+    mismatches.extend(
+        differing
+            .into_iter()
+            .map(|file| (file.path().clone(), Mismatch::Contents)),
+    );
+    mismatches.sort();
+
+    if !mismatches.is_empty() {
+        warn!(
+            "Warning -- {} entries of {:?} do not match {:?}:",
+            mismatches.len(),
+            dest,
+            src
+        );
+        for (path, mismatch) in mismatches.iter() {
+            warn!("  {:?} {}", path, mismatch);
+        }
+        return Err(io::Error::other(format!(
+            "{} does not match {}, no state was recorded",
+            dest.display(),
+            src.display()
+        )));
+    }
+
+    state.commit(src_file_sets.skipped(), &hashes)?;
+    info!(
+        "Verified {} files in {:?} against {:?} and recorded their state",
+        src_file_sets.files().len(),
+        dest,
+        src
+    );
+    Ok(())
+}
+
+/// Verifies that `src` and `dest` hold exactly the same entries, and reports all mismatches
+///
+/// With `--repair`, every mismatch is repaired by synchronizing the mismatched entries from the
+/// side taken as correct to the other side, so that a verification also fixes what it finds.
+/// Both sides are verified again once they are repaired.
+///
+/// # Arguments
+/// * `src`: Source directory
+/// * `dest`: Destination directory
+/// * `options`: command line options, of which `repair` selects the side taken as correct
+///
+/// # Errors
+/// This function will return an error if either directory cannot be read, anything does not
+/// match without `repair`, or anything still does not match once it is repaired
+pub fn verify(src: &Path, dest: &Path, options: &Options) -> Result<(), io::Error> {
+    let mismatches = find_mismatches(src, dest, options)?;
+    if mismatches.is_empty() {
+        return Ok(());
+    }
+    for (path, mismatch) in mismatches.iter() {
+        events::record(
+            Action::Warning,
+            path,
+            format_args!("Warning -- {:?} {}", path, mismatch),
+        );
+    }
+
+    let (from, to) = match options.repair {
+        Some(Side::Src) => (src, dest),
+        Some(Side::Dest) => (dest, src),
+        None => {
+            return Err(io::Error::other(format!(
+                "{} entries of {} do not match {}",
+                mismatches.len(),
+                dest.display(),
+                src.display()
+            )))
+        }
+    };
+
+    // Only the mismatched entries are synchronized, everything else is known to match, and they
+    // are compared by their contents since that is how they were found to mismatch, without the
+    // hash cache, which takes a file changed in place for the file it cached
+    info!(
+        "Repairing {} entries of {:?} from {:?}",
+        mismatches.len(),
+        to,
+        from
+    );
+    let repair_options = Options {
+        only: mismatches.into_iter().map(|(path, _)| path).collect(),
+        flags: options.flags | Flag::CHECKSUM | Flag::NO_CACHE,
+        ..options.clone()
+    };
+    core::synchronize(from, to, &repair_options)?;
+
+    let remaining = find_mismatches(src, dest, options)?;
+    for (path, mismatch) in remaining.iter() {
+        events::record(
+            Action::Error,
+            path,
+            format_args!("Error -- {:?} {} after repairing it", path, mismatch),
+        );
+    }
+    if !remaining.is_empty() {
+        return Err(io::Error::other(format!(
+            "{} entries of {} still do not match {} after repairing them",
+            remaining.len(),
+            dest.display(),
+            src.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Compares every entry of `src` and `dest`, including the contents of files even where their
+/// sizes and modification times match
+///
+/// # Arguments
+/// * `src`: Source directory
+/// * `dest`: Destination directory
+/// * `options`: command line options
+///
+/// # Returns
+/// The entries that do not match, sorted by path
+///
+/// # Errors
+/// This function will return an error if either directory cannot be read
+fn find_mismatches(
+    src: &Path,
+    dest: &Path,
+    options: &Options,
+) -> Result<Vec<(PathBuf, Mismatch)>, io::Error> {
+    progress::scan_init("Scanning source");
+    let src_file_sets = file_ops::get_all_files(src, options)?;
+    progress::scan_init("Scanning destination");
+    let dest_file_sets = file_ops::get_all_files(dest, options)?;
+
+    let (mut mismatches, files_to_compare) = compare_entries(&src_file_sets, &dest_file_sets);
+
+    progress::phase_init("Comparing", files_to_compare.len() as u64);
+    let differing = file_ops::differing_files(
+        files_to_compare.into_par_iter(),
+        src,
+        dest,
+        options.flags | Flag::CHECKSUM,
+        &HashStore::default(),
+    );
+    mismatches.extend(
+        differing
+            .into_iter()
+            .map(|file| (file.path().clone(), Mismatch::Contents)),
+    );
+    mismatches.sort();
+
+    info!(
+        "Verified {} files in {:?} against {:?}",
+        src_file_sets.files().len(),
+        dest,
+        src
+    );
+    Ok(mismatches)
+}
+
+/// Compares the entries of a source and a destination by path, without reading any file
+///
+/// # Arguments
+/// * `src_file_sets`: files, dirs, and symlinks in the source
+/// * `dest_file_sets`: files, dirs, and symlinks in the destination
+///
+/// # Returns
+/// The entries that do not match, and the files whose contents must be compared
+fn compare_entries<'a>(
+    src_file_sets: &'a FileSets,
+    dest_file_sets: &FileSets,
+) -> (Vec<(PathBuf, Mismatch)>, Vec<&'a File>) {
+    let mut mismatches = Vec::new();
+    let mut files_to_compare = Vec::new();
+
+    let dest_sizes: HashMap<&PathBuf, u64> = dest_file_sets
+        .files()
+        .iter()
+        .map(|file| (file.path(), file.size()))
+        .collect();
+    for file in src_file_sets.files() {
+        match dest_sizes.get(file.path()) {
+            None => mismatches.push((file.path().clone(), Mismatch::Missing)),
+            Some(&actual) if actual != file.size() => mismatches.push((
+                file.path().clone(),
+                Mismatch::Size {
+                    expected: file.size(),
+                    actual,
+                },
+            )),
+            Some(_) => files_to_compare.push(file),
+        }
+    }
+
+    let src_targets: HashMap<&PathBuf, &Path> = src_file_sets
+        .symlinks()
+        .iter()
+        .map(|symlink| (symlink.path(), symlink.target()))
+        .collect();
+    let dest_targets: HashMap<&PathBuf, &Path> = dest_file_sets
+        .symlinks()
+        .iter()
+        .map(|symlink| (symlink.path(), symlink.target()))
+        .collect();
+    for (path, target) in src_targets.iter() {
+        match dest_targets.get(path) {
+            None => mismatches.push(((*path).clone(), Mismatch::Missing)),
+            Some(dest_target) if dest_target != target => {
+                mismatches.push(((*path).clone(), Mismatch::Target))
+            }
+            Some(_) => (),
+        }
+    }
+
+    mismatches.extend(
+        src_file_sets
+            .dirs()
+            .difference(dest_file_sets.dirs())
+            .map(|dir| (dir.path().clone(), Mismatch::Missing)),
+    );
+
+    // Everything in the destination must also be in the source
+    let src_paths: HashSet<&PathBuf> = src_file_sets
+        .files()
+        .iter()
+        .map(FileOps::path)
+        .chain(src_file_sets.dirs().iter().map(FileOps::path))
+        .chain(src_targets.keys().copied())
+        .collect();
+    mismatches.extend(
+        dest_file_sets
+            .files()
+            .iter()
+            .map(FileOps::path)
+            .chain(dest_file_sets.dirs().iter().map(FileOps::path))
+            .chain(dest_targets.keys().copied())
+            .filter(|path| !src_paths.contains(path))
+            .map(|path| (path.clone(), Mismatch::Extra)),
+    );
+
+    (mismatches, files_to_compare)
+}
+
+/// Compares the files in `dest` with their records, in parallel
+///
+/// Contents are only compared for records that hold a hash, with the same hash function.
+///
+/// # Arguments
+/// * `records`: recorded files, with their paths relative to `dest`
+/// * `dest`: base directory of the files to compare
+///
+/// # Returns
+/// The files that do not match their records, sorted by path
+fn compare(records: &[(PathBuf, FileRecord)], dest: &Path) -> Vec<(PathBuf, Mismatch)> {
+    let mut mismatches: Vec<(PathBuf, Mismatch)> = records
+        .par_iter()
+        .filter_map(|(path, record)| {
+            progress::bar().inc(1);
+            compare_file(path, record, dest).map(|mismatch| (path.clone(), mismatch))
+        })
+        .collect();
+    mismatches.sort();
+    mismatches
+}
+
+/// Compares a single file in `dest` with its record
+///
+/// # Returns
+/// * Some: How the file differs from its record
+/// * None: If the file matches its record
+fn compare_file(path: &Path, record: &FileRecord, dest: &Path) -> Option<Mismatch> {
+    let metadata = match fs::metadata(dest.join(path)) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => return Some(Mismatch::Missing),
+    };
+    if metadata.len() != record.size {
+        return Some(Mismatch::Size {
+            expected: record.size,
+            actual: metadata.len(),
+        });
+    }
+
+    let flags = match record.hash {
+        Some(FileHash::Fast(_)) => Flag::empty(),
+        Some(FileHash::Secure(_)) => Flag::SECURE,
+        None => return None,
+    };
+    let file = File::with_path(path.to_path_buf(), metadata.len());
+    if file_ops::hash_file_with_flags(&file, dest, flags) != record.hash {
+        return Some(Mismatch::Contents);
+    }
+    None
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod test_verify {
+    use super::*;
+    use crate::lumins::file_ops::HashStore;
+    use crate::lumins::state::State;
+    use hashbrown::HashSet;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn restore_mismatches() {
+        const TEST_SRC: &str = "test_verify_restore_mismatches_src";
+        const TEST_BACKUP: &str = "test_verify_restore_mismatches_backup";
+        const TEST_RESTORE: &str = "test_verify_restore_mismatches_restore";
+        const TEST_FILES: [(&str, &[u8]); 4] = [
+            ("same.txt", b"same"),
+            ("changed.txt", b"original"),
+            ("truncated.txt", b"original"),
+            ("missing.txt", b"original"),
+        ];
+
+        fs::create_dir_all(TEST_SRC).unwrap();
+        fs::create_dir_all(TEST_BACKUP).unwrap();
+        fs::create_dir_all(TEST_RESTORE).unwrap();
+
+        assert_eq!(
+            verify_restore(
+                Path::new(TEST_BACKUP),
+                Path::new(TEST_RESTORE),
+                Flag::empty()
+            )
+            .is_err(),
+            true
+        );
+
+        {
+            let state =
+                State::open(Path::new(TEST_SRC), Path::new(TEST_BACKUP), Flag::STATE).unwrap();
+            let hashes = HashStore::default();
+            for (file, contents) in TEST_FILES.iter() {
+                let record = FileRecord {
+                    size: contents.len() as u64,
+                    modified: UNIX_EPOCH,
+                    inode: 0,
+                    hash: None,
+                };
+                state.observe_file(Path::new(file), record);
+                hashes.insert(PathBuf::from(file), FileHash::Fast(seahash::hash(contents)));
+            }
+            state.commit(&HashSet::new(), &hashes).unwrap();
+        }
+
+        fs::write([TEST_RESTORE, "same.txt"].join("/"), b"same").unwrap();
+        fs::write([TEST_RESTORE, "changed.txt"].join("/"), b"0riginal").unwrap();
+        fs::write([TEST_RESTORE, "truncated.txt"].join("/"), b"orig").unwrap();
+
+        let records = state::recorded_files(Path::new(TEST_BACKUP))
+            .unwrap()
+            .unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(
+            compare(&records, Path::new(TEST_RESTORE)),
+            vec![
+                (PathBuf::from("changed.txt"), Mismatch::Contents),
+                (PathBuf::from("missing.txt"), Mismatch::Missing),
+                (
+                    PathBuf::from("truncated.txt"),
+                    Mismatch::Size {
+                        expected: 8,
+                        actual: 4
+                    }
+                ),
+            ]
+        );
+
+        assert_eq!(
+            verify_restore(
+                Path::new(TEST_BACKUP),
+                Path::new(TEST_RESTORE),
+                Flag::empty()
+            )
+            .is_ok(),
+            true
+        );
+        assert_eq!(
+            verify_restore(
+                Path::new(TEST_BACKUP),
+                Path::new(TEST_RESTORE),
+                Flag::STRICT
+            )
+            .is_err(),
+            true
+        );
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_BACKUP).unwrap();
+        fs::remove_dir_all(TEST_RESTORE).unwrap();
+    }
+
+    #[test]
+    fn seed_mismatches() {
+        const TEST_SRC: &str = "test_verify_seed_mismatches_src";
+        const TEST_DEST: &str = "test_verify_seed_mismatches_dest";
+
+        for dir in [TEST_SRC, TEST_DEST].iter() {
+            fs::create_dir_all([dir, "dir"].join("/")).unwrap();
+            fs::write([dir, "dir/same.txt"].join("/"), b"same").unwrap();
+            fs::write([dir, "changed.txt"].join("/"), b"original").unwrap();
+        }
+
+        let options = Options::default();
+        assert_eq!(
+            seed_check(Path::new(TEST_SRC), Path::new(TEST_DEST), &options).is_ok(),
+            true
+        );
+
+        let records = state::recorded_files(Path::new(TEST_DEST))
+            .unwrap()
+            .unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            records.iter().all(|(_, record)| record.hash.is_some()),
+            true
+        );
+
+        fs::write([TEST_DEST, "changed.txt"].join("/"), b"0riginal").unwrap();
+        fs::write([TEST_DEST, "extra.txt"].join("/"), b"extra").unwrap();
+        fs::remove_file([TEST_DEST, "dir/same.txt"].join("/")).unwrap();
+
+        let src_file_sets = file_ops::get_all_files(Path::new(TEST_SRC), &options).unwrap();
+        let dest_file_sets = file_ops::get_all_files(Path::new(TEST_DEST), &options).unwrap();
+        let (mut mismatches, files_to_compare) = compare_entries(&src_file_sets, &dest_file_sets);
+        mismatches.sort();
+        assert_eq!(
+            mismatches,
+            vec![
+                (PathBuf::from("dir/same.txt"), Mismatch::Missing),
+                (PathBuf::from("extra.txt"), Mismatch::Extra),
+            ]
+        );
+        assert_eq!(files_to_compare.len(), 1);
+
+        assert_eq!(
+            seed_check(Path::new(TEST_SRC), Path::new(TEST_DEST), &options).is_err(),
+            true
+        );
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+
+    #[test]
+    fn repair_mismatches() {
+        const TEST_SRC: &str = "test_verify_repair_mismatches_src";
+        const TEST_DEST: &str = "test_verify_repair_mismatches_dest";
+
+        for dir in [TEST_SRC, TEST_DEST].iter() {
+            fs::create_dir_all([dir, "dir"].join("/")).unwrap();
+            fs::write([dir, "dir/same.txt"].join("/"), b"same").unwrap();
+        }
+        fs::write([TEST_SRC, "changed.txt"].join("/"), b"original").unwrap();
+        fs::write([TEST_DEST, "changed.txt"].join("/"), b"0riginal").unwrap();
+        fs::write([TEST_DEST, "extra.txt"].join("/"), b"extra").unwrap();
+
+        let mut options = Options::default();
+        assert_eq!(
+            verify(Path::new(TEST_SRC), Path::new(TEST_DEST), &options).is_err(),
+            true
+        );
+
+        // The destination is taken as correct, so the source is repaired to match it
+        options.repair = Some(Side::Dest);
+        assert_eq!(
+            verify(Path::new(TEST_SRC), Path::new(TEST_DEST), &options).is_ok(),
+            true
+        );
+        assert_eq!(
+            fs::read([TEST_SRC, "changed.txt"].join("/")).unwrap(),
+            b"0riginal"
+        );
+        assert_eq!(
+            fs::read([TEST_SRC, "extra.txt"].join("/")).unwrap(),
+            b"extra"
+        );
+
+        options.repair = None;
+        assert_eq!(
+            verify(Path::new(TEST_SRC), Path::new(TEST_DEST), &options).is_ok(),
+            true
+        );
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+
+    #[test]
+    fn repair_in_place() {
+        const TEST_SRC: &str = "test_verify_repair_in_place_src";
+        const TEST_DEST: &str = "test_verify_repair_in_place_dest";
+
+        fs::create_dir_all(TEST_SRC).unwrap();
+        fs::create_dir_all(TEST_DEST).unwrap();
+        let src_file = Path::new(TEST_SRC).join("file.txt");
+        let dest_file = Path::new(TEST_DEST).join("file.txt");
+        fs::write(&src_file, b"original").unwrap();
+        fs::write(&dest_file, b"0riginal").unwrap();
+        file_ops::copy_times(&src_file, &dest_file).unwrap();
+
+        // The corrupted dest file keeps the size and time that its cached hash was recorded with
+        {
+            let cache = HashCache::open(Path::new(TEST_DEST)).unwrap();
+            let metadata = fs::metadata(&dest_file).unwrap();
+            let hash = FileHash::Fast(seahash::hash(b"original"));
+            cache.hashes().insert(&dest_file, &metadata, &hash);
+        }
+
+        let options = Options {
+            repair: Some(Side::Src),
+            ..Options::default()
+        };
+        assert_eq!(
+            verify(Path::new(TEST_SRC), Path::new(TEST_DEST), &options).is_ok(),
+            true
+        );
+        assert_eq!(fs::read(&dest_file).unwrap(), b"original");
+
+        fs::remove_dir_all(TEST_SRC).unwrap();
+        fs::remove_dir_all(TEST_DEST).unwrap();
+    }
+}
@@ -0,0 +1,299 @@
+//! Contains the copying of extended attributes given with --xattrs, which carries the user and
+//! trusted attributes of files, dirs and symlinks over to the destination, and the comparison
+//! that lets a change to the attributes alone still update a file.
+
+use std::ffi::CString;
+use std::io;
+use std::path::Path;
+
+use hashbrown::HashMap;
+use log::info;
+
+/// Names and values of the extended attributes of a path
+type Attributes = HashMap<Vec<u8>, Vec<u8>>;
+
+/// Copies the extended attributes of `src` onto `dest`, without following symlinks, removing
+/// those that `dest` has but `src` does not
+///
+/// # Arguments
+/// * `src`: absolute path of the file, dir, or symlink to copy the attributes of
+/// * `dest`: absolute path of the file, dir, or symlink to copy the attributes to
+///
+/// # Errors
+/// This function will return an error if the attributes of `src` cannot be read,
+/// or those of `dest` cannot be changed
+pub fn copy(src: &Path, dest: &Path) -> Result<(), io::Error> {
+    let src_attributes = read(src)?;
+    let dest_attributes = read(dest)?;
+    if src_attributes == dest_attributes {
+        return Ok(());
+    }
+
+    for (name, value) in src_attributes.iter() {
+        if dest_attributes.get(name) != Some(value) {
+            sys::set(dest, &c_name(name)?, value)?;
+        }
+    }
+    for name in dest_attributes.keys() {
+        if !src_attributes.contains_key(name) {
+            sys::remove(dest, &c_name(name)?)?;
+        }
+    }
+    info!("Changing extended attributes of {:?}", dest);
+    Ok(())
+}
+
+/// Checks whether `src` and `dest` have different extended attributes, taking paths whose
+/// attributes cannot be read as different
+pub fn differ(src: &Path, dest: &Path) -> bool {
+    match (read(src), read(dest)) {
+        (Ok(src_attributes), Ok(dest_attributes)) => src_attributes != dest_attributes,
+        _ => true,
+    }
+}
+
+/// Reads the extended attributes of `path` that are preserved, without following symlinks
+///
+/// # Errors
+/// This function will return an error if the attributes cannot be listed or read
+fn read(path: &Path) -> Result<Attributes, io::Error> {
+    let names = sys::list(path)?;
+    let mut attributes = HashMap::new();
+    for name in names.split(|&byte| byte == 0) {
+        if name.is_empty() || !sys::is_preserved(name) {
+            continue;
+        }
+        match sys::get(path, &c_name(name)?) {
+            Ok(value) => {
+                attributes.insert(name.to_vec(), value);
+            }
+            // The attribute was removed since it was listed
+            Err(ref e) if e.raw_os_error() == Some(sys::ENOATTR) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(attributes)
+}
+
+/// Converts the name of an attribute for the system calls
+fn c_name(name: &[u8]) -> Result<CString, io::Error> {
+    CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Converts `path` for the system calls
+#[cfg(target_family = "unix")]
+fn c_path(path: &Path) -> Result<CString, io::Error> {
+    use std::os::unix::ffi::OsStrExt;
+
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Reads a list or value of unknown length with `call`, which returns the length of the whole
+/// list or value when given an empty buffer, and retries if it grew in between
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+fn read_sized<F>(mut call: F) -> Result<Vec<u8>, io::Error>
+where
+    F: FnMut(*mut libc::c_void, libc::size_t) -> libc::ssize_t,
+{
+    loop {
+        let len = call(std::ptr::null_mut(), 0);
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buffer = vec![0u8; len as usize];
+        let read = call(buffer.as_mut_ptr() as *mut libc::c_void, buffer.len());
+        if read >= 0 {
+            buffer.truncate(read as usize);
+            return Ok(buffer);
+        }
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::ERANGE) {
+            return Err(e);
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod sys {
+    use super::{c_path, read_sized};
+    use std::ffi::CStr;
+    use std::io;
+    use std::path::Path;
+
+    /// Error of an attribute that does not exist
+    pub const ENOATTR: i32 = libc::ENODATA;
+
+    /// Checks whether the attribute `name` is preserved, out of the user and trusted namespaces,
+    /// since security and system attributes belong to the system the file is on
+    pub fn is_preserved(name: &[u8]) -> bool {
+        name.starts_with(b"user.") || name.starts_with(b"trusted.")
+    }
+
+    pub fn list(path: &Path) -> Result<Vec<u8>, io::Error> {
+        let path = c_path(path)?;
+        read_sized(|buffer, size| unsafe {
+            libc::llistxattr(path.as_ptr(), buffer as *mut libc::c_char, size)
+        })
+    }
+
+    pub fn get(path: &Path, name: &CStr) -> Result<Vec<u8>, io::Error> {
+        let path = c_path(path)?;
+        read_sized(|buffer, size| unsafe {
+            libc::lgetxattr(path.as_ptr(), name.as_ptr(), buffer, size)
+        })
+    }
+
+    pub fn set(path: &Path, name: &CStr, value: &[u8]) -> Result<(), io::Error> {
+        let path = c_path(path)?;
+        let value_ptr = value.as_ptr() as *const libc::c_void;
+        match unsafe { libc::lsetxattr(path.as_ptr(), name.as_ptr(), value_ptr, value.len(), 0) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    pub fn remove(path: &Path, name: &CStr) -> Result<(), io::Error> {
+        let path = c_path(path)?;
+        match unsafe { libc::lremovexattr(path.as_ptr(), name.as_ptr()) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod sys {
+    use super::{c_path, read_sized};
+    use std::ffi::CStr;
+    use std::io;
+    use std::path::Path;
+
+    /// Error of an attribute that does not exist
+    pub const ENOATTR: i32 = libc::ENOATTR;
+
+    /// Checks whether the attribute `name` is preserved, which all are, since macOS has no
+    /// namespaces for attributes
+    pub fn is_preserved(_name: &[u8]) -> bool {
+        true
+    }
+
+    pub fn list(path: &Path) -> Result<Vec<u8>, io::Error> {
+        let path = c_path(path)?;
+        read_sized(|buffer, size| unsafe {
+            libc::listxattr(
+                path.as_ptr(),
+                buffer as *mut libc::c_char,
+                size,
+                libc::XATTR_NOFOLLOW,
+            )
+        })
+    }
+
+    pub fn get(path: &Path, name: &CStr) -> Result<Vec<u8>, io::Error> {
+        let path = c_path(path)?;
+        read_sized(|buffer, size| unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                buffer,
+                size,
+                0,
+                libc::XATTR_NOFOLLOW,
+            )
+        })
+    }
+
+    pub fn set(path: &Path, name: &CStr, value: &[u8]) -> Result<(), io::Error> {
+        let path = c_path(path)?;
+        let value_ptr = value.as_ptr() as *const libc::c_void;
+        let result = unsafe {
+            libc::setxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value_ptr,
+                value.len(),
+                0,
+                libc::XATTR_NOFOLLOW,
+            )
+        };
+        match result {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    pub fn remove(path: &Path, name: &CStr) -> Result<(), io::Error> {
+        let path = c_path(path)?;
+        match unsafe { libc::removexattr(path.as_ptr(), name.as_ptr(), libc::XATTR_NOFOLLOW) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+mod sys {
+    use std::ffi::CStr;
+    use std::io;
+    use std::path::Path;
+
+    /// Error of an attribute that does not exist, which cannot occur without attributes
+    pub const ENOATTR: i32 = -1;
+
+    pub fn is_preserved(_name: &[u8]) -> bool {
+        false
+    }
+
+    /// Lists no attributes, so that paths on systems without them are taken as the same
+    pub fn list(_path: &Path) -> Result<Vec<u8>, io::Error> {
+        Ok(Vec::new())
+    }
+
+    pub fn get(_path: &Path, _name: &CStr) -> Result<Vec<u8>, io::Error> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    pub fn set(_path: &Path, _name: &CStr, _value: &[u8]) -> Result<(), io::Error> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    pub fn remove(_path: &Path, _name: &CStr) -> Result<(), io::Error> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tests
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(all(test, target_os = "linux"))]
+mod test_xattrs {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn copied() {
+        const TEST_DIR: &str = "test_xattrs_copied";
+
+        fs::create_dir_all(TEST_DIR).unwrap();
+        let src = Path::new(TEST_DIR).join("src.txt");
+        let dest = Path::new(TEST_DIR).join("dest.txt");
+        fs::write(&src, b"contents").unwrap();
+        fs::write(&dest, b"contents").unwrap();
+
+        let name = c_name(b"user.lumins.test").unwrap();
+        sys::set(&src, &name, b"value").unwrap();
+        sys::set(&dest, &c_name(b"user.lumins.stale").unwrap(), b"stale").unwrap();
+        assert_eq!(differ(&src, &dest), true);
+
+        // Attributes only in dest are removed
+        assert_eq!(copy(&src, &dest).is_ok(), true);
+        assert_eq!(differ(&src, &dest), false);
+        assert_eq!(read(&dest).unwrap(), read(&src).unwrap());
+        assert_eq!(sys::get(&dest, &name).unwrap(), b"value");
+
+        fs::remove_dir_all(TEST_DIR).unwrap();
+    }
+}
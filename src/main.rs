@@ -1,39 +1,159 @@
+use std::path::{Path, PathBuf};
 use std::process;
+use std::time::{Instant, SystemTime};
 
 use clap::{load_yaml, App};
 
+use lms::config;
+use lms::context;
 use lms::core;
-use lms::parse::{self, SubCommandType};
-use lms::progress::PROGRESS_BAR;
+use lms::dedupe;
+use lms::events;
+use lms::history;
+use lms::ingest;
+use lms::journal;
+use lms::listing;
+use lms::locale;
+use lms::mtree;
+use lms::parse::{self, Output, SubCommandType};
+use lms::progress::{self, ProgressReporter};
+use lms::report;
+use lms::rollback;
+use lms::session;
+use lms::usage;
+use lms::verify;
 
 fn main() {
+    let started = SystemTime::now();
+    let timer = Instant::now();
+
+    // Messages are translated from the start, since parsing the args already reports errors
+    if let Err(e) = locale::configure() {
+        eprintln!("{}", e);
+    }
+
     // Parse command args
     let yaml = load_yaml!("cli.yml");
     let args = App::from_yaml(yaml).get_matches();
 
     // Determine subcommands and flags from args
-    let (sub_command, flags) = match parse::parse_args(&args) {
-        Ok(f) => (f.sub_command, f.flags),
+    let (sub_command, options) = match parse::parse_args(&args) {
+        Ok(f) => (f.sub_command, f.options),
         Err(_) => process::exit(1),
     };
 
-    parse::set_env(flags);
+    // The session must be started before logging, so that every record reaches its log
+    if let Some(log_dir) = &options.log_dir {
+        if let Err(e) = session::start(log_dir) {
+            eprintln!(
+                "{}",
+                locale::text(
+                    "log-dir-start-error",
+                    &[("path", &log_dir.display()), ("error", &e)]
+                )
+            );
+            process::exit(1);
+        }
+    }
+
+    parse::set_env(options.flags, options.log_level);
+    context::configure(&options);
+    // The runs shown by `lms history` and the checks of `lms config check` are not actions of
+    // this run
+    let is_report = matches!(
+        sub_command.sub_command_type,
+        SubCommandType::History | SubCommandType::ConfigCheck
+    );
+    events::configure(options.output == Output::Json && !is_report);
+    report::configure(
+        options.report.clone(),
+        sub_command
+            .src
+            .iter()
+            .chain(sub_command.dest.iter())
+            .cloned()
+            .collect(),
+    );
+
+    let progress_reporter = match options
+        .progress_fd
+        .as_deref()
+        .map(ProgressReporter::start)
+        .transpose()
+    {
+        Ok(progress_reporter) => progress_reporter,
+        Err(e) => {
+            eprintln!("{}", locale::text("progress-fd-error", &[("error", &e)]));
+            process::exit(1);
+        }
+    };
 
     // Call correct core function depending on subcommand
-    let result = match sub_command.sub_command_type {
-        SubCommandType::Copy => core::copy(sub_command.src.unwrap(), &sub_command.dest[0], flags),
+    let dests: Vec<&Path> = sub_command.dest.iter().map(PathBuf::as_path).collect();
+    let result = parse::run_with_threads(&options, || match sub_command.sub_command_type {
+        SubCommandType::Copy => {
+            core::copy_all(sub_command.src.as_deref().unwrap(), &dests, &options)
+        }
         SubCommandType::Remove => sub_command
             .dest
             .iter()
-            .map(|dest| core::remove(dest, flags))
+            .map(|dest| core::remove(dest, &options))
             .collect(),
         SubCommandType::Synchronize => {
-            core::synchronize(sub_command.src.unwrap(), &sub_command.dest[0], flags)
+            core::synchronize_all(sub_command.src.as_deref().unwrap(), &dests, &options)
         }
-    };
+        SubCommandType::Journal => {
+            journal::record(sub_command.src.as_deref().unwrap(), &sub_command.dest[0])
+        }
+        SubCommandType::Ingest => ingest::watch(
+            sub_command.src.as_deref().unwrap(),
+            &sub_command.dest[0],
+            &options,
+        ),
+        SubCommandType::SeedCheck => verify::seed_check(
+            sub_command.src.as_deref().unwrap(),
+            &sub_command.dest[0],
+            &options,
+        ),
+        SubCommandType::Dedupe => dedupe::dedupe(&sub_command.dest[0], &options),
+        SubCommandType::Rollback => rollback::rollback(&sub_command.dest[0]),
+        SubCommandType::Usage => usage::report(&sub_command.dest[0], &options),
+        SubCommandType::Verify => verify::verify(
+            sub_command.src.as_deref().unwrap(),
+            &sub_command.dest[0],
+            &options,
+        ),
+        SubCommandType::List => listing::list(&sub_command.dest[0], &options),
+        SubCommandType::MtreeCheck => mtree::check(
+            sub_command.src.as_deref().unwrap(),
+            &sub_command.dest[0],
+            &options,
+        ),
+        SubCommandType::History => history::show(options.profile.as_deref().unwrap(), &options),
+        SubCommandType::ConfigCheck => config::check(options.profile.as_deref()),
+    });
 
     // End and remove progress bars
-    PROGRESS_BAR.finish_and_clear();
+    progress::bar().finish_and_clear();
+    if let Some(progress_reporter) = progress_reporter {
+        progress_reporter.finish(result.is_ok());
+    }
+    events::summary(result.as_ref().err());
+    if let Err(e) = report::write(result.as_ref().err()) {
+        eprintln!("{}", locale::text("report-error", &[("error", &e)]));
+    }
+    if !is_report {
+        if let Some(profile) = &options.profile {
+            if let Err(e) =
+                history::record(profile, started, timer.elapsed(), result.as_ref().err())
+            {
+                eprintln!("{}", locale::text("profile-error", &[("error", &e)]));
+            }
+        }
+    }
+    if let Err(e) = session::finish(result.as_ref().err()) {
+        eprintln!("{}", locale::text("log-dir-error", &[("error", &e)]));
+    }
 
     // If error, print to stderr and exit
     if let Err(e) = result {
@@ -284,7 +404,7 @@ mod test_main {
             .unwrap();
 
         Command::new("target/release/lms")
-            .args(&["rm", TEST_DEST])
+            .args(&["rm", "--permanent", TEST_DEST])
             .output()
             .unwrap();
 
@@ -315,7 +435,7 @@ mod test_main {
             .unwrap();
 
         Command::new("target/release/lms")
-            .args(&["rm", TEST_DEST[0], TEST_DEST[1]])
+            .args(&["rm", "--permanent", TEST_DEST[0], TEST_DEST[1]])
             .output()
             .unwrap();
 
@@ -341,7 +461,7 @@ mod test_main {
             .unwrap();
 
         Command::new("target/release/lms")
-            .args(&["rm", "-S", TEST_DEST])
+            .args(&["rm", "--permanent", "-S", TEST_DEST])
             .output()
             .unwrap();
 